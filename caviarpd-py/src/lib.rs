@@ -0,0 +1,334 @@
+// Python bindings for the CaviarPD method
+
+use epa::api::{self, CaviarpdOptions, Draws, Loss, SamplerOptions};
+use epa::epa::{Anchors, Attraction, Constraints, Noise, SquareMatrixBorrower};
+use numpy::ndarray::Array2;
+use numpy::{IntoPyArray, PyArray2, PyReadonlyArray2};
+use pyo3::exceptions::PyValueError;
+use pyo3::prelude::*;
+use pyo3::types::PyDict;
+use rand::SeedableRng;
+use rand_pcg::Pcg64Mcg;
+use std::sync::Arc;
+
+/// The similarity in column-major order, validated to be square, finite, and nonnegative.
+fn similarity_data(similarity: &PyReadonlyArray2<f64>) -> PyResult<(Vec<f64>, usize)> {
+    let similarity = similarity.as_array();
+    let n_items = similarity.nrows();
+    if similarity.ncols() != n_items {
+        return Err(PyValueError::new_err(
+            "'similarity' must be a square matrix.",
+        ));
+    }
+    let data: Vec<f64> = similarity.t().iter().copied().collect();
+    if data.iter().any(|x| !(x.is_finite() && *x >= 0.0)) {
+        return Err(PyValueError::new_err(
+            "'similarity' must contain only finite, nonnegative values.",
+        ));
+    }
+    Ok((data, n_items))
+}
+
+fn attraction(attraction: &str) -> PyResult<Attraction> {
+    match attraction {
+        "raw" => Ok(Attraction::Raw),
+        "softmax" => Ok(Attraction::Softmax),
+        "rank" => Ok(Attraction::Rank),
+        _ => Err(PyValueError::new_err(
+            "'attraction' must be one of 'raw', 'softmax', or 'rank'.",
+        )),
+    }
+}
+
+/// Constraints given pairs of items (starting at 0) which must be, and which cannot be, clustered
+/// together.
+fn constraints(
+    n_items: usize,
+    must_link: Option<Vec<(usize, usize)>>,
+    cannot_link: Option<Vec<(usize, usize)>>,
+) -> PyResult<Option<Arc<Constraints>>> {
+    if must_link.is_none() && cannot_link.is_none() {
+        return Ok(None);
+    }
+    Constraints::new(
+        n_items,
+        &must_link.unwrap_or_default(),
+        &cannot_link.unwrap_or_default(),
+    )
+    .map(|x| Some(Arc::new(x)))
+    .map_err(PyValueError::new_err)
+}
+
+/// Anchors given the label of each item, or `None` for a free item.
+fn anchors(n_items: usize, labels: Option<Vec<Option<usize>>>) -> PyResult<Option<Arc<Anchors>>> {
+    match labels {
+        Some(labels) if labels.len() != n_items => Err(PyValueError::new_err(
+            "'anchors' must have one element per item.",
+        )),
+        Some(labels) => Ok(Some(Arc::new(Anchors::new(&labels)))),
+        None => Ok(None),
+    }
+}
+
+/// Multiplicities given for each item, all positive.
+fn multiplicities(n_items: usize, x: Option<Vec<usize>>) -> PyResult<Option<Arc<[usize]>>> {
+    match x {
+        Some(x) if x.len() != n_items || x.contains(&0) => Err(PyValueError::new_err(
+            "'multiplicities' must have one positive element per item.",
+        )),
+        Some(x) => Ok(Some(Arc::from(x))),
+        None => Ok(None),
+    }
+}
+
+/// Noise for the items whose total similarity to the others is less than `threshold`, if given.
+fn noise(
+    data: &[f64],
+    n_items: usize,
+    threshold: Option<f64>,
+    weight: f64,
+) -> PyResult<Option<Arc<Noise>>> {
+    let Some(threshold) = threshold else {
+        return Ok(None);
+    };
+    let similarity = SquareMatrixBorrower::from_slice(data, n_items);
+    Noise::new(&similarity, threshold, weight)
+        .map(|x| Some(Arc::new(x)))
+        .ok_or_else(|| PyValueError::new_err("'noise_weight' must be finite and positive."))
+}
+
+/// Stop unless the anchors respect the constraints and noise is not combined with constraints,
+/// as the sampler requires.
+fn check_conditions(
+    constraints: Option<&Constraints>,
+    anchors: Option<&Anchors>,
+    noise: Option<&Noise>,
+) -> PyResult<()> {
+    if let (Some(constraints), Some(anchors)) = (constraints, anchors) {
+        if !constraints.is_satisfied_by(anchors.labels()) {
+            return Err(PyValueError::new_err(
+                "The anchors violate the constraints.",
+            ));
+        }
+    }
+    if constraints.is_some() && noise.is_some() {
+        return Err(PyValueError::new_err(
+            "Noise cannot be combined with constraints.",
+        ));
+    }
+    Ok(())
+}
+
+fn rng(seed: Option<u64>) -> Pcg64Mcg {
+    match seed {
+        Some(seed) => Pcg64Mcg::seed_from_u64(seed),
+        None => Pcg64Mcg::from_rng(&mut rand::rng()),
+    }
+}
+
+fn n_threads(n_threads: usize) -> usize {
+    if n_threads == 0 {
+        std::thread::available_parallelism().map_or(1, |n| n.get())
+    } else {
+        n_threads
+    }
+}
+
+fn check_mass(mass: f64, discount: f64) -> PyResult<()> {
+    if !(0.0..1.0).contains(&discount) {
+        return Err(PyValueError::new_err("'discount' must be in [0, 1)."));
+    }
+    if !(mass.is_finite() && mass > -discount) {
+        return Err(PyValueError::new_err(format!(
+            "'mass' must be finite and greater than {}.",
+            -discount
+        )));
+    }
+    Ok(())
+}
+
+/// The rows of an array are the labels (starting at 0) of consecutive chunks of `labels`.
+fn labels_array(labels: impl IntoIterator<Item = usize>, n_items: usize) -> Array2<i64> {
+    let labels: Vec<i64> = labels.into_iter().map(|x| x as i64).collect();
+    Array2::from_shape_vec((labels.len() / n_items.max(1), n_items), labels).unwrap()
+}
+
+/// Sample `n_samples` partitions (at least one) from the EPA distribution, one per row, with
+/// labels starting at 0.  The partitions respect the `must_link` and `cannot_link` pairs of
+/// items and keep the labels of the items whose element of `anchors` is not `None`.  Each item
+/// stands for as many replicated items as its element of `multiplicities`.  Given a
+/// `noise_threshold`, the items whose total similarity to the others is less than it may be
+/// allocated to a noise pseudo-cluster with weight `noise_weight`.
+#[pyfunction]
+#[allow(clippy::too_many_arguments)]
+#[pyo3(signature = (similarity, mass, n_samples=200, discount=0.0, n_threads=0, strict=false, attraction="raw", subset_size=None, must_link=None, cannot_link=None, anchors=None, multiplicities=None, noise_threshold=None, noise_weight=1.0, seed=None))]
+fn sample_epa<'py>(
+    py: Python<'py>,
+    similarity: PyReadonlyArray2<'py, f64>,
+    mass: f64,
+    n_samples: usize,
+    discount: f64,
+    n_threads: usize,
+    strict: bool,
+    attraction: &str,
+    subset_size: Option<usize>,
+    must_link: Option<Vec<(usize, usize)>>,
+    cannot_link: Option<Vec<(usize, usize)>>,
+    anchors: Option<Vec<Option<usize>>>,
+    multiplicities: Option<Vec<usize>>,
+    noise_threshold: Option<f64>,
+    noise_weight: f64,
+    seed: Option<u64>,
+) -> PyResult<Bound<'py, PyArray2<i64>>> {
+    check_mass(mass, discount)?;
+    let attraction = self::attraction(attraction)?;
+    let (data, n_items) = similarity_data(&similarity)?;
+    let constraints = self::constraints(n_items, must_link, cannot_link)?;
+    let anchors = self::anchors(n_items, anchors)?;
+    let multiplicities = self::multiplicities(n_items, multiplicities)?;
+    let noise = self::noise(&data, n_items, noise_threshold, noise_weight)?;
+    check_conditions(constraints.as_deref(), anchors.as_deref(), noise.as_deref())?;
+    let n_threads = self::n_threads(n_threads);
+    let mut rng = rng(seed);
+    let samples = py.allow_threads(move || {
+        let similarity = SquareMatrixBorrower::from_slice(&data, n_items).detect_symmetry();
+        let options = SamplerOptions {
+            strict,
+            attraction,
+            subset_size,
+            constraints,
+            anchors,
+            multiplicities,
+            noise,
+            ..SamplerOptions::default()
+        };
+        let (samples, _) = api::sample_epa::<u32, _, _>(
+            n_samples, similarity, mass, discount, n_threads, options, &mut rng,
+        );
+        samples
+    });
+    Ok(labels_array(samples.into_iter().map(|x| x as usize), n_items).into_pyarray(py))
+}
+
+/// Cluster items given their similarities by the CaviarPD method.
+///
+/// Returns a dict with the estimate, the candidate estimates (one per row), the mass of each
+/// candidate, whether each item is noise in the estimate, and, if `keep_draws`, the draws for
+/// each mass.  Labels start at 0.  The draws respect
+/// the `must_link` and `cannot_link` pairs of items, and the draws and estimates keep together
+/// (and apart) the items with equal (and unequal) non-`None` elements of `anchors`.  Each item
+/// stands for as many replicated items as its element of `multiplicities`.  Given a
+/// `noise_threshold`, the items whose total similarity to the others is less than it may be noise,
+/// as for `sample_epa`.
+#[pyfunction]
+#[allow(clippy::too_many_arguments)]
+#[pyo3(name = "caviarpd", signature = (similarity, n_clusters, n_samples=200, grid_length=5, loss="binder", discount=0.0, n_runs=4, n_threads=0, strict=false, attraction="raw", subset_size=None, must_link=None, cannot_link=None, anchors=None, multiplicities=None, noise_threshold=None, noise_weight=1.0, keep_draws=false, seed=None))]
+fn fit<'py>(
+    py: Python<'py>,
+    similarity: PyReadonlyArray2<'py, f64>,
+    n_clusters: (f64, f64),
+    n_samples: usize,
+    grid_length: usize,
+    loss: &str,
+    discount: f64,
+    n_runs: u32,
+    n_threads: usize,
+    strict: bool,
+    attraction: &str,
+    subset_size: Option<usize>,
+    must_link: Option<Vec<(usize, usize)>>,
+    cannot_link: Option<Vec<(usize, usize)>>,
+    anchors: Option<Vec<Option<usize>>>,
+    multiplicities: Option<Vec<usize>>,
+    noise_threshold: Option<f64>,
+    noise_weight: f64,
+    keep_draws: bool,
+    seed: Option<u64>,
+) -> PyResult<Bound<'py, PyDict>> {
+    let loss = Loss::from_name(loss).map_err(PyValueError::new_err)?;
+    let (data, n_items) = similarity_data(&similarity)?;
+    let options = CaviarpdOptions::new(n_clusters.0, n_clusters.1)
+        .with_n_samples(n_samples)
+        .with_grid_length(grid_length)
+        .with_loss(loss)
+        .with_discount(discount)
+        .with_n_runs(n_runs)
+        .with_n_threads(self::n_threads(n_threads))
+        .with_sampler_options(SamplerOptions {
+            strict,
+            attraction: self::attraction(attraction)?,
+            subset_size,
+            constraints: self::constraints(n_items, must_link, cannot_link)?,
+            anchors: self::anchors(n_items, anchors)?,
+            multiplicities: self::multiplicities(n_items, multiplicities)?,
+            noise: self::noise(&data, n_items, noise_threshold, noise_weight)?,
+            ..SamplerOptions::default()
+        })
+        .with_keep_draws(keep_draws);
+    let mut rng = rng(seed);
+    let fit = py
+        .allow_threads(move || {
+            let similarity = SquareMatrixBorrower::from_slice(&data, n_items).detect_symmetry();
+            api::caviarpd(similarity, &options, &mut rng)
+        })
+        .map_err(PyValueError::new_err)?;
+    let result = PyDict::new(py);
+    let estimate: Vec<i64> = fit.estimate.into_iter().map(|x| x as i64).collect();
+    result.set_item("estimate", estimate.into_pyarray(py))?;
+    result.set_item(
+        "candidates",
+        labels_array(fit.candidates.into_iter().flatten(), n_items).into_pyarray(py),
+    )?;
+    result.set_item("masses", fit.masses.into_pyarray(py))?;
+    result.set_item("noise", fit.noise)?;
+    if keep_draws {
+        let draws: Vec<_> = fit
+            .draws
+            .into_iter()
+            .map(|draws| {
+                let Draws::Clusterings(draws) = draws else {
+                    unreachable!("the draws are kept without the pairwise similarity matrix")
+                };
+                let labels = (0..draws.n_clusterings())
+                    .flat_map(|j| draws.labels(j).iter().map(|x| usize::from(*x)));
+                labels_array(labels, n_items).into_pyarray(py)
+            })
+            .collect();
+        result.set_item("draws", draws)?;
+    }
+    Ok(result)
+}
+
+/// The expected number of clusters among `n_items` items under the Pitman-Yor process.
+#[pyfunction]
+#[pyo3(signature = (mass, n_items, discount=0.0))]
+fn expected_number_of_clusters(mass: f64, n_items: usize, discount: f64) -> PyResult<f64> {
+    check_mass(mass, discount)?;
+    Ok(api::expected_number_of_clusters(mass, discount, n_items))
+}
+
+/// The mass giving `expected_number_of_clusters` among `n_items` items under the Pitman-Yor
+/// process.
+#[pyfunction]
+#[pyo3(signature = (expected_number_of_clusters, n_items, discount=0.0, tol=1e-8))]
+fn find_mass(
+    expected_number_of_clusters: f64,
+    n_items: usize,
+    discount: f64,
+    tol: f64,
+) -> PyResult<f64> {
+    if !(0.0..1.0).contains(&discount) {
+        return Err(PyValueError::new_err("'discount' must be in [0, 1)."));
+    }
+    api::find_mass(expected_number_of_clusters, discount, n_items, tol)
+        .map_err(PyValueError::new_err)
+}
+
+#[pymodule]
+fn caviarpd(m: &Bound<'_, PyModule>) -> PyResult<()> {
+    m.add_function(wrap_pyfunction!(sample_epa, m)?)?;
+    m.add_function(wrap_pyfunction!(fit, m)?)?;
+    m.add_function(wrap_pyfunction!(expected_number_of_clusters, m)?)?;
+    m.add_function(wrap_pyfunction!(find_mass, m)?)?;
+    Ok(())
+}
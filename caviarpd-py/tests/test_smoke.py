@@ -0,0 +1,49 @@
+# Smoke tests of the Python bindings, run after building them, e.g., by:
+#   maturin develop && python -m unittest discover -s tests
+
+import unittest
+
+import caviarpd
+
+try:
+    import numpy
+except ImportError:
+    numpy = None
+
+
+class TestSmoke(unittest.TestCase):
+    def test_mass(self):
+        enoc = caviarpd.expected_number_of_clusters(1.0, 10)
+        self.assertAlmostEqual(enoc, sum(1.0 / (1.0 + i) for i in range(10)))
+        self.assertAlmostEqual(caviarpd.find_mass(enoc, 10), 1.0, places=6)
+        with self.assertRaises(ValueError):
+            caviarpd.expected_number_of_clusters(1.0, 10, discount=1.0)
+
+    @unittest.skipIf(numpy is None, "numpy is not installed")
+    def test_sample_epa(self):
+        similarity = numpy.exp(-numpy.abs(numpy.subtract.outer(range(6), range(6))))
+        draws = caviarpd.sample_epa(similarity, 1.0, n_samples=10, seed=1)
+        self.assertEqual(draws.shape, (10, 6))
+        with self.assertRaises(ValueError):
+            caviarpd.sample_epa(similarity[:, :5], 1.0)
+
+    @unittest.skipIf(numpy is None, "numpy is not installed")
+    def test_sample_epa_conditions(self):
+        similarity = numpy.exp(-numpy.abs(numpy.subtract.outer(range(6), range(6))))
+        with self.assertRaisesRegex(ValueError, "anchors violate the constraints"):
+            caviarpd.sample_epa(similarity, 1.0, must_link=[(0, 1)], anchors=[0, 1, None, None, None, None])
+        with self.assertRaisesRegex(ValueError, "Noise cannot be combined with constraints"):
+            caviarpd.sample_epa(similarity, 1.0, cannot_link=[(0, 1)], noise_threshold=0.5)
+
+    @unittest.skipIf(numpy is None, "numpy is not installed")
+    def test_caviarpd(self):
+        blocks = numpy.repeat([0, 1], 4)
+        similarity = numpy.where(numpy.equal.outer(blocks, blocks), 1.0, 0.01)
+        fit = caviarpd.caviarpd(similarity, (2, 2), n_samples=50, seed=1, keep_draws=True)
+        self.assertEqual(len(fit["estimate"]), 8)
+        self.assertEqual(fit["candidates"].shape[1], 8)
+        self.assertEqual(len(fit["draws"]), len(fit["masses"]))
+
+
+if __name__ == "__main__":
+    unittest.main()
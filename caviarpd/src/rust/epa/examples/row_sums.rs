@@ -0,0 +1,65 @@
+// Benchmark of the sums of similarities that dominate the cost of sampling
+//
+// Run with `cargo run --release --example row_sums`.
+
+use epa::epa::SquareMatrixBorrower;
+use rand::seq::SliceRandom;
+use rand::SeedableRng;
+use std::hint::black_box;
+use std::time::Instant;
+
+// The sum as it was computed before: one accumulator, reading along a row of the column-major
+// matrix.
+fn scalar_sum(similarity: &SquareMatrixBorrower, row: usize, columns: &[usize]) -> f64 {
+    let mut sum = 0.0;
+    for j in columns {
+        sum += similarity[(row, *j)];
+    }
+    sum
+}
+
+// The fastest of several repetitions.
+fn seconds(mut f: impl FnMut() -> f64) -> f64 {
+    (0..10)
+        .map(|_| {
+            let start = Instant::now();
+            black_box(f());
+            start.elapsed().as_secs_f64()
+        })
+        .fold(f64::INFINITY, f64::min)
+}
+
+fn main() {
+    let mut rng = rand::rngs::StdRng::seed_from_u64(1);
+    println!("n_items  columns      scalar  accumulated  speedup");
+    for n_items in [1000, 2000, 4000] {
+        let data: Vec<f64> = (0..n_items * n_items)
+            .map(|x| 1.0 + ((x / n_items) * (x % n_items) % 97) as f64)
+            .collect();
+        let similarity = SquareMatrixBorrower::from_slice(&data, n_items).detect_symmetry();
+        let mut shuffled: Vec<_> = (0..n_items).collect();
+        shuffled.shuffle(&mut rng);
+        let consecutive: Vec<_> = (0..n_items).collect();
+        for (name, items) in [("shuffled", &shuffled), ("consecutive", &consecutive)] {
+            // The prefixes of the permutation, as summed by one draw of the sampler.
+            let run = |f: &dyn Fn(&[usize]) -> f64| {
+                seconds(|| {
+                    (0..n_items)
+                        .map(|i| f(&items[..i]))
+                        .fold(0.0, |sum, x| sum + x)
+                })
+            };
+            let scalar = run(&|columns| scalar_sum(&similarity, columns.len() % n_items, columns));
+            let accumulated =
+                run(&|columns| similarity.sum_of_row_subset(columns.len() % n_items, columns));
+            println!(
+                "{:>7}  {:<11} {:>7.4}s {:>11.4}s {:>7.2}x",
+                n_items,
+                name,
+                scalar,
+                accumulated,
+                scalar / accumulated
+            );
+        }
+    }
+}
@@ -0,0 +1,324 @@
+// Alignment of the labels of two partitions of the same items
+
+/// A contingency table (i.e., confusion matrix) between two partitions.
+///
+/// Both partitions must use labels `0, 1, ..., k - 1`.  Rows index the labels of the first
+/// partition and columns index the labels of the second partition.
+#[derive(Debug, Clone)]
+pub struct ContingencyTable {
+    counts: Vec<usize>,
+    n_rows: usize,
+    n_cols: usize,
+}
+
+impl ContingencyTable {
+    pub fn new(x: &[usize], y: &[usize]) -> Self {
+        assert_eq!(x.len(), y.len());
+        let n_rows = x.iter().max().map_or(0, |m| m + 1);
+        let n_cols = y.iter().max().map_or(0, |m| m + 1);
+        let mut counts = vec![0; n_rows * n_cols];
+        for (i, j) in x.iter().zip(y) {
+            counts[i * n_cols + j] += 1;
+        }
+        Self {
+            counts,
+            n_rows,
+            n_cols,
+        }
+    }
+
+    pub fn n_rows(&self) -> usize {
+        self.n_rows
+    }
+
+    pub fn n_cols(&self) -> usize {
+        self.n_cols
+    }
+
+    pub fn get(&self, row: usize, col: usize) -> usize {
+        self.counts[row * self.n_cols + col]
+    }
+
+    pub fn row_sums(&self) -> Vec<usize> {
+        self.counts
+            .chunks(self.n_cols.max(1))
+            .take(self.n_rows)
+            .map(|row| row.iter().sum())
+            .collect()
+    }
+
+    pub fn col_sums(&self) -> Vec<usize> {
+        let mut sums = vec![0; self.n_cols];
+        for row in self.counts.chunks(self.n_cols.max(1)).take(self.n_rows) {
+            for (s, c) in sums.iter_mut().zip(row) {
+                *s += c;
+            }
+        }
+        sums
+    }
+
+    /// The cells with positive counts as `(row, col, count)` triples in row-major order.
+    pub fn nonzero_cells(&self) -> Vec<(usize, usize, usize)> {
+        let mut cells = Vec::new();
+        for row in 0..self.n_rows {
+            for col in 0..self.n_cols {
+                let count = self.get(row, col);
+                if count > 0 {
+                    cells.push((row, col, count));
+                }
+            }
+        }
+        cells
+    }
+
+    /// Match columns to rows so that the total overlap of matched pairs is maximized.
+    ///
+    /// Returns, for each column, the matched row (if any).  Every row and every column is
+    /// matched at most once, and pairs with no overlap are never matched.
+    pub fn max_overlap_matching(&self) -> Vec<Option<usize>> {
+        let transpose = self.n_rows > self.n_cols;
+        let (n, m) = if transpose {
+            (self.n_cols, self.n_rows)
+        } else {
+            (self.n_rows, self.n_cols)
+        };
+        let cost = |i: usize, j: usize| -> i64 {
+            let count = if transpose {
+                self.get(j, i)
+            } else {
+                self.get(i, j)
+            };
+            -(count as i64)
+        };
+        let assignment = hungarian(n, m, cost);
+        let mut matching = vec![None; self.n_cols];
+        for (i, j) in assignment.into_iter().enumerate() {
+            let (row, col) = if transpose { (j, i) } else { (i, j) };
+            if self.get(row, col) > 0 {
+                matching[col] = Some(row);
+            }
+        }
+        matching
+    }
+}
+
+// Minimum cost assignment of each of 'n' rows to a distinct column among 'm >= n' columns using
+// the Hungarian algorithm with potentials, which is O(n^2 m).  Returns the column of each row.
+fn hungarian<F: Fn(usize, usize) -> i64>(n: usize, m: usize, cost: F) -> Vec<usize> {
+    assert!(n <= m);
+    let inf = i64::MAX / 4;
+    let mut u = vec![0; n + 1];
+    let mut v = vec![0; m + 1];
+    let mut p = vec![0; m + 1];
+    let mut way = vec![0; m + 1];
+    for i in 1..=n {
+        p[0] = i;
+        let mut j0 = 0;
+        let mut minv = vec![inf; m + 1];
+        let mut used = vec![false; m + 1];
+        loop {
+            used[j0] = true;
+            let i0 = p[j0];
+            let mut delta = inf;
+            let mut j1 = 0;
+            for j in 1..=m {
+                if !used[j] {
+                    let cur = cost(i0 - 1, j - 1) - u[i0] - v[j];
+                    if cur < minv[j] {
+                        minv[j] = cur;
+                        way[j] = j0;
+                    }
+                    if minv[j] < delta {
+                        delta = minv[j];
+                        j1 = j;
+                    }
+                }
+            }
+            for j in 0..=m {
+                if used[j] {
+                    u[p[j]] += delta;
+                    v[j] -= delta;
+                } else {
+                    minv[j] -= delta;
+                }
+            }
+            j0 = j1;
+            if p[j0] == 0 {
+                break;
+            }
+        }
+        loop {
+            let j1 = way[j0];
+            p[j0] = p[j1];
+            j0 = j1;
+            if j0 == 0 {
+                break;
+            }
+        }
+    }
+    let mut assignment = vec![0; n];
+    for j in 1..=m {
+        if p[j] != 0 {
+            assignment[p[j] - 1] = j - 1;
+        }
+    }
+    assignment
+}
+
+/// Relabel `labels` so that its clusters take the labels of the maximally overlapping clusters
+/// of `reference`.
+///
+/// Both partitions must use labels `0, 1, ..., k - 1`.  Clusters without a match in the
+/// reference receive new labels starting after the largest label of the reference, in order of
+/// first appearance.
+pub fn align_to_reference(reference: &[usize], labels: &[usize]) -> Vec<usize> {
+    let table = ContingencyTable::new(reference, labels);
+    let matching = table.max_overlap_matching();
+    let mut map: Vec<Option<usize>> = matching;
+    let mut next_new_label = table.n_rows();
+    labels
+        .iter()
+        .map(|label| {
+            *map[*label].get_or_insert_with(|| {
+                let new_label = next_new_label;
+                next_new_label += 1;
+                new_label
+            })
+        })
+        .collect()
+}
+
+/// The kinds of changes in cluster structure between a reference partition and a new one.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ClusterEventKind {
+    Birth,
+    Death,
+    Split,
+    Merge,
+}
+
+impl ClusterEventKind {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Self::Birth => "birth",
+            Self::Death => "death",
+            Self::Split => "split",
+            Self::Merge => "merge",
+        }
+    }
+}
+
+/// A change in cluster structure, linking a reference cluster to a new cluster.
+///
+/// A birth has no reference cluster and a death has no new cluster.  A split of one reference
+/// cluster (or a merge into one new cluster) is reported as one event per involved pair.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ClusterEvent {
+    pub kind: ClusterEventKind,
+    pub reference: Option<usize>,
+    pub estimate: Option<usize>,
+}
+
+/// Find births, deaths, splits, and merges of clusters between two partitions.
+///
+/// A pair of clusters is linked when their overlap is at least `min_overlap` times the size of
+/// each of the two clusters.  A reference cluster without links dies, a new cluster without links
+/// is born, a reference cluster linked to several new clusters splits, and a new cluster linked
+/// to several reference clusters is a merge.
+pub fn cluster_events(
+    reference: &[usize],
+    labels: &[usize],
+    min_overlap: f64,
+) -> Vec<ClusterEvent> {
+    let table = ContingencyTable::new(reference, labels);
+    let row_sums = table.row_sums();
+    let col_sums = table.col_sums();
+    let linked = |i: usize, j: usize| {
+        let count = table.get(i, j) as f64;
+        count > 0.0
+            && count >= min_overlap * (row_sums[i] as f64)
+            && count >= min_overlap * (col_sums[j] as f64)
+    };
+    let n_links_of_row: Vec<_> = (0..table.n_rows())
+        .map(|i| (0..table.n_cols()).filter(|j| linked(i, *j)).count())
+        .collect();
+    let n_links_of_col: Vec<_> = (0..table.n_cols())
+        .map(|j| (0..table.n_rows()).filter(|i| linked(*i, j)).count())
+        .collect();
+    let mut events = Vec::new();
+    for (i, n_links) in n_links_of_row.iter().enumerate() {
+        if row_sums[i] > 0 && *n_links == 0 {
+            events.push(ClusterEvent {
+                kind: ClusterEventKind::Death,
+                reference: Some(i),
+                estimate: None,
+            });
+        }
+    }
+    for (j, n_links) in n_links_of_col.iter().enumerate() {
+        if col_sums[j] > 0 && *n_links == 0 {
+            events.push(ClusterEvent {
+                kind: ClusterEventKind::Birth,
+                reference: None,
+                estimate: Some(j),
+            });
+        }
+    }
+    for (i, n_links_of_row) in n_links_of_row.iter().enumerate() {
+        for (j, n_links_of_col) in n_links_of_col.iter().enumerate() {
+            if linked(i, j) {
+                if *n_links_of_row > 1 {
+                    events.push(ClusterEvent {
+                        kind: ClusterEventKind::Split,
+                        reference: Some(i),
+                        estimate: Some(j),
+                    });
+                }
+                if *n_links_of_col > 1 {
+                    events.push(ClusterEvent {
+                        kind: ClusterEventKind::Merge,
+                        reference: Some(i),
+                        estimate: Some(j),
+                    });
+                }
+            }
+        }
+    }
+    events
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_align_to_reference() {
+        let reference = [0, 0, 0, 1, 1, 2, 2];
+        let labels = [2, 2, 2, 0, 0, 1, 3];
+        assert_eq!(
+            align_to_reference(&reference, &labels),
+            vec![0, 0, 0, 1, 1, 2, 3]
+        );
+    }
+
+    #[test]
+    fn test_cluster_events() {
+        let reference = [0, 0, 0, 0, 1, 1, 2, 2];
+        let labels = [0, 0, 1, 1, 2, 2, 2, 2];
+        let events = cluster_events(&reference, &labels, 0.5);
+        let kinds: Vec<_> = events.iter().map(|e| e.kind).collect();
+        assert_eq!(
+            kinds,
+            vec![
+                ClusterEventKind::Split,
+                ClusterEventKind::Split,
+                ClusterEventKind::Merge,
+                ClusterEventKind::Merge
+            ]
+        );
+        let events = cluster_events(&[0, 0, 1, 1], &[0, 0, 0, 0], 0.5);
+        assert!(events.iter().all(|e| e.kind == ClusterEventKind::Merge));
+        let events = cluster_events(&[0, 0, 0, 0], &[0, 0, 0, 1], 0.5);
+        assert_eq!(events[0].kind, ClusterEventKind::Birth);
+    }
+}
@@ -0,0 +1,2525 @@
+// A public interface to the CaviarPD method which does not depend on R
+
+use crate::clust::FromUsize;
+use crate::distance::{adjusted_rand_index, variation_of_information};
+use crate::epa::{
+    coclustering_probabilities, log_probability, sample_into, Anchors, Attraction,
+    ConstantSimilarity, Constraints, EpaParameters, Noise, Scratch, Similarity, SquareMatrixBorrower,
+};
+use crate::ordering::{perturb_order, PermutationStrategy};
+use crate::perm::Permutation;
+use crate::map::{canonical, deduplicate};
+use crate::similarity::{simulate_similarity, sub_similarity};
+use crate::summary::{exemplars, item_disagreement, item_uncertainty};
+use dahl_partition::SquareMatrixBorrower as PsmBorrower;
+use dahl_salso::clustering::Clusterings;
+use dahl_salso::optimize::{minimize_by_salso, SALSOParameters, SALSOResults};
+use dahl_salso::{InitializationMethod, LabelType, LossFunction, PartitionDistributionInformation};
+use rand::prelude::SliceRandom;
+use rand::seq::index;
+use rand::Rng;
+use rand_distr::{Beta, Distribution};
+use rand_pcg::Pcg64Mcg;
+use roots::find_root_brent;
+use std::mem::MaybeUninit;
+use std::sync::{Arc, Mutex};
+use std::time::Instant;
+
+/// The expected number of clusters among `n_items` items under the Pitman-Yor process.
+///
+/// The probability that the next item starts a new cluster is linear in the current number of
+/// clusters, so the expectation follows a simple recursion.
+pub fn expected_number_of_clusters(mass: f64, discount: f64, n_items: usize) -> f64 {
+    (0..n_items).fold(0.0, |sum, i| {
+        sum + (mass + discount * sum) / (mass + (i as f64))
+    })
+}
+
+/// The mass giving `enoc` expected clusters among `n_items` items under the Pitman-Yor process.
+///
+/// The expected number of clusters increases from 1 (as the mass approaches `-discount`) to
+/// `n_items` (as the mass grows without bound), so the upper end of the bracket is doubled until
+/// it contains the root, which is then found by Brent's method with tolerance `tol`.  One expected
+/// cluster is only reached in the limit, so it gives the lower end of the bracket.
+pub fn find_mass(enoc: f64, discount: f64, n_items: usize, tol: f64) -> Result<f64, String> {
+    let lower = f64::EPSILON - discount;
+    if enoc == 1.0 {
+        return Ok(lower);
+    }
+    if !(enoc > 1.0 && enoc < n_items as f64) {
+        return Err(format!(
+            "The expected number of clusters must be at least 1 and less than the number of items ({}), but is {}.",
+            n_items, enoc
+        ));
+    }
+    let f = |mass| expected_number_of_clusters(mass, discount, n_items) - enoc;
+    let mut upper = enoc;
+    while f(upper) < 0.0 {
+        upper *= 2.0;
+        if !upper.is_finite() {
+            return Err(format!(
+                "Could not bracket the mass giving {} expected clusters among {} items.",
+                enoc, n_items
+            ));
+        }
+    }
+    let mut convergency = tol;
+    find_root_brent(lower, upper, f, &mut convergency).map_err(|e| {
+        format!(
+            "Could not find the mass giving {} expected clusters among {} items ({}).",
+            enoc, n_items, e
+        )
+    })
+}
+
+/// Options for [`sample_epa`].
+///
+/// With `compensated`, sums of similarities use compensated summation.  With `strict`, the draws
+/// are reproducible across platforms and numbers of threads: each draw has its own seed and starts
+/// from the natural permutation, and sums of similarities are compensated.  The `attraction`
+/// sets how similarities become attractions to clusters.  With `subset_size`, draws for very many
+/// items are approximated as described in [`EpaParameters::with_subset_size`].  The draws respect
+/// any `constraints`, as described in [`EpaParameters::with_constraints`], and allocate the free
+/// items given any `anchors`, as described in [`EpaParameters::with_anchors`].  Items stand for
+/// replicated items given any `multiplicities`, as described in
+/// [`EpaParameters::with_multiplicities`], and may be allocated as noise given any `noise`, as
+/// described in [`EpaParameters::with_noise`].  The `permutation` sets how the items are ordered
+/// for each draw, with a heuristic order perturbed for each draw unless `fixed_permutation`, as
+/// described in [`PermutationStrategy`].  With `fixed_permutation` and uniform shuffling, every
+/// draw uses the natural order.  The heuristic order is computed for each call unless it is given
+/// by `order`, as set by [`SamplerOptions::with_order`].
+#[derive(Debug, Clone, Default)]
+pub struct SamplerOptions {
+    pub compensated: bool,
+    pub strict: bool,
+    pub attraction: Attraction,
+    pub subset_size: Option<usize>,
+    pub constraints: Option<Arc<Constraints>>,
+    pub anchors: Option<Arc<Anchors>>,
+    pub multiplicities: Option<Arc<[usize]>>,
+    pub noise: Option<Arc<Noise>>,
+    pub permutation: PermutationStrategy,
+    pub fixed_permutation: bool,
+    pub order: Option<Arc<[usize]>>,
+}
+
+impl SamplerOptions {
+    /// Compute the heuristic order of the permutation strategy for `similarity` once, rather than
+    /// for each call, when sampling repeatedly from the same similarities (e.g., for a grid of
+    /// masses).
+    pub fn with_order<M: Similarity>(mut self, similarity: M) -> Self {
+        self.order = self.permutation.order(similarity).map(Arc::from);
+        self
+    }
+
+    // The heuristic order of the permutation strategy, or none for uniform shuffling.
+    pub(crate) fn heuristic_order<M: Similarity>(&self, similarity: M) -> Option<Arc<[usize]>> {
+        match self.permutation {
+            PermutationStrategy::Uniform => None,
+            _ => self
+                .order
+                .clone()
+                .or_else(|| self.permutation.order(similarity).map(Arc::from)),
+        }
+    }
+
+    /// The number of items seen by SALSO, which sees each item repeated by its multiplicity.
+    pub fn n_salso_items(&self, n_items: usize) -> usize {
+        self.multiplicities
+            .as_deref()
+            .map_or(n_items, |x| x.iter().sum())
+    }
+
+    /// The draws as seen by SALSO, if they differ from the draws.
+    pub fn expand(&self, draws: &Draws) -> Option<Draws> {
+        let multiplicities = self.multiplicities.as_deref()?;
+        Some(match draws {
+            Draws::Clusterings(clusterings) => {
+                Draws::Clusterings(expand_clusterings(clusterings, multiplicities))
+            }
+            Draws::Psm(psm) => {
+                let n_items = multiplicities.len();
+                let items: Vec<_> = multiplicities
+                    .iter()
+                    .enumerate()
+                    .flat_map(|(i, m)| std::iter::repeat_n(i, *m))
+                    .collect();
+                Draws::Psm(sub_similarity(psm, n_items, &items))
+            }
+        })
+    }
+
+    /// The clusterings as seen by SALSO, as for [`Self::expand`].
+    pub fn expand_clusterings(&self, clusterings: Clusterings) -> Clusterings {
+        match self.multiplicities.as_deref() {
+            Some(multiplicities) => expand_clusterings(&clusterings, multiplicities),
+            None => clusterings,
+        }
+    }
+
+    /// An estimate for the items (rather than their repeats) from an estimate of SALSO, which
+    /// respects the anchors, as the draws always do.
+    pub fn impose(&self, clustering: Vec<usize>) -> Vec<usize> {
+        let clustering = match self.multiplicities.as_deref() {
+            Some(multiplicities) => collapse_labels(&clustering, multiplicities),
+            None => clustering,
+        };
+        match &self.anchors {
+            Some(anchors) => anchors.impose(&clustering),
+            None => clustering,
+        }
+    }
+}
+
+// The number of draws in a block of work claimed by a sampling thread, outside of strict mode.
+const DRAW_BLOCK_SIZE: usize = 8;
+
+// Storage for perturbing the heuristic order, reused across the draws of a thread.
+#[derive(Default)]
+pub(crate) struct PerturbationBuffer {
+    keys: Vec<(f64, usize)>,
+    order: Vec<usize>,
+}
+
+// Order the items for the next draw as the options say, where 'order' is the heuristic order of
+// the permutation strategy, if any, and 'buffer' holds its perturbations.
+pub(crate) fn next_permutation<M: Similarity, T: Rng>(
+    params: &mut EpaParameters<M>,
+    order: Option<&[usize]>,
+    fixed: bool,
+    buffer: &mut PerturbationBuffer,
+    rng: &mut T,
+) {
+    match (order, fixed) {
+        (None, false) => params.shuffle_permutation(rng),
+        (None, true) => params.reset_permutation(),
+        (Some(order), true) => params.order_permutation(order),
+        (Some(order), false) => {
+            perturb_order(order, rng, &mut buffer.keys, &mut buffer.order);
+            params.order_permutation(&buffer.order);
+        }
+    }
+}
+
+// The parameters of the sampler for the options, with the natural permutation.
+pub(crate) fn sampler_parameters<M: Similarity>(
+    similarity: M,
+    mass: f64,
+    discount: f64,
+    compensated: bool,
+    options: &SamplerOptions,
+) -> EpaParameters<M> {
+    EpaParameters::new(similarity, Permutation::natural(similarity.n_items()), mass)
+        .and_then(|x| x.with_discount(discount))
+        .and_then(|x| x.with_constraints(options.constraints.clone()))
+        .and_then(|x| x.with_anchors(options.anchors.clone()))
+        .and_then(|x| x.with_multiplicities(options.multiplicities.clone()))
+        .and_then(|x| x.with_noise(options.noise.clone()))
+        .unwrap()
+        .with_compensated_summation(compensated)
+        .with_attraction(options.attraction)
+        .with_subset_size(options.subset_size)
+}
+
+/// Sample `n_samples` partitions from the EPA distribution on `n_threads` threads.
+///
+/// Returns the labels of exactly `n_samples` draws (but at least one), one draw after another and
+/// each with labels `0, 1, ..., k - 1`, and the number of clusters of each draw.  The draws are
+/// divided into small blocks, each with its own seed, which idle threads take from a shared queue,
+/// so a slow thread does not hold up the others and the draws do not depend on the number of
+/// threads.  In strict mode, each draw is its own block.
+///
+/// # Panics
+///
+/// Panics if the mass and discount are not valid for [`EpaParameters::with_discount`], the
+/// constraints and anchors are not valid for [`EpaParameters::with_anchors`], the multiplicities
+/// are not valid for [`EpaParameters::with_multiplicities`], or the noise is not valid for
+/// [`EpaParameters::with_noise`].
+pub fn sample_epa<L, M, T>(
+    n_samples: usize,
+    similarity: M,
+    mass: f64,
+    discount: f64,
+    n_threads: usize,
+    options: SamplerOptions,
+    rng: &mut T,
+) -> (Vec<L>, Vec<L>)
+where
+    L: FromUsize + Copy + Send,
+    M: Similarity,
+    T: Rng,
+{
+    let (samples, n_clusters, _) = sample_epa_blocks(
+        n_samples, similarity, mass, discount, n_threads, options, None, rng,
+    );
+    (samples, n_clusters)
+}
+
+/// `n_samples` draws of `n_items` items from the Chinese restaurant process (or the Pitman-Yor
+/// process with a discount), i.e., without attraction, for contrast with EPA draws.
+///
+/// The draws come from the same sampler as [`sample_epa`], with all similarities equal, but using
+/// [`ConstantSimilarity`] so no similarities are stored or summed.
+///
+/// # Panics
+///
+/// Panics as [`sample_epa`] does.
+pub fn sample_crp<L, T>(
+    n_samples: usize,
+    n_items: usize,
+    mass: f64,
+    discount: f64,
+    n_threads: usize,
+    rng: &mut T,
+) -> (Vec<L>, Vec<L>)
+where
+    L: FromUsize + Copy + Send,
+    T: Rng,
+{
+    let similarity = ConstantSimilarity::new(n_items);
+    let options = SamplerOptions::default();
+    sample_epa(n_samples, similarity, mass, discount, n_threads, options, rng)
+}
+
+/// Like [`sample_epa`] (and giving the same draws), but also returning the log of the importance
+/// weight of each draw for the EPA distribution with the fixed permutation `target`.
+///
+/// A draw sampled with the random permutation `s` has the weight `p(x | target) / p(x | s)`,
+/// where `p` is the EPA probability as computed by [`log_probability`].  Since the permutations
+/// are uniform, averages of functions of the draws weighted by their (normalized) importance
+/// weights estimate expectations under the EPA distribution with the permutation `target`, rather
+/// than the average over permutations.  Like [`log_probability`], the weights ignore
+/// constraints, anchors, noise, and the subset size, so these should not be in the options.
+///
+/// # Panics
+///
+/// Panics as [`sample_epa`] does, or if `target` is not for the number of items.
+#[allow(clippy::too_many_arguments)]
+pub fn sample_epa_weighted<L, M, T>(
+    n_samples: usize,
+    similarity: M,
+    mass: f64,
+    discount: f64,
+    n_threads: usize,
+    options: SamplerOptions,
+    target: &Permutation,
+    rng: &mut T,
+) -> (Vec<L>, Vec<L>, Vec<f64>)
+where
+    L: FromUsize + Copy + Send,
+    M: Similarity,
+    T: Rng,
+{
+    assert_eq!(target.n_items(), similarity.n_items());
+    sample_epa_blocks(
+        n_samples,
+        similarity,
+        mass,
+        discount,
+        n_threads,
+        options,
+        Some(target),
+        rng,
+    )
+}
+
+// The draws of 'sample_epa', with the log importance weights for the 'target' permutation (or
+// zeros without one).
+#[allow(clippy::too_many_arguments)]
+fn sample_epa_blocks<L, M, T>(
+    n_samples: usize,
+    similarity: M,
+    mass: f64,
+    discount: f64,
+    n_threads: usize,
+    options: SamplerOptions,
+    target: Option<&Permutation>,
+    rng: &mut T,
+) -> (Vec<L>, Vec<L>, Vec<f64>)
+where
+    L: FromUsize + Copy + Send,
+    M: Similarity,
+    T: Rng,
+{
+    let strict = options.strict;
+    let compensated = options.compensated || strict;
+    let n_items = similarity.n_items();
+    let n_samples = n_samples.max(1);
+    if n_items == 0 {
+        return (
+            Vec::new(),
+            vec![L::from_usize(0); n_samples],
+            vec![0.0; n_samples],
+        );
+    }
+    let block_size = if strict { 1 } else { DRAW_BLOCK_SIZE };
+    // The buffers may be huge, so they are not initialized here; the threads fill every block.
+    let mut samples: Vec<L> = Vec::with_capacity(n_samples * n_items);
+    let mut n_clusters: Vec<L> = Vec::with_capacity(n_samples);
+    let seeds: Vec<u128> = (0..n_samples.div_ceil(block_size))
+        .map(|_| rng.random())
+        .collect();
+    let n_threads = n_threads.clamp(1, seeds.len());
+    let order = options.heuristic_order(similarity);
+    // The parameters for the weights have the target permutation, which anchors would reset.
+    let target_params = |target: &Permutation| {
+        EpaParameters::new(similarity, target.clone(), mass)
+            .and_then(|x| x.with_discount(discount))
+            .and_then(|x| x.with_multiplicities(options.multiplicities.clone()))
+            .unwrap()
+            .with_compensated_summation(compensated)
+            .with_attraction(options.attraction)
+    };
+    let mut log_weights = vec![0.0; n_samples];
+    let label_blocks =
+        samples.spare_capacity_mut()[..n_samples * n_items].chunks_mut(block_size * n_items);
+    let n_clusters_blocks = n_clusters.spare_capacity_mut()[..n_samples].chunks_mut(block_size);
+    let log_weight_blocks = log_weights.chunks_mut(block_size);
+    let blocks = Mutex::new(
+        seeds
+            .into_iter()
+            .zip(label_blocks)
+            .zip(n_clusters_blocks)
+            .zip(log_weight_blocks),
+    );
+
+    crossbeam::scope(|s| {
+        for _ in 0..n_threads {
+            s.spawn(|_| {
+                let mut params =
+                    sampler_parameters(similarity, mass, discount, compensated, &options);
+                let target = target.map(target_params);
+                let mut scratch = Scratch::default();
+                let mut draw = vec![MaybeUninit::<usize>::uninit(); n_items];
+                let mut labels = Vec::with_capacity(n_items);
+                let mut buffer = PerturbationBuffer::default();
+                loop {
+                    let next = blocks.lock().unwrap().next();
+                    let Some((((seed, samples), n_clusters), log_weights)) = next else {
+                        break;
+                    };
+                    let mut rng = Pcg64Mcg::new(seed);
+                    params.reset_permutation();
+                    for (i, n_clusters) in n_clusters.iter_mut().enumerate() {
+                        let fixed = options.fixed_permutation;
+                        let order = order.as_deref();
+                        next_permutation(&mut params, order, fixed, &mut buffer, &mut rng);
+                        let output = &mut samples[i * n_items..(i + 1) * n_items];
+                        let k = match &target {
+                            None => sample_into(&params, &mut scratch, &mut rng, output),
+                            Some(target) => {
+                                let k = sample_into(&params, &mut scratch, &mut rng, &mut draw);
+                                labels.clear();
+                                // SAFETY: 'sample_into' initializes every label.
+                                labels.extend(draw.iter().map(|x| unsafe { x.assume_init() }));
+                                for (x, label) in output.iter_mut().zip(&labels) {
+                                    x.write(L::from_usize(*label));
+                                }
+                                log_weights[i] = log_probability(&labels, target)
+                                    - log_probability(&labels, &params);
+                                k
+                            }
+                        };
+                        n_clusters.write(L::from_usize(k));
+                    }
+                }
+            });
+        }
+    })
+    .expect("a sampling thread panicked");
+    // SAFETY: The scope returned normally, so the queue was emptied and every block written.
+    unsafe {
+        samples.set_len(n_samples * n_items);
+        n_clusters.set_len(n_samples);
+    }
+    (samples, n_clusters, log_weights)
+}
+
+// The scale of the fixed-point sums of probabilities in [`rao_blackwellized_psm`], whose sums do
+// not depend on the order of addition.
+const PROBABILITY_SCALE: f64 = (1_u64 << 32) as f64;
+
+/// The pairwise similarity matrix (column-major) of `n_samples` EPA draws, sampled as by
+/// [`sample_epa`] (and so giving the same draws), but averaging the probabilities of
+/// [`coclustering_probabilities`] rather than whether the items are clustered together.
+///
+/// The estimate has less variance for the same number of draws, and it does not depend on the
+/// number of threads.  The draws are not kept, so memory is bounded regardless of `n_samples`.
+///
+/// # Panics
+///
+/// Panics as [`sample_epa`] does.
+pub fn rao_blackwellized_psm<M: Similarity, T: Rng>(
+    n_samples: usize,
+    similarity: M,
+    mass: f64,
+    discount: f64,
+    n_threads: usize,
+    options: SamplerOptions,
+    rng: &mut T,
+) -> Vec<f64> {
+    let strict = options.strict;
+    let compensated = options.compensated || strict;
+    let n_items = similarity.n_items();
+    let n_samples = n_samples.max(1);
+    let block_size = if strict { 1 } else { DRAW_BLOCK_SIZE };
+    let seeds: Vec<u128> = (0..n_samples.div_ceil(block_size))
+        .map(|_| rng.random())
+        .collect();
+    let n_threads = n_threads.clamp(1, seeds.len());
+    let order = options.heuristic_order(similarity);
+    let blocks = Mutex::new(seeds.into_iter().enumerate());
+    let sums = crossbeam::scope(|s| {
+        let handles: Vec<_> = (0..n_threads)
+            .map(|_| {
+                s.spawn(|_| {
+                    let mut params =
+                        sampler_parameters(similarity, mass, discount, compensated, &options);
+                    let mut scratch = Scratch::default();
+                    let mut draw = vec![MaybeUninit::<usize>::uninit(); n_items];
+                    let mut labels = vec![0; n_items];
+                    let mut sums = vec![0_u64; n_items * n_items];
+                    let mut buffer = PerturbationBuffer::default();
+                    loop {
+                        let next = blocks.lock().unwrap().next();
+                        let Some((block, seed)) = next else {
+                            break;
+                        };
+                        let mut rng = Pcg64Mcg::new(seed);
+                        params.reset_permutation();
+                        let n = block_size.min(n_samples - block * block_size);
+                        for _ in 0..n {
+                            let fixed = options.fixed_permutation;
+                            let order = order.as_deref();
+                            next_permutation(&mut params, order, fixed, &mut buffer, &mut rng);
+                            sample_into(&params, &mut scratch, &mut rng, &mut draw);
+                            for (label, x) in labels.iter_mut().zip(&draw) {
+                                // SAFETY: 'sample_into' initializes every label.
+                                *label = unsafe { x.assume_init() };
+                            }
+                            coclustering_probabilities(&labels, &params, |i, j, p| {
+                                let (i, j) = (i.min(j), i.max(j));
+                                sums[n_items * j + i] += (p * PROBABILITY_SCALE).round() as u64;
+                            });
+                        }
+                    }
+                    sums
+                })
+            })
+            .collect();
+        handles.into_iter().fold(vec![0_u64; n_items * n_items], |mut total, handle| {
+            for (x, y) in total.iter_mut().zip(handle.join().unwrap()) {
+                *x += y;
+            }
+            total
+        })
+    })
+    .expect("a sampling thread panicked");
+    let scale = PROBABILITY_SCALE * n_samples as f64;
+    let mut psm = vec![1.0; n_items * n_items];
+    for j in 0..n_items {
+        for i in 0..j {
+            let p = (sums[n_items * j + i] as f64 / scale).min(1.0);
+            psm[n_items * j + i] = p;
+            psm[n_items * i + j] = p;
+        }
+    }
+    psm
+}
+
+/// The pairwise similarity matrix (column-major) of `n_samples` EPA draws, accumulated in batches
+/// so that only one batch of draws is held in memory at a time.
+///
+/// Identical draws within a batch are counted once, weighted by their number.  With
+/// `rao_blackwell`, the matrix is that of [`rao_blackwellized_psm`] instead.  Given a `target`
+/// permutation, which takes precedence, the draws are weighted by their importance weights for it,
+/// as given by [`sample_epa_weighted`].
+///
+/// # Panics
+///
+/// Panics as [`sample_epa_weighted`] does.
+#[allow(clippy::too_many_arguments)]
+pub fn sample_psm<M: Similarity, T: Rng>(
+    n_samples: usize,
+    similarity: M,
+    mass: f64,
+    discount: f64,
+    n_threads: usize,
+    options: &SamplerOptions,
+    rao_blackwell: bool,
+    target: Option<&Permutation>,
+    rng: &mut T,
+) -> Vec<f64> {
+    if let Some(target) = target {
+        return sample_weighted_psm(
+            n_samples, similarity, mass, discount, n_threads, options, target, rng,
+        );
+    }
+    if rao_blackwell {
+        let options = options.clone();
+        return rao_blackwellized_psm(n_samples, similarity, mass, discount, n_threads, options, rng);
+    }
+    let batch_size = 100;
+    let n_items = similarity.n_items();
+    let mut counts = vec![0_u32; n_items * n_items];
+    let mut n_done = 0;
+    while n_done < n_samples {
+        let n = batch_size.min(n_samples - n_done);
+        let (samples, _) = sample_epa::<LabelType, _, _>(
+            n,
+            similarity,
+            mass,
+            discount,
+            n_threads,
+            options.clone(),
+            rng,
+        );
+        let (draws, weights) = deduplicate(&samples[..n * n_items], n_items);
+        for (draw, weight) in draws.iter().zip(weights) {
+            let weight = u32::try_from(weight).unwrap();
+            for j in 0..n_items {
+                for i in 0..j {
+                    if draw[i] == draw[j] {
+                        counts[n_items * j + i] += weight;
+                    }
+                }
+            }
+        }
+        n_done += n;
+    }
+    let n_samples = n_samples.max(1) as f64;
+    let mut psm = vec![1.0; n_items * n_items];
+    for j in 0..n_items {
+        for i in 0..j {
+            let p = f64::from(counts[n_items * j + i]) / n_samples;
+            psm[n_items * j + i] = p;
+            psm[n_items * i + j] = p;
+        }
+    }
+    psm
+}
+
+// The pairwise similarity matrix (column-major) of EPA draws for the fixed permutation 'target',
+// from draws with random permutations weighted by their importance weights.  The weights are
+// rescaled by the largest seen so far, so that they cannot overflow.
+#[allow(clippy::too_many_arguments)]
+fn sample_weighted_psm<M: Similarity, T: Rng>(
+    n_samples: usize,
+    similarity: M,
+    mass: f64,
+    discount: f64,
+    n_threads: usize,
+    options: &SamplerOptions,
+    target: &Permutation,
+    rng: &mut T,
+) -> Vec<f64> {
+    let batch_size = 100;
+    let n_items = similarity.n_items();
+    let mut sums = vec![0.0; n_items * n_items];
+    let mut total = 0.0;
+    let mut max_log_weight = f64::NEG_INFINITY;
+    let mut n_done = 0;
+    while n_done < n_samples {
+        let n = batch_size.min(n_samples - n_done);
+        let (samples, _, log_weights): (Vec<LabelType>, _, _) = sample_epa_weighted(
+            n,
+            similarity,
+            mass,
+            discount,
+            n_threads,
+            options.clone(),
+            target,
+            rng,
+        );
+        for (draw, log_weight) in samples.chunks(n_items).zip(log_weights) {
+            if log_weight > max_log_weight {
+                let scale = (max_log_weight - log_weight).exp();
+                sums.iter_mut().for_each(|x| *x *= scale);
+                total *= scale;
+                max_log_weight = log_weight;
+            }
+            let weight = (log_weight - max_log_weight).exp();
+            total += weight;
+            for j in 0..n_items {
+                for i in 0..j {
+                    if draw[i] == draw[j] {
+                        sums[n_items * j + i] += weight;
+                    }
+                }
+            }
+        }
+        n_done += n;
+    }
+    let mut psm = vec![1.0; n_items * n_items];
+    for j in 0..n_items {
+        for i in 0..j {
+            let p = if total > 0.0 {
+                sums[n_items * j + i] / total
+            } else {
+                0.0
+            };
+            psm[n_items * j + i] = p;
+            psm[n_items * i + j] = p;
+        }
+    }
+    psm
+}
+
+/// The draws with each item repeated as many times as its multiplicity, so that SALSO counts each
+/// item with its multiplicity.
+pub fn expand_clusterings(clusterings: &Clusterings, multiplicities: &[usize]) -> Clusterings {
+    let n_items: usize = multiplicities.iter().sum();
+    let n_clusterings = clusterings.n_clusterings();
+    let mut labels = Vec::with_capacity(n_clusterings * n_items);
+    for k in 0..n_clusterings {
+        for (label, m) in clusterings.labels(k).iter().zip(multiplicities) {
+            labels.extend(std::iter::repeat_n(*label, *m));
+        }
+    }
+    let n_clusters = (0..n_clusterings)
+        .map(|k| clusterings.n_clusters(k))
+        .collect();
+    Clusterings::unvalidated(n_clusterings, n_items, labels, n_clusters)
+}
+
+/// The labels of the items given the labels of their repeats in [`expand_clusterings`], taking
+/// the label of the first repeat of each item and relabeling to `0, 1, ..., k - 1`.
+pub fn collapse_labels(labels: &[usize], multiplicities: &[usize]) -> Vec<usize> {
+    let mut first = 0;
+    let labels: Vec<_> = multiplicities
+        .iter()
+        .map(|m| {
+            first += m;
+            labels[first - m]
+        })
+        .collect();
+    crate::map::canonical(&labels)
+}
+
+/// The loss whose expectation SALSO minimizes.  Only the Binder and VI losses have a parameter `a`
+/// in (0, 2), which the search tunes to give candidates in the range for the number of clusters.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Loss {
+    Binder,
+    VI,
+    OneMinusARI,
+    OneMinusARIapprox,
+    NVI,
+    ID,
+    NID,
+}
+
+impl Loss {
+    const NAMES: [(&'static str, Self); 7] = [
+        ("binder", Self::Binder),
+        ("VI", Self::VI),
+        ("omARI", Self::OneMinusARI),
+        ("omARIapprox", Self::OneMinusARIapprox),
+        ("NVI", Self::NVI),
+        ("ID", Self::ID),
+        ("NID", Self::NID),
+    ];
+
+    /// The loss of the given name, as in the salso package, e.g., `"binder"` or `"omARI"`.
+    pub fn from_name(name: &str) -> Result<Self, String> {
+        Self::NAMES
+            .iter()
+            .find(|x| x.0 == name)
+            .map(|x| x.1)
+            .ok_or_else(|| {
+                "The loss must be one of 'binder', 'VI', 'omARI', 'omARIapprox', 'NVI', 'ID', or 'NID'."
+                    .to_string()
+            })
+    }
+
+    pub fn name(self) -> &'static str {
+        Self::NAMES.iter().find(|x| x.1 == self).unwrap().0
+    }
+
+    pub fn has_parameter(self) -> bool {
+        matches!(self, Self::Binder | Self::VI)
+    }
+
+    /// The loss function for SALSO, where `a` is ignored by losses without a parameter.
+    pub fn with_parameter(self, a: f64) -> LossFunction {
+        match self {
+            Self::Binder => LossFunction::BinderDraws(a),
+            Self::VI => LossFunction::VI(a),
+            Self::OneMinusARI => LossFunction::OneMinusARI,
+            Self::OneMinusARIapprox => LossFunction::OneMinusARIapprox,
+            Self::NVI => LossFunction::NVI,
+            Self::ID => LossFunction::ID,
+            Self::NID => LossFunction::NID,
+        }
+    }
+}
+
+/// How the loss parameter `a` is searched for an estimate whose number of clusters is in range,
+/// as described in [`search_loss_parameter`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ParameterSearch {
+    /// Bisection starting from a draw centered at the `a` of the preceding point.
+    Bisection,
+    /// Brent's method for the root of the number of clusters less the middle of the range.
+    Brent,
+    /// The points 0.1, 0.2, ..., 1.9 in order of their distance from the `a` of the preceding point.
+    Grid,
+    /// A single search at the given `a`, passed in place of the `a` of the preceding point.
+    Fixed,
+}
+
+impl ParameterSearch {
+    /// The search of the given name: `"bisection"`, `"brent"`, or `"grid"`.
+    pub fn from_name(name: &str) -> Result<Self, String> {
+        match name {
+            "bisection" => Ok(Self::Bisection),
+            "brent" => Ok(Self::Brent),
+            "grid" => Ok(Self::Grid),
+            _ => Err("The search for 'a' must be 'bisection', 'brent', or 'grid'.".to_string()),
+        }
+    }
+}
+
+/// The `a`, the number of clusters of the estimate, and its expected loss for one SALSO search made
+/// while searching for the loss parameter.
+pub type SearchStep = (f64, usize, f64);
+
+/// Search the loss parameter `a` over (0, 2) until the estimate from `minimize` has a number of
+/// clusters in `n_clusters_range` or the search narrows to `tol`.
+///
+/// Returns the estimate, its `a`, its expected loss, and the trace of the searches.  The prior for
+/// the initial `a` of a bisection is a beta distribution with `n0` pseudo-observations centered at
+/// `previous`, which is the value of `a` itself for a fixed search.  Bisection returns its last
+/// estimate, but the other methods return the estimate closest to the range if none is in it.  For
+/// a loss without a parameter, there is a single search.
+#[allow(clippy::too_many_arguments)]
+pub fn search_loss_parameter<T: Rng>(
+    mut minimize: impl FnMut(f64, &mut T) -> (Vec<usize>, f64),
+    loss: Loss,
+    method: ParameterSearch,
+    n_clusters_range: (f64, f64),
+    n0: f64,
+    tol: f64,
+    previous: f64,
+    rng: &mut T,
+) -> (Vec<usize>, f64, f64, Vec<SearchStep>) {
+    let (min_n_clusters, max_n_clusters) = n_clusters_range;
+    let miss = |n_clusters: usize| {
+        let x = n_clusters as f64;
+        (min_n_clusters - x).max(x - max_n_clusters).max(0.0)
+    };
+    let mut evaluations: Vec<(f64, Vec<usize>, f64)> = Vec::new();
+    let mut evaluate = |a: f64, rng: &mut T| {
+        let (clustering, loss_value) = minimize(a, rng);
+        let n_clusters = clustering.iter().max().unwrap() + 1;
+        evaluations.push((a, clustering, loss_value));
+        n_clusters
+    };
+    let mut closest = false;
+    match method {
+        ParameterSearch::Bisection => {
+            let (mut lower, mut upper) = (0.0, 2.0);
+            let beta = Beta::new(n0 * previous / 2.0, n0 * (1.0 - previous / 2.0)).unwrap();
+            let mut a = 2.0 * beta.sample(rng);
+            loop {
+                let n_clusters = evaluate(a, rng);
+                if upper - lower <= tol || !loss.has_parameter() {
+                    break;
+                } else if (n_clusters as f64) < min_n_clusters {
+                    upper = a;
+                    a = (lower + a) / 2.0;
+                } else if (n_clusters as f64) > max_n_clusters {
+                    lower = a;
+                    a = (upper + a) / 2.0;
+                } else {
+                    break;
+                }
+            }
+        }
+        ParameterSearch::Fixed => {
+            evaluate(previous, rng);
+        }
+        _ if !loss.has_parameter() => {
+            evaluate(1.0, rng);
+        }
+        ParameterSearch::Brent => {
+            closest = true;
+            // The number of clusters decreases in 'a', so the root is bracketed by the ends of
+            // the range unless the whole range gives too few or too many clusters.
+            let middle = (min_n_clusters + max_n_clusters) / 2.0;
+            let mut f = |a: f64, rng: &mut T| {
+                let n_clusters = evaluate(a, rng);
+                (n_clusters as f64 - middle, miss(n_clusters) == 0.0)
+            };
+            let (mut x0, mut x1) = (tol, 2.0 - tol);
+            let (mut f0, done0) = f(x0, rng);
+            let (mut f1, done1) = f(x1, rng);
+            if !done0 && !done1 && f0 * f1 < 0.0 {
+                if f0.abs() < f1.abs() {
+                    std::mem::swap(&mut x0, &mut x1);
+                    std::mem::swap(&mut f0, &mut f1);
+                }
+                let (mut c, mut fc, mut d) = (x0, f0, x0);
+                let mut bisected = true;
+                while (x1 - x0).abs() > tol {
+                    let mut x = if f0 != fc && f1 != fc {
+                        x0 * f1 * fc / ((f0 - f1) * (f0 - fc))
+                            + x1 * f0 * fc / ((f1 - f0) * (f1 - fc))
+                            + c * f0 * f1 / ((fc - f0) * (fc - f1))
+                    } else {
+                        x1 - f1 * (x1 - x0) / (f1 - f0)
+                    };
+                    let bound = (3.0 * x0 + x1) / 4.0;
+                    let step = if bisected {
+                        (x1 - c).abs()
+                    } else {
+                        (c - d).abs()
+                    };
+                    if (x - bound) * (x - x1) >= 0.0 || (x - x1).abs() >= step / 2.0 || step < tol {
+                        x = (x0 + x1) / 2.0;
+                        bisected = true;
+                    } else {
+                        bisected = false;
+                    }
+                    let (fx, done) = f(x, rng);
+                    if done {
+                        break;
+                    }
+                    (d, c, fc) = (c, x1, f1);
+                    if f0 * fx < 0.0 {
+                        (x1, f1) = (x, fx);
+                    } else {
+                        (x0, f0) = (x, fx);
+                    }
+                    if f0.abs() < f1.abs() {
+                        std::mem::swap(&mut x0, &mut x1);
+                        std::mem::swap(&mut f0, &mut f1);
+                    }
+                }
+            }
+        }
+        ParameterSearch::Grid => {
+            closest = true;
+            let mut points: Vec<f64> = (1..20).map(|k| (k as f64) / 10.0).collect();
+            points.sort_by(|x, y| (x - previous).abs().total_cmp(&(y - previous).abs()));
+            for a in points {
+                if miss(evaluate(a, rng)) == 0.0 {
+                    break;
+                }
+            }
+        }
+    }
+    let trace: Vec<_> = evaluations
+        .iter()
+        .map(|(a, clustering, loss_value)| (*a, clustering.iter().max().unwrap() + 1, *loss_value))
+        .collect();
+    let selected = if closest {
+        // The search stops at the first estimate in range, or else this is the first estimate
+        // closest to the range.
+        (0..trace.len())
+            .min_by(|i, j| miss(trace[*i].1).total_cmp(&miss(trace[*j].1)))
+            .unwrap()
+    } else {
+        trace.len() - 1
+    };
+    let (a, candidate, expected_loss) = evaluations.swap_remove(selected);
+    (candidate, a, expected_loss, trace)
+}
+
+/// The information from EPA draws used by SALSO: either the draws themselves or, to bound memory
+/// regardless of the number of draws, their pairwise similarity matrix (column-major).
+#[derive(Debug, Clone)]
+pub enum Draws {
+    Clusterings(Clusterings),
+    Psm(Vec<f64>),
+}
+
+impl Draws {
+    /// The estimate of SALSO and its expected loss for `loss` with parameter `a`.  For the pairwise
+    /// similarity matrix, the loss is always the generalized Binder loss.
+    pub fn minimize<T: Rng>(
+        &self,
+        loss: Loss,
+        a: f64,
+        p: &SALSOParameters,
+        seconds: f64,
+        n_threads: u32,
+        rng: &mut T,
+    ) -> (Vec<usize>, f64) {
+        let fit = match self {
+            Self::Clusterings(clusterings) => {
+                let pdi = PartitionDistributionInformation::Draws(clusterings);
+                minimize_by_salso(pdi, loss.with_parameter(a), p, seconds, n_threads, rng)
+            }
+            Self::Psm(psm) => {
+                // The expected generalized Binder loss with parameter 'a' is proportional to the
+                // expected Binder loss with the similarities divided by '2 - a'.
+                let mut scaled: Vec<_> = psm.iter().map(|x| x / (2.0 - a)).collect();
+                let psm = PsmBorrower::from_slice(&mut scaled, p.n_items);
+                let pdi = PartitionDistributionInformation::PairwiseSimilarityMatrix(&psm);
+                minimize_by_salso(pdi, LossFunction::BinderPSM, p, seconds, n_threads, rng)
+            }
+        };
+        (fit.clustering, fit.expected_loss)
+    }
+
+    /// For each item, the mean proportion of the other items on whose co-clustering with it the
+    /// draws and the estimate disagree, as given by [`item_disagreement`] or [`item_uncertainty`].
+    pub fn uncertainty(&self, estimate: &[usize]) -> Vec<f64> {
+        match self {
+            Self::Clusterings(clusterings) => {
+                let draws = (0..clusterings.n_clusterings()).map(|j| {
+                    clusterings
+                        .labels(j)
+                        .iter()
+                        .map(|x| *x as usize)
+                        .collect::<Vec<_>>()
+                });
+                item_disagreement(draws, estimate)
+            }
+            Self::Psm(psm) => item_uncertainty(psm, estimate),
+        }
+    }
+}
+
+/// The candidates to exclude from the final consensus, given by their `labels`, one candidate
+/// after another.
+///
+/// The medoid candidate minimizes the total variation of information to the other candidates, and
+/// a candidate is trimmed when its distance to the medoid exceeds `max_distance` or is among the
+/// largest `proportion` of such distances.  Candidates identical to the medoid are never trimmed.
+/// The result is sorted.
+pub fn trimmed_candidates(
+    labels: &[LabelType],
+    n_items: usize,
+    proportion: f64,
+    max_distance: f64,
+) -> Vec<usize> {
+    if proportion == 0.0 && max_distance == f64::INFINITY {
+        return Vec::new();
+    }
+    let candidates: Vec<Vec<usize>> = labels
+        .chunks(n_items)
+        .map(|x| x.iter().map(|label| usize::from(*label)).collect())
+        .collect();
+    let n = candidates.len();
+    let mut distances = vec![0.0; n * n];
+    for i in 0..n {
+        for j in (i + 1)..n {
+            let d = variation_of_information(&candidates[i], &candidates[j]);
+            distances[i * n + j] = d;
+            distances[j * n + i] = d;
+        }
+    }
+    let total = |i: usize| -> f64 { distances[i * n..(i + 1) * n].iter().sum() };
+    let medoid = (0..n)
+        .min_by(|i, j| total(*i).total_cmp(&total(*j)))
+        .unwrap();
+    let to_medoid = &distances[medoid * n..(medoid + 1) * n];
+    let n_trim = ((proportion * n as f64).floor() as usize).min(n - 1);
+    let mut order: Vec<_> = (0..n).filter(|i| to_medoid[*i] > 0.0).collect();
+    order.sort_by(|i, j| to_medoid[*j].total_cmp(&to_medoid[*i]));
+    let mut trimmed: Vec<_> = order
+        .into_iter()
+        .enumerate()
+        .filter(|(rank, i)| *rank < n_trim || to_medoid[*i] > max_distance)
+        .map(|(_, i)| i)
+        .collect();
+    trimmed.sort_unstable();
+    trimmed
+}
+
+/// The candidates (given by their labels and numbers of clusters) which are not `trimmed`.
+pub fn kept_candidates(
+    candidates_labels: &[LabelType],
+    candidates_n_clusters: &[LabelType],
+    n_items: usize,
+    trimmed: &[usize],
+) -> Clusterings {
+    let mut labels = Vec::with_capacity(candidates_labels.len());
+    let mut n_clusters = Vec::with_capacity(candidates_n_clusters.len());
+    for (k, (x, n)) in candidates_labels
+        .chunks(n_items)
+        .zip(candidates_n_clusters)
+        .enumerate()
+    {
+        if trimmed.binary_search(&k).is_err() {
+            labels.extend_from_slice(x);
+            n_clusters.push(*n);
+        }
+    }
+    Clusterings::unvalidated(n_clusters.len(), n_items, labels, n_clusters)
+}
+
+/// The `n_alternatives` best distinct estimates, in increasing order of expected loss, found by the
+/// runs of `p` made as separate searches on `n_threads` threads.
+///
+/// Each run has its own seed, so the alternatives do not depend on the number of threads.  The
+/// estimates are made for the items by [`SamplerOptions::impose`], and the expected losses are
+/// those of the searches.
+#[allow(clippy::too_many_arguments)]
+pub fn salso_alternatives<T: Rng>(
+    pdi: PartitionDistributionInformation,
+    loss_function: LossFunction,
+    p: &SALSOParameters,
+    seconds: f64,
+    n_threads: usize,
+    sampler: &SamplerOptions,
+    n_alternatives: usize,
+    rng: &mut T,
+) -> Vec<(Vec<usize>, f64)> {
+    let seeds: Vec<u128> = (0..p.n_runs.max(1)).map(|_| rng.random()).collect();
+    let p = SALSOParameters {
+        n_runs: 1,
+        ..p.clone()
+    };
+    let fits = parallel_map(&seeds, n_threads, |seed| {
+        let fit = minimize_by_salso(
+            pdi,
+            loss_function,
+            &p,
+            seconds,
+            1,
+            &mut Pcg64Mcg::new(*seed),
+        );
+        (fit.clustering, fit.expected_loss)
+    });
+    let mut alternatives: Vec<(Vec<usize>, f64)> = Vec::new();
+    for (clustering, expected_loss) in fits {
+        let clustering = canonical(&sampler.impose(clustering));
+        match alternatives.iter_mut().find(|x| x.0 == clustering) {
+            Some(x) => x.1 = x.1.min(expected_loss),
+            None => alternatives.push((clustering, expected_loss)),
+        }
+    }
+    alternatives.sort_by(|x, y| x.1.total_cmp(&y.1));
+    alternatives.truncate(n_alternatives);
+    alternatives
+}
+
+// Apply 'f' to each element of 'x', dividing the elements evenly among 'n_threads' threads.
+fn parallel_map<T: Sync, U: Send>(x: &[T], n_threads: usize, f: impl Fn(&T) -> U + Sync) -> Vec<U> {
+    let n_threads = n_threads.min(x.len()).max(1);
+    let chunk_size = x.len().div_ceil(n_threads).max(1);
+    crossbeam::scope(|s| {
+        let f = &f;
+        let handles: Vec<_> = x
+            .chunks(chunk_size)
+            .map(|chunk| s.spawn(move |_| chunk.iter().map(f).collect::<Vec<_>>()))
+            .collect();
+        handles
+            .into_iter()
+            .flat_map(|handle| handle.join().unwrap())
+            .collect()
+    })
+    .unwrap()
+}
+
+// The masses of 'n_refine' additional grid points around 'best', half between it and the next
+// smaller mass of the grid (or half of it) and half between it and the next larger mass (or twice
+// it), evenly spaced on the log scale of the mass plus the discount.
+fn refined_masses(masses: &[f64], best: f64, discount: f64, n_refine: usize) -> Vec<f64> {
+    let shifted = |x: f64| x + discount;
+    let lower = masses
+        .iter()
+        .copied()
+        .filter(|x| *x < best)
+        .max_by(f64::total_cmp)
+        .map_or(shifted(best) / 2.0, shifted);
+    let upper = masses
+        .iter()
+        .copied()
+        .filter(|x| *x > best)
+        .min_by(f64::total_cmp)
+        .map_or(shifted(best) * 2.0, shifted);
+    let n_below = n_refine / 2;
+    let n_above = n_refine - n_below;
+    let between = |from: f64, to: f64, n: usize| {
+        (1..=n).map(move |r| from * (to / from).powf((r as f64) / ((n + 1) as f64)) - discount)
+    };
+    between(lower, shifted(best), n_below)
+        .chain(between(shifted(best), upper, n_above))
+        .collect()
+}
+
+/// Tuning parameters of SALSO, as in the salso package.  The time budget `seconds` applies to each
+/// call of SALSO.
+#[derive(Debug, Clone, PartialEq)]
+pub struct SalsoOptions {
+    pub max_scans: u32,
+    pub max_zealous_updates: u32,
+    pub prob_sequential_allocation: f64,
+    pub prob_singletons_initialization: f64,
+    pub seconds: f64,
+}
+
+impl Default for SalsoOptions {
+    fn default() -> Self {
+        Self {
+            max_scans: u32::MAX,
+            max_zealous_updates: 10,
+            prob_sequential_allocation: 0.5,
+            prob_singletons_initialization: 0.0,
+            seconds: f64::INFINITY,
+        }
+    }
+}
+
+/// Options for [`caviarpd`], built from the range for the number of clusters.
+#[derive(Debug, Clone)]
+pub struct CaviarpdOptions {
+    min_n_clusters: f64,
+    max_n_clusters: f64,
+    masses: Option<Vec<f64>>,
+    n_samples: usize,
+    grid_length: usize,
+    loss: Loss,
+    salso_max_n_clusters: Option<usize>,
+    n_runs: u32,
+    n_threads: usize,
+    salso_n_threads: Option<usize>,
+    discount: f64,
+    n0: f64,
+    tol: f64,
+    mass_tol: f64,
+    sampler: SamplerOptions,
+    keep_draws: bool,
+    a_search: ParameterSearch,
+    a: Option<Vec<f64>>,
+    use_psm: bool,
+    rao_blackwell: bool,
+    target: Option<Permutation>,
+    trim_proportion: f64,
+    trim_distance: f64,
+    salso: SalsoOptions,
+    n_alternatives: usize,
+    exact_n_clusters: bool,
+    n_refine: usize,
+    endpoints: bool,
+    parallel_grid: bool,
+}
+
+impl CaviarpdOptions {
+    /// The defaults match those of the R package.
+    pub fn new(min_n_clusters: f64, max_n_clusters: f64) -> Self {
+        Self {
+            min_n_clusters: min_n_clusters.min(max_n_clusters),
+            max_n_clusters: min_n_clusters.max(max_n_clusters),
+            masses: None,
+            n_samples: 200,
+            grid_length: 5,
+            loss: Loss::Binder,
+            salso_max_n_clusters: None,
+            n_runs: 4,
+            n_threads: 1,
+            salso_n_threads: None,
+            discount: 0.0,
+            n0: 100.0,
+            tol: 0.01,
+            mass_tol: 1e-8,
+            sampler: SamplerOptions::default(),
+            keep_draws: false,
+            a_search: ParameterSearch::Bisection,
+            a: None,
+            use_psm: false,
+            rao_blackwell: false,
+            target: None,
+            trim_proportion: 0.0,
+            trim_distance: f64::INFINITY,
+            salso: SalsoOptions::default(),
+            n_alternatives: 0,
+            exact_n_clusters: false,
+            n_refine: 0,
+            endpoints: false,
+            parallel_grid: false,
+        }
+    }
+
+    /// Use these masses (one, or one per candidate) instead of those calibrated to the range for
+    /// the number of clusters.
+    pub fn with_masses(mut self, masses: Vec<f64>) -> Self {
+        self.masses = Some(masses);
+        self
+    }
+
+    pub fn with_n_samples(mut self, n_samples: usize) -> Self {
+        self.n_samples = n_samples;
+        self
+    }
+
+    pub fn with_grid_length(mut self, grid_length: usize) -> Self {
+        self.grid_length = grid_length;
+        self
+    }
+
+    pub fn with_loss(mut self, loss: Loss) -> Self {
+        self.loss = loss;
+        self
+    }
+
+    /// The maximum number of clusters considered by SALSO, which is one more than the largest
+    /// number of clusters in the range by default.
+    pub fn with_salso_max_n_clusters(mut self, max_n_clusters: usize) -> Self {
+        self.salso_max_n_clusters = Some(max_n_clusters);
+        self
+    }
+
+    pub fn with_n_runs(mut self, n_runs: u32) -> Self {
+        self.n_runs = n_runs;
+        self
+    }
+
+    pub fn with_n_threads(mut self, n_threads: usize) -> Self {
+        self.n_threads = n_threads;
+        self
+    }
+
+    /// The number of threads for SALSO, which is the number for sampling by default.  In strict
+    /// mode, SALSO runs on one thread.
+    pub fn with_salso_n_threads(mut self, n_threads: usize) -> Self {
+        self.salso_n_threads = Some(n_threads);
+        self
+    }
+
+    pub fn with_discount(mut self, discount: f64) -> Self {
+        self.discount = discount;
+        self
+    }
+
+    /// The tolerance of the search for the parameter of the loss and of the calibration of the
+    /// masses.
+    pub fn with_tolerances(mut self, tol: f64, mass_tol: f64) -> Self {
+        self.tol = tol;
+        self.mass_tol = mass_tol;
+        self
+    }
+
+    /// The number of pseudo-observations of the prior for the initial `a` of a bisection, as
+    /// described in [`search_loss_parameter`].
+    pub fn with_n0(mut self, n0: f64) -> Self {
+        self.n0 = n0;
+        self
+    }
+
+    pub fn with_sampler_options(mut self, sampler: SamplerOptions) -> Self {
+        self.sampler = sampler;
+        self
+    }
+
+    /// Keep the draws sampled for each mass in [`CaviarpdFit::draws`].
+    pub fn with_keep_draws(mut self, keep_draws: bool) -> Self {
+        self.keep_draws = keep_draws;
+        self
+    }
+
+    /// How each candidate searches for the loss parameter `a`, which is bisection by default.
+    pub fn with_parameter_search(mut self, a_search: ParameterSearch) -> Self {
+        self.a_search = a_search;
+        self
+    }
+
+    /// Use these values of the loss parameter `a` (one, or one per candidate in the order of the
+    /// masses) without any search.
+    pub fn with_a(mut self, a: Vec<f64>) -> Self {
+        self.a = Some(a);
+        self
+    }
+
+    /// Give SALSO the pairwise similarity matrix of the draws, as made by [`sample_psm`], rather
+    /// than the draws, which bounds memory regardless of the number of samples but requires the
+    /// Binder loss.
+    pub fn with_psm(mut self, use_psm: bool, rao_blackwell: bool) -> Self {
+        self.use_psm = use_psm;
+        self.rao_blackwell = rao_blackwell;
+        self
+    }
+
+    /// Weight the draws for the EPA distribution with the fixed permutation `target`, as described
+    /// in [`sample_psm`], which requires the pairwise similarity matrix.
+    pub fn with_target_permutation(mut self, target: Permutation) -> Self {
+        self.target = Some(target);
+        self
+    }
+
+    /// Exclude candidates from the final consensus as described in [`trimmed_candidates`].
+    pub fn with_trimming(mut self, proportion: f64, max_distance: f64) -> Self {
+        self.trim_proportion = proportion;
+        self.trim_distance = max_distance;
+        self
+    }
+
+    pub fn with_salso_options(mut self, salso: SalsoOptions) -> Self {
+        self.salso = salso;
+        self
+    }
+
+    /// Find up to `n_alternatives` alternatives to the estimate, as given by
+    /// [`salso_alternatives`].
+    pub fn with_n_alternatives(mut self, n_alternatives: usize) -> Self {
+        self.n_alternatives = n_alternatives;
+        self
+    }
+
+    /// Require the estimate to have exactly the number of clusters of the range, which must then
+    /// be a single integer.  If the consensus of the candidates does not, its loss parameter is
+    /// searched as well.
+    pub fn with_exact_n_clusters(mut self, exact: bool) -> Self {
+        self.exact_n_clusters = exact;
+        self
+    }
+
+    /// After the grid, add `n_refine` candidates at masses surrounding the mass whose candidate is
+    /// closest to the consensus of the candidates so far.
+    pub fn with_refinement(mut self, n_refine: usize) -> Self {
+        self.n_refine = n_refine;
+        self
+    }
+
+    /// Also compute, for each candidate, the estimates at the extremes of the range searched for
+    /// `a`, as given by [`CaviarpdFit::endpoints`].
+    pub fn with_endpoints(mut self, endpoints: bool) -> Self {
+        self.endpoints = endpoints;
+        self
+    }
+
+    /// Compute the candidates concurrently, sharing the threads, with each search for `a`
+    /// starting near 1 rather than near the `a` of the preceding candidate.
+    pub fn with_parallel_grid(mut self, parallel_grid: bool) -> Self {
+        self.parallel_grid = parallel_grid;
+        self
+    }
+}
+
+/// The settings and diagnostics of the SALSO search for an estimate, as in the `info` attribute of
+/// estimates from the salso package.
+#[derive(Debug, Clone)]
+pub struct SalsoInfo {
+    pub loss: Loss,
+    pub a: f64,
+    pub max_n_clusters: LabelType,
+    pub expected_loss: f64,
+    pub initialization_method: InitializationMethod,
+    pub n_scans: u32,
+    pub n_zealous_accepts: u32,
+    pub n_zealous_attempts: u32,
+    pub n_runs: u32,
+    pub seconds: f64,
+}
+
+impl SalsoInfo {
+    fn new(fit: &SALSOResults, loss: Loss, a: f64) -> Self {
+        Self {
+            loss,
+            a,
+            max_n_clusters: fit.max_size,
+            expected_loss: fit.expected_loss,
+            initialization_method: fit.initialization_method,
+            n_scans: fit.n_scans,
+            n_zealous_accepts: fit.n_zealous_accepts,
+            n_zealous_attempts: fit.n_zealous_attempts,
+            n_runs: fit.n_runs,
+            seconds: fit.seconds,
+        }
+    }
+}
+
+/// The result of [`caviarpd`].  The candidates are in the order in which they were computed, which
+/// is that of the masses, and labels are `0, 1, ..., k - 1`.
+#[derive(Debug, Clone)]
+pub struct CaviarpdFit {
+    /// The clustering estimate.
+    pub estimate: Vec<usize>,
+    /// The candidate estimates, one per mass.
+    pub candidates: Vec<Vec<usize>>,
+    /// The mass used for each candidate.
+    pub masses: Vec<f64>,
+    /// The loss parameter `a` of each candidate.
+    pub a: Vec<f64>,
+    /// The expected loss of each candidate with respect to its draws.
+    pub expected_losses: Vec<f64>,
+    /// The SALSO searches made in the search for `a` of each candidate.
+    pub traces: Vec<Vec<SearchStep>>,
+    /// The elapsed seconds spent on each candidate.
+    pub seconds: Vec<f64>,
+    /// The estimates for each candidate at the smallest and largest `a` searched, if
+    /// [`CaviarpdOptions::with_endpoints`], and otherwise empty.
+    pub endpoints: Vec<(Vec<usize>, Vec<usize>)>,
+    /// The indices of the candidates excluded from the final consensus, in increasing order.
+    pub trimmed: Vec<usize>,
+    /// The candidate closest to the estimate, whose draws give the uncertainty.
+    pub selected: usize,
+    /// The uncertainty of each item, as given by [`Draws::uncertainty`].
+    pub uncertainty: Vec<f64>,
+    /// The alternatives to the estimate with their expected losses, as given by
+    /// [`salso_alternatives`].
+    pub alternatives: Vec<(Vec<usize>, f64)>,
+    /// The exemplar of each cluster of the estimate, in order of first appearance of the clusters,
+    /// as given by [`exemplars`].
+    pub exemplars: Vec<usize>,
+    /// The SALSO search for the estimate, whose expected loss is with respect to the candidates
+    /// which are not trimmed.
+    pub info: SalsoInfo,
+    /// The draws for each mass, if [`CaviarpdOptions::with_keep_draws`], and otherwise empty.
+    pub draws: Vec<Draws>,
+    /// Whether each item is noise in the estimate, as given by [`Noise::relabel`], which is never
+    /// the case without noise in the sampler options.
+    pub noise: Vec<bool>,
+}
+
+/// Hooks into the progress of [`caviarpd_with_observer`], e.g., to report the resources used or
+/// the consensus of the candidates so far.  The default methods do nothing.
+pub trait Observer {
+    /// Run `f`, a phase of the computation such as `"sampling"` or `"consensus"`.  Phases of the
+    /// same name recur.
+    fn time<R>(&mut self, _phase: &'static str, f: impl FnOnce() -> R) -> R {
+        f()
+    }
+
+    /// Whether to compute the snapshots passed to [`Self::snapshot`], which is not supported for a
+    /// parallel grid.
+    fn wants_snapshots(&self) -> bool {
+        false
+    }
+
+    /// Receive the consensus of the candidates so far after each candidate.  An error ends the fit.
+    fn snapshot(&mut self, _snapshot: Snapshot) -> Result<(), String> {
+        Ok(())
+    }
+}
+
+impl Observer for () {}
+
+/// The consensus of the candidates after the candidate `grid_point` (counting from zero) out of
+/// `n_points`, searched with a copy of the random number generator so that taking snapshots does
+/// not change the fit.
+#[derive(Debug, Clone)]
+pub struct Snapshot<'a> {
+    pub grid_point: usize,
+    pub n_points: usize,
+    pub estimate: Vec<usize>,
+    pub masses: &'a [f64],
+    pub n_clusters: Vec<usize>,
+    pub expected_loss: f64,
+}
+
+/// Cluster items given their similarities by the CaviarPD method.
+///
+/// For each mass, partitions are sampled from the EPA distribution and SALSO finds a candidate
+/// estimate, tuning the parameter of the loss so that the candidate's number of clusters is in the
+/// range.  The estimate is then SALSO's consensus of the candidates.
+pub fn caviarpd<M: Similarity, T: Rng + Clone>(
+    similarity: M,
+    options: &CaviarpdOptions,
+    rng: &mut T,
+) -> Result<CaviarpdFit, String> {
+    caviarpd_with_observer(similarity, options, &mut (), rng)
+}
+
+/// Like [`caviarpd`], but reporting its progress to `observer`.
+pub fn caviarpd_with_observer<M: Similarity, O: Observer, T: Rng + Clone>(
+    similarity: M,
+    options: &CaviarpdOptions,
+    observer: &mut O,
+    rng: &mut T,
+) -> Result<CaviarpdFit, String> {
+    let n_items = similarity.n_items();
+    if n_items == 0 || n_items > usize::from(LabelType::MAX) {
+        return Err(format!(
+            "The number of items must be between 1 and {}.",
+            LabelType::MAX
+        ));
+    }
+    if !(0.0..1.0).contains(&options.discount) {
+        return Err("The discount must be in [0, 1).".to_string());
+    }
+    if !(options.n0.is_finite() && options.n0 > 0.0) {
+        return Err("'n0' must be finite and greater than 0.".to_string());
+    }
+    if !(options.tol > 0.0 && options.tol < 1.0) {
+        return Err("The tolerance must be in (0, 1).".to_string());
+    }
+    if !(options.mass_tol.is_finite() && options.mass_tol > 0.0) {
+        return Err("The tolerance for the mass must be finite and greater than 0.".to_string());
+    }
+    if !(0.0..1.0).contains(&options.trim_proportion) {
+        return Err("The proportion of candidates trimmed must be in [0, 1).".to_string());
+    }
+    if options.trim_distance.is_nan() || options.trim_distance < 0.0 {
+        return Err("The distance beyond which candidates are trimmed must be nonnegative.".to_string());
+    }
+    let (min_n_clusters, max_n_clusters) = (options.min_n_clusters, options.max_n_clusters);
+    if !(min_n_clusters.is_finite() && max_n_clusters.is_finite()) {
+        return Err("The range for the number of clusters must be finite.".to_string());
+    }
+    if options.exact_n_clusters && !(min_n_clusters == max_n_clusters && min_n_clusters.fract() == 0.0)
+    {
+        return Err("An exact number of clusters must be a single integer.".to_string());
+    }
+    if options.use_psm && options.loss != Loss::Binder {
+        return Err(
+            "Only the 'binder' loss is supported when using the pairwise similarity matrix."
+                .to_string(),
+        );
+    }
+    if options.parallel_grid && observer.wants_snapshots() {
+        return Err("Snapshots are not supported for a parallel grid.".to_string());
+    }
+    if let Some(constraints) = &options.sampler.constraints {
+        if constraints.n_items() != n_items {
+            return Err(format!(
+                "The constraints are for {} items, but there are {}.",
+                constraints.n_items(),
+                n_items
+            ));
+        }
+    }
+    if let Some(anchors) = &options.sampler.anchors {
+        if anchors.n_items() != n_items {
+            return Err(format!(
+                "The anchors are for {} items, but there are {}.",
+                anchors.n_items(),
+                n_items
+            ));
+        }
+        if let Some(constraints) = &options.sampler.constraints {
+            if !constraints.is_satisfied_by(anchors.labels()) {
+                return Err("The anchors violate the constraints.".to_string());
+            }
+        }
+    }
+    if let Some(noise) = &options.sampler.noise {
+        if noise.n_items() != n_items {
+            return Err(format!(
+                "The noise is for {} items, but there are {}.",
+                noise.n_items(),
+                n_items
+            ));
+        }
+        if options.sampler.constraints.is_some() {
+            return Err("Noise cannot be combined with constraints.".to_string());
+        }
+    }
+    if let Some(multiplicities) = options.sampler.multiplicities.as_deref() {
+        if multiplicities.len() != n_items || multiplicities.contains(&0) {
+            return Err(format!(
+                "There must be {} multiplicities, all positive.",
+                n_items
+            ));
+        }
+    }
+    if let Some(target) = &options.target {
+        if !options.use_psm {
+            return Err("A target permutation requires the pairwise similarity matrix.".to_string());
+        }
+        let sampler = &options.sampler;
+        if sampler.constraints.is_some()
+            || sampler.anchors.is_some()
+            || sampler.noise.is_some()
+            || sampler.subset_size.is_some()
+        {
+            return Err("A target permutation cannot be combined with constraints, anchors, noise, or a subset size.".to_string());
+        }
+        if target.n_items() != n_items {
+            return Err("The target permutation must be a permutation of the items.".to_string());
+        }
+    }
+    let n_salso_items = options.sampler.n_salso_items(n_items);
+    if n_salso_items > usize::from(LabelType::MAX) - 1 {
+        return Err(format!(
+            "The number of items counted with their multiplicities ({}) exceeds the maximum of {} supported by the SALSO step.",
+            n_salso_items,
+            LabelType::MAX - 1
+        ));
+    }
+    let grid_length = options
+        .grid_length
+        .max(if min_n_clusters == max_n_clusters {
+            1
+        } else {
+            2
+        });
+    let n_points = grid_length + options.n_refine;
+    let (mut masses, order) = observer.time("mass", || {
+        let masses = match &options.masses {
+            None => {
+                let step_size = (max_n_clusters - min_n_clusters) / (grid_length as f64);
+                let enocs: Vec<_> = (0..grid_length)
+                    .map(|x| min_n_clusters + (x as f64) * step_size)
+                    .collect();
+                parallel_map(&enocs, options.n_threads, |enoc| {
+                    find_mass(*enoc, options.discount, n_items, options.mass_tol)
+                })
+                .into_iter()
+                .collect::<Result<Vec<_>, _>>()?
+            }
+            Some(masses) if masses.len() == 1 => vec![masses[0]; grid_length],
+            Some(masses) if masses.len() == grid_length => masses.clone(),
+            Some(masses) => {
+                return Err(format!(
+                    "There must be 1 or {} masses (the grid length), but there are {}.",
+                    grid_length,
+                    masses.len()
+                ))
+            }
+        };
+        if let Some(mass) = masses
+            .iter()
+            .find(|mass| !(mass.is_finite() && **mass > -options.discount))
+        {
+            return Err(format!(
+                "The mass must be finite and greater than {}, but is {}.",
+                -options.discount, mass
+            ));
+        }
+        let mut order: Vec<_> = (0..masses.len()).collect();
+        order.shuffle(rng);
+        let masses: Vec<_> = order.iter().map(|k| masses[*k]).collect();
+        Ok((masses, order))
+    })?;
+    // Fixed values of 'a' are given in the order of the masses, before they are shuffled.
+    let fixed_a: Option<Vec<f64>> = match &options.a {
+        None => None,
+        Some(a) => {
+            if a.len() != 1 && a.len() != grid_length {
+                return Err(format!(
+                    "There must be 1 or {} values of 'a' (the grid length), but there are {}.",
+                    grid_length,
+                    a.len()
+                ));
+            }
+            if a.iter().any(|x| !(*x > 0.0 && *x < 2.0)) {
+                return Err("The values of 'a' must be in (0, 2).".to_string());
+            }
+            Some(order.iter().map(|k| a[*k % a.len()]).collect())
+        }
+    };
+    let max_size = options
+        .salso_max_n_clusters
+        .unwrap_or(max_n_clusters.ceil() as usize + 1);
+    let strict = options.sampler.strict;
+    let salso = SalsoOptions {
+        seconds: if strict {
+            f64::INFINITY
+        } else {
+            options.salso.seconds
+        },
+        ..options.salso.clone()
+    };
+    let p = SALSOParameters {
+        n_items: n_salso_items,
+        max_size: LabelType::try_from(max_size).map_err(|_| {
+            format!(
+                "The maximum number of clusters must be at most {}.",
+                LabelType::MAX
+            )
+        })?,
+        max_size_as_rf: false,
+        max_scans: salso.max_scans,
+        max_zealous_updates: salso.max_zealous_updates,
+        n_runs: options.n_runs.max(1),
+        prob_sequential_allocation: salso.prob_sequential_allocation,
+        prob_singletons_initialization: salso.prob_singletons_initialization,
+    };
+    let salso_n_threads = options.salso_n_threads.unwrap_or(options.n_threads);
+    let salso_n_cores = if strict {
+        1
+    } else {
+        u32::try_from(salso_n_threads).unwrap_or(u32::MAX)
+    };
+    // The heuristic order of the items, if any, is the same for every mass.
+    let sampler = match options.sampler.order {
+        Some(_) => options.sampler.clone(),
+        None => options.sampler.clone().with_order(similarity),
+    };
+    let search = GridSearch {
+        similarity,
+        options,
+        sampler: &sampler,
+        n_threads: options.n_threads,
+        salso_n_cores,
+        p: &p,
+        seconds: salso.seconds,
+        n_clusters_range: (min_n_clusters, max_n_clusters),
+        a_search: if fixed_a.is_some() {
+            ParameterSearch::Fixed
+        } else {
+            options.a_search
+        },
+    };
+    // In a parallel grid, the points are independent (each centers the prior for 'a' at 1) and
+    // share the threads.
+    let mut parallel_points = if options.parallel_grid {
+        let seeds: Vec<u128> = masses.iter().map(|_| rng.random()).collect();
+        let search = GridSearch {
+            n_threads: (options.n_threads / grid_length).max(1),
+            salso_n_cores: 1,
+            ..search
+        };
+        let points: Vec<_> = observer.time("grid", || {
+            crossbeam::scope(|s| {
+                let search = &search;
+                let fixed_a = &fixed_a;
+                let handles: Vec<_> = masses
+                    .iter()
+                    .zip(seeds)
+                    .enumerate()
+                    .map(|(i, (mass, seed))| {
+                        let start = fixed_a.as_ref().map_or(1.0, |a| a[i]);
+                        s.spawn(move |_| {
+                            search.point(*mass, start, &mut (), &mut Pcg64Mcg::new(seed))
+                        })
+                    })
+                    .collect();
+                handles
+                    .into_iter()
+                    .map(|handle| handle.join().unwrap())
+                    .collect()
+            })
+            .expect("a grid point panicked")
+        });
+        Some(points.into_iter())
+    } else {
+        None
+    };
+    let consensus = |labels: &[LabelType], n_clusters: &[LabelType], rng: &mut T| {
+        let trimmed = trimmed_candidates(
+            labels,
+            n_items,
+            options.trim_proportion,
+            options.trim_distance,
+        );
+        let kept = sampler.expand_clusterings(kept_candidates(labels, n_clusters, n_items, &trimmed));
+        let fit = minimize_by_salso(
+            PartitionDistributionInformation::Draws(&kept),
+            options.loss.with_parameter(1.0),
+            &p,
+            salso.seconds,
+            salso_n_cores,
+            rng,
+        );
+        (sampler.impose(fit.clustering), fit.expected_loss)
+    };
+    let mut previous = 1.0;
+    let mut candidates_labels = Vec::with_capacity(n_points * n_items);
+    let mut candidates_n_clusters = Vec::with_capacity(n_points);
+    let mut grid_a = Vec::with_capacity(n_points);
+    let mut expected_losses = Vec::with_capacity(n_points);
+    let mut traces = Vec::with_capacity(n_points);
+    let mut seconds = Vec::with_capacity(n_points);
+    let mut endpoints = Vec::new();
+    let mut draws = Vec::with_capacity(n_points);
+    for i in 0..n_points {
+        if i == grid_length {
+            // The refinement points surround the mass whose candidate is closest to the consensus
+            // of the candidates so far, and their searches for 'a' start near its value.
+            let best = observer.time("refinement", || {
+                let (estimate, _) = consensus(&candidates_labels, &candidates_n_clusters, rng);
+                closest_candidate(&candidates_labels, n_items, &estimate)
+            });
+            previous = grid_a[best];
+            let refined = refined_masses(&masses, masses[best], options.discount, options.n_refine);
+            masses.extend(refined);
+        }
+        if let Some(a) = fixed_a.as_ref().filter(|_| i < grid_length) {
+            previous = a[i];
+        }
+        let point = match parallel_points.as_mut().and_then(|points| points.next()) {
+            Some(point) => point,
+            None => search.point(masses[i], previous, observer, rng),
+        };
+        previous = point.a;
+        grid_a.push(point.a);
+        expected_losses.push(point.expected_loss);
+        traces.push(point.trace);
+        if let Some(x) = point.endpoints {
+            endpoints.push(x);
+        }
+        let candidate = point.candidate;
+        candidates_labels.extend(candidate.iter().map(|x| LabelType::try_from(*x).unwrap()));
+        candidates_n_clusters.push(LabelType::try_from(candidate.iter().max().unwrap() + 1).unwrap());
+        seconds.push(point.seconds);
+        draws.push(point.draws);
+        if observer.wants_snapshots() {
+            let (estimate, expected_loss) = observer.time("snapshot", || {
+                consensus(&candidates_labels, &candidates_n_clusters, &mut rng.clone())
+            });
+            observer.snapshot(Snapshot {
+                grid_point: i,
+                n_points,
+                estimate,
+                masses: &masses[..=i],
+                n_clusters: candidates_n_clusters.iter().map(|x| usize::from(*x)).collect(),
+                expected_loss,
+            })?;
+        }
+    }
+    let trimmed = observer.time("trimming", || {
+        trimmed_candidates(
+            &candidates_labels,
+            n_items,
+            options.trim_proportion,
+            options.trim_distance,
+        )
+    });
+    let kept = sampler.expand_clusterings(kept_candidates(
+        &candidates_labels,
+        &candidates_n_clusters,
+        n_items,
+        &trimmed,
+    ));
+    let pdi = PartitionDistributionInformation::Draws(&kept);
+    let fit = observer.time("consensus", || {
+        minimize_by_salso(
+            pdi,
+            options.loss.with_parameter(1.0),
+            &p,
+            salso.seconds,
+            salso_n_cores,
+            rng,
+        )
+    });
+    let mut estimate = sampler.impose(fit.clustering.clone());
+    let mut info = SalsoInfo::new(&fit, options.loss, 1.0);
+    let n_clusters = |x: &[usize]| x.iter().max().map_or(0, |x| x + 1) as f64;
+    if options.exact_n_clusters && min_n_clusters == 1.0 && n_clusters(&estimate) != 1.0 {
+        // No loss parameter below 2 merges clusters whose items are never clustered together, but
+        // the single cluster is the only partition with one cluster.  SALSO limited to one cluster
+        // finds it with its expected loss.
+        let p = SALSOParameters {
+            max_size: 1,
+            ..p.clone()
+        };
+        let fit = observer.time("consensus", || {
+            minimize_by_salso(
+                pdi,
+                options.loss.with_parameter(1.0),
+                &p,
+                salso.seconds,
+                salso_n_cores,
+                rng,
+            )
+        });
+        estimate = sampler.impose(fit.clustering.clone());
+        info = SalsoInfo::new(&fit, options.loss, 1.0);
+        if n_clusters(&estimate) != 1.0 {
+            return Err(format!(
+                "The anchors require {} clusters, so no estimate has exactly 1 cluster.",
+                n_clusters(&estimate)
+            ));
+        }
+    } else if options.exact_n_clusters && n_clusters(&estimate) != min_n_clusters {
+        // The consensus with the loss parameter searched, as for the candidates, and the number
+        // of clusters capped by the maximum number of clusters of SALSO.
+        let mut last_fit = None;
+        let (candidate, a, _, _) = observer.time("consensus", || {
+            search_loss_parameter(
+                |a, rng| {
+                    let fit = minimize_by_salso(
+                        pdi,
+                        options.loss.with_parameter(a),
+                        &p,
+                        salso.seconds,
+                        salso_n_cores,
+                        rng,
+                    );
+                    let result = (sampler.impose(fit.clustering.clone()), fit.expected_loss);
+                    last_fit = Some(fit);
+                    result
+                },
+                options.loss,
+                ParameterSearch::Bisection,
+                (min_n_clusters, max_n_clusters),
+                options.n0,
+                options.tol,
+                1.0,
+                rng,
+            )
+        });
+        if n_clusters(&candidate) != min_n_clusters {
+            return Err(format!(
+                "Could not find an estimate with exactly {} clusters. The closest has {} clusters.",
+                min_n_clusters,
+                n_clusters(&candidate)
+            ));
+        }
+        // Bisection returns its last estimate.
+        estimate = candidate;
+        info = SalsoInfo::new(&last_fit.unwrap(), options.loss, a);
+    }
+    let selected = closest_candidate(&candidates_labels, n_items, &estimate);
+    let uncertainty = observer.time("uncertainty", || draws[selected].uncertainty(&estimate));
+    let alternatives = if options.n_alternatives > 0 {
+        observer.time("alternatives", || {
+            salso_alternatives(
+                pdi,
+                options.loss.with_parameter(1.0),
+                &p,
+                salso.seconds,
+                salso_n_threads,
+                &sampler,
+                options.n_alternatives,
+                rng,
+            )
+        })
+    } else {
+        Vec::new()
+    };
+    if !options.keep_draws {
+        draws.clear();
+    }
+    let noise = match &options.sampler.noise {
+        Some(noise) => noise.relabel(&estimate).iter().map(|x| x.is_none()).collect(),
+        None => vec![false; n_items],
+    };
+    Ok(CaviarpdFit {
+        exemplars: exemplars(similarity, &canonical(&estimate)),
+        estimate,
+        candidates: candidates_labels
+            .chunks(n_items)
+            .map(|x| x.iter().map(|x| usize::from(*x)).collect())
+            .collect(),
+        masses,
+        a: grid_a,
+        expected_losses,
+        traces,
+        seconds,
+        endpoints,
+        trimmed,
+        selected,
+        uncertainty,
+        alternatives,
+        info,
+        draws,
+        noise,
+    })
+}
+
+// The index of the candidate (given by their labels, one after another) closest to 'estimate' in
+// variation of information, the first in case of ties.
+fn closest_candidate(labels: &[LabelType], n_items: usize, estimate: &[usize]) -> usize {
+    labels
+        .chunks(n_items)
+        .map(|candidate| {
+            let candidate: Vec<_> = candidate.iter().map(|x| usize::from(*x)).collect();
+            variation_of_information(&candidate, estimate)
+        })
+        .enumerate()
+        .min_by(|x, y| x.1.total_cmp(&y.1))
+        .unwrap()
+        .0
+}
+
+// The settings shared by the points of the mass grid of 'caviarpd'.
+struct GridSearch<'a, M> {
+    similarity: M,
+    options: &'a CaviarpdOptions,
+    sampler: &'a SamplerOptions,
+    n_threads: usize,
+    salso_n_cores: u32,
+    p: &'a SALSOParameters,
+    seconds: f64,
+    n_clusters_range: (f64, f64),
+    a_search: ParameterSearch,
+}
+
+// The draws for one mass of the grid, the candidate found by the search for the loss parameter
+// 'a', and, if requested, the estimates at the extremes of the range searched for 'a'.
+struct GridPoint {
+    draws: Draws,
+    candidate: Vec<usize>,
+    a: f64,
+    expected_loss: f64,
+    trace: Vec<SearchStep>,
+    endpoints: Option<(Vec<usize>, Vec<usize>)>,
+    seconds: f64,
+}
+
+impl<M: Similarity> GridSearch<'_, M> {
+    // The prior for the initial 'a' is centered at 'previous', the 'a' of the preceding point, which
+    // is the value of 'a' itself for a fixed search.
+    fn point<O: Observer, T: Rng>(
+        &self,
+        mass: f64,
+        previous: f64,
+        observer: &mut O,
+        rng: &mut T,
+    ) -> GridPoint {
+        let start = Instant::now();
+        let options = self.options;
+        let draws = observer.time("sampling", || {
+            if options.use_psm {
+                Draws::Psm(sample_psm(
+                    options.n_samples,
+                    self.similarity,
+                    mass,
+                    options.discount,
+                    self.n_threads,
+                    self.sampler,
+                    options.rao_blackwell,
+                    options.target.as_ref(),
+                    rng,
+                ))
+            } else {
+                let (samples, n_clusters) = sample_epa::<LabelType, _, _>(
+                    options.n_samples,
+                    self.similarity,
+                    mass,
+                    options.discount,
+                    self.n_threads,
+                    self.sampler.clone(),
+                    rng,
+                );
+                let n_items = self.similarity.n_items();
+                Draws::Clusterings(Clusterings::unvalidated(
+                    samples.len() / n_items,
+                    n_items,
+                    samples,
+                    n_clusters,
+                ))
+            }
+        });
+        let expanded = self.sampler.expand(&draws);
+        let minimize = |a: f64, rng: &mut T| {
+            let (clustering, loss_value) = expanded.as_ref().unwrap_or(&draws).minimize(
+                options.loss,
+                a,
+                self.p,
+                self.seconds,
+                self.salso_n_cores,
+                rng,
+            );
+            (self.sampler.impose(clustering), loss_value)
+        };
+        let (candidate, a, expected_loss, trace) = search_loss_parameter(
+            |a, rng| observer.time("search", || minimize(a, rng)),
+            options.loss,
+            self.a_search,
+            self.n_clusters_range,
+            options.n0,
+            options.tol,
+            previous,
+            rng,
+        );
+        let endpoints = options.endpoints.then(|| {
+            // The extremes of the range (0, 2) searched for 'a', whatever the search found.
+            let mut extreme = |a: f64| observer.time("search", || minimize(a, rng)).0;
+            let lower = extreme(options.tol);
+            (lower, extreme(2.0 - options.tol))
+        });
+        GridPoint {
+            draws,
+            candidate,
+            a,
+            expected_loss,
+            trace,
+            endpoints,
+            seconds: start.elapsed().as_secs_f64(),
+        }
+    }
+}
+
+/// The number of items in the pilot fit of [`preview`], unless the range for the number of
+/// clusters needs more.
+const PILOT_N_ITEMS: usize = 50;
+
+/// The result of [`preview`].
+#[derive(Debug, Clone)]
+pub struct Preview {
+    /// The subsampled items, in increasing order.
+    pub items: Vec<usize>,
+    /// The fit to the subsampled items, with one label per element of `items`.
+    pub fit: CaviarpdFit,
+    /// The seconds taken by the fit to the subsampled items.
+    pub seconds: f64,
+    /// The seconds that a fit to all the items is expected to take.
+    pub estimated_seconds: f64,
+}
+
+/// Fit a random subsample of the items, as large as can be fit in about `budget_seconds`, and
+/// extrapolate the time needed to fit all the items.
+///
+/// The time of a fit grows with the square of the number of items, since each draw sums the
+/// similarities between each item and those allocated before it.  A pilot fit to a small
+/// subsample gives the number of items that can be fit in what remains of the budget, and the
+/// time of that fit is then extrapolated to all the items.  When even the pilot fit exceeds the
+/// budget, it is the preview.  Constraints, anchors, multiplicities, noise, any heuristic order,
+/// and any target permutation are ignored, since they need not involve the subsampled items, so
+/// the subsample is fit with the draws averaged over permutations.
+pub fn preview<M: Similarity, T: Rng + Clone>(
+    similarity: M,
+    options: &CaviarpdOptions,
+    budget_seconds: f64,
+    rng: &mut T,
+) -> Result<Preview, String> {
+    let n_items = similarity.n_items();
+    let mut options = options.clone();
+    options.sampler.constraints = None;
+    options.sampler.anchors = None;
+    options.sampler.multiplicities = None;
+    options.sampler.noise = None;
+    options.sampler.order = None;
+    options.target = None;
+    let fit_subsample = |n_subsample: usize, rng: &mut T| {
+        let mut items = index::sample(rng, n_items, n_subsample).into_vec();
+        items.sort_unstable();
+        let mut data = Vec::with_capacity(n_subsample * n_subsample);
+        for j in &items {
+            data.extend(items.iter().map(|i| similarity.get(*i, *j)));
+        }
+        let start = Instant::now();
+        let fit = caviarpd(
+            SquareMatrixBorrower::from_slice(&data, n_subsample).detect_symmetry(),
+            &options,
+            rng,
+        )?;
+        let seconds = start.elapsed().as_secs_f64();
+        Ok::<_, String>(Preview {
+            estimated_seconds: seconds * ((n_items * n_items) as f64)
+                / ((n_subsample * n_subsample) as f64),
+            items,
+            fit,
+            seconds,
+        })
+    };
+    let n_pilot = n_items.min(PILOT_N_ITEMS.max(4 * options.max_n_clusters.ceil() as usize));
+    let pilot = fit_subsample(n_pilot, rng)?;
+    let remaining = budget_seconds - pilot.seconds;
+    if n_pilot == n_items || remaining <= pilot.seconds {
+        return Ok(pilot);
+    }
+    let n_subsample = ((n_pilot as f64) * (remaining / pilot.seconds).sqrt()) as usize;
+    if n_subsample <= n_pilot {
+        return Ok(pilot);
+    }
+    fit_subsample(n_subsample.min(n_items), rng)
+}
+
+/// How well an estimate recovers the ground truth in one replicate of [`simulation_study`].
+#[derive(Debug, Clone, PartialEq)]
+pub struct Recovery {
+    /// The adjusted Rand index between the estimate and the truth.
+    pub ari: f64,
+    /// The variation of information (in base 2) between the estimate and the truth.
+    pub vi: f64,
+    /// The number of clusters in the estimate.
+    pub n_clusters: usize,
+}
+
+/// Fit `n_replicates` similarity matrices simulated by [`simulate_similarity`] from the partition
+/// given by `truth` and report how well each estimate recovers it.
+///
+/// The replicates are fit in parallel, one per thread of the options, so each fit is itself
+/// single-threaded.  Each replicate has its own random number generator seeded from `rng`, so the
+/// results do not depend on the number of threads.
+pub fn simulation_study<T: Rng>(
+    truth: &[usize],
+    within: f64,
+    between: f64,
+    noise_sd: f64,
+    n_replicates: usize,
+    options: &CaviarpdOptions,
+    rng: &mut T,
+) -> Result<Vec<Recovery>, String> {
+    let n_items = truth.len();
+    let seeds: Vec<u128> = (0..n_replicates).map(|_| rng.random()).collect();
+    let n_threads = options.n_threads.clamp(1, n_replicates.max(1));
+    let options = options.clone().with_n_threads(1);
+    let replicates = Mutex::new(seeds.into_iter().enumerate());
+    let results = crossbeam::scope(|s| {
+        let handles: Vec<_> = (0..n_threads)
+            .map(|_| {
+                s.spawn(|_| {
+                    let mut results = Vec::new();
+                    loop {
+                        let next = replicates.lock().unwrap().next();
+                        let Some((replicate, seed)) = next else {
+                            break;
+                        };
+                        let mut rng = Pcg64Mcg::new(seed);
+                        let data = simulate_similarity(truth, within, between, noise_sd, &mut rng);
+                        let similarity = SquareMatrixBorrower::from_slice(&data, n_items);
+                        let recovery = caviarpd(similarity, &options, &mut rng).map(|fit| Recovery {
+                            ari: adjusted_rand_index(&fit.estimate, truth),
+                            vi: variation_of_information(&fit.estimate, truth),
+                            n_clusters: fit.estimate.iter().max().map_or(0, |x| x + 1),
+                        });
+                        results.push((replicate, recovery));
+                    }
+                    results
+                })
+            })
+            .collect();
+        let mut results: Vec<_> = handles
+            .into_iter()
+            .flat_map(|handle| handle.join().unwrap())
+            .collect();
+        results.sort_unstable_by_key(|x| x.0);
+        results
+    })
+    .expect("a fitting thread panicked");
+    results.into_iter().map(|x| x.1).collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::similarity::{balanced_partition, similarity_from_partition};
+    use rand::SeedableRng;
+
+    #[test]
+    fn test_find_mass() {
+        assert_eq!(expected_number_of_clusters(2.0, 0.0, 1), 1.0);
+        let mass = find_mass(5.0, 0.25, 100, 1e-10).unwrap();
+        assert!((expected_number_of_clusters(mass, 0.25, 100) - 5.0).abs() < 1e-6);
+        assert!(find_mass(100.0, 0.0, 100, 1e-10).is_err());
+        assert!(find_mass(0.5, 0.0, 100, 1e-10).is_err());
+    }
+
+    #[test]
+    fn test_find_mass_one_cluster() {
+        for discount in [0.0, 0.25] {
+            let mass = find_mass(1.0, discount, 100, 1e-10).unwrap();
+            assert!(mass > -discount);
+            assert!(expected_number_of_clusters(mass, discount, 100) - 1.0 < 1e-6);
+        }
+        assert!(find_mass(1.0, 0.0, 1, 1e-10).is_ok());
+    }
+
+    #[test]
+    fn test_sample_epa_thread_invariance() {
+        let data: Vec<_> = (0..64).map(|x| 1.0 + (x % 7) as f64).collect();
+        let similarity = SquareMatrixBorrower::from_slice(&data, 8);
+        for strict in [true, false] {
+            let options = SamplerOptions {
+                strict,
+                ..SamplerOptions::default()
+            };
+            let draws: Vec<(Vec<u32>, Vec<u32>)> = [1, 3, 20]
+                .into_iter()
+                .map(|n_threads| {
+                    let mut rng = Pcg64Mcg::seed_from_u64(7);
+                    let options = options.clone();
+                    sample_epa(10, similarity, 1.0, 0.0, n_threads, options, &mut rng)
+                })
+                .collect();
+            assert_eq!(draws[0].0.len(), 10 * 8);
+            assert_eq!(draws[0].1.len(), 10);
+            assert!(draws.iter().all(|x| *x == draws[0]));
+        }
+    }
+
+    #[test]
+    fn test_sampler_order() {
+        let data: Vec<_> = (0..64).map(|x| 1.0 + (x % 7) as f64).collect();
+        let similarity = SquareMatrixBorrower::from_slice(&data, 8);
+        let options = SamplerOptions {
+            permutation: PermutationStrategy::Fiedler,
+            ..SamplerOptions::default()
+        };
+        let ordered = options.clone().with_order(similarity);
+        assert_eq!(ordered.order.as_deref().map(<[usize]>::len), Some(8));
+        let draws: Vec<(Vec<u32>, Vec<u32>)> = [options, ordered]
+            .into_iter()
+            .map(|options| {
+                let mut rng = Pcg64Mcg::seed_from_u64(7);
+                sample_epa(10, similarity, 1.0, 0.0, 2, options, &mut rng)
+            })
+            .collect();
+        assert_eq!(draws[0], draws[1]);
+    }
+
+    #[test]
+    fn test_sample_crp() {
+        let data = vec![1.0; 36];
+        let similarity = SquareMatrixBorrower::from_slice(&data, 6);
+        for discount in [0.0, 0.3] {
+            let mut rng = Pcg64Mcg::seed_from_u64(5);
+            let draws: (Vec<u32>, Vec<u32>) = sample_crp(50, 6, 1.5, discount, 2, &mut rng);
+            let mut rng = Pcg64Mcg::seed_from_u64(5);
+            let options = SamplerOptions::default();
+            let expected = sample_epa(50, similarity, 1.5, discount, 2, options, &mut rng);
+            assert_eq!(draws, expected);
+        }
+    }
+
+    #[test]
+    fn test_rao_blackwellized_psm() {
+        let data: Vec<_> = (0..64).map(|x| 1.0 + (x % 7) as f64).collect();
+        let similarity = SquareMatrixBorrower::from_slice(&data, 8);
+        let psms: Vec<_> = [1, 3]
+            .into_iter()
+            .map(|n_threads| {
+                let mut rng = Pcg64Mcg::seed_from_u64(7);
+                let options = SamplerOptions::default();
+                rao_blackwellized_psm(2000, similarity, 1.0, 0.0, n_threads, options, &mut rng)
+            })
+            .collect();
+        assert_eq!(psms[0], psms[1]);
+        // The estimate agrees with the proportion of the same draws clustering items together.
+        let mut rng = Pcg64Mcg::seed_from_u64(7);
+        let options = SamplerOptions::default();
+        let (draws, _) = sample_epa::<u32, _, _>(2000, similarity, 1.0, 0.0, 1, options, &mut rng);
+        for j in 0..8 {
+            assert_eq!(psms[0][8 * j + j], 1.0);
+            for i in 0..j {
+                let together = draws.chunks(8).filter(|x| x[i] == x[j]).count();
+                let p = together as f64 / 2000.0;
+                assert!((psms[0][8 * j + i] - p).abs() < 0.05);
+                assert_eq!(psms[0][8 * j + i], psms[0][8 * i + j]);
+            }
+        }
+    }
+
+    #[test]
+    fn test_sample_epa_weighted() {
+        let data = [1.0, 4.0, 1.0, 4.0, 1.0, 0.5, 1.0, 0.5, 1.0];
+        let similarity = SquareMatrixBorrower::from_slice(&data, 3);
+        let target = Permutation::from_vector(vec![2, 0, 1]).unwrap();
+        let options = SamplerOptions::default();
+        let mut rng = Pcg64Mcg::seed_from_u64(3);
+        let (draws, _, log_weights): (Vec<u32>, _, _) =
+            sample_epa_weighted(20000, similarity, 1.0, 0.0, 2, options.clone(), &target, &mut rng);
+        let mut rng = Pcg64Mcg::seed_from_u64(3);
+        let (unweighted, _): (Vec<u32>, _) =
+            sample_epa(20000, similarity, 1.0, 0.0, 2, options, &mut rng);
+        assert_eq!(draws, unweighted);
+        // The probability that items 0 and 1 are clustered together given the target permutation.
+        let parameters = EpaParameters::new(similarity, target, 1.0).unwrap();
+        let expected: f64 = [[0, 0, 0], [0, 0, 1]]
+            .iter()
+            .map(|x| log_probability(x, &parameters).exp())
+            .sum();
+        let weights: Vec<_> = log_weights.iter().map(|x| x.exp()).collect();
+        let together: f64 = draws
+            .chunks(3)
+            .zip(&weights)
+            .filter(|(x, _)| x[0] == x[1])
+            .map(|(_, w)| w)
+            .sum();
+        let estimate = together / weights.iter().sum::<f64>();
+        assert!((estimate - expected).abs() < 0.02);
+    }
+
+    #[test]
+    fn test_multiplicities() {
+        let clusterings = Clusterings::unvalidated(2, 3, vec![0, 1, 1, 0, 0, 1], vec![2, 2]);
+        let expanded = expand_clusterings(&clusterings, &[2, 1, 3]);
+        assert_eq!(expanded.n_items(), 6);
+        assert_eq!(expanded.labels(1), &[0, 0, 0, 1, 1, 1]);
+        assert_eq!(collapse_labels(&[1, 1, 0, 2, 2, 2], &[2, 1, 3]), vec![0, 1, 2]);
+        let data = vec![1.0; 9];
+        let similarity = SquareMatrixBorrower::from_slice(&data, 3);
+        let options = CaviarpdOptions::new(1.0, 2.0)
+            .with_masses(vec![1.0])
+            .with_sampler_options(SamplerOptions {
+                multiplicities: Some(Arc::from([2, 1, 3])),
+                ..SamplerOptions::default()
+            });
+        let mut rng = Pcg64Mcg::seed_from_u64(3);
+        let fit = caviarpd(similarity, &options, &mut rng).unwrap();
+        assert_eq!(fit.estimate.len(), 3);
+        let options = options.with_sampler_options(SamplerOptions {
+            multiplicities: Some(Arc::from([2, 0, 3])),
+            ..SamplerOptions::default()
+        });
+        assert!(caviarpd(similarity, &options, &mut rng).is_err());
+    }
+
+    #[test]
+    fn test_caviarpd_exactly_one_cluster() {
+        let mut rng = Pcg64Mcg::seed_from_u64(11);
+        let truth = [0, 0, 0, 0, 1, 1, 1, 1, 2, 2, 2, 2];
+        let data = similarity_from_partition(&truth, 100.0, 0.01, 0.0, &mut rng);
+        let similarity = SquareMatrixBorrower::from_slice(&data, truth.len());
+        // The candidates have three clusters, which no loss parameter merges.
+        let options = CaviarpdOptions::new(1.0, 1.0)
+            .with_n_samples(50)
+            .with_masses(vec![5.0])
+            .with_a(vec![0.05])
+            .with_salso_max_n_clusters(3)
+            .with_exact_n_clusters(true);
+        let fit = caviarpd(similarity, &options, &mut rng).unwrap();
+        assert!(fit.candidates.iter().all(|x| x.iter().max() == Some(&2)));
+        assert_eq!(fit.estimate, vec![0; truth.len()]);
+        assert_eq!(fit.info.max_n_clusters, 1);
+        assert!(fit.info.expected_loss > 0.0);
+    }
+
+    #[test]
+    fn test_caviarpd() {
+        let mut rng = Pcg64Mcg::seed_from_u64(11);
+        let truth = [0, 0, 0, 0, 1, 1, 1, 1, 2, 2, 2, 2];
+        let data = similarity_from_partition(&truth, 100.0, 0.01, 0.0, &mut rng);
+        let similarity = SquareMatrixBorrower::from_slice(&data, truth.len());
+        let options = CaviarpdOptions::new(2.0, 4.0)
+            .with_n_samples(50)
+            .with_grid_length(3);
+        let fit = caviarpd(similarity, &options, &mut rng).unwrap();
+        assert_eq!(fit.candidates.len(), 3);
+        assert_eq!(fit.masses.len(), 3);
+        assert!(fit.draws.is_empty());
+        for i in 0..truth.len() {
+            for j in 0..truth.len() {
+                assert_eq!(truth[i] == truth[j], fit.estimate[i] == fit.estimate[j]);
+            }
+        }
+    }
+
+    #[test]
+    fn test_preview() {
+        let mut rng = Pcg64Mcg::seed_from_u64(13);
+        let truth: Vec<_> = (0..120).map(|i| i % 3).collect();
+        let data = similarity_from_partition(&truth, 100.0, 0.01, 0.0, &mut rng);
+        let similarity = SquareMatrixBorrower::from_slice(&data, truth.len());
+        let options = CaviarpdOptions::new(2.0, 4.0).with_n_samples(20);
+        let preview = preview(similarity, &options, 0.0, &mut rng).unwrap();
+        assert_eq!(preview.items.len(), PILOT_N_ITEMS);
+        assert!(preview.items.windows(2).all(|x| x[0] < x[1]));
+        assert_eq!(preview.fit.estimate.len(), PILOT_N_ITEMS);
+        let ratio = preview.estimated_seconds / preview.seconds;
+        assert!((ratio - (120.0_f64 / 50.0).powi(2)).abs() < 1e-9);
+    }
+
+    #[test]
+    fn test_preview_ignores_target_permutation() {
+        let mut rng = Pcg64Mcg::seed_from_u64(19);
+        let truth: Vec<_> = (0..60).map(|i| i % 3).collect();
+        let data = similarity_from_partition(&truth, 100.0, 0.01, 0.0, &mut rng);
+        let similarity = SquareMatrixBorrower::from_slice(&data, truth.len());
+        let target = Permutation::from_vector((0..truth.len()).rev().collect()).unwrap();
+        let options = CaviarpdOptions::new(2.0, 4.0)
+            .with_n_samples(20)
+            .with_psm(true, false)
+            .with_target_permutation(target)
+            .with_salso_options(SalsoOptions {
+                max_scans: 10,
+                prob_sequential_allocation: 1.0,
+                ..SalsoOptions::default()
+            });
+        let preview = preview(similarity, &options, 0.0, &mut rng).unwrap();
+        assert_eq!(preview.fit.estimate.len(), PILOT_N_ITEMS);
+    }
+
+    #[test]
+    fn test_simulation_study() {
+        let truth = balanced_partition(12, 3);
+        let options = CaviarpdOptions::new(2.0, 4.0)
+            .with_n_samples(50)
+            .with_grid_length(3);
+        let studies: Vec<_> = [1, 3]
+            .into_iter()
+            .map(|n_threads| {
+                let mut rng = Pcg64Mcg::seed_from_u64(17);
+                let options = options.clone().with_n_threads(n_threads);
+                simulation_study(&truth, 100.0, 0.01, 0.5, 4, &options, &mut rng).unwrap()
+            })
+            .collect();
+        assert_eq!(studies[0].len(), 4);
+        assert_eq!(studies[0], studies[1]);
+        for recovery in &studies[0] {
+            assert_eq!(recovery.ari, 1.0);
+            assert!(recovery.vi.abs() < 1e-12);
+            assert_eq!(recovery.n_clusters, 3);
+        }
+    }
+}
@@ -0,0 +1,466 @@
+// Batch clustering by the CaviarPD method from the command line
+
+use epa::api::{caviarpd, CaviarpdOptions, Draws, Loss, SamplerOptions};
+use epa::epa::{Attraction, Similarity, SquareMatrixBorrower};
+use epa::features::similarity_from_distance_in_place;
+use epa::ordering::PermutationStrategy;
+use epa::tiled::TiledSimilarity;
+use rand::SeedableRng;
+use rand_pcg::Pcg64Mcg;
+use std::fs::File;
+use std::io::{BufRead, BufReader, BufWriter, Write};
+use std::process::ExitCode;
+
+const USAGE: &str = "\
+Usage: caviarpd-cli (--similarity FILE | --distance FILE | --tiled FILE) --n-clusters MIN[,MAX] --output PREFIX [OPTIONS]
+
+Reads a square matrix from a CSV file (an optional header row and an optional leading column of
+item names are ignored) or, when built with the parquet feature, from a .parquet file (whose
+numeric columns are the columns of the matrix), runs the mass-grid search, and writes
+PREFIX.estimate.csv (the label of each item) and PREFIX.draws.csv (the mass and labels of each
+draw).  Labels start at 1.  With
+--tiled, the similarity is instead memory-mapped from a file written by TiledSimilarity::write
+(e.g., by writeTiledSimilarity in the R package).
+A distance is divided by its median and then converted to a similarity as in the R package.
+
+Options:
+  --temperature T          Temperature converting distance to similarity [default: 100]
+  --max-resident-gb G      Gigabytes of tiles of --tiled kept in memory [default: 1]
+  --similarity-function F  exponential or reciprocal [default: exponential]
+  --n-samples N            Number of draws for each mass [default: 200]
+  --grid-length N          Number of masses [default: 5]
+  --loss L                 binder, VI, omARI, omARIapprox, NVI, ID, or NID [default: binder]
+  --n-runs N               Number of SALSO runs [default: 4]
+  --discount D             Discount of the EPA distribution [default: 0]
+  --attraction A           raw, softmax, or rank [default: raw]
+  --subset-size M          Allocate the other items given a random subset of M [default: all]
+  --permutation P          uniform, total-similarity, or fiedler [default: uniform]
+  --fixed-permutation      Use the same order of the items for every draw
+  --threads N              Number of threads, where 0 uses all available [default: 0]
+  --seed S                 Seed for the random number generator [default: random]
+  --strict                 Reproducible results regardless of the number of threads
+  --help                   Print this message";
+
+struct Args {
+    input: String,
+    is_distance: bool,
+    is_tiled: bool,
+    max_resident_gb: f64,
+    temperature: f64,
+    reciprocal: bool,
+    n_clusters: (f64, f64),
+    output: String,
+    n_samples: usize,
+    grid_length: usize,
+    loss: Loss,
+    n_runs: u32,
+    discount: f64,
+    attraction: Attraction,
+    subset_size: Option<usize>,
+    permutation: PermutationStrategy,
+    fixed_permutation: bool,
+    n_threads: usize,
+    seed: Option<u64>,
+    strict: bool,
+}
+
+fn parse<T: std::str::FromStr>(name: &str, value: Option<String>) -> Result<T, String> {
+    let value = value.ok_or_else(|| format!("'{}' requires a value.", name))?;
+    value
+        .parse()
+        .map_err(|_| format!("'{}' cannot be '{}'.", name, value))
+}
+
+fn parse_args(mut args: impl Iterator<Item = String>) -> Result<Option<Args>, String> {
+    let mut input = None;
+    let mut is_distance = false;
+    let mut is_tiled = false;
+    let mut n_clusters = None;
+    let mut output = None;
+    let mut x = Args {
+        input: String::new(),
+        is_distance: false,
+        is_tiled: false,
+        max_resident_gb: 1.0,
+        temperature: 100.0,
+        reciprocal: false,
+        n_clusters: (0.0, 0.0),
+        output: String::new(),
+        n_samples: 200,
+        grid_length: 5,
+        loss: Loss::Binder,
+        n_runs: 4,
+        discount: 0.0,
+        attraction: Attraction::Raw,
+        subset_size: None,
+        permutation: PermutationStrategy::Uniform,
+        fixed_permutation: false,
+        n_threads: 0,
+        seed: None,
+        strict: false,
+    };
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "--similarity" | "--distance" | "--tiled" => {
+                if input.is_some() {
+                    return Err(
+                        "Only one of '--similarity', '--distance', and '--tiled' may be given."
+                            .into(),
+                    );
+                }
+                is_distance = arg == "--distance";
+                is_tiled = arg == "--tiled";
+                input = Some(parse::<String>(&arg, args.next())?);
+            }
+            "--n-clusters" => {
+                let value: String = parse(&arg, args.next())?;
+                let range = value
+                    .split(',')
+                    .map(|y| y.trim().parse::<f64>())
+                    .collect::<Result<Vec<_>, _>>()
+                    .map_err(|_| format!("'--n-clusters' cannot be '{}'.", value))?;
+                n_clusters = match range[..] {
+                    [a] => Some((a, a)),
+                    [a, b] => Some((a.min(b), a.max(b))),
+                    _ => return Err("'--n-clusters' must be one or two numbers.".into()),
+                };
+            }
+            "--output" => output = Some(parse(&arg, args.next())?),
+            "--temperature" => x.temperature = parse(&arg, args.next())?,
+            "--max-resident-gb" => x.max_resident_gb = parse(&arg, args.next())?,
+            "--similarity-function" => {
+                x.reciprocal = match parse::<String>(&arg, args.next())?.as_str() {
+                    "exponential" => false,
+                    "reciprocal" => true,
+                    y => return Err(format!("Unsupported similarity function: {}", y)),
+                }
+            }
+            "--n-samples" => x.n_samples = parse(&arg, args.next())?,
+            "--grid-length" => x.grid_length = parse(&arg, args.next())?,
+            "--loss" => x.loss = Loss::from_name(&parse::<String>(&arg, args.next())?)?,
+            "--n-runs" => x.n_runs = parse(&arg, args.next())?,
+            "--discount" => x.discount = parse(&arg, args.next())?,
+            "--attraction" => {
+                x.attraction = match parse::<String>(&arg, args.next())?.as_str() {
+                    "raw" => Attraction::Raw,
+                    "softmax" => Attraction::Softmax,
+                    "rank" => Attraction::Rank,
+                    y => return Err(format!("Unsupported attraction: {}", y)),
+                }
+            }
+            "--subset-size" => x.subset_size = Some(parse(&arg, args.next())?),
+            "--permutation" => {
+                x.permutation = match parse::<String>(&arg, args.next())?.as_str() {
+                    "uniform" => PermutationStrategy::Uniform,
+                    "total-similarity" => PermutationStrategy::TotalSimilarity,
+                    "fiedler" => PermutationStrategy::Fiedler,
+                    y => return Err(format!("Unsupported permutation: {}", y)),
+                }
+            }
+            "--fixed-permutation" => x.fixed_permutation = true,
+            "--threads" => x.n_threads = parse(&arg, args.next())?,
+            "--seed" => x.seed = Some(parse(&arg, args.next())?),
+            "--strict" => x.strict = true,
+            "--help" => return Ok(None),
+            _ => return Err(format!("Unrecognized argument: {}", arg)),
+        }
+    }
+    x.input = input.ok_or("One of '--similarity', '--distance', and '--tiled' is required.")?;
+    x.is_distance = is_distance;
+    x.is_tiled = is_tiled;
+    x.n_clusters = n_clusters.ok_or("'--n-clusters' is required.")?;
+    x.output = output.ok_or("'--output' is required.")?;
+    if x.temperature.is_nan() || x.temperature < 0.0 {
+        return Err("'--temperature' must be nonnegative.".into());
+    }
+    if x.n_threads == 0 {
+        x.n_threads = std::thread::available_parallelism().map_or(1, |n| n.get());
+    }
+    Ok(Some(x))
+}
+
+/// Read a square matrix from a CSV file in column-major order.
+fn read_matrix(path: &str) -> Result<(Vec<f64>, usize), String> {
+    if path.to_ascii_lowercase().ends_with(".parquet") {
+        #[cfg(feature = "parquet")]
+        return read_parquet(path);
+        #[cfg(not(feature = "parquet"))]
+        return Err(
+            "Parquet files require building with the 'parquet' feature; please convert to CSV."
+                .into(),
+        );
+    }
+    let file = File::open(path).map_err(|e| format!("Could not open '{}': {}", path, e))?;
+    let mut rows: Vec<Vec<f64>> = Vec::new();
+    for (line_number, line) in BufReader::new(file).lines().enumerate() {
+        let line = line.map_err(|e| format!("Could not read '{}': {}", path, e))?;
+        if line.trim().is_empty() {
+            continue;
+        }
+        let mut fields: Vec<&str> = line
+            .split(',')
+            .map(|y| y.trim().trim_matches('"'))
+            .collect();
+        if fields[0].is_empty() || fields[0].parse::<f64>().is_err() {
+            fields.remove(0);
+        }
+        match fields.iter().map(|y| y.parse()).collect() {
+            Ok(row) => rows.push(row),
+            Err(_) if rows.is_empty() && line_number == 0 => {}
+            Err(_) => {
+                return Err(format!(
+                    "Line {} of '{}' is not numeric.",
+                    line_number + 1,
+                    path
+                ))
+            }
+        }
+    }
+    let n_items = rows.len();
+    if n_items == 0 || rows.iter().any(|row| row.len() != n_items) {
+        return Err(format!("'{}' does not contain a square matrix.", path));
+    }
+    let mut data = vec![0.0; n_items * n_items];
+    for (i, row) in rows.iter().enumerate() {
+        for (j, value) in row.iter().enumerate() {
+            data[n_items * j + i] = *value;
+        }
+    }
+    Ok((data, n_items))
+}
+
+// The numeric columns of a Parquet file are the columns of the matrix, with a row for each item,
+// and other columns (e.g., item names) are ignored.
+#[cfg(feature = "parquet")]
+fn read_parquet(path: &str) -> Result<(Vec<f64>, usize), String> {
+    use arrow_array::cast::AsArray;
+    use arrow_array::types::Float64Type;
+    use arrow_schema::DataType;
+    use parquet::arrow::arrow_reader::ParquetRecordBatchReaderBuilder;
+    let error = |e: &dyn std::fmt::Display| format!("Could not read '{}': {}", path, e);
+    let file = File::open(path).map_err(|e| format!("Could not open '{}': {}", path, e))?;
+    let reader = ParquetRecordBatchReaderBuilder::try_new(file)
+        .and_then(|x| x.build())
+        .map_err(|e| error(&e))?;
+    let mut columns: Vec<Vec<f64>> = Vec::new();
+    for batch in reader {
+        let batch = batch.map_err(|e| error(&e))?;
+        let numeric: Vec<_> = batch
+            .columns()
+            .iter()
+            .filter(|x| x.data_type().is_numeric())
+            .collect();
+        columns.resize_with(numeric.len(), Vec::new);
+        for (column, array) in columns.iter_mut().zip(numeric) {
+            let array = arrow_cast::cast(array, &DataType::Float64).map_err(|e| error(&e))?;
+            if array.null_count() > 0 {
+                return Err(format!("'{}' has missing values.", path));
+            }
+            column.extend(array.as_primitive::<Float64Type>().values().iter());
+        }
+    }
+    let n_items = columns.len();
+    if n_items == 0 || columns.iter().any(|column| column.len() != n_items) {
+        return Err(format!("'{}' does not contain a square matrix.", path));
+    }
+    Ok((columns.concat(), n_items))
+}
+
+fn write_csv(path: &str, rows: impl Iterator<Item = String>) -> Result<(), String> {
+    let error = |e: std::io::Error| format!("Could not write '{}': {}", path, e);
+    let mut file = BufWriter::new(File::create(path).map_err(error)?);
+    for row in rows {
+        writeln!(file, "{}", row).map_err(error)?;
+    }
+    file.flush().map_err(error)
+}
+
+fn labels_to_csv<L: Copy + Into<usize>>(labels: &[L]) -> String {
+    labels
+        .iter()
+        .map(|x| ((*x).into() + 1).to_string())
+        .collect::<Vec<_>>()
+        .join(",")
+}
+
+fn run(args: Args) -> Result<(), String> {
+    if args.is_tiled {
+        let similarity = TiledSimilarity::open(&args.input, args.max_resident_gb)
+            .map_err(|e| format!("Could not open '{}': {}", args.input, e))?;
+        return fit_and_write(&similarity, &args);
+    }
+    let (mut data, n_items) = read_matrix(&args.input)?;
+    if args.is_distance {
+        similarity_from_distance_in_place(&mut data, args.temperature, args.reciprocal)?;
+    }
+    if data.iter().any(|x| !(x.is_finite() && *x >= 0.0)) {
+        return Err("The similarity must be finite and nonnegative.".into());
+    }
+    fit_and_write(
+        SquareMatrixBorrower::from_slice(&data, n_items).detect_symmetry(),
+        &args,
+    )
+}
+
+fn fit_and_write<M: Similarity>(similarity: M, args: &Args) -> Result<(), String> {
+    let n_items = similarity.n_items();
+    let options = CaviarpdOptions::new(args.n_clusters.0, args.n_clusters.1)
+        .with_n_samples(args.n_samples)
+        .with_grid_length(args.grid_length)
+        .with_loss(args.loss)
+        .with_n_runs(args.n_runs)
+        .with_discount(args.discount)
+        .with_n_threads(args.n_threads)
+        .with_sampler_options(SamplerOptions {
+            strict: args.strict,
+            attraction: args.attraction,
+            subset_size: args.subset_size,
+            permutation: args.permutation,
+            fixed_permutation: args.fixed_permutation,
+            ..SamplerOptions::default()
+        })
+        .with_keep_draws(true);
+    let mut rng = match args.seed {
+        Some(seed) => Pcg64Mcg::seed_from_u64(seed),
+        None => Pcg64Mcg::from_rng(&mut rand::rng()),
+    };
+    let fit = caviarpd(similarity, &options, &mut rng)?;
+    write_csv(
+        &format!("{}.estimate.csv", args.output),
+        std::iter::once("label".to_string())
+            .chain(fit.estimate.iter().map(|x| (x + 1).to_string())),
+    )?;
+    let header = std::iter::once("mass".to_string())
+        .chain((1..=n_items).map(|i| format!("item{}", i)))
+        .collect::<Vec<_>>()
+        .join(",");
+    let draws = fit.masses.iter().zip(&fit.draws).flat_map(|(mass, draws)| {
+        let Draws::Clusterings(draws) = draws else {
+            unreachable!("the draws are kept without the pairwise similarity matrix")
+        };
+        (0..draws.n_clusterings())
+            .map(move |j| format!("{},{}", mass, labels_to_csv(draws.labels(j))))
+    });
+    write_csv(
+        &format!("{}.draws.csv", args.output),
+        std::iter::once(header).chain(draws),
+    )
+}
+
+fn main() -> ExitCode {
+    let result = parse_args(std::env::args().skip(1)).and_then(|args| match args {
+        Some(args) => run(args),
+        None => {
+            println!("{}", USAGE);
+            Ok(())
+        }
+    });
+    match result {
+        Ok(()) => ExitCode::SUCCESS,
+        Err(message) => {
+            eprintln!("Error: {}\n\n{}", message, USAGE);
+            ExitCode::FAILURE
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_distance() {
+        // Distances far from one, which would give similarities of zero or infinity without being
+        // divided by their median, and a zero diagonal, which the reciprocal function offsets.
+        let n_items: usize = 6;
+        let rows: Vec<_> = (0..n_items)
+            .map(|i| {
+                let row: Vec<_> = (0..n_items)
+                    .map(|j| (1000 * (i / 3).abs_diff(j / 3) + 50 * i.abs_diff(j)).to_string())
+                    .collect();
+                row.join(",")
+            })
+            .collect();
+        let prefix = std::env::temp_dir().join(format!("caviarpd-cli-{}", std::process::id()));
+        let prefix = prefix.to_str().unwrap();
+        let input = format!("{}.distance.csv", prefix);
+        write_csv(&input, rows.into_iter()).unwrap();
+        for function in ["exponential", "reciprocal"] {
+            let args = [
+                "--distance",
+                &input,
+                "--similarity-function",
+                function,
+                "--temperature",
+                "1",
+                "--n-clusters",
+                "2",
+                "--n-samples",
+                "20",
+                "--grid-length",
+                "2",
+                "--seed",
+                "1",
+                "--threads",
+                "1",
+                "--output",
+                prefix,
+            ];
+            let args = parse_args(args.iter().map(|x| x.to_string())).unwrap().unwrap();
+            run(args).unwrap();
+            let estimate = std::fs::read_to_string(format!("{}.estimate.csv", prefix)).unwrap();
+            assert_eq!(estimate.lines().count(), n_items + 1);
+        }
+        for suffix in ["distance", "estimate", "draws"] {
+            std::fs::remove_file(format!("{}.{}.csv", prefix, suffix)).unwrap();
+        }
+    }
+
+    #[cfg(feature = "parquet")]
+    #[test]
+    fn test_parquet() {
+        use arrow_array::{ArrayRef, Float64Array, Int32Array, RecordBatch, StringArray};
+        use parquet::arrow::ArrowWriter;
+        use std::sync::Arc;
+        let n_items: usize = 6;
+        let mut columns: Vec<(String, ArrayRef)> = vec![(
+            "item".into(),
+            Arc::new(StringArray::from_iter_values(
+                (0..n_items).map(|i| format!("x{}", i)),
+            )),
+        )];
+        for j in 0..n_items {
+            let column: ArrayRef = if j == 0 {
+                Arc::new(Int32Array::from_iter_values((0..n_items).map(|i| i as i32)))
+            } else {
+                Arc::new(Float64Array::from_iter_values(
+                    (0..n_items).map(|i| (i * n_items + j) as f64),
+                ))
+            };
+            columns.push((format!("y{}", j), column));
+        }
+        let batch = RecordBatch::try_from_iter(columns).unwrap();
+        let path =
+            std::env::temp_dir().join(format!("caviarpd-cli-{}.parquet", std::process::id()));
+        let path = path.to_str().unwrap();
+        let mut writer =
+            ArrowWriter::try_new(File::create(path).unwrap(), batch.schema(), None).unwrap();
+        // Two batches, as a large file would have.
+        writer.write(&batch.slice(0, 2)).unwrap();
+        writer.write(&batch.slice(2, n_items - 2)).unwrap();
+        writer.close().unwrap();
+        let (data, n) = read_matrix(path).unwrap();
+        assert_eq!(n, n_items);
+        let expected: Vec<_> = (0..n_items * n_items)
+            .map(|x| {
+                if x < n_items {
+                    x as f64
+                } else {
+                    ((x % n_items) * n_items + x / n_items) as f64
+                }
+            })
+            .collect();
+        assert_eq!(data, expected);
+        std::fs::remove_file(path).unwrap();
+    }
+}
@@ -469,6 +469,20 @@ impl FromUsize for u16 {
     }
 }
 
+impl FromUsize for u32 {
+    #[inline]
+    fn from_usize(x: usize) -> Self {
+        x as Self
+    }
+}
+
+impl FromUsize for usize {
+    #[inline]
+    fn from_usize(x: usize) -> Self {
+        x
+    }
+}
+
 pub trait UnitIncrementor {
     fn next(x: &mut Self);
 }
@@ -487,6 +501,13 @@ impl UnitIncrementor for u16 {
     }
 }
 
+impl UnitIncrementor for u32 {
+    #[inline]
+    fn next(x: &mut u32) {
+        *x += 1
+    }
+}
+
 #[doc(hidden)]
 pub struct ClusteringIterator {
     n_items: usize,
@@ -0,0 +1,144 @@
+// Distances between two partitions of the same items
+
+use crate::align::ContingencyTable;
+use crate::features::fill_columns;
+
+/// The variation of information (in base 2) between two partitions.
+///
+/// Both partitions must use labels `0, 1, ..., k - 1`.
+pub fn variation_of_information(x: &[usize], y: &[usize]) -> f64 {
+    let n = x.len() as f64;
+    if n == 0.0 {
+        return 0.0;
+    }
+    let table = ContingencyTable::new(x, y);
+    let entropy = |counts: &[usize]| -> f64 {
+        counts
+            .iter()
+            .filter(|c| **c > 0)
+            .map(|c| {
+                let p = (*c as f64) / n;
+                -p * p.log2()
+            })
+            .sum()
+    };
+    let joint: Vec<_> = table.nonzero_cells().into_iter().map(|c| c.2).collect();
+    2.0 * entropy(&joint) - entropy(&table.row_sums()) - entropy(&table.col_sums())
+}
+
+/// The number of pairs of `n` things.
+fn pairs(n: usize) -> f64 {
+    (n as f64) * ((n as f64) - 1.0) / 2.0
+}
+
+/// The numbers of pairs of distinct items clustered together in `x`, in `y`, and in both.
+fn pair_counts(x: &[usize], y: &[usize]) -> (f64, f64, f64) {
+    let table = ContingencyTable::new(x, y);
+    let sum = |counts: &[usize]| counts.iter().map(|c| pairs(*c)).sum::<f64>();
+    let both: f64 = table.nonzero_cells().iter().map(|c| pairs(c.2)).sum();
+    (sum(&table.row_sums()), sum(&table.col_sums()), both)
+}
+
+/// The Binder distance between two partitions: the proportion of pairs of distinct items which
+/// are clustered together in one partition but not in the other.
+///
+/// Both partitions must use labels `0, 1, ..., k - 1`.
+pub fn binder_distance(x: &[usize], y: &[usize]) -> f64 {
+    if x.len() < 2 {
+        return 0.0;
+    }
+    let (in_x, in_y, in_both) = pair_counts(x, y);
+    (in_x + in_y - 2.0 * in_both) / pairs(x.len())
+}
+
+/// The adjusted Rand index between two partitions, which is 1 for equal partitions and 0 in
+/// expectation for unrelated ones.
+///
+/// Both partitions must use labels `0, 1, ..., k - 1`.
+pub fn adjusted_rand_index(x: &[usize], y: &[usize]) -> f64 {
+    let (in_x, in_y, in_both) = pair_counts(x, y);
+    let expected = in_x * in_y / pairs(x.len());
+    let maximum = (in_x + in_y) / 2.0;
+    if maximum == expected {
+        // Both partitions put all items together or all apart, so they are equal.
+        return 1.0;
+    }
+    (in_both - expected) / (maximum - expected)
+}
+
+/// A distance between partitions.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PartitionDistance {
+    /// The variation of information, as given by [`variation_of_information`].
+    VI,
+    /// The Binder distance, as given by [`binder_distance`].
+    Binder,
+    /// One minus the [`adjusted_rand_index`].
+    ARI,
+}
+
+impl PartitionDistance {
+    pub fn distance(self, x: &[usize], y: &[usize]) -> f64 {
+        match self {
+            Self::VI => variation_of_information(x, y),
+            Self::Binder => binder_distance(x, y),
+            Self::ARI => 1.0 - adjusted_rand_index(x, y),
+        }
+    }
+}
+
+/// The matrix (column-major) of the distances between each pair of `partitions`, computed on
+/// `n_threads` threads.
+pub fn pairwise_distances(
+    partitions: &[Vec<usize>],
+    distance: PartitionDistance,
+    n_threads: usize,
+) -> Vec<f64> {
+    let n = partitions.len();
+    let mut distances = vec![0.0; n * n];
+    fill_columns(&mut distances, n, n_threads, |j, column| {
+        for (i, value) in column.iter_mut().enumerate() {
+            // The arguments are in the same order for both halves, so the matrix is symmetric.
+            let (first, second) = (i.min(j), i.max(j));
+            if first != second {
+                *value = distance.distance(&partitions[first], &partitions[second]);
+            }
+        }
+    });
+    distances
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_variation_of_information() {
+        assert_eq!(variation_of_information(&[0, 0, 1, 1], &[1, 1, 0, 0]), 0.0);
+        assert!((variation_of_information(&[0, 0, 0, 0], &[0, 1, 2, 3]) - 2.0).abs() < 1e-12);
+        assert!((variation_of_information(&[0, 0, 1, 1], &[0, 1, 0, 1]) - 2.0).abs() < 1e-12);
+    }
+
+    #[test]
+    fn test_pairwise_distances() {
+        let partitions = [vec![0, 0, 1, 1], vec![1, 1, 0, 0], vec![0, 1, 0, 1], vec![0, 0, 0, 1]];
+        assert!((binder_distance(&partitions[0], &partitions[2]) - 4.0 / 6.0).abs() < 1e-12);
+        assert!((adjusted_rand_index(&partitions[0], &partitions[2]) + 0.5).abs() < 1e-12);
+        assert_eq!(adjusted_rand_index(&[0, 1, 2], &[2, 0, 1]), 1.0);
+        for distance in [
+            PartitionDistance::VI,
+            PartitionDistance::Binder,
+            PartitionDistance::ARI,
+        ] {
+            let d = pairwise_distances(&partitions, distance, 3);
+            assert_eq!(d[1], 0.0);
+            for i in 0..4 {
+                assert_eq!(d[5 * i], 0.0);
+                for j in 0..4 {
+                    assert!((d[4 * j + i] - distance.distance(&partitions[i], &partitions[j])).abs() < 1e-12);
+                    assert_eq!(d[4 * j + i], d[4 * i + j]);
+                }
+            }
+        }
+    }
+}
@@ -1,27 +1,368 @@
 // Ewens Pitman attraction partition distribution
 
-use crate::clust::Clustering;
+use crate::clust::{Clustering, FromUsize};
 use crate::perm::Permutation;
 // use roots::find_root_regula_falsi as find_root;
 
+use rand::distr::Uniform;
 use rand::prelude::*;
+use std::mem::MaybeUninit;
 use std::slice;
+use std::sync::Arc;
 
-type SimilarityBorrower<'a> = SquareMatrixBorrower<'a>;
+/// A type that can be stored as an element of a similarity matrix.
+///
+/// Computations are always done in double precision, so an element only needs to be convertible
+/// to an `f64`.  Storing elements as `f32` halves the memory needed for large matrices.
+pub trait SimilarityValue: Copy + Send + Sync + Into<f64> {}
+
+impl SimilarityValue for f64 {}
+impl SimilarityValue for f32 {}
+
+/// A square matrix of similarities, as needed by the EPA distribution.
+///
+/// This is implemented for matrices in memory ([`SquareMatrixBorrower`]) and on disk
+/// ([`crate::tiled::TiledSimilarity`]).
+pub trait Similarity: Copy + Send + Sync {
+    fn n_items(&self) -> usize;
+
+    /// The similarity between items `i` and `j`.
+    fn get(&self, i: usize, j: usize) -> f64;
+
+    /// The sum of the similarities between item `row` and the items in `columns`.
+    fn sum_of_row_subset(&self, row: usize, columns: &[usize]) -> f64;
+
+    /// Like [`Self::sum_of_row_subset`], but using Neumaier's compensated summation.
+    fn sum_of_row_subset_compensated(&self, row: usize, columns: &[usize]) -> f64;
+}
+
+/// The first pair of items `(i, j)` whose similarity is not finite and nonnegative, if any.
+///
+/// A single NaN spreads through the sums of similarities into the probabilities of every
+/// allocation, so [`EpaParameters::new`] checks for such similarities in debug builds.
+pub fn first_invalid_similarity<M: Similarity>(similarity: &M) -> Option<(usize, usize)> {
+    let n_items = similarity.n_items();
+    (0..n_items)
+        .flat_map(|j| (0..n_items).map(move |i| (i, j)))
+        .find(|&(i, j)| {
+            let x = similarity.get(i, j);
+            !(x.is_finite() && x >= 0.0)
+        })
+}
+
+/// The sum by Neumaier's compensated summation.
+pub(crate) fn compensated_sum(values: impl Iterator<Item = f64>) -> f64 {
+    let mut sum = 0.0;
+    let mut compensation = 0.0;
+    for x in values {
+        let t = sum + x;
+        if f64::abs(sum) >= x.abs() {
+            compensation += (sum - t) + x;
+        } else {
+            compensation += (x - t) + sum;
+        }
+        sum = t;
+    }
+    sum + compensation
+}
+
+/// How an item's similarities to the items allocated before it become its attraction to their
+/// clusters.
+///
+/// The attraction to a cluster is the sum of the transformed similarities to its items relative
+/// to the sum over all the items allocated before.  `Raw` uses the similarities themselves,
+/// `Softmax` their exponentials (so the attraction is a softmax), and `Rank` their ranks among
+/// the items allocated before (with ties given their average rank), so a few large similarities
+/// cannot dominate the attraction.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum Attraction {
+    #[default]
+    Raw,
+    Softmax,
+    Rank,
+}
+
+/// Must-link and cannot-link constraints between pairs of items, enforced by [`sample`].
+///
+/// Items linked by must-link constraints, directly or through other items, form a group which is
+/// always clustered together.  A cannot-link constraint keeps the groups of its items apart.
+#[derive(Debug, Clone)]
+pub struct Constraints {
+    // The group of each item.
+    group: Vec<usize>,
+    // The groups which each group cannot be clustered with.
+    cannot: Vec<Vec<usize>>,
+}
+
+impl Constraints {
+    /// Constraints among `n_items` items given pairs of items which must be, and which cannot be,
+    /// clustered together.  Fails if an item is out of range or the constraints contradict one
+    /// another.
+    pub fn new(
+        n_items: usize,
+        must: &[(usize, usize)],
+        cannot: &[(usize, usize)],
+    ) -> Result<Self, String> {
+        if let Some((i, j)) = must
+            .iter()
+            .chain(cannot)
+            .find(|(i, j)| *i >= n_items || *j >= n_items)
+        {
+            return Err(format!(
+                "The pair ({}, {}) refers to an item beyond the {} items.",
+                i, j, n_items
+            ));
+        }
+        let mut parent: Vec<usize> = (0..n_items).collect();
+        fn root(parent: &mut [usize], mut i: usize) -> usize {
+            while parent[i] != i {
+                parent[i] = parent[parent[i]];
+                i = parent[i];
+            }
+            i
+        }
+        for &(i, j) in must {
+            let (i, j) = (root(&mut parent, i), root(&mut parent, j));
+            parent[i.max(j)] = i.min(j);
+        }
+        let mut group = vec![usize::MAX; n_items];
+        let mut n_groups = 0;
+        for i in 0..n_items {
+            let r = root(&mut parent, i);
+            if group[r] == usize::MAX {
+                group[r] = n_groups;
+                n_groups += 1;
+            }
+            group[i] = group[r];
+        }
+        let mut cannot_groups = vec![Vec::new(); n_groups];
+        for &(i, j) in cannot {
+            let (g, h) = (group[i], group[j]);
+            if g == h {
+                return Err(format!(
+                    "Items {} and {} cannot be clustered apart, since must-link constraints join them.",
+                    i, j
+                ));
+            }
+            cannot_groups[g].push(h);
+            cannot_groups[h].push(g);
+        }
+        Ok(Self {
+            group,
+            cannot: cannot_groups,
+        })
+    }
+
+    pub fn n_items(&self) -> usize {
+        self.group.len()
+    }
+
+    /// Whether `labels` clusters together the items of each must-link constraint and apart those
+    /// of each cannot-link constraint.  Items labeled `usize::MAX` are unallocated and satisfy any
+    /// constraint.
+    pub fn is_satisfied_by(&self, labels: &[usize]) -> bool {
+        let mut group_labels = vec![usize::MAX; self.cannot.len()];
+        for (g, label) in self.group.iter().zip(labels) {
+            if *label == usize::MAX {
+                continue;
+            }
+            if group_labels[*g] == usize::MAX {
+                group_labels[*g] = *label;
+            } else if group_labels[*g] != *label {
+                return false;
+            }
+        }
+        self.cannot.iter().enumerate().all(|(g, others)| {
+            group_labels[g] == usize::MAX
+                || others
+                    .iter()
+                    .all(|h| group_labels[g] != group_labels[*h])
+        })
+    }
+}
 
+/// Fixed labels for some of the items, the anchored items, given which [`sample`] allocates the
+/// other items.
 #[derive(Debug, Clone)]
-pub struct EpaParameters<'a> {
-    similarity: SimilarityBorrower<'a>,
+pub struct Anchors {
+    // The label of each item, in order of first appearance, or 'usize::MAX' for a free item.
+    labels: Vec<usize>,
+    // The anchored items in increasing order.
+    items: Vec<usize>,
+    n_clusters: usize,
+}
+
+impl Anchors {
+    /// Anchors given the label of each item, or `None` for a free item.  Anchored items with equal
+    /// labels are clustered together; the labels need not be consecutive.
+    pub fn new(labels: &[Option<usize>]) -> Self {
+        let mut map = std::collections::HashMap::new();
+        let labels: Vec<_> = labels
+            .iter()
+            .map(|label| match label {
+                Some(label) => {
+                    let next = map.len();
+                    *map.entry(*label).or_insert(next)
+                }
+                None => usize::MAX,
+            })
+            .collect();
+        let items = (0..labels.len())
+            .filter(|i| labels[*i] != usize::MAX)
+            .collect();
+        Self {
+            labels,
+            items,
+            n_clusters: map.len(),
+        }
+    }
+
+    pub fn n_items(&self) -> usize {
+        self.labels.len()
+    }
+
+    pub fn n_anchored(&self) -> usize {
+        self.items.len()
+    }
+
+    /// The label of each item, `0, 1, ..., k - 1` in order of first appearance for the anchored
+    /// items and `usize::MAX` for the free items.
+    pub fn labels(&self) -> &[usize] {
+        &self.labels
+    }
+
+    /// Modify the clustering `labels` to respect the anchors.  A cluster joins the anchored
+    /// cluster to which most of its anchored items belong (the first in case of ties), anchored
+    /// items move to their own cluster, and clusters without anchored items are kept.  The result
+    /// has labels `0, 1, ..., k - 1`, those of the anchored clusters coming first, and is
+    /// unchanged (up to relabeling) if `labels` already respects the anchors.
+    pub fn impose(&self, labels: &[usize]) -> Vec<usize> {
+        let n_clusters = labels.iter().max().map_or(0, |x| x + 1);
+        let mut counts = vec![vec![0_usize; self.n_clusters]; n_clusters];
+        for item in &self.items {
+            counts[labels[*item]][self.labels[*item]] += 1;
+        }
+        let mut next = self.n_clusters;
+        let map: Vec<_> = counts
+            .iter()
+            .map(|counts| {
+                let (label, count) = counts
+                    .iter()
+                    .enumerate()
+                    .fold((0, 0), |best, x| if *x.1 > best.1 { (x.0, *x.1) } else { best });
+                if count > 0 {
+                    label
+                } else {
+                    next += 1;
+                    next - 1
+                }
+            })
+            .collect();
+        labels
+            .iter()
+            .zip(&self.labels)
+            .map(|(label, anchor)| {
+                if *anchor == usize::MAX {
+                    map[*label]
+                } else {
+                    *anchor
+                }
+            })
+            .collect()
+    }
+}
+
+/// Items which [`sample`] may allocate as noise rather than to a cluster, namely those whose total
+/// similarity to the other items is below a threshold.
+///
+/// When such an item is allocated, a noise pseudo-cluster with a fixed weight competes with the
+/// clusters and a new cluster.  An item allocated as noise is a singleton which no later item
+/// joins, and noise items do not count as clusters in the discount.
+#[derive(Debug, Clone)]
+pub struct Noise {
+    eligible: Vec<bool>,
+    weight: f64,
+}
+
+impl Noise {
+    /// Items whose total similarity to the other items is less than `threshold` are eligible to
+    /// be noise, and the noise pseudo-cluster has `weight` on the scale of the mass (the weight of
+    /// a new cluster).  Returns `None` unless `weight` is finite and positive.
+    pub fn new<M: Similarity>(similarity: &M, threshold: f64, weight: f64) -> Option<Self> {
+        if !(weight.is_finite() && weight > 0.0) {
+            return None;
+        }
+        let n_items = similarity.n_items();
+        let mut others: Vec<_> = (1..n_items).collect();
+        let eligible = (0..n_items)
+            .map(|i| {
+                if i > 0 {
+                    others[i - 1] = i - 1;
+                }
+                similarity.sum_of_row_subset(i, &others) < threshold
+            })
+            .collect();
+        Some(Self { eligible, weight })
+    }
+
+    pub fn n_items(&self) -> usize {
+        self.eligible.len()
+    }
+
+    /// Whether each item is eligible to be noise.
+    pub fn eligible(&self) -> &[bool] {
+        &self.eligible
+    }
+
+    /// The labels of an estimate `labels` with the eligible items in singleton clusters taken as
+    /// noise (`None`) and the other clusters relabeled `0, 1, ..., k - 1` in order of first
+    /// appearance.
+    pub fn relabel(&self, labels: &[usize]) -> Vec<Option<usize>> {
+        let mut sizes = vec![0_usize; labels.iter().max().map_or(0, |x| x + 1)];
+        for label in labels {
+            sizes[*label] += 1;
+        }
+        let mut map = vec![usize::MAX; sizes.len()];
+        let mut next = 0;
+        labels
+            .iter()
+            .zip(&self.eligible)
+            .map(|(label, eligible)| {
+                if *eligible && sizes[*label] == 1 {
+                    return None;
+                }
+                if map[*label] == usize::MAX {
+                    map[*label] = next;
+                    next += 1;
+                }
+                Some(map[*label])
+            })
+            .collect()
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct EpaParameters<M: Similarity> {
+    similarity: M,
     permutation: Permutation,
     mass: f64,
+    discount: f64,
+    compensated: bool,
+    attraction: Attraction,
+    subset_size: Option<usize>,
+    constraints: Option<Arc<Constraints>>,
+    anchors: Option<Arc<Anchors>>,
+    multiplicities: Option<Arc<[usize]>>,
+    noise: Option<Arc<Noise>>,
 }
 
-impl<'a> EpaParameters<'a> {
-    pub fn new(
-        similarity: SimilarityBorrower<'a>,
-        permutation: Permutation,
-        mass: f64,
-    ) -> Option<Self> {
+impl<M: Similarity> EpaParameters<M> {
+    pub fn new(similarity: M, permutation: Permutation, mass: f64) -> Option<Self> {
+        debug_assert_eq!(
+            first_invalid_similarity(&similarity),
+            None,
+            "similarities must be finite and nonnegative"
+        );
         if similarity.n_items() != permutation.n_items() {
             None
         } else {
@@ -29,12 +370,256 @@ impl<'a> EpaParameters<'a> {
                 similarity,
                 permutation,
                 mass,
+                discount: 0.0,
+                compensated: false,
+                attraction: Attraction::Raw,
+                subset_size: None,
+                constraints: None,
+                anchors: None,
+                multiplicities: None,
+                noise: None,
             })
         }
     }
 
+    /// Set the discount, which must be in `[0, 1)` with the mass greater than `-discount`.
+    pub fn with_discount(mut self, discount: f64) -> Option<Self> {
+        if (0.0..1.0).contains(&discount) && self.mass > -discount {
+            self.discount = discount;
+            Some(self)
+        } else {
+            None
+        }
+    }
+
+    /// Use compensated summation for the sums of similarities, which guards against the loss of
+    /// precision when summing many similarities of widely varying magnitudes.
+    pub fn with_compensated_summation(mut self, compensated: bool) -> Self {
+        self.compensated = compensated;
+        self
+    }
+
+    pub fn with_attraction(mut self, attraction: Attraction) -> Self {
+        self.attraction = attraction;
+        self
+    }
+
+    /// Approximate the draws of [`sample`] for very many items: only the first `subset_size`
+    /// items of the permutation (a random subset, once the permutation is shuffled) are allocated
+    /// sequentially, and each other item is then allocated independently as if it came right
+    /// after them.  It joins a cluster of the subset with probability proportional to its
+    /// attraction to the cluster, or else starts a cluster of its own, so a draw costs time
+    /// proportional to `subset_size` rather than the number of items for each item.
+    ///
+    /// [`log_probability`] and [`allocation_probabilities`] are for the exact distribution and
+    /// ignore this setting.
+    pub fn with_subset_size(mut self, subset_size: Option<usize>) -> Self {
+        self.subset_size = subset_size;
+        self
+    }
+
+    /// Enforce `constraints`, which must be for the same number of items, when sampling.
+    ///
+    /// An item whose group already has a cluster is forced into it, and otherwise it cannot join
+    /// a cluster holding an item it cannot be clustered with.  [`log_probability`] and
+    /// [`allocation_probabilities`] are for the unconstrained distribution and ignore this
+    /// setting.
+    pub fn with_constraints(mut self, constraints: Option<Arc<Constraints>>) -> Option<Self> {
+        self.constraints = constraints;
+        self.check_conditions().then_some(self)
+    }
+
+    /// Sample the free items given the labels of the anchored items of `anchors`, which must be
+    /// for the same number of items and (with constraints) satisfy the constraints.
+    ///
+    /// The anchored items come first in the permutation, and shuffling the permutation shuffles
+    /// only the free items, which are then allocated sequentially as usual.  [`log_probability`]
+    /// and [`allocation_probabilities`] ignore this setting.
+    pub fn with_anchors(mut self, anchors: Option<Arc<Anchors>>) -> Option<Self> {
+        self.anchors = anchors;
+        if !self.check_conditions() {
+            return None;
+        }
+        self.reset_permutation();
+        Some(self)
+    }
+
+    /// Give each item the positive multiplicity in `multiplicities`, which must have one element
+    /// per item, so that an item stands for that many replicated items that are always clustered
+    /// together.
+    ///
+    /// The similarities of an item to the items allocated before it, and the number of those
+    /// items, are counted with their multiplicities, as are the normalizing constants of
+    /// [`log_probability`] and [`allocation_probabilities`].
+    pub fn with_multiplicities(mut self, multiplicities: Option<Arc<[usize]>>) -> Option<Self> {
+        if let Some(x) = &multiplicities {
+            if x.len() != self.similarity.n_items() || x.contains(&0) {
+                return None;
+            }
+        }
+        self.multiplicities = multiplicities;
+        Some(self)
+    }
+
+    /// Allow the items eligible under `noise`, which must be for the same number of items, to be
+    /// allocated as noise when sampling, except the first item allocated.  Noise cannot be
+    /// combined with constraints.  [`log_probability`] and [`allocation_probabilities`] ignore
+    /// this setting.
+    pub fn with_noise(mut self, noise: Option<Arc<Noise>>) -> Option<Self> {
+        self.noise = noise;
+        self.check_conditions().then_some(self)
+    }
+
+    // The number of items allocated before the item at 'position' of the permutation, counted
+    // with their multiplicities.
+    fn preceding_count(&self, position: usize) -> f64 {
+        match &self.multiplicities {
+            Some(multiplicities) => self
+                .permutation
+                .slice_until(position)
+                .iter()
+                .map(|j| multiplicities[*j] as f64)
+                .sum(),
+            None => position as f64,
+        }
+    }
+
+    // Whether the constraints, the anchors, and the noise are for the right number of items and
+    // agree with one another.
+    fn check_conditions(&self) -> bool {
+        let n_items = self.similarity.n_items();
+        if let Some(noise) = &self.noise {
+            if noise.n_items() != n_items || self.constraints.is_some() {
+                return false;
+            }
+        }
+        if let Some(constraints) = &self.constraints {
+            if constraints.n_items() != n_items {
+                return false;
+            }
+        }
+        if let Some(anchors) = &self.anchors {
+            if anchors.n_items() != n_items {
+                return false;
+            }
+            if let Some(constraints) = &self.constraints {
+                return constraints.is_satisfied_by(anchors.labels());
+            }
+        }
+        true
+    }
+
+    fn sum_of_row_subset(&self, row: usize, columns: &[usize]) -> f64 {
+        if self.compensated {
+            self.similarity.sum_of_row_subset_compensated(row, columns)
+        } else {
+            self.similarity.sum_of_row_subset(row, columns)
+        }
+    }
+
+    /// Set `attractions[j]` to the transformed similarity between item `row` and each item `j` in
+    /// `columns`, times the multiplicity of `j`.
+    ///
+    /// If the similarities to all of `columns` are zero, they give no preference among the
+    /// clusters, so the attractions are all one and clusters are weighted by their sizes (as under
+    /// the CRP) rather than by an undefined ratio of zero to zero.
+    fn set_attractions(&self, row: usize, columns: &[usize], attractions: &mut [f64]) {
+        self.set_unweighted_attractions(row, columns, attractions);
+        if columns.iter().all(|j| attractions[*j] == 0.0) {
+            for j in columns {
+                attractions[*j] = 1.0;
+            }
+        }
+        if let Some(multiplicities) = &self.multiplicities {
+            for j in columns {
+                attractions[*j] *= multiplicities[*j] as f64;
+            }
+        }
+    }
+
+    fn set_unweighted_attractions(&self, row: usize, columns: &[usize], attractions: &mut [f64]) {
+        let get = |j: &usize| self.similarity.get(row, *j);
+        match self.attraction {
+            Attraction::Raw => {
+                for j in columns {
+                    attractions[*j] = get(j);
+                }
+            }
+            Attraction::Softmax => {
+                let max = columns.iter().map(get).fold(f64::NEG_INFINITY, f64::max);
+                for j in columns {
+                    attractions[*j] = (get(j) - max).exp();
+                }
+            }
+            Attraction::Rank => {
+                let mut order = columns.to_vec();
+                order.sort_by(|a, b| get(a).total_cmp(&get(b)));
+                let mut start = 0;
+                while start < order.len() {
+                    let value = get(&order[start]);
+                    let end = start
+                        + order[start..]
+                            .iter()
+                            .take_while(|j| get(j) == value)
+                            .count();
+                    for j in &order[start..end] {
+                        attractions[*j] = ((start + end + 1) as f64) / 2.0;
+                    }
+                    start = end;
+                }
+            }
+        }
+    }
+
+    fn sum_of_attractions(&self, columns: &[usize], attractions: &[f64]) -> f64 {
+        let values = columns.iter().map(|j| attractions[*j]);
+        if self.compensated {
+            compensated_sum(values)
+        } else {
+            values.fold(0.0, |sum, x| sum + x)
+        }
+    }
+
+    /// The order in which the items are allocated by the next draw.
+    pub fn permutation(&self) -> &Permutation {
+        &self.permutation
+    }
+
+    /// Return the permutation to the natural order, as [`Self::new`] would with
+    /// [`Permutation::natural`], except that anchored items come first.
+    pub fn reset_permutation(&mut self) {
+        match &self.anchors {
+            Some(anchors) if anchors.n_anchored() > 0 => {
+                let free = (0..anchors.n_items()).filter(|i| anchors.labels[*i] == usize::MAX);
+                let x = anchors.items.iter().copied().chain(free).collect();
+                self.permutation = Permutation::from_vector(x).unwrap();
+            }
+            _ => self.permutation.reset(),
+        }
+    }
+
+    /// Put the items in the order of `order`, a permutation of the items, except that anchored
+    /// items come first.
+    ///
+    /// # Panics
+    ///
+    /// Panics if `order` is not a permutation of the items.
+    pub fn order_permutation(&mut self, order: &[usize]) {
+        let x = match &self.anchors {
+            Some(anchors) if anchors.n_anchored() > 0 => {
+                let free = order.iter().filter(|i| anchors.labels[**i] == usize::MAX);
+                anchors.items.iter().chain(free).copied().collect()
+            }
+            _ => order.to_vec(),
+        };
+        self.permutation = Permutation::from_vector(x).expect("'order' should be a permutation");
+    }
+
     pub fn shuffle_permutation<T: Rng>(&mut self, rng: &mut T) {
-        self.permutation.shuffle(rng);
+        match &self.anchors {
+            Some(anchors) => self.permutation.shuffle_from(anchors.n_anchored(), rng),
+            None => self.permutation.shuffle(rng),
+        }
         /*
         match std::env::var("DBD_METHOD").as_deref() {
             Ok("jumps" | "biased") => {
@@ -66,8 +651,8 @@ impl<'a> EpaParameters<'a> {
 /// A data structure representing a square matrix.
 ///
 #[derive(Debug)]
-pub struct SquareMatrix {
-    data: Vec<f64>,
+pub struct SquareMatrix<S: SimilarityValue = f64> {
+    data: Vec<S>,
     n_items: usize,
 }
 
@@ -97,16 +682,29 @@ impl SquareMatrix {
         }
         Self { data, n_items }
     }
+}
 
-    pub fn data(&self) -> &[f64] {
+impl SquareMatrix<f32> {
+    /// Store a square matrix of doubles in single precision.
+    pub fn from_f64(data: &[f64], n_items: usize) -> Self {
+        assert_eq!(data.len(), n_items * n_items);
+        Self {
+            data: data.iter().map(|x| *x as f32).collect(),
+            n_items,
+        }
+    }
+}
+
+impl<S: SimilarityValue> SquareMatrix<S> {
+    pub fn data(&self) -> &[S] {
         &self.data[..]
     }
 
-    pub fn data_mut(&mut self) -> &mut [f64] {
+    pub fn data_mut(&mut self) -> &mut [S] {
         &mut self.data[..]
     }
 
-    pub fn view(&mut self) -> SquareMatrixBorrower<'_> {
+    pub fn view(&self) -> SquareMatrixBorrower<'_, S> {
         SquareMatrixBorrower::from_slice(&self.data[..], self.n_items)
     }
 
@@ -116,30 +714,54 @@ impl SquareMatrix {
 }
 
 #[derive(Debug, Copy, Clone)]
-pub struct SquareMatrixBorrower<'a> {
-    data: &'a [f64],
+pub struct SquareMatrixBorrower<'a, S: SimilarityValue = f64> {
+    data: &'a [S],
     n_items: usize,
+    symmetric: bool,
 }
 
-impl std::ops::Index<(usize, usize)> for SquareMatrixBorrower<'_> {
-    type Output = f64;
+impl<S: SimilarityValue> std::ops::Index<(usize, usize)> for SquareMatrixBorrower<'_, S> {
+    type Output = S;
     fn index(&self, (i, j): (usize, usize)) -> &Self::Output {
         &self.data[self.n_items * j + i]
     }
 }
 
-impl<'a> SquareMatrixBorrower<'a> {
-    pub fn from_slice(data: &'a [f64], n_items: usize) -> Self {
+impl<'a, S: SimilarityValue> SquareMatrixBorrower<'a, S> {
+    pub fn from_slice(data: &'a [S], n_items: usize) -> Self {
         assert_eq!(data.len(), n_items * n_items);
-        Self { data, n_items }
+        Self {
+            data,
+            n_items,
+            symmetric: false,
+        }
     }
 
     /// # Safety
     ///
     /// You're on your own.
-    pub unsafe fn from_ptr(data: *const f64, n_items: usize) -> Self {
+    pub unsafe fn from_ptr(data: *const S, n_items: usize) -> Self {
         let data = slice::from_raw_parts(data, n_items * n_items);
-        Self { data, n_items }
+        Self {
+            data,
+            n_items,
+            symmetric: false,
+        }
+    }
+
+    /// Check whether the matrix is symmetric, in which case sums of similarities read memory
+    /// contiguously.  The check costs about as much as one draw from the EPA distribution.
+    pub fn detect_symmetry(mut self) -> Self {
+        self.symmetric = (0..self.n_items).all(|j| (0..j).all(|i| self.get(i, j) == self.get(j, i)));
+        self
+    }
+
+    /// Declare whether the matrix is symmetric instead of checking, which avoids the strided reads
+    /// of [`Self::detect_symmetry`] (e.g., for a large matrix memory-mapped from disk).  Sums of
+    /// similarities are wrong if a matrix which is not symmetric is declared to be.
+    pub fn with_symmetry(mut self, symmetric: bool) -> Self {
+        self.symmetric = symmetric;
+        self
     }
 
     pub fn n_items(&self) -> usize {
@@ -149,11 +771,11 @@ impl<'a> SquareMatrixBorrower<'a> {
     /// # Safety
     ///
     /// You're on your own.
-    pub unsafe fn get_unchecked(&self, (i, j): (usize, usize)) -> &f64 {
+    pub unsafe fn get_unchecked(&self, (i, j): (usize, usize)) -> &S {
         self.data.get_unchecked(self.n_items * j + i)
     }
 
-    pub fn data(&self) -> &[f64] {
+    pub fn data(&self) -> &[S] {
         self.data
     }
 
@@ -161,24 +783,325 @@ impl<'a> SquareMatrixBorrower<'a> {
         let mut sum = 0.0;
         for i in 0..self.n_items {
             for j in 0..i {
-                sum += unsafe { *self.get_unchecked((i, j)) };
+                sum += unsafe { (*self.get_unchecked((i, j))).into() };
             }
         }
         sum
     }
 
+    /// The sum of the similarities between item `row` and the items in `columns`.
+    ///
+    /// The similarities are added into several independent accumulators, so the additions are not
+    /// serialized and can be vectorized.  If the matrix is known to be symmetric (see
+    /// [`Self::detect_symmetry`]), the similarities are read from column `row`, which is contiguous
+    /// in memory, and a run of consecutive items (e.g., a prefix of the natural permutation or a
+    /// cluster of items sorted by group) is summed directly from a slice.  Either way, the sum is
+    /// the same.
     pub fn sum_of_row_subset(&self, row: usize, columns: &[usize]) -> f64 {
-        let mut sum = 0.0;
-        for j in columns {
-            sum += unsafe { *self.get_unchecked((row, *j)) };
+        if !self.symmetric {
+            return gather_sum(columns, |j| unsafe {
+                (*self.get_unchecked((row, j))).into()
+            });
         }
-        sum
+        let column = &self.data[self.n_items * row..self.n_items * (row + 1)];
+        if is_consecutive(columns) {
+            let mut sums = [0.0; LANES];
+            let mut chunks = column[columns[0]..=columns[columns.len() - 1]].chunks_exact(LANES);
+            for chunk in &mut chunks {
+                for (sum, x) in sums.iter_mut().zip(chunk) {
+                    *sum += (*x).into();
+                }
+            }
+            for (sum, x) in sums.iter_mut().zip(chunks.remainder()) {
+                *sum += (*x).into();
+            }
+            combine_lanes(sums)
+        } else {
+            gather_sum(columns, |j| unsafe { (*column.get_unchecked(j)).into() })
+        }
+    }
+
+    /// Like [`Self::sum_of_row_subset`], but using Neumaier's compensated summation.
+    pub fn sum_of_row_subset_compensated(&self, row: usize, columns: &[usize]) -> f64 {
+        compensated_sum(
+            columns
+                .iter()
+                .map(|j| unsafe { (*self.get_unchecked((row, *j))).into() }),
+        )
+    }
+}
+
+// The number of accumulators in `SquareMatrixBorrower::sum_of_row_subset`.
+const LANES: usize = 4;
+
+fn combine_lanes(sums: [f64; LANES]) -> f64 {
+    (sums[0] + sums[1]) + (sums[2] + sums[3])
+}
+
+/// The sum accumulated in the same order as [`SquareMatrixBorrower::sum_of_row_subset`], so other
+/// similarities give identical sums.
+pub(crate) fn lane_sum(values: impl Iterator<Item = f64>) -> f64 {
+    let mut sums = [0.0; LANES];
+    for (k, x) in values.enumerate() {
+        sums[k % LANES] += x;
     }
+    combine_lanes(sums)
 }
 
-pub fn sample<T: Rng>(parameters: &EpaParameters, rng: &mut T) -> Clustering {
+fn gather_sum(columns: &[usize], value: impl Fn(usize) -> f64) -> f64 {
+    let mut sums = [0.0; LANES];
+    let mut chunks = columns.chunks_exact(LANES);
+    for chunk in &mut chunks {
+        for (sum, j) in sums.iter_mut().zip(chunk) {
+            *sum += value(*j);
+        }
+    }
+    for (sum, j) in sums.iter_mut().zip(chunks.remainder()) {
+        *sum += value(*j);
+    }
+    combine_lanes(sums)
+}
+
+/// Whether `x` is an increasing run of consecutive integers, which is checked in constant time
+/// for most other slices.
+fn is_consecutive(x: &[usize]) -> bool {
+    match (x.first(), x.last()) {
+        (Some(first), Some(last)) => {
+            *last >= *first
+                && last - first + 1 == x.len()
+                && x.windows(2).all(|w| w[0] + 1 == w[1])
+        }
+        _ => false,
+    }
+}
+
+impl<S: SimilarityValue> Similarity for SquareMatrixBorrower<'_, S> {
+    fn n_items(&self) -> usize {
+        self.n_items
+    }
+
+    fn get(&self, i: usize, j: usize) -> f64 {
+        self[(i, j)].into()
+    }
+
+    fn sum_of_row_subset(&self, row: usize, columns: &[usize]) -> f64 {
+        SquareMatrixBorrower::sum_of_row_subset(self, row, columns)
+    }
+
+    fn sum_of_row_subset_compensated(&self, row: usize, columns: &[usize]) -> f64 {
+        SquareMatrixBorrower::sum_of_row_subset_compensated(self, row, columns)
+    }
+}
+
+/// A square matrix of similarities quantized to 16 bits, a quarter of the memory of doubles.
+///
+/// A similarity `x` is stored as the integer nearest to `(x - offset) / scale`, where `offset` is
+/// the smallest similarity and `scale` spreads the range of the similarities over the 65,536
+/// levels, so each similarity is off by at most `scale / 2` (see [`Self::max_error`]).  The
+/// levels are summed exactly as integers and dequantized once per sum.
+#[derive(Debug)]
+pub struct QuantizedMatrix {
+    data: Vec<u16>,
+    n_items: usize,
+    offset: f64,
+    scale: f64,
+    symmetric: bool,
+}
+
+impl QuantizedMatrix {
+    /// Quantize a square matrix of finite doubles.
+    pub fn from_f64(data: &[f64], n_items: usize) -> Self {
+        assert_eq!(data.len(), n_items * n_items);
+        let (min, max) = data
+            .iter()
+            .fold((f64::INFINITY, f64::NEG_INFINITY), |(min, max), x| {
+                (min.min(*x), max.max(*x))
+            });
+        let offset = if min.is_finite() { min } else { 0.0 };
+        let scale = if max > min {
+            (max - min) / f64::from(u16::MAX)
+        } else {
+            1.0
+        };
+        let data: Vec<_> = data
+            .iter()
+            .map(|x| ((x - offset) / scale).round().clamp(0.0, f64::from(u16::MAX)) as u16)
+            .collect();
+        let symmetric =
+            (0..n_items).all(|j| (0..j).all(|i| data[n_items * j + i] == data[n_items * i + j]));
+        Self {
+            data,
+            n_items,
+            offset,
+            scale,
+            symmetric,
+        }
+    }
+
+    pub fn view(&self) -> QuantizedMatrixBorrower<'_> {
+        QuantizedMatrixBorrower {
+            data: &self.data[..],
+            n_items: self.n_items,
+            offset: self.offset,
+            scale: self.scale,
+            symmetric: self.symmetric,
+        }
+    }
+
+    pub fn n_items(&self) -> usize {
+        self.n_items
+    }
+
+    /// The largest difference between a similarity and its quantized value.
+    pub fn max_error(&self) -> f64 {
+        self.scale / 2.0
+    }
+}
+
+/// A view of a [`QuantizedMatrix`], which dequantizes its similarities on the fly.
+#[derive(Debug, Copy, Clone)]
+pub struct QuantizedMatrixBorrower<'a> {
+    data: &'a [u16],
+    n_items: usize,
+    offset: f64,
+    scale: f64,
+    symmetric: bool,
+}
+
+impl QuantizedMatrixBorrower<'_> {
+    // The sum of the levels of the similarities between item `row` and the items in `columns`,
+    // which is exact.  As for `SquareMatrixBorrower`, a symmetric matrix is read by column.
+    fn sum_of_levels(&self, row: usize, columns: &[usize]) -> u64 {
+        let n_items = self.n_items;
+        if self.symmetric {
+            let column = &self.data[n_items * row..n_items * (row + 1)];
+            columns.iter().map(|j| u64::from(column[*j])).sum()
+        } else {
+            columns
+                .iter()
+                .map(|j| u64::from(self.data[n_items * j + row]))
+                .sum()
+        }
+    }
+}
+
+impl Similarity for QuantizedMatrixBorrower<'_> {
+    fn n_items(&self) -> usize {
+        self.n_items
+    }
+
+    fn get(&self, i: usize, j: usize) -> f64 {
+        self.offset + self.scale * f64::from(self.data[self.n_items * j + i])
+    }
+
+    fn sum_of_row_subset(&self, row: usize, columns: &[usize]) -> f64 {
+        let levels = self.sum_of_levels(row, columns) as f64;
+        self.offset * (columns.len() as f64) + self.scale * levels
+    }
+
+    // The levels are summed exactly, so there is nothing to compensate.
+    fn sum_of_row_subset_compensated(&self, row: usize, columns: &[usize]) -> f64 {
+        self.sum_of_row_subset(row, columns)
+    }
+}
+
+/// Similarities which are all one, for which the EPA distribution is the Chinese restaurant
+/// process (or the Pitman-Yor process with a discount).
+///
+/// No matrix is stored and a sum of similarities is just the number of items summed over, so
+/// draws cost no more than under the exchangeable process itself.
+#[derive(Debug, Clone, Copy)]
+pub struct ConstantSimilarity {
+    n_items: usize,
+}
+
+impl ConstantSimilarity {
+    pub fn new(n_items: usize) -> Self {
+        Self { n_items }
+    }
+}
+
+impl Similarity for ConstantSimilarity {
+    fn n_items(&self) -> usize {
+        self.n_items
+    }
+
+    fn get(&self, _i: usize, _j: usize) -> f64 {
+        1.0
+    }
+
+    fn sum_of_row_subset(&self, _row: usize, columns: &[usize]) -> f64 {
+        columns.len() as f64
+    }
+
+    fn sum_of_row_subset_compensated(&self, _row: usize, columns: &[usize]) -> f64 {
+        columns.len() as f64
+    }
+}
+
+/// Buffers reused across draws by [`sample_into`], so that repeated draws do not allocate.
+#[derive(Debug, Default)]
+pub struct Scratch {
+    // The label of each item, in order of creation of the clusters.
+    labels: Vec<usize>,
+    // The items of each cluster (indexed by label) in increasing order, maintained as items are
+    // allocated since finding them from the labels would cost a pass over all the items for every
+    // cluster, making a draw cubic rather than quadratic in the number of items.  Clusters beyond
+    // the number of clusters of the current draw keep their capacity for later draws.
+    members: Vec<Vec<usize>>,
+    attractions: Vec<f64>,
+    cumulative_weights: Vec<f64>,
+    canonical_labels: Vec<usize>,
+    // The label of each group of the constraints, or 'usize::MAX' until one of its items is
+    // allocated.
+    group_labels: Vec<usize>,
+}
+
+pub fn sample<M: Similarity, T: Rng>(parameters: &EpaParameters<M>, rng: &mut T) -> Clustering {
+    let mut scratch = Scratch::default();
+    draw(parameters, &mut scratch, rng);
+    Clustering::from_vector(scratch.labels)
+}
+
+/// Like [`sample`], but reusing the buffers in `scratch` and writing canonical labels (i.e.,
+/// numbered from zero in order of first appearance) into `labels`, which need not be initialized
+/// beforehand.  Every element of `labels` is initialized.  Returns the number of clusters.
+///
+/// # Panics
+///
+/// Panics if the length of `labels` is not the number of items.
+pub fn sample_into<M: Similarity, T: Rng, L: FromUsize>(
+    parameters: &EpaParameters<M>,
+    scratch: &mut Scratch,
+    rng: &mut T,
+    labels: &mut [MaybeUninit<L>],
+) -> usize {
+    assert_eq!(labels.len(), parameters.similarity.n_items());
+    let n_clusters = draw(parameters, scratch, rng);
+    let canonical_labels = &mut scratch.canonical_labels;
+    canonical_labels.clear();
+    canonical_labels.resize(n_clusters, usize::MAX);
+    let mut next_label = 0;
+    for (label, x) in scratch.labels.iter().zip(labels.iter_mut()) {
+        let canonical_label = &mut canonical_labels[*label];
+        if *canonical_label == usize::MAX {
+            *canonical_label = next_label;
+            next_label += 1;
+        }
+        x.write(L::from_usize(*canonical_label));
+    }
+    n_clusters
+}
+
+// Draw a partition into `scratch.labels`, returning the number of clusters.
+fn draw<M: Similarity, T: Rng>(
+    parameters: &EpaParameters<M>,
+    scratch: &mut Scratch,
+    rng: &mut T,
+) -> usize {
     let ni = parameters.similarity.n_items();
     let (mass, path): (f64, Option<Vec<f64>>) = (parameters.mass, None);
+    let discount = parameters.discount;
     /*
     let (mass, path) = match std::env::var("DBD_METHOD").as_deref() {
         Ok("jumps") => {
@@ -215,32 +1138,700 @@ pub fn sample<T: Rng>(parameters: &EpaParameters, rng: &mut T) -> Clustering {
         _ => (parameters.mass, None),
     };
     */
-    let mut clustering = Clustering::unallocated(ni);
-    for i in 0..ni {
+    // Raw attractions without multiplicities are summed directly from the similarity.
+    let raw = parameters.attraction == Attraction::Raw && parameters.multiplicities.is_none();
+    let Scratch {
+        labels,
+        members,
+        attractions,
+        cumulative_weights,
+        group_labels,
+        ..
+    } = scratch;
+    labels.clear();
+    labels.resize(ni, usize::MAX);
+    if !raw {
+        attractions.resize(ni, 0.0);
+    }
+    let constraints = parameters.constraints.as_deref();
+    if let Some(constraints) = constraints {
+        group_labels.clear();
+        group_labels.resize(constraints.cannot.len(), usize::MAX);
+    }
+    let mut n_clusters = 0;
+    let mut n_anchored = 0;
+    if let Some(anchors) = &parameters.anchors {
+        // The anchored items come first in the permutation and keep their labels.
+        n_anchored = anchors.n_anchored();
+        n_clusters = anchors.n_clusters;
+        if members.len() < n_clusters {
+            members.resize_with(n_clusters, Vec::new);
+        }
+        for items in &mut members[..n_clusters] {
+            items.clear();
+        }
+        for ii in &anchors.items {
+            let label = anchors.labels[*ii];
+            labels[*ii] = label;
+            members[label].push(*ii);
+            if let Some(constraints) = constraints {
+                group_labels[constraints.group[*ii]] = label;
+            }
+        }
+    }
+    // Items after the subset are allocated given only the subset, so they cannot join the
+    // clusters started by one another.
+    let n_sequential = parameters
+        .subset_size
+        .map_or(ni, |m| m.clamp(1, ni.max(1)))
+        .max(n_anchored);
+    let mut n_subset_clusters = 0;
+    // The numbers of noise items among the clusters and among those of the subset.
+    let (mut n_noise, mut n_subset_noise) = (0, 0);
+    for i in n_anchored..ni {
         let ii = parameters.permutation.get(i);
         let jump_density = match path {
             Some(ref path) => path[i],
             None => 1.0,
         };
-        let kt = (i as f64)
-            / parameters
-                .similarity
-                .sum_of_row_subset(ii, parameters.permutation.slice_until(i));
-        let labels_and_weights = clustering
-            .available_labels_for_allocation_with_target(None, ii)
-            .map(|label| {
-                let n_items_in_cluster = clustering.size_of(label);
-                let weight = if n_items_in_cluster == 0 {
-                    mass * jump_density
-                } else {
-                    kt * parameters
-                        .similarity
-                        .sum_of_row_subset(ii, &clustering.items_of(label)[..])
-                };
-                (label, weight)
+        if i == 0 {
+            // The first item starts a cluster regardless of the (possibly negative) mass.
+            labels[ii] = 0;
+            n_clusters = 1;
+            if members.is_empty() {
+                members.push(Vec::with_capacity(ni));
+            }
+            members[0].clear();
+            members[0].push(ii);
+            if let Some(constraints) = constraints {
+                group_labels[constraints.group[ii]] = 0;
+            }
+            continue;
+        }
+        if i == n_sequential {
+            n_subset_clusters = n_clusters;
+            n_subset_noise = n_noise;
+        }
+        let (position, n_joinable, n_joinable_noise) = if i < n_sequential {
+            (i, n_clusters, n_noise)
+        } else {
+            (n_sequential, n_subset_clusters, n_subset_noise)
+        };
+        let n_real = (n_joinable - n_joinable_noise) as f64;
+        // The cannot-link constraints of the item, unless it must join the cluster of its group.
+        let mut cannot: &[usize] = &[];
+        if let Some(constraints) = constraints {
+            let group = constraints.group[ii];
+            let label = group_labels[group];
+            if label != usize::MAX {
+                labels[ii] = label;
+                if i < n_sequential {
+                    let items = &mut members[label];
+                    items.insert(items.partition_point(|j| *j < ii), ii);
+                }
+                continue;
+            }
+            cannot = &constraints.cannot[group];
+        }
+        let blocked = |label: usize| cannot.iter().any(|h| group_labels[*h] == label);
+        let preceding = parameters.permutation.slice_until(position);
+        let mut total = if raw {
+            parameters.sum_of_row_subset(ii, preceding)
+        } else {
+            parameters.set_attractions(ii, preceding, attractions);
+            parameters.sum_of_attractions(preceding, attractions)
+        };
+        // As in 'set_attractions', zero similarity to all the preceding items weights the
+        // clusters by their sizes.
+        let by_size = raw && total == 0.0;
+        if by_size {
+            total = position as f64;
+        }
+        let kt = (parameters.preceding_count(position) - discount * n_real) / total;
+        // Select the label as 'WeightedIndex' would, but without allocating.
+        cumulative_weights.clear();
+        let mut total_weight = 0.0;
+        let weights = members[..n_joinable].iter().enumerate().map(|(label, items)| {
+            if blocked(label) {
+                return 0.0;
+            }
+            kt * if by_size {
+                items.len() as f64
+            } else if raw {
+                parameters.sum_of_row_subset(ii, items)
+            } else {
+                parameters.sum_of_attractions(items, attractions)
+            }
+        });
+        let new_weight = (mass + discount * n_real) * jump_density;
+        let noise_weight = match &parameters.noise {
+            Some(noise) if noise.eligible[ii] => Some(noise.weight),
+            _ => None,
+        };
+        let weights = weights.chain(std::iter::once(new_weight)).chain(noise_weight);
+        for (label, weight) in weights.enumerate() {
+            if label == 0 {
+                total_weight = weight;
+            } else {
+                cumulative_weights.push(total_weight);
+                total_weight += weight;
+            }
+        }
+        let chosen_weight = Uniform::new(0.0, total_weight)
+            .expect("the weights of the clusters should be positive")
+            .sample(rng);
+        let mut label = cumulative_weights.partition_point(|w| *w <= chosen_weight);
+        // A noise item starts a cluster without members, which no later item can join.
+        let is_noise = label > n_joinable;
+        if is_noise {
+            label = n_joinable;
+        }
+        if i >= n_sequential && label == n_joinable {
+            label = n_clusters;
+            n_clusters += 1;
+        }
+        labels[ii] = label;
+        if let Some(constraints) = constraints {
+            group_labels[constraints.group[ii]] = label;
+        }
+        if i >= n_sequential {
+            continue;
+        }
+        if label == n_clusters {
+            n_clusters += 1;
+            if members.len() < n_clusters {
+                members.push(Vec::new());
+            }
+            members[label].clear();
+        }
+        if is_noise {
+            n_noise += 1;
+            continue;
+        }
+        let items = &mut members[label];
+        items.insert(items.partition_point(|j| *j < ii), ii);
+    }
+    n_clusters
+}
+
+/// The log of the probability of a partition under the EPA distribution.
+///
+/// Items with equal labels are clustered together; the labels need not be consecutive.
+pub fn log_probability<M: Similarity>(labels: &[usize], parameters: &EpaParameters<M>) -> f64 {
+    let ni = parameters.similarity.n_items();
+    assert_eq!(labels.len(), ni);
+    let mass = parameters.mass;
+    let discount = parameters.discount;
+    let mut seen = vec![false; labels.iter().max().map_or(0, |x| x + 1)];
+    let mut n_clusters = 0.0;
+    let mut sum = 0.0;
+    let mut attractions = vec![0.0; ni];
+    for i in 0..ni {
+        let ii = parameters.permutation.get(i);
+        let (mut total, mut within, mut any_within) = (0.0, 0.0, false);
+        let preceding = parameters.permutation.slice_until(i);
+        parameters.set_attractions(ii, preceding, &mut attractions);
+        for jj in preceding {
+            let s = attractions[*jj];
+            total += s;
+            if labels[*jj] == labels[ii] {
+                within += s;
+                any_within = true;
+            }
+        }
+        let count = parameters.preceding_count(i);
+        let weight = if any_within {
+            (count - discount * n_clusters) / total * within
+        } else {
+            mass + discount * n_clusters
+        };
+        if !seen[labels[ii]] {
+            seen[labels[ii]] = true;
+            n_clusters += 1.0;
+        }
+        sum += (weight / (mass + count)).ln();
+    }
+    sum
+}
+
+/// The probabilities that `item` is allocated to each cluster of the other items, or to a new
+/// cluster, when items are allocated in the order of the permutation under the EPA distribution.
+///
+/// The other items are clustered by `labels`, which must use labels `0, 1, ..., k - 1` (the label
+/// of `item` itself is ignored).  The result has length `k + 1`, whose last element is the
+/// probability of a new cluster.  Clusters none of whose items precede `item` have probability
+/// zero.
+pub fn allocation_probabilities<M: Similarity>(
+    labels: &[usize],
+    item: usize,
+    parameters: &EpaParameters<M>,
+) -> Vec<f64> {
+    let ni = parameters.similarity.n_items();
+    assert_eq!(labels.len(), ni);
+    let n_labels = (0..ni)
+        .filter(|j| *j != item)
+        .map(|j| labels[j] + 1)
+        .max()
+        .unwrap_or(0);
+    let mut probabilities = vec![0.0; n_labels + 1];
+    let i = (0..ni)
+        .position(|i| parameters.permutation.get(i) == item)
+        .unwrap();
+    if i == 0 {
+        probabilities[n_labels] = 1.0;
+        return probabilities;
+    }
+    let mass = parameters.mass;
+    let discount = parameters.discount;
+    let mut total = 0.0;
+    let mut seen = vec![false; n_labels];
+    let mut attractions = vec![0.0; ni];
+    let preceding = parameters.permutation.slice_until(i);
+    parameters.set_attractions(item, preceding, &mut attractions);
+    for jj in preceding {
+        let s = attractions[*jj];
+        total += s;
+        probabilities[labels[*jj]] += s;
+        seen[labels[*jj]] = true;
+    }
+    let n_clusters = seen.iter().filter(|x| **x).count() as f64;
+    let count = parameters.preceding_count(i);
+    let kt = (count - discount * n_clusters) / total / (mass + count);
+    for p in &mut probabilities[..n_labels] {
+        *p *= kt;
+    }
+    probabilities[n_labels] = (mass + discount * n_clusters) / (mass + count);
+    probabilities
+}
+
+/// Calls `f(i, j, p)` for each pair of items `i` and `j`, where `j` precedes `i` in the
+/// permutation and `p` is the probability that `i` is allocated to the cluster of `j` given the
+/// allocations in `labels` of the items preceding `i`.
+///
+/// Averaged over draws, these give a Rao-Blackwellized estimate of the probabilities that items
+/// are clustered together, with less variance than the proportion of draws in which they are.
+/// Anchored items, and all items under constraints, noise, or a subset size, contribute `1` or
+/// `0` according to their allocations instead, which is unbiased but does not reduce variance.
+pub fn coclustering_probabilities<M: Similarity>(
+    labels: &[usize],
+    parameters: &EpaParameters<M>,
+    mut f: impl FnMut(usize, usize, f64),
+) {
+    let ni = parameters.similarity.n_items();
+    assert_eq!(labels.len(), ni);
+    let hard = parameters.constraints.is_some()
+        || parameters.noise.is_some()
+        || parameters.subset_size.is_some_and(|m| m < ni);
+    let n_anchored = parameters.anchors.as_ref().map_or(0, |x| x.n_anchored());
+    let n_labels = labels.iter().max().map_or(0, |x| x + 1);
+    let mass = parameters.mass;
+    let discount = parameters.discount;
+    let mut attractions = vec![0.0; ni];
+    let mut sums = vec![0.0; n_labels];
+    let mut seen = vec![false; n_labels];
+    for i in 0..ni {
+        let ii = parameters.permutation.get(i);
+        let preceding = parameters.permutation.slice_until(i);
+        if hard || i < n_anchored {
+            for jj in preceding {
+                f(ii, *jj, if labels[*jj] == labels[ii] { 1.0 } else { 0.0 });
+            }
+            continue;
+        }
+        sums.fill(0.0);
+        seen.fill(false);
+        parameters.set_attractions(ii, preceding, &mut attractions);
+        let mut n_clusters = 0.0;
+        for jj in preceding {
+            let label = labels[*jj];
+            sums[label] += attractions[*jj];
+            if !seen[label] {
+                seen[label] = true;
+                n_clusters += 1.0;
+            }
+        }
+        let total = parameters.sum_of_attractions(preceding, &attractions);
+        let count = parameters.preceding_count(i);
+        let kt = (count - discount * n_clusters) / total / (mass + count);
+        for jj in preceding {
+            f(ii, *jj, kt * sums[labels[*jj]]);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_compensated_summation() {
+        let n_items = 12;
+        let mut data = vec![1.0; n_items * n_items];
+        data[n_items] = 1e16;
+        data[1] = 1e16;
+        let similarity = SquareMatrixBorrower::from_slice(&data, n_items);
+        let columns: Vec<_> = (1..n_items).collect();
+        // The accumulator holding 1e16 loses its ones, but the other accumulators keep theirs.
+        assert_eq!(similarity.sum_of_row_subset(0, &columns), 1e16 + 8.0);
+        assert_eq!(
+            similarity.sum_of_row_subset_compensated(0, &columns),
+            1e16 + 10.0
+        );
+        let parameters = EpaParameters::new(similarity, Permutation::natural(n_items), 1.0)
+            .unwrap()
+            .with_compensated_summation(true);
+        assert_eq!(parameters.sum_of_row_subset(0, &columns), 1e16 + 10.0);
+    }
+
+    #[test]
+    fn test_quantized_matrix() {
+        let n_items = 20;
+        let mut rng = rand::rngs::StdRng::seed_from_u64(1);
+        let mut data = vec![0.0; n_items * n_items];
+        for j in 0..n_items {
+            for i in 0..=j {
+                let x = if i == j { 1.0 } else { 0.5 + rng.random::<f64>() * 99.5 };
+                data[n_items * j + i] = x;
+                data[n_items * i + j] = x;
+            }
+        }
+        let quantized = QuantizedMatrix::from_f64(&data, n_items);
+        let min = data.iter().fold(f64::INFINITY, |a, b| a.min(*b));
+        let max = data.iter().fold(0.0, |a: f64, b| a.max(*b));
+        assert!((quantized.max_error() - (max - min) / 65535.0 / 2.0).abs() < 1e-15);
+        let similarity = quantized.view();
+        let exact = SquareMatrixBorrower::from_slice(&data, n_items);
+        for j in 0..n_items {
+            for i in 0..n_items {
+                assert!((similarity.get(i, j) - exact.get(i, j)).abs() <= quantized.max_error());
+            }
+        }
+        let columns = [0, 3, 4, 5, 11, 19];
+        for row in [2, 7] {
+            let sum = similarity.sum_of_row_subset(row, &columns);
+            let expected = exact.sum_of_row_subset(row, &columns);
+            assert!((sum - expected).abs() <= 6.0 * quantized.max_error() + 1e-9);
+            assert_eq!(sum, similarity.sum_of_row_subset_compensated(row, &columns));
+        }
+        // A constant matrix is exact.
+        let constant = QuantizedMatrix::from_f64(&[2.5; 9], 3);
+        assert_eq!(constant.view().sum_of_row_subset(1, &[0, 2]), 5.0);
+    }
+
+    #[test]
+    fn test_sum_of_row_subset() {
+        let n_items = 11;
+        let data: Vec<_> = (0..n_items * n_items).map(|x| x as f64).collect();
+        let similarity = SquareMatrixBorrower::from_slice(&data, n_items);
+        assert!(!similarity.detect_symmetry().symmetric);
+        let mut symmetric_data = data.clone();
+        for i in 0..n_items {
+            for j in 0..i {
+                symmetric_data[n_items * i + j] = data[n_items * j + i];
+            }
+        }
+        let symmetric = SquareMatrixBorrower::from_slice(&symmetric_data, n_items);
+        let detected = symmetric.detect_symmetry();
+        assert!(detected.symmetric);
+        let declared = symmetric.with_symmetry(true);
+        for columns in [vec![], vec![3], vec![2, 3, 4, 5, 6, 7, 8], vec![4, 3, 9, 0, 10]] {
+            let expected: f64 = columns.iter().map(|j| similarity[(5, *j)]).sum();
+            assert_eq!(similarity.sum_of_row_subset(5, &columns), expected);
+            let expected = symmetric.sum_of_row_subset(5, &columns);
+            assert_eq!(detected.sum_of_row_subset(5, &columns), expected);
+            assert_eq!(declared.sum_of_row_subset(5, &columns), expected);
+        }
+        assert!(is_consecutive(&[2, 3, 4]) && !is_consecutive(&[2, 4, 3]) && !is_consecutive(&[]));
+    }
+
+    #[test]
+    fn test_sample_into() {
+        let n_items = 9;
+        let data: Vec<_> = (0..n_items * n_items)
+            .map(|x| 1.0 / (1.0 + ((x % n_items) as f64 - (x / n_items) as f64).abs()))
+            .collect();
+        let similarity = SquareMatrixBorrower::from_slice(&data, n_items);
+        let mut parameters =
+            EpaParameters::new(similarity, Permutation::natural(n_items), 2.0).unwrap();
+        let mut scratch = Scratch::default();
+        let mut labels = vec![MaybeUninit::<u16>::uninit(); n_items];
+        for seed in 0..20 {
+            let mut rng = rand::rngs::StdRng::seed_from_u64(seed);
+            parameters.shuffle_permutation(&mut rng);
+            let expected = sample(&parameters, &mut rng.clone());
+            let n_clusters = sample_into(&parameters, &mut scratch, &mut rng, &mut labels);
+            let mut canonical = vec![0_u16; n_items];
+            expected.relabel_into_slice(0, &mut canonical);
+            let labels: Vec<_> = labels.iter().map(|x| unsafe { x.assume_init() }).collect();
+            assert_eq!(labels, canonical);
+            assert_eq!(n_clusters, expected.n_clusters());
+        }
+    }
+
+    #[test]
+    fn test_subset_size() {
+        let n_items = 30;
+        let data: Vec<_> = (0..n_items * n_items)
+            .map(|x| 1.0 / (1.0 + ((x % n_items) as f64 - (x / n_items) as f64).abs()))
+            .collect();
+        let similarity = SquareMatrixBorrower::from_slice(&data, n_items);
+        let exact = EpaParameters::new(similarity, Permutation::natural(n_items), 1.0).unwrap();
+        let whole = exact.clone().with_subset_size(Some(n_items + 5));
+        let subset = exact.clone().with_subset_size(Some(4));
+        for seed in 0..20 {
+            let rng = rand::rngs::StdRng::seed_from_u64(seed);
+            assert_eq!(
+                sample(&exact, &mut rng.clone()).allocation(),
+                sample(&whole, &mut rng.clone()).allocation()
+            );
+            let clustering = sample(&subset, &mut rng.clone());
+            assert_eq!(clustering.n_items(), n_items);
+            // Each cluster has an item of the subset or is a singleton.
+            for &label in clustering.active_labels() {
+                let items = clustering.items_of(label);
+                assert!(items.len() == 1 || items.iter().any(|&i| i < 4));
+            }
+        }
+    }
+
+    #[test]
+    fn test_constraints() {
+        assert!(Constraints::new(3, &[(0, 3)], &[]).is_err());
+        assert!(Constraints::new(4, &[(0, 1), (1, 2)], &[(2, 0)]).is_err());
+        let n_items = 12;
+        let constraints = Constraints::new(n_items, &[(0, 11), (11, 5)], &[(1, 2), (5, 2)]).unwrap();
+        assert!(constraints.is_satisfied_by(&[0, 0, 1, 0, 0, 0, 0, 0, 0, 0, 0, 0]));
+        assert!(!constraints.is_satisfied_by(&[0, 1, 1, 0, 0, 0, 0, 0, 0, 0, 0, 0]));
+        assert!(!constraints.is_satisfied_by(&[0, 0, 1, 0, 0, 1, 0, 0, 0, 0, 0, 1]));
+        let data = vec![1.0; n_items * n_items];
+        let similarity = SquareMatrixBorrower::from_slice(&data, n_items);
+        let parameters = EpaParameters::new(similarity, Permutation::natural(n_items), 0.5)
+            .unwrap()
+            .with_constraints(Some(Arc::new(constraints.clone())))
+            .unwrap();
+        assert!(parameters
+            .clone()
+            .with_constraints(Some(Arc::new(Constraints::new(3, &[], &[]).unwrap())))
+            .is_none());
+        for subset_size in [None, Some(3)] {
+            let mut parameters = parameters.clone().with_subset_size(subset_size);
+            let mut rng = rand::rngs::StdRng::seed_from_u64(3);
+            for _ in 0..50 {
+                parameters.shuffle_permutation(&mut rng);
+                let clustering = sample(&parameters, &mut rng);
+                assert!(constraints.is_satisfied_by(clustering.allocation()));
+            }
+        }
+    }
+
+    #[test]
+    fn test_anchors() {
+        let n_items = 10;
+        let mut anchor_labels = vec![None; n_items];
+        anchor_labels[7] = Some(4);
+        anchor_labels[2] = Some(9);
+        anchor_labels[5] = Some(4);
+        let anchors = Anchors::new(&anchor_labels);
+        assert_eq!(anchors.n_anchored(), 3);
+        assert_eq!(anchors.labels()[2], 0);
+        assert_eq!(anchors.labels()[5], 1);
+        assert_eq!(anchors.labels()[7], 1);
+        // Anchored items move to their clusters, which absorb the clusters where most of their
+        // items were.
+        let x = anchors.impose(&[0, 1, 0, 2, 2, 1, 1, 1, 0, 3]);
+        assert_eq!(x, vec![0, 1, 0, 2, 2, 1, 1, 1, 0, 3]);
+        let x = anchors.impose(&[0, 1, 1, 2, 2, 1, 1, 0, 0, 0]);
+        assert_eq!(x, vec![1, 0, 0, 2, 2, 1, 0, 1, 1, 1]);
+        let data = vec![1.0; n_items * n_items];
+        let similarity = SquareMatrixBorrower::from_slice(&data, n_items);
+        let parameters = EpaParameters::new(similarity, Permutation::natural(n_items), 2.0)
+            .unwrap()
+            .with_anchors(Some(Arc::new(anchors)))
+            .unwrap();
+        let cannot = Constraints::new(n_items, &[], &[(2, 7)]).unwrap();
+        assert!(parameters
+            .clone()
+            .with_constraints(Some(Arc::new(cannot)))
+            .is_some());
+        let conflict = Constraints::new(n_items, &[(2, 7)], &[]).unwrap();
+        assert!(parameters
+            .clone()
+            .with_constraints(Some(Arc::new(conflict)))
+            .is_none());
+        for subset_size in [None, Some(2), Some(5)] {
+            let mut parameters = parameters.clone().with_subset_size(subset_size);
+            let mut rng = rand::rngs::StdRng::seed_from_u64(5);
+            for _ in 0..50 {
+                parameters.shuffle_permutation(&mut rng);
+                let x = sample(&parameters, &mut rng).into_vector();
+                assert!(x[5] == x[7] && x[2] != x[5]);
+            }
+        }
+    }
+
+    #[test]
+    fn test_allocation_probabilities() {
+        let data = [1.0, 1.0, 3.0, 1.0, 1.0, 1.0, 3.0, 1.0, 1.0];
+        let similarity = SquareMatrixBorrower::from_slice(&data, 3);
+        let permutation = Permutation::from_vector(vec![1, 2, 0]).unwrap();
+        let parameters = EpaParameters::new(similarity, permutation, 2.0).unwrap();
+        let p = allocation_probabilities(&[0, 0, 1], 0, &parameters);
+        assert_eq!(p.len(), 3);
+        assert!((p[0] - 0.25 * 2.0 / 4.0).abs() < 1e-12);
+        assert!((p[1] - 0.75 * 2.0 / 4.0).abs() < 1e-12);
+        assert!((p[2] - 2.0 / 4.0).abs() < 1e-12);
+        let permutation = Permutation::from_vector(vec![0, 1, 2]).unwrap();
+        let parameters = EpaParameters::new(similarity, permutation, 2.0).unwrap();
+        assert_eq!(
+            allocation_probabilities(&[0, 0, 1], 0, &parameters),
+            vec![0.0, 0.0, 1.0]
+        );
+    }
+
+    #[test]
+    fn test_coclustering_probabilities() {
+        let data = [1.0, 1.0, 3.0, 1.0, 1.0, 1.0, 3.0, 1.0, 1.0];
+        let similarity = SquareMatrixBorrower::from_slice(&data, 3);
+        let permutation = Permutation::from_vector(vec![1, 2, 0]).unwrap();
+        let parameters = EpaParameters::new(similarity, permutation, 2.0).unwrap();
+        let labels = [0, 0, 1];
+        let mut pairs = Vec::new();
+        coclustering_probabilities(&labels, &parameters, |i, j, p| pairs.push((i, j, p)));
+        let p = allocation_probabilities(&labels, 0, &parameters);
+        let expected = [(2, 1, 1.0 / 3.0), (0, 1, p[0]), (0, 2, p[1])];
+        assert_eq!(pairs.len(), expected.len());
+        for ((i, j, p), (k, l, q)) in pairs.iter().zip(expected) {
+            assert_eq!((*i, *j), (k, l));
+            assert!((p - q).abs() < 1e-12);
+        }
+        // The average over draws estimates the probability of clustering together.
+        let similarity = SquareMatrix::ones(3);
+        let mut parameters =
+            EpaParameters::new(similarity.view(), Permutation::natural(3), 1.0).unwrap();
+        let mut rng = rand::rngs::StdRng::seed_from_u64(1);
+        let n_samples = 1000;
+        let mut sum = 0.0;
+        for _ in 0..n_samples {
+            parameters.shuffle_permutation(&mut rng);
+            let labels = sample(&parameters, &mut rng).allocation().to_vec();
+            coclustering_probabilities(&labels, &parameters, |i, j, p| {
+                if i.min(j) == 0 && i.max(j) == 1 {
+                    sum += p;
+                }
             });
-        let subset_index = Clustering::select(labels_and_weights, false, 0, Some(rng), false).0;
-        clustering.allocate(ii, subset_index);
+        }
+        // Under the Chinese restaurant process, two items are clustered together with
+        // probability 1 / (1 + mass).
+        assert!((sum / n_samples as f64 - 0.5).abs() < 0.03);
+    }
+
+    #[test]
+    fn test_multiplicities() {
+        let data = [1.0, 1.0, 3.0, 1.0, 1.0, 1.0, 3.0, 1.0, 1.0];
+        let similarity = SquareMatrixBorrower::from_slice(&data, 3);
+        let permutation = Permutation::from_vector(vec![1, 2, 0]).unwrap();
+        let parameters = EpaParameters::new(similarity, permutation, 2.0).unwrap();
+        assert!(parameters
+            .clone()
+            .with_multiplicities(Some(Arc::from([1, 0, 1])))
+            .is_none());
+        let ones = parameters
+            .clone()
+            .with_multiplicities(Some(Arc::from([1, 1, 1])))
+            .unwrap();
+        let p = allocation_probabilities(&[0, 0, 1], 0, &parameters);
+        assert_eq!(allocation_probabilities(&[0, 0, 1], 0, &ones), p);
+        let labels = [0, 1, 0];
+        let expected = log_probability(&labels, &parameters);
+        assert!((log_probability(&labels, &ones) - expected).abs() < 1e-12);
+        // Item 1 counts as three items, each with similarity 1 to item 0.
+        let weighted = parameters
+            .with_multiplicities(Some(Arc::from([1, 3, 1])))
+            .unwrap();
+        let p = allocation_probabilities(&[0, 0, 1], 0, &weighted);
+        assert!((p[0] - 0.5 * 4.0 / 6.0).abs() < 1e-12);
+        assert!((p[1] - 0.5 * 4.0 / 6.0).abs() < 1e-12);
+        assert!((p[2] - 2.0 / 6.0).abs() < 1e-12);
+        let mut rng = rand::rngs::StdRng::seed_from_u64(7);
+        assert_eq!(sample(&weighted, &mut rng).n_items(), 3);
+    }
+
+    #[test]
+    fn test_noise() {
+        let n_items = 6;
+        let mut data = vec![1.0; n_items * n_items];
+        for i in 0..n_items {
+            data[n_items * i + 5] = 0.01;
+            data[n_items * 5 + i] = 0.01;
+        }
+        let similarity = SquareMatrixBorrower::from_slice(&data, n_items);
+        assert!(Noise::new(&similarity, 1.0, 0.0).is_none());
+        let noise = Noise::new(&similarity, 1.0, 1e6).unwrap();
+        assert_eq!(noise.eligible(), &[false, false, false, false, false, true]);
+        assert_eq!(
+            noise.relabel(&[2, 2, 0, 0, 1, 3]),
+            vec![Some(0), Some(0), Some(1), Some(1), Some(2), None]
+        );
+        let parameters = EpaParameters::new(similarity, Permutation::natural(n_items), 1.0)
+            .unwrap()
+            .with_noise(Some(Arc::new(noise)))
+            .unwrap();
+        let constraints = Constraints::new(n_items, &[(0, 1)], &[]).unwrap();
+        assert!(parameters
+            .clone()
+            .with_constraints(Some(Arc::new(constraints)))
+            .is_none());
+        let mut rng = rand::rngs::StdRng::seed_from_u64(9);
+        for _ in 0..20 {
+            // With a huge weight, the last item is always noise and so a singleton.
+            let clustering = sample(&parameters, &mut rng);
+            assert_eq!(clustering.items_of(clustering.get(5)), vec![5]);
+        }
+    }
+
+    #[test]
+    fn test_attraction() {
+        let mut data = vec![1.0; 16];
+        data[3] = 100.0;
+        data[12] = 100.0;
+        let similarity = SquareMatrixBorrower::from_slice(&data, 4);
+        let probabilities = |attraction| {
+            let permutation = Permutation::from_vector(vec![1, 2, 3, 0]).unwrap();
+            let parameters = EpaParameters::new(similarity, permutation, 1.0)
+                .unwrap()
+                .with_attraction(attraction);
+            allocation_probabilities(&[0, 0, 0, 1], 0, &parameters)
+        };
+        let p = probabilities(Attraction::Raw);
+        assert!((p[1] - 0.75 * 100.0 / 102.0).abs() < 1e-12);
+        let p = probabilities(Attraction::Rank);
+        assert!((p[0] - 0.375).abs() < 1e-12 && (p[1] - 0.375).abs() < 1e-12);
+        let p = probabilities(Attraction::Softmax);
+        assert!((p[1] - 0.75).abs() < 1e-12 && (p[2] - 0.25).abs() < 1e-12);
+    }
+
+    #[test]
+    fn test_zero_similarity_between_blocks() {
+        // Two blocks of three items with similarity one within and zero between.
+        let n_items = 6;
+        let data: Vec<_> = (0..n_items * n_items)
+            .map(|x| f64::from((x % n_items) / 3 == (x / n_items) / 3))
+            .collect();
+        let similarity = SquareMatrixBorrower::from_slice(&data, n_items);
+        let mut parameters =
+            EpaParameters::new(similarity, Permutation::natural(n_items), 1.0).unwrap();
+        for seed in 0..50 {
+            let mut rng = rand::rngs::StdRng::seed_from_u64(seed);
+            parameters.shuffle_permutation(&mut rng);
+            let labels = sample(&parameters, &mut rng).allocation().to_vec();
+            assert!(log_probability(&labels, &parameters).is_finite());
+        }
+        // The first item of the second block, preceded only by the first block, joins its clusters
+        // in proportion to their sizes or starts a new cluster.
+        let permutation = Permutation::from_vector(vec![0, 1, 2, 3, 4, 5]).unwrap();
+        let parameters = EpaParameters::new(similarity, permutation, 1.0).unwrap();
+        let p = allocation_probabilities(&[0, 0, 1, 2, 2, 2], 3, &parameters);
+        assert!((p[0] - 0.5).abs() < 1e-12 && (p[1] - 0.25).abs() < 1e-12);
+        assert!(p[2] == 0.0 && (p[3] - 0.25).abs() < 1e-12);
     }
-    clustering
 }
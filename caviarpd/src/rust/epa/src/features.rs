@@ -0,0 +1,737 @@
+// Similarities computed from feature data, such as word counts or gene expression
+
+/// The similarity between the feature vectors of two items.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum FeatureSimilarity {
+    /// The cosine of the angle between the vectors, which is 0 if either vector is zero.
+    Cosine,
+    /// The weighted Jaccard similarity, i.e., the sum of the elementwise minima divided by the sum
+    /// of the elementwise maxima, which is the usual Jaccard similarity for binary features and is
+    /// 0 if both vectors are zero.
+    Jaccard,
+}
+
+/// Nonnegative features of items, stored sparsely both by item and by feature.
+#[derive(Debug, Clone)]
+pub struct SparseFeatures {
+    n_items: usize,
+    // For each item, its nonzero features and their values.
+    by_item: Vec<Vec<(usize, f64)>>,
+    // For each feature, the items for which it is nonzero and their values.
+    by_feature: Vec<Vec<(usize, f64)>>,
+}
+
+impl SparseFeatures {
+    /// Features given by triplets, where `values[k]` is feature `features[k]` of item `items[k]`.
+    ///
+    /// Values of repeated pairs of items and features are summed, and zeros are dropped.
+    pub fn from_triplets(
+        n_items: usize,
+        n_features: usize,
+        items: &[usize],
+        features: &[usize],
+        values: &[f64],
+    ) -> Result<Self, &'static str> {
+        if items.len() != features.len() || items.len() != values.len() {
+            return Err("The items, features, and values must have the same length.");
+        }
+        if values.iter().any(|x| !(x.is_finite() && *x >= 0.0)) {
+            return Err("The values must be finite and nonnegative.");
+        }
+        let mut by_item = vec![Vec::new(); n_items];
+        for ((item, feature), value) in items.iter().zip(features).zip(values) {
+            if *item >= n_items || *feature >= n_features {
+                return Err("An item or feature is out of bounds.");
+            }
+            by_item[*item].push((*feature, *value));
+        }
+        let mut by_feature = vec![Vec::new(); n_features];
+        for (item, x) in by_item.iter_mut().enumerate() {
+            x.sort_unstable_by_key(|y| y.0);
+            x.dedup_by(|y, z| {
+                let same = y.0 == z.0;
+                if same {
+                    z.1 += y.1;
+                }
+                same
+            });
+            x.retain(|y| y.1 > 0.0);
+            for (feature, value) in x.iter() {
+                by_feature[*feature].push((item, *value));
+            }
+        }
+        Ok(Self {
+            n_items,
+            by_item,
+            by_feature,
+        })
+    }
+
+    pub fn n_items(&self) -> usize {
+        self.n_items
+    }
+
+    /// Write the `n_items` by `n_items` similarity (column-major) into `similarity`, dividing the
+    /// columns among `n_threads` threads.
+    ///
+    /// The work is proportional to the number of pairs of items sharing a feature, so a dense
+    /// intermediate is never formed.
+    pub fn similarity_into(
+        &self,
+        kind: FeatureSimilarity,
+        n_threads: usize,
+        similarity: &mut [f64],
+    ) {
+        let n_items = self.n_items;
+        assert_eq!(similarity.len(), n_items * n_items);
+        if n_items == 0 {
+            return;
+        }
+        let totals: Vec<f64> = self
+            .by_item
+            .iter()
+            .map(|x| match kind {
+                FeatureSimilarity::Cosine => x.iter().map(|y| y.1 * y.1).sum::<f64>().sqrt(),
+                FeatureSimilarity::Jaccard => x.iter().map(|y| y.1).sum(),
+            })
+            .collect();
+        fill_columns(similarity, n_items, n_threads, |j, column| {
+            column.fill(0.0);
+            for (feature, x) in &self.by_item[j] {
+                for (i, y) in &self.by_feature[*feature] {
+                    column[*i] += match kind {
+                        FeatureSimilarity::Cosine => x * y,
+                        FeatureSimilarity::Jaccard => x.min(*y),
+                    };
+                }
+            }
+            for (i, value) in column.iter_mut().enumerate() {
+                let denominator = match kind {
+                    FeatureSimilarity::Cosine => totals[i] * totals[j],
+                    FeatureSimilarity::Jaccard => totals[i] + totals[j] - *value,
+                };
+                *value = if denominator > 0.0 {
+                    (*value / denominator).min(1.0)
+                } else {
+                    0.0
+                };
+            }
+        });
+    }
+}
+
+/// Fill the columns of an `n_items` by `n_items` matrix (column-major), dividing them among
+/// `n_threads` threads, where `f(j, column)` fills column `j`.
+pub(crate) fn fill_columns(
+    matrix: &mut [f64],
+    n_items: usize,
+    n_threads: usize,
+    f: impl Fn(usize, &mut [f64]) + Sync,
+) {
+    if n_items == 0 {
+        return;
+    }
+    let columns_per_thread = n_items.div_ceil(n_threads.max(1));
+    let f = &f;
+    std::thread::scope(|s| {
+        for (t, chunk) in matrix.chunks_mut(columns_per_thread * n_items).enumerate() {
+            s.spawn(move || {
+                for (jj, column) in chunk.chunks_mut(n_items).enumerate() {
+                    f(t * columns_per_thread + jj, column);
+                }
+            });
+        }
+    });
+}
+
+/// The correlation between the features of two items.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Correlation {
+    Pearson,
+    /// The Pearson correlation of the ranks (with ties given their average rank).
+    Spearman,
+}
+
+/// The mapping from a correlation `r` in [-1, 1] to a similarity in [0, 1].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum CorrelationTransform {
+    /// `(1 + r) / 2`, so anticorrelated items are the least similar.
+    Linear,
+    /// `|r|`, so anticorrelated items are as similar as correlated ones.
+    Absolute,
+    /// `max(r, 0)`, so anticorrelated and uncorrelated items are equally dissimilar.
+    Positive,
+}
+
+impl CorrelationTransform {
+    fn apply(self, r: f64) -> f64 {
+        match self {
+            Self::Linear => (1.0 + r) / 2.0,
+            Self::Absolute => r.abs(),
+            Self::Positive => r.max(0.0),
+        }
+    }
+}
+
+/// Replace values by their ranks, giving tied values their average rank.
+fn rank_in_place(values: &mut [f64]) {
+    let mut order: Vec<_> = (0..values.len()).collect();
+    order.sort_by(|a, b| values[*a].total_cmp(&values[*b]));
+    let mut ranks = vec![0.0; values.len()];
+    let mut start = 0;
+    while start < order.len() {
+        let value = values[order[start]];
+        let end = start
+            + order[start..]
+                .iter()
+                .take_while(|k| values[**k] == value)
+                .count();
+        for k in &order[start..end] {
+            ranks[*k] = ((start + end + 1) as f64) / 2.0;
+        }
+        start = end;
+    }
+    values.copy_from_slice(&ranks);
+}
+
+/// Write into `similarity` the similarities (column-major) of the `n_items` items whose features
+/// are the rows of `data`, an `n_items` by `n_features` matrix (column-major).
+///
+/// The off-diagonal correlations are multiplied by `1 - shrinkage`, shrinking the correlation
+/// matrix toward the identity, before being mapped to similarities by `transform`.  Items whose
+/// features are constant are uncorrelated with all other items.  The diagonal is the similarity
+/// for a correlation of 1.
+#[allow(clippy::too_many_arguments)]
+pub fn correlation_similarity_into(
+    data: &[f64],
+    n_items: usize,
+    n_features: usize,
+    correlation: Correlation,
+    shrinkage: f64,
+    transform: CorrelationTransform,
+    n_threads: usize,
+    similarity: &mut [f64],
+) -> Result<(), &'static str> {
+    if data.len() != n_items * n_features || similarity.len() != n_items * n_items {
+        return Err("The dimensions of the data and the similarity are inconsistent.");
+    }
+    if data.iter().any(|x| !x.is_finite()) {
+        return Err("The data must be finite.");
+    }
+    if !(0.0..=1.0).contains(&shrinkage) {
+        return Err("The shrinkage must be in [0, 1].");
+    }
+    // Each item's features, centered and scaled to unit length, so correlations are dot products.
+    let mut rows = vec![0.0; n_items * n_features];
+    for (i, row) in rows.chunks_mut(n_features.max(1)).enumerate().take(n_items) {
+        for (k, x) in row.iter_mut().enumerate() {
+            *x = data[n_items * k + i];
+        }
+        if correlation == Correlation::Spearman {
+            rank_in_place(row);
+        }
+        let mean = row.iter().sum::<f64>() / (n_features as f64);
+        row.iter_mut().for_each(|x| *x -= mean);
+        let norm = row.iter().map(|x| x * x).sum::<f64>().sqrt();
+        row.iter_mut()
+            .for_each(|x| *x = if norm > 0.0 { *x / norm } else { 0.0 });
+    }
+    let rows = &rows;
+    fill_columns(similarity, n_items, n_threads, |j, column| {
+        let y = &rows[n_features * j..n_features * (j + 1)];
+        for (i, value) in column.iter_mut().enumerate() {
+            let r = if i == j {
+                1.0
+            } else {
+                let x = &rows[n_features * i..n_features * (i + 1)];
+                let r: f64 = x.iter().zip(y).map(|(a, b)| a * b).sum();
+                (1.0 - shrinkage) * r.clamp(-1.0, 1.0)
+            };
+            *value = transform.apply(r);
+        }
+    });
+    Ok(())
+}
+
+/// The type of a column of mixed-type data for [`gower_similarity_into`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum GowerColumn {
+    /// Values whose similarity is one less their absolute difference divided by the range of the
+    /// column, or 1 if the column is constant.
+    Numeric,
+    /// Asymmetric binary values (0 or 1), whose similarity is 1 when both are 1 and 0 when only
+    /// one is, and which are not compared when both are 0.
+    Binary,
+    /// Categories (coded as numbers), whose similarity is 1 when they are equal and 0 otherwise.
+    Categorical,
+}
+
+/// Write into `similarity` the Gower similarities (column-major) of the `n_items` items whose
+/// features are the rows of `data`, an `n_items` by `columns.len()` matrix (column-major) with
+/// missing values as `NaN`.
+///
+/// The similarity of two items is the weighted mean, over the columns in which they can be
+/// compared, of their similarity in each column as given by its [`GowerColumn`] type.  A column
+/// is not compared when either value is missing (or for both values 0 in a binary column).  Items
+/// with no columns in which they can be compared have similarity 0, and the diagonal is 1.
+pub fn gower_similarity_into(
+    data: &[f64],
+    n_items: usize,
+    columns: &[GowerColumn],
+    weights: &[f64],
+    n_threads: usize,
+    similarity: &mut [f64],
+) -> Result<(), &'static str> {
+    let n_columns = columns.len();
+    if data.len() != n_items * n_columns || similarity.len() != n_items * n_items {
+        return Err("The dimensions of the data and the similarity are inconsistent.");
+    }
+    if weights.len() != n_columns || weights.iter().any(|w| !(w.is_finite() && *w >= 0.0)) {
+        return Err("There must be a finite, nonnegative weight for each column.");
+    }
+    if data.iter().any(|x| x.is_infinite()) {
+        return Err("The data may not contain infinite values.");
+    }
+    for (column, kind) in data.chunks(n_items.max(1)).zip(columns) {
+        let binary = |x: &f64| x.is_nan() || *x == 0.0 || *x == 1.0;
+        if *kind == GowerColumn::Binary && !column.iter().all(binary) {
+            return Err("Binary columns must contain only 0, 1, or missing values.");
+        }
+    }
+    // The range of each numeric column over its values which are not missing.
+    let ranges: Vec<_> = data
+        .chunks(n_items.max(1))
+        .zip(columns)
+        .map(|(column, kind)| {
+            if *kind != GowerColumn::Numeric {
+                return 0.0;
+            }
+            let values = column.iter().filter(|x| !x.is_nan());
+            let (min, max) = values.fold((f64::INFINITY, f64::NEG_INFINITY), |(a, b), x| {
+                (a.min(*x), b.max(*x))
+            });
+            if max > min {
+                max - min
+            } else {
+                0.0
+            }
+        })
+        .collect();
+    let ranges = &ranges;
+    fill_columns(similarity, n_items, n_threads, |j, column| {
+        for (i, value) in column.iter_mut().enumerate() {
+            if i == j {
+                *value = 1.0;
+                continue;
+            }
+            let (mut sum, mut total) = (0.0, 0.0);
+            for (k, kind) in columns.iter().enumerate() {
+                let (x, y) = (data[n_items * k + i], data[n_items * k + j]);
+                if x.is_nan() || y.is_nan() || weights[k] == 0.0 {
+                    continue;
+                }
+                let s = match kind {
+                    GowerColumn::Numeric if ranges[k] > 0.0 => 1.0 - (x - y).abs() / ranges[k],
+                    GowerColumn::Numeric => 1.0,
+                    GowerColumn::Binary if x == 0.0 && y == 0.0 => continue,
+                    GowerColumn::Binary | GowerColumn::Categorical => f64::from(u8::from(x == y)),
+                };
+                sum += weights[k] * s;
+                total += weights[k];
+            }
+            *value = if total > 0.0 { sum / total } else { 0.0 };
+        }
+    });
+    Ok(())
+}
+
+/// The distance between the feature vectors of two items.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum DataDistance {
+    Euclidean,
+    Manhattan,
+    /// One less the cosine of the angle between the vectors, which is 1 if either vector is zero.
+    Cosine,
+    /// One less the Pearson correlation, which is 1 if either vector is constant.
+    Correlation,
+}
+
+// The number of items in a tile of the distance computation, so that the rows of two tiles of
+// items with a moderate number of features stay in cache while all their pairs are computed.
+const DISTANCE_TILE_SIZE: usize = 64;
+
+/// Write into `distance` the distances (column-major) between the `n_items` items whose features
+/// are the rows of `data`, an `n_items` by `n_features` matrix (column-major) with missing values
+/// as `NaN`.
+///
+/// The features of each item are first made contiguous (and, for the cosine and correlation
+/// distances, centered and scaled as needed), and the pairs are then computed tile by tile, with
+/// the tiles of columns divided among `n_threads` threads.  Only the upper triangle is computed
+/// and it is then mirrored, so the distance is exactly symmetric with a zero diagonal.
+///
+/// When either item of a pair has missing values, the pair's distance uses only the features
+/// observed for both (its overlap), with the Euclidean and Manhattan distances scaled up as if all
+/// the features were observed, as by R's `dist`.  Pairs whose overlap has fewer than
+/// `min_overlap` features (or none) are given the largest distance of the other pairs and are
+/// returned, each as `(i, j)` with `i < j`.
+pub fn data_distance_into(
+    data: &[f64],
+    n_items: usize,
+    n_features: usize,
+    kind: DataDistance,
+    min_overlap: usize,
+    n_threads: usize,
+    distance: &mut [f64],
+) -> Result<Vec<(usize, usize)>, &'static str> {
+    if data.len() != n_items * n_features || distance.len() != n_items * n_items {
+        return Err("The dimensions of the data and the distance are inconsistent.");
+    }
+    if data.iter().any(|x| x.is_infinite()) {
+        return Err("The data may not contain infinite values.");
+    }
+    if n_items == 0 {
+        return Ok(Vec::new());
+    }
+    let min_overlap = min_overlap.max(1);
+    let mut raw = vec![0.0; n_items * n_features];
+    for (i, row) in raw.chunks_mut(n_features.max(1)).enumerate().take(n_items) {
+        for (k, x) in row.iter_mut().enumerate() {
+            *x = data[n_items * k + i];
+        }
+    }
+    let complete: Vec<_> = raw
+        .chunks(n_features.max(1))
+        .take(n_items)
+        .map(|row| row.iter().all(|x| !x.is_nan()))
+        .collect();
+    // Rows without missing values are prepared once, so their pairs need no masking.
+    let mut rows = raw.clone();
+    for (row, complete) in rows.chunks_mut(n_features.max(1)).zip(&complete) {
+        if *complete {
+            normalize_row(row, kind);
+        }
+    }
+    let (raw, rows, complete) = (&raw, &rows, &complete);
+    let pair = |i: usize, j: usize| -> f64 {
+        let (ri, rj) = (n_features * i..n_features * (i + 1), n_features * j..n_features * (j + 1));
+        if complete[i] && complete[j] {
+            if n_features < min_overlap {
+                return f64::NAN;
+            }
+            return row_distance(&rows[ri], &rows[rj], kind, 1.0);
+        }
+        let (x, y) = (&raw[ri], &raw[rj]);
+        let mut overlap_x = Vec::with_capacity(n_features);
+        let mut overlap_y = Vec::with_capacity(n_features);
+        for (a, b) in x.iter().zip(y) {
+            if !a.is_nan() && !b.is_nan() {
+                overlap_x.push(*a);
+                overlap_y.push(*b);
+            }
+        }
+        let m = overlap_x.len();
+        if m < min_overlap {
+            return f64::NAN;
+        }
+        normalize_row(&mut overlap_x, kind);
+        normalize_row(&mut overlap_y, kind);
+        let scale = (n_features as f64) / (m as f64);
+        row_distance(&overlap_x, &overlap_y, kind, scale)
+    };
+    // Tiles of columns are claimed from a shared queue, since later tiles have more pairs in the
+    // upper triangle.
+    let tiles = std::sync::Mutex::new(
+        distance
+            .chunks_mut(DISTANCE_TILE_SIZE * n_items)
+            .enumerate(),
+    );
+    std::thread::scope(|s| {
+        for _ in 0..n_threads.max(1) {
+            s.spawn(|| loop {
+                let next = tiles.lock().unwrap().next();
+                let Some((tile, columns)) = next else {
+                    break;
+                };
+                let first = tile * DISTANCE_TILE_SIZE;
+                let n_columns = columns.len() / n_items;
+                for i0 in (0..first + n_columns).step_by(DISTANCE_TILE_SIZE) {
+                    for jj in 0..n_columns {
+                        let j = first + jj;
+                        let column = &mut columns[n_items * jj..n_items * (jj + 1)];
+                        let end = (i0 + DISTANCE_TILE_SIZE).min(j);
+                        for (i, value) in column.iter_mut().enumerate().take(end).skip(i0) {
+                            *value = pair(i, j);
+                        }
+                    }
+                }
+            });
+        }
+    });
+    let mut insufficient = Vec::new();
+    let mut largest: f64 = 0.0;
+    for j in 0..n_items {
+        for i in 0..j {
+            let d = distance[n_items * j + i];
+            if d.is_nan() {
+                insufficient.push((i, j));
+            } else {
+                largest = largest.max(d);
+            }
+        }
+    }
+    for (i, j) in &insufficient {
+        distance[n_items * j + i] = largest;
+    }
+    for j in 0..n_items {
+        distance[n_items * j + j] = 0.0;
+        for i in 0..j {
+            distance[n_items * i + j] = distance[n_items * j + i];
+        }
+    }
+    Ok(insufficient)
+}
+
+// Center and scale the features of an item as the distance needs.
+fn normalize_row(row: &mut [f64], kind: DataDistance) {
+    if kind == DataDistance::Correlation && !row.is_empty() {
+        let mean = row.iter().sum::<f64>() / (row.len() as f64);
+        row.iter_mut().for_each(|x| *x -= mean);
+    }
+    if matches!(kind, DataDistance::Cosine | DataDistance::Correlation) {
+        let norm = row.iter().map(|x| x * x).sum::<f64>().sqrt();
+        row.iter_mut()
+            .for_each(|x| *x = if norm > 0.0 { *x / norm } else { 0.0 });
+    }
+}
+
+// The distance between two items whose features are prepared by 'normalize_row', with the
+// Euclidean and Manhattan distances scaled up by 'scale', the ratio of the number of features to
+// the number compared.
+fn row_distance(x: &[f64], y: &[f64], kind: DataDistance, scale: f64) -> f64 {
+    match kind {
+        DataDistance::Euclidean => {
+            let sum: f64 = x.iter().zip(y).map(|(a, b)| (a - b) * (a - b)).sum();
+            (scale * sum).sqrt()
+        }
+        DataDistance::Manhattan => scale * x.iter().zip(y).map(|(a, b)| (a - b).abs()).sum::<f64>(),
+        DataDistance::Cosine | DataDistance::Correlation => {
+            let r: f64 = x.iter().zip(y).map(|(a, b)| a * b).sum();
+            1.0 - r.clamp(-1.0, 1.0)
+        }
+    }
+}
+
+/// Convert a distance (column-major) to a similarity in place, as the R function
+/// `similarityFromDistance` does.
+///
+/// The distances are divided by their median (including the zero diagonal) and then mapped to
+/// `exp(-temperature * d)` or, if `reciprocal`, to `1 / (d + 0.01)^temperature`, where the offset
+/// is only added when some distance is zero.
+pub fn similarity_from_distance_in_place(
+    values: &mut [f64],
+    temperature: f64,
+    reciprocal: bool,
+) -> Result<(), &'static str> {
+    if values.is_empty() {
+        return Ok(());
+    }
+    let median = {
+        let mut sorted = values.to_vec();
+        sorted.sort_unstable_by(|x, y| x.total_cmp(y));
+        let m = sorted.len();
+        (sorted[(m - 1) / 2] + sorted[m / 2]) / 2.0
+    };
+    if median.is_nan() || median <= 0.0 {
+        return Err("The median distance must be greater than 0.");
+    }
+    let offset = if values.contains(&0.0) { 0.01 } else { 0.0 };
+    for x in values.iter_mut() {
+        let d = *x / median;
+        *x = if reciprocal {
+            1.0 / (d + offset).powf(temperature)
+        } else {
+            (-temperature * d).exp()
+        };
+    }
+    if values.iter().any(|x| !x.is_finite()) {
+        return Err("The distance and temperature yield similarity with nonfinite values.");
+    }
+    Ok(())
+}
+
+/// Sparsify a symmetric similarity (column-major) in place by keeping only the similarities to
+/// each item's `k` nearest neighbors, i.e., the `k` other items most similar to it.
+///
+/// The result stays symmetric: a similarity is kept if either item is among the nearest neighbors
+/// of the other.  The diagonal is kept, and ties are broken by the index of the item.
+pub fn sparsify_nearest_neighbors(similarity: &mut [f64], n_items: usize, k: usize) {
+    if k + 1 >= n_items {
+        return;
+    }
+    let mut keep = vec![false; n_items * n_items];
+    let mut others: Vec<usize> = Vec::with_capacity(n_items);
+    for j in 0..n_items {
+        let column = &similarity[n_items * j..n_items * (j + 1)];
+        others.clear();
+        others.extend((0..n_items).filter(|i| *i != j));
+        if k > 0 {
+            others.select_nth_unstable_by(k - 1, |a, b| {
+                column[*b].total_cmp(&column[*a]).then(a.cmp(b))
+            });
+        }
+        keep[n_items * j + j] = true;
+        for i in &others[..k] {
+            keep[n_items * j + i] = true;
+            keep[n_items * i + j] = true;
+        }
+    }
+    for (value, keep) in similarity.iter_mut().zip(keep) {
+        if !keep {
+            *value = 0.0;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_feature_similarity() {
+        // Items are rows: [1, 0, 2], [0, 3, 0], [2, 0, 1], and [0, 0, 0].
+        let features = SparseFeatures::from_triplets(
+            4,
+            3,
+            &[0, 0, 1, 2, 2, 2],
+            &[0, 2, 1, 0, 2, 2],
+            &[1.0, 2.0, 3.0, 2.0, 0.5, 0.5],
+        )
+        .unwrap();
+        assert!(SparseFeatures::from_triplets(1, 1, &[0], &[1], &[1.0]).is_err());
+        let mut x = vec![f64::NAN; 16];
+        features.similarity_into(FeatureSimilarity::Cosine, 3, &mut x);
+        assert!((x[0] - 1.0).abs() < 1e-12 && (x[2] - 0.8).abs() < 1e-12);
+        assert_eq!((x[1], x[3], x[15], x[8]), (0.0, 0.0, 0.0, x[2]));
+        features.similarity_into(FeatureSimilarity::Jaccard, 1, &mut x);
+        assert!((x[2] - 0.5).abs() < 1e-12 && x[5] == 1.0 && x[7] == 0.0);
+        sparsify_nearest_neighbors(&mut x, 4, 1);
+        assert_eq!(x[2], 0.5);
+        assert_eq!(x[1], x[4]);
+        assert!(x.iter().all(|y| !y.is_nan()));
+    }
+
+    #[test]
+    fn test_gower_similarity() {
+        // Items are rows: [1, 1, 0], [3, 0, 1], [5, 0, 0], and [NaN, 1, 0].
+        #[rustfmt::skip]
+        let data = [
+            1.0, 3.0, 5.0, f64::NAN,
+            1.0, 0.0, 0.0, 1.0,
+            0.0, 1.0, 0.0, 0.0,
+        ];
+        let columns = [GowerColumn::Numeric, GowerColumn::Binary, GowerColumn::Categorical];
+        let mut x = vec![f64::NAN; 16];
+        gower_similarity_into(&data, 4, &columns, &[1.0, 1.0, 2.0], 2, &mut x).unwrap();
+        assert_eq!((x[0], x[5], x[10], x[15]), (1.0, 1.0, 1.0, 1.0));
+        // Items 0 and 1: numeric 1 - 2 / 4, binary 0, and categorical 0 with weight 2.
+        assert!((x[4] - 0.5 / 4.0).abs() < 1e-12);
+        // Items 1 and 2: numeric 1 - 2 / 4, binary not compared, and categorical 0 with weight 2.
+        assert!((x[9] - 0.5 / 3.0).abs() < 1e-12);
+        // Items 0 and 3: numeric missing, binary 1, and categorical 1.
+        assert_eq!(x[12], 1.0);
+        for j in 0..4 {
+            for i in 0..4 {
+                assert_eq!(x[4 * j + i], x[4 * i + j]);
+            }
+        }
+        assert!(gower_similarity_into(&data, 4, &columns, &[1.0, -1.0, 1.0], 1, &mut x).is_err());
+        let columns = [GowerColumn::Numeric, GowerColumn::Binary, GowerColumn::Binary];
+        let mut data = data;
+        data[9] = 2.0;
+        assert!(gower_similarity_into(&data, 4, &columns, &[1.0; 3], 1, &mut x).is_err());
+    }
+
+    #[test]
+    fn test_data_distance() {
+        // Items are rows: [0, 0], [3, 4], [1, 1], and [-2, -2], with more items than a tile.
+        let n_items = 2 * DISTANCE_TILE_SIZE + 3;
+        let points = [[0.0, 0.0], [3.0, 4.0], [1.0, 1.0], [-2.0, -2.0]];
+        let mut data = vec![0.0; 2 * n_items];
+        for i in 0..n_items {
+            data[i] = points[i % 4][0];
+            data[n_items + i] = points[i % 4][1];
+        }
+        let mut x = vec![f64::NAN; n_items * n_items];
+        let mut y = x.clone();
+        data_distance_into(&data, n_items, 2, DataDistance::Euclidean, 1, 3, &mut x).unwrap();
+        data_distance_into(&data, n_items, 2, DataDistance::Euclidean, 1, 1, &mut y).unwrap();
+        assert_eq!(x, y);
+        for j in 0..n_items {
+            for i in 0..n_items {
+                let (a, b) = (points[i % 4], points[j % 4]);
+                let expected = ((a[0] - b[0]).powi(2) + (a[1] - b[1]).powi(2)).sqrt();
+                assert!((x[n_items * j + i] - expected).abs() < 1e-12);
+            }
+        }
+        data_distance_into(&data, n_items, 2, DataDistance::Manhattan, 1, 2, &mut x).unwrap();
+        assert_eq!((x[1], x[n_items + 2], x[3 * n_items + 2]), (7.0, 5.0, 6.0));
+        data_distance_into(&data, n_items, 2, DataDistance::Cosine, 1, 2, &mut x).unwrap();
+        assert!((x[n_items * 3 + 2] - 2.0).abs() < 1e-12);
+        assert_eq!(x[1], 1.0);
+        data_distance_into(&data, n_items, 2, DataDistance::Correlation, 1, 2, &mut x).unwrap();
+        assert_eq!(x[n_items * 3 + 2], 1.0);
+        assert!(data_distance_into(&data, n_items, 3, DataDistance::Cosine, 1, 2, &mut x).is_err());
+        // Items are rows: [1, NaN, 3], [2, 5, NaN], [NaN, 1, 1], and [4, 4, 4].
+        let nan = f64::NAN;
+        #[rustfmt::skip]
+        let data = [
+            1.0, 2.0, nan, 4.0,
+            nan, 5.0, 1.0, 4.0,
+            3.0, nan, 1.0, 4.0,
+        ];
+        let mut x = vec![f64::NAN; 16];
+        let insufficient =
+            data_distance_into(&data, 4, 3, DataDistance::Euclidean, 1, 2, &mut x).unwrap();
+        assert!(insufficient.is_empty());
+        // Items 0 and 1 overlap in the first feature only, so its squared difference is tripled.
+        assert!((x[4] - 3.0_f64.sqrt()).abs() < 1e-12);
+        assert!((x[12] - (1.5 * (9.0 + 1.0_f64)).sqrt()).abs() < 1e-12);
+        let insufficient =
+            data_distance_into(&data, 4, 3, DataDistance::Manhattan, 2, 2, &mut x).unwrap();
+        assert_eq!(insufficient, vec![(0, 1), (0, 2), (1, 2)]);
+        // Insufficient pairs have the largest distance of the others, i.e., items 2 and 3.
+        assert_eq!((x[4], x[1], x[9], x[14]), (9.0, 9.0, 9.0, 9.0));
+        assert_eq!(x[12], 1.5 * 4.0);
+        let mut z = [0.0, 2.0, 2.0, 0.0];
+        similarity_from_distance_in_place(&mut z, 1.0, false).unwrap();
+        assert_eq!(z, [1.0, (-2.0_f64).exp(), (-2.0_f64).exp(), 1.0]);
+        let mut z = [0.0, 2.0, 2.0, 0.0];
+        similarity_from_distance_in_place(&mut z, 2.0, true).unwrap();
+        assert!((z[1] - 1.0 / 2.01_f64.powi(2)).abs() < 1e-12);
+        assert!(similarity_from_distance_in_place(&mut [0.0; 4], 1.0, false).is_err());
+    }
+
+    #[test]
+    fn test_correlation_similarity() {
+        // Items are rows: [1, 2, 3, 4], [2, 4, 6, 80], [4, 3, 2, 1], and [5, 5, 5, 5].
+        #[rustfmt::skip]
+        let data = [
+            1.0, 2.0, 4.0, 5.0,
+            2.0, 4.0, 3.0, 5.0,
+            3.0, 6.0, 2.0, 5.0,
+            4.0, 80.0, 1.0, 5.0,
+        ];
+        let mut x = vec![f64::NAN; 16];
+        let spearman = Correlation::Spearman;
+        correlation_similarity_into(&data, 4, 4, spearman, 0.0, CorrelationTransform::Linear, 2, &mut x)
+            .unwrap();
+        assert_eq!((x[0], x[1], x[2], x[3], x[15]), (1.0, 1.0, 0.0, 0.5, 1.0));
+        correlation_similarity_into(&data, 4, 4, spearman, 0.5, CorrelationTransform::Absolute, 3, &mut x)
+            .unwrap();
+        assert_eq!((x[1], x[2], x[3]), (0.5, 0.5, 0.0));
+        let pearson = Correlation::Pearson;
+        correlation_similarity_into(&data, 4, 4, pearson, 0.0, CorrelationTransform::Positive, 1, &mut x)
+            .unwrap();
+        assert!(x[1] < 1.0 && x[1] > 0.5 && x[2] == 0.0 && x[8] == 0.0);
+    }
+}
@@ -0,0 +1,134 @@
+// Estimates of the EPA parameters from observed partitions
+
+use crate::epa::{EpaParameters, Similarity};
+use crate::map::log_probability_averaged;
+use crate::perm::Permutation;
+use rand::Rng;
+
+/// The result of [`fit_parameters`].
+#[derive(Debug, Clone)]
+pub struct EpaFit {
+    pub mass: f64,
+    pub discount: f64,
+    /// The estimated log likelihood of the observed partitions at the fitted parameters.
+    pub log_likelihood: f64,
+}
+
+// The search ranges for the log of the mass and for the discount.
+const LOG_MASS_RANGE: (f64, f64) = (-9.0, 9.0);
+const DISCOUNT_RANGE: (f64, f64) = (0.0, 0.99);
+
+// The number of rounds of alternating searches for the mass and the discount.
+const N_ROUNDS: usize = 5;
+
+/// Estimate the mass (and, if `fit_discount`, the discount) of the EPA distribution by maximizing
+/// the likelihood of the observed `partitions`, which are independent given the parameters.
+///
+/// The likelihood of a partition is its EPA probability averaged over `n_permutations` random
+/// permutations, as in [`log_probability_averaged`].  The permutations are drawn once, so the
+/// estimated likelihood is a smooth function of the parameters, which is maximized by golden
+/// section searches over the log of the mass in `[-9, 9]` and over the discount in `[0, 0.99]`,
+/// alternating between the two when the discount is fitted.  Without `fit_discount`, the
+/// discount is zero.
+///
+/// # Panics
+///
+/// Panics if a partition does not have a label for each item.
+pub fn fit_parameters<M: Similarity, T: Rng>(
+    similarity: M,
+    partitions: &[Vec<usize>],
+    fit_discount: bool,
+    n_permutations: usize,
+    rng: &mut T,
+) -> EpaFit {
+    let n_items = similarity.n_items();
+    for partition in partitions {
+        assert_eq!(partition.len(), n_items);
+    }
+    let permutations: Vec<_> = (0..n_permutations.max(1))
+        .map(|_| Permutation::random(n_items, rng))
+        .collect();
+    let log_likelihood = |mass: f64, discount: f64| {
+        let parameters: Vec<_> = permutations
+            .iter()
+            .map(|permutation| {
+                EpaParameters::new(similarity, permutation.clone(), mass)
+                    .and_then(|x| x.with_discount(discount))
+                    .unwrap()
+            })
+            .collect();
+        partitions
+            .iter()
+            .map(|labels| log_probability_averaged(labels, &parameters))
+            .sum::<f64>()
+    };
+    let mut discount = 0.0;
+    let mut log_mass = 0.0;
+    for _ in 0..(if fit_discount { N_ROUNDS } else { 1 }) {
+        log_mass = golden_section(|x| log_likelihood(x.exp(), discount), LOG_MASS_RANGE);
+        if fit_discount {
+            let mass = log_mass.exp();
+            discount = golden_section(|x| log_likelihood(mass, x), DISCOUNT_RANGE);
+        }
+    }
+    let mass = log_mass.exp();
+    EpaFit {
+        mass,
+        discount,
+        log_likelihood: log_likelihood(mass, discount),
+    }
+}
+
+// The maximizer of a unimodal 'f' over the 'range', to a tolerance of 1e-6, or an end of the range
+// if it is better, so that a maximum on the boundary is found exactly.
+fn golden_section(f: impl Fn(f64) -> f64, range: (f64, f64)) -> f64 {
+    let ratio = (5.0_f64.sqrt() - 1.0) / 2.0;
+    let (mut a, mut b) = range;
+    let mut c = b - ratio * (b - a);
+    let mut d = a + ratio * (b - a);
+    let (mut fc, mut fd) = (f(c), f(d));
+    while b - a > 1e-6 {
+        if fc > fd {
+            (b, d, fd) = (d, c, fc);
+            c = b - ratio * (b - a);
+            fc = f(c);
+        } else {
+            (a, c, fc) = (c, d, fd);
+            d = a + ratio * (b - a);
+            fd = f(d);
+        }
+    }
+    [(a + b) / 2.0, range.0, range.1]
+        .into_iter()
+        .map(|x| (x, f(x)))
+        .fold((f64::NAN, f64::NEG_INFINITY), |best, x| if x.1 > best.1 { x } else { best })
+        .0
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::epa::{sample, SquareMatrix};
+    use rand::SeedableRng;
+
+    #[test]
+    fn test_fit_parameters() {
+        // With constant similarity, the EPA distribution is the Chinese restaurant process, whose
+        // likelihood does not depend on the permutation.
+        let similarity = SquareMatrix::ones(50);
+        let mut rng = rand::rngs::StdRng::seed_from_u64(2);
+        let mut parameters =
+            EpaParameters::new(similarity.view(), Permutation::natural(50), 3.0).unwrap();
+        let partitions: Vec<_> = (0..40)
+            .map(|_| {
+                parameters.shuffle_permutation(&mut rng);
+                sample(&parameters, &mut rng).allocation().to_vec()
+            })
+            .collect();
+        let fit = fit_parameters(similarity.view(), &partitions, false, 1, &mut rng);
+        assert_eq!(fit.discount, 0.0);
+        assert!((fit.mass - 3.0).abs() < 0.75);
+        let fit_discount = fit_parameters(similarity.view(), &partitions, true, 1, &mut rng);
+        assert!(fit_discount.log_likelihood >= fit.log_likelihood - 1e-9);
+    }
+}
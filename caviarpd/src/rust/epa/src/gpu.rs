@@ -0,0 +1,426 @@
+// Sampling from the EPA distribution on a GPU
+
+use crate::api::{next_permutation, sampler_parameters, PerturbationBuffer, SamplerOptions};
+use crate::clust::FromUsize;
+use crate::epa::{Attraction, Similarity};
+use rand::Rng;
+use wgpu::util::DeviceExt;
+
+// One workgroup makes one draw.  Its threads share the sums of the similarities between the item
+// being allocated and the members of each cluster, one cluster at a time, and the first thread
+// selects the cluster with a uniform from the host.  Clusters are labeled in order of creation.
+const SHADER: &str = r#"
+struct Params {
+    n_items: u32,
+    n_draws: u32,
+    mass: f32,
+    discount: f32,
+}
+
+@group(0) @binding(0) var<uniform> params: Params;
+@group(0) @binding(1) var<storage, read> similarity: array<f32>;
+@group(0) @binding(2) var<storage, read> permutations: array<u32>;
+@group(0) @binding(3) var<storage, read> uniforms: array<f32>;
+@group(0) @binding(4) var<storage, read_write> labels: array<u32>;
+@group(0) @binding(5) var<storage, read_write> sums: array<f32>;
+@group(0) @binding(6) var<storage, read_write> n_clusters: array<u32>;
+
+const WORKGROUP_SIZE: u32 = 64u;
+
+var<workgroup> partial_sums: array<f32, 64>;
+var<workgroup> partial_sizes: array<f32, 64>;
+var<workgroup> shared_k: u32;
+
+@compute @workgroup_size(64)
+fn main(@builtin(workgroup_id) group: vec3<u32>, @builtin(local_invocation_index) t: u32) {
+    let d = group.x;
+    let n = params.n_items;
+    let base = d * n;
+    if (t == 0u) {
+        labels[base + permutations[base]] = 0u;
+        shared_k = 1u;
+    }
+    storageBarrier();
+    workgroupBarrier();
+    for (var i = 1u; i < n; i++) {
+        let row = permutations[base + i] * n;
+        let k = workgroupUniformLoad(&shared_k);
+        // The sums and sizes of the clusters among the preceding items.
+        for (var c = 0u; c < k; c++) {
+            var sum = 0.0;
+            var size = 0.0;
+            for (var p = t; p < i; p += WORKGROUP_SIZE) {
+                let jj = permutations[base + p];
+                if (labels[base + jj] == c) {
+                    sum += similarity[row + jj];
+                    size += 1.0;
+                }
+            }
+            partial_sums[t] = sum;
+            partial_sizes[t] = size;
+            workgroupBarrier();
+            for (var stride = WORKGROUP_SIZE / 2u; stride > 0u; stride /= 2u) {
+                if (t < stride) {
+                    partial_sums[t] += partial_sums[t + stride];
+                    partial_sizes[t] += partial_sizes[t + stride];
+                }
+                workgroupBarrier();
+            }
+            if (t == 0u) {
+                sums[2u * base + 2u * c] = partial_sums[0];
+                sums[2u * base + 2u * c + 1u] = partial_sizes[0];
+            }
+            workgroupBarrier();
+        }
+        if (t == 0u) {
+            var total = 0.0;
+            for (var c = 0u; c < k; c++) {
+                total += sums[2u * base + 2u * c];
+            }
+            // Zero similarity to all the preceding items weights the clusters by their sizes.
+            let by_size = total == 0.0;
+            if (by_size) {
+                total = f32(i);
+            }
+            let kt = (f32(i) - params.discount * f32(k)) / total;
+            let new_weight = params.mass + params.discount * f32(k);
+            var total_weight = new_weight;
+            for (var c = 0u; c < k; c++) {
+                total_weight += kt * sums[2u * base + 2u * c + select(0u, 1u, by_size)];
+            }
+            let chosen = uniforms[base + i] * total_weight;
+            var label = k;
+            var cumulative = 0.0;
+            for (var c = 0u; c < k; c++) {
+                cumulative += kt * sums[2u * base + 2u * c + select(0u, 1u, by_size)];
+                if (chosen < cumulative) {
+                    label = c;
+                    break;
+                }
+            }
+            labels[base + permutations[base + i]] = label;
+            if (label == k) {
+                shared_k = k + 1u;
+            }
+        }
+        storageBarrier();
+        workgroupBarrier();
+    }
+    if (t == 0u) {
+        n_clusters[d] = shared_k;
+    }
+}
+"#;
+
+/// Like [`crate::api::sample_epa`], but computing the draws on a GPU, which is worthwhile for
+/// thousands of items and many draws.
+///
+/// Each draw is made by a workgroup of the GPU, which sums the similarities to the clusters in
+/// parallel, with the draws made concurrently.  The permutations and the uniforms which select the
+/// clusters come from `rng` on the host, so the draws are reproducible for a GPU, but they are not
+/// those of [`crate::api::sample_epa`], and the similarities are summed in single precision.
+///
+/// Only the options for uniform or heuristic permutations are supported.  An error is returned for
+/// other options, or if no GPU is available.
+///
+/// # Panics
+///
+/// Panics if the mass and discount are not valid for [`crate::epa::EpaParameters::with_discount`].
+pub fn sample_epa<L, M, T>(
+    n_samples: usize,
+    similarity: M,
+    mass: f64,
+    discount: f64,
+    options: &SamplerOptions,
+    rng: &mut T,
+) -> Result<(Vec<L>, Vec<L>), String>
+where
+    L: FromUsize + Copy,
+    M: Similarity,
+    T: Rng,
+{
+    if options.constraints.is_some()
+        || options.anchors.is_some()
+        || options.multiplicities.is_some()
+        || options.noise.is_some()
+        || options.subset_size.is_some()
+        || options.attraction != Attraction::Raw
+        || options.strict
+        || options.compensated
+    {
+        return Err("The GPU backend does not support constraints, anchors, multiplicities, noise, a subset size, attractions other than raw, or strict or compensated sums.".to_string());
+    }
+    let n_items = similarity.n_items();
+    let n_samples = n_samples.max(1);
+    if n_items == 0 {
+        return Ok((Vec::new(), vec![L::from_usize(0); n_samples]));
+    }
+    let mut params = sampler_parameters(similarity, mass, discount, false, options);
+    let order = options.heuristic_order(similarity);
+    let mut buffer = PerturbationBuffer::default();
+    let n_words = u32::try_from(n_items * n_items)
+        .map_err(|_| format!("The GPU backend supports at most {} items.", 1 << 16))?;
+    let device = Gpu::new(n_words)?;
+    // Each batch of draws fits in the largest buffer of the device.
+    let max_words = (device.max_binding_size / 8) as usize;
+    let batch_size = (max_words / n_items)
+        .min(device.max_workgroups as usize)
+        .min(n_samples);
+    if batch_size == 0 {
+        return Err("The GPU cannot hold the draws for this many items.".to_string());
+    }
+    let data: Vec<f32> = (0..n_items)
+        .flat_map(|i| (0..n_items).map(move |j| similarity.get(i, j) as f32))
+        .collect();
+    let similarity_buffer = device.storage(&data, "similarity");
+    let mut samples = Vec::with_capacity(n_samples * n_items);
+    let mut n_clusters = Vec::with_capacity(n_samples);
+    let mut canonical_labels = Vec::new();
+    let mut n_done = 0;
+    while n_done < n_samples {
+        let n_draws = batch_size.min(n_samples - n_done);
+        let mut permutations = Vec::with_capacity(n_draws * n_items);
+        let mut uniforms = Vec::with_capacity(n_draws * n_items);
+        for _ in 0..n_draws {
+            let fixed = options.fixed_permutation;
+            next_permutation(&mut params, order.as_deref(), fixed, &mut buffer, rng);
+            let permutation = params.permutation();
+            permutations.extend((0..n_items).map(|i| permutation.get(i) as u32));
+            uniforms.extend((0..n_items).map(|_| rng.random::<f32>()));
+        }
+        let (labels, counts) = device.draws(
+            &similarity_buffer,
+            &permutations,
+            &uniforms,
+            n_items,
+            n_draws,
+            mass as f32,
+            discount as f32,
+        )?;
+        // Relabel in order of first appearance, as the draws on the CPU are.
+        for (draw, k) in labels.chunks(n_items).zip(counts) {
+            canonical_labels.clear();
+            canonical_labels.resize(k as usize, usize::MAX);
+            let mut next_label = 0;
+            for label in draw {
+                let canonical_label = &mut canonical_labels[*label as usize];
+                if *canonical_label == usize::MAX {
+                    *canonical_label = next_label;
+                    next_label += 1;
+                }
+                samples.push(L::from_usize(*canonical_label));
+            }
+            n_clusters.push(L::from_usize(k as usize));
+        }
+        n_done += n_draws;
+    }
+    Ok((samples, n_clusters))
+}
+
+// A device with the sampling pipeline.
+struct Gpu {
+    device: wgpu::Device,
+    queue: wgpu::Queue,
+    pipeline: wgpu::ComputePipeline,
+    max_binding_size: u32,
+    max_workgroups: u32,
+}
+
+impl Gpu {
+    fn new(n_words: u32) -> Result<Self, String> {
+        let instance = wgpu::Instance::default();
+        let adapter = pollster::block_on(instance.request_adapter(&wgpu::RequestAdapterOptions {
+            power_preference: wgpu::PowerPreference::HighPerformance,
+            ..Default::default()
+        }))
+        .map_err(|e| format!("No GPU is available ({}).", e))?;
+        let limits = adapter.limits();
+        if u64::from(n_words) * 4 > u64::from(limits.max_storage_buffer_binding_size) {
+            return Err("The similarities are too large for the GPU.".to_string());
+        }
+        let (device, queue) = pollster::block_on(adapter.request_device(&wgpu::DeviceDescriptor {
+            label: Some("epa"),
+            required_limits: limits.clone(),
+            ..Default::default()
+        }))
+        .map_err(|e| format!("Could not open the GPU ({}).", e))?;
+        let module = device.create_shader_module(wgpu::ShaderModuleDescriptor {
+            label: Some("epa"),
+            source: wgpu::ShaderSource::Wgsl(SHADER.into()),
+        });
+        let pipeline = device.create_compute_pipeline(&wgpu::ComputePipelineDescriptor {
+            label: Some("epa"),
+            layout: None,
+            module: &module,
+            entry_point: Some("main"),
+            compilation_options: Default::default(),
+            cache: None,
+        });
+        Ok(Self {
+            device,
+            queue,
+            pipeline,
+            max_binding_size: limits.max_storage_buffer_binding_size,
+            max_workgroups: limits.max_compute_workgroups_per_dimension,
+        })
+    }
+
+    fn storage<V: bytemuck::Pod>(&self, contents: &[V], label: &str) -> wgpu::Buffer {
+        self.device
+            .create_buffer_init(&wgpu::util::BufferInitDescriptor {
+                label: Some(label),
+                contents: bytemuck::cast_slice(contents),
+                usage: wgpu::BufferUsages::STORAGE,
+            })
+    }
+
+    fn output(&self, n_words: usize, label: &str) -> wgpu::Buffer {
+        self.device.create_buffer(&wgpu::BufferDescriptor {
+            label: Some(label),
+            size: (4 * n_words) as u64,
+            usage: wgpu::BufferUsages::STORAGE | wgpu::BufferUsages::COPY_SRC,
+            mapped_at_creation: false,
+        })
+    }
+
+    // The labels of the draws (in order of creation of the clusters) and their numbers of
+    // clusters.
+    #[allow(clippy::too_many_arguments)]
+    fn draws(
+        &self,
+        similarity: &wgpu::Buffer,
+        permutations: &[u32],
+        uniforms: &[f32],
+        n_items: usize,
+        n_draws: usize,
+        mass: f32,
+        discount: f32,
+    ) -> Result<(Vec<u32>, Vec<u32>), String> {
+        let mut params = Vec::with_capacity(16);
+        params.extend_from_slice(&(n_items as u32).to_le_bytes());
+        params.extend_from_slice(&(n_draws as u32).to_le_bytes());
+        params.extend_from_slice(&mass.to_le_bytes());
+        params.extend_from_slice(&discount.to_le_bytes());
+        let params = self
+            .device
+            .create_buffer_init(&wgpu::util::BufferInitDescriptor {
+                label: Some("params"),
+                contents: &params,
+                usage: wgpu::BufferUsages::UNIFORM,
+            });
+        let permutations = self.storage(permutations, "permutations");
+        let uniforms = self.storage(uniforms, "uniforms");
+        let labels = self.output(n_draws * n_items, "labels");
+        let sums = self.output(2 * n_draws * n_items, "sums");
+        let n_clusters = self.output(n_draws, "n_clusters");
+        let buffers = [
+            &params,
+            similarity,
+            &permutations,
+            &uniforms,
+            &labels,
+            &sums,
+            &n_clusters,
+        ];
+        let entries: Vec<_> = buffers
+            .iter()
+            .enumerate()
+            .map(|(binding, buffer)| wgpu::BindGroupEntry {
+                binding: binding as u32,
+                resource: buffer.as_entire_binding(),
+            })
+            .collect();
+        let bind_group = self.device.create_bind_group(&wgpu::BindGroupDescriptor {
+            label: Some("epa"),
+            layout: &self.pipeline.get_bind_group_layout(0),
+            entries: &entries,
+        });
+        let read_labels = self.readback(n_draws * n_items);
+        let read_n_clusters = self.readback(n_draws);
+        let mut encoder = self
+            .device
+            .create_command_encoder(&wgpu::CommandEncoderDescriptor { label: Some("epa") });
+        {
+            let mut pass = encoder.begin_compute_pass(&wgpu::ComputePassDescriptor {
+                label: Some("epa"),
+                timestamp_writes: None,
+            });
+            pass.set_pipeline(&self.pipeline);
+            pass.set_bind_group(0, &bind_group, &[]);
+            pass.dispatch_workgroups(n_draws as u32, 1, 1);
+        }
+        encoder.copy_buffer_to_buffer(&labels, 0, &read_labels, 0, read_labels.size());
+        encoder.copy_buffer_to_buffer(&n_clusters, 0, &read_n_clusters, 0, read_n_clusters.size());
+        self.queue.submit([encoder.finish()]);
+        Ok((self.read(&read_labels)?, self.read(&read_n_clusters)?))
+    }
+
+    fn readback(&self, n_words: usize) -> wgpu::Buffer {
+        self.device.create_buffer(&wgpu::BufferDescriptor {
+            label: Some("readback"),
+            size: (4 * n_words) as u64,
+            usage: wgpu::BufferUsages::MAP_READ | wgpu::BufferUsages::COPY_DST,
+            mapped_at_creation: false,
+        })
+    }
+
+    fn read(&self, buffer: &wgpu::Buffer) -> Result<Vec<u32>, String> {
+        let slice = buffer.slice(..);
+        slice.map_async(wgpu::MapMode::Read, |_| {});
+        self.device
+            .poll(wgpu::PollType::wait_indefinitely())
+            .map_err(|e| format!("The GPU failed ({}).", e))?;
+        let result = bytemuck::cast_slice(&slice.get_mapped_range()).to_vec();
+        buffer.unmap();
+        Ok(result)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::epa::SquareMatrixBorrower;
+    use rand::SeedableRng;
+    use rand_pcg::Pcg64Mcg;
+
+    #[test]
+    fn test_sample_epa_gpu() {
+        let truth = [0, 0, 0, 1, 1, 1];
+        let data: Vec<_> = (0..36)
+            .map(|x| {
+                if truth[x % 6] == truth[x / 6] {
+                    10.0
+                } else {
+                    0.1
+                }
+            })
+            .collect();
+        let similarity = SquareMatrixBorrower::from_slice(&data, 6);
+        let options = SamplerOptions::default();
+        let mut rng = Pcg64Mcg::seed_from_u64(5);
+        let Ok((draws, n_clusters)) =
+            sample_epa::<u32, _, _>(2000, similarity, 0.5, 0.1, &options, &mut rng)
+        else {
+            // No GPU is available to test.
+            return;
+        };
+        assert_eq!(draws.len(), 2000 * 6);
+        // Labels are canonical and the numbers of clusters match.
+        for (draw, k) in draws.chunks(6).zip(&n_clusters) {
+            assert_eq!(draw[0], 0);
+            assert_eq!(draw.iter().max().unwrap() + 1, *k);
+        }
+        // The co-clustering probabilities agree with those of the CPU.
+        let (cpu, _) =
+            crate::api::sample_epa::<u32, _, _>(2000, similarity, 0.5, 0.1, 2, options, &mut rng);
+        for (i, j) in [(0, 1), (0, 3), (4, 5)] {
+            let p = |x: &[u32]| x.chunks(6).filter(|x| x[i] == x[j]).count() as f64 / 2000.0;
+            assert!((p(&draws) - p(&cpu)).abs() < 0.05);
+        }
+        let options = SamplerOptions {
+            subset_size: Some(2),
+            ..SamplerOptions::default()
+        };
+        assert!(sample_epa::<u32, _, _>(10, similarity, 0.5, 0.0, &options, &mut rng).is_err());
+    }
+}
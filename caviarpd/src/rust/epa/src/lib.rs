@@ -1,3 +1,21 @@
+pub mod align;
+#[cfg(feature = "api")]
+pub mod api;
 pub mod clust;
+pub mod distance;
 pub mod epa;
+pub mod features;
+pub mod fit;
+#[cfg(feature = "gpu")]
+pub mod gpu;
+pub mod loss;
+pub mod map;
+pub mod ordering;
 pub mod perm;
+pub mod seriation;
+pub mod similarity;
+pub mod subjects;
+pub mod summary;
+pub mod temporal;
+pub mod tiled;
+pub mod views;
@@ -0,0 +1,350 @@
+// Expected loss of a partition estimate computed from a pairwise similarity matrix
+
+use rand::prelude::*;
+
+/// Loss functions whose expectations can be computed from a pairwise similarity matrix.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum PsmLoss {
+    /// Generalized Binder loss, where separating a pair of items that belong together costs `a`
+    /// and joining a pair of items that belong apart costs `2 - a`.
+    Binder(f64),
+    /// The lower bound of the expectation of the variation of information (in base 2).
+    VIlb,
+}
+
+/// A partition estimate along with the quantities needed to update its expected loss
+/// incrementally when items move between clusters.
+#[derive(Debug, Clone)]
+pub struct PsmLossState<'a> {
+    psm: &'a [f64],
+    n_items: usize,
+    loss: PsmLoss,
+    labels: Vec<usize>,
+    sizes: Vec<usize>,
+    within: Vec<f64>,
+}
+
+impl<'a> PsmLossState<'a> {
+    /// The pairwise similarity matrix `psm` is column-major with `n_items` rows and columns,
+    /// and `labels` are any nonnegative integers.
+    pub fn new(psm: &'a [f64], n_items: usize, labels: &[usize], loss: PsmLoss) -> Self {
+        assert_eq!(psm.len(), n_items * n_items);
+        assert_eq!(labels.len(), n_items);
+        let mut sizes = vec![0; labels.iter().max().map_or(0, |x| x + 1)];
+        for label in labels {
+            sizes[*label] += 1;
+        }
+        let within = (0..n_items)
+            .map(|i| {
+                (0..n_items)
+                    .filter(|j| labels[*j] == labels[i])
+                    .map(|j| psm[n_items * j + i])
+                    .sum()
+            })
+            .collect();
+        Self {
+            psm,
+            n_items,
+            loss,
+            labels: labels.to_vec(),
+            sizes,
+            within,
+        }
+    }
+
+    pub fn labels(&self) -> &[usize] {
+        &self.labels
+    }
+
+    pub fn n_items(&self) -> usize {
+        self.n_items
+    }
+
+    /// The labels of the nonempty clusters.
+    pub fn active_labels(&self) -> Vec<usize> {
+        (0..self.sizes.len())
+            .filter(|label| self.sizes[*label] > 0)
+            .collect()
+    }
+
+    /// A label not used by any item.
+    pub fn new_label(&self) -> usize {
+        self.sizes
+            .iter()
+            .position(|size| *size == 0)
+            .unwrap_or(self.sizes.len())
+    }
+
+    fn p(&self, i: usize, j: usize) -> f64 {
+        self.psm[self.n_items * j + i]
+    }
+
+    /// The expected loss of the current partition.
+    pub fn expected_loss(&self) -> f64 {
+        let n = self.n_items;
+        match self.loss {
+            PsmLoss::Binder(a) => {
+                let mut sum = 0.0;
+                for j in 0..n {
+                    for i in 0..j {
+                        let p = self.p(i, j);
+                        sum += if self.labels[i] == self.labels[j] {
+                            (2.0 - a) * (1.0 - p)
+                        } else {
+                            a * p
+                        };
+                    }
+                }
+                sum
+            }
+            PsmLoss::VIlb => {
+                let mut sum = 0.0;
+                for i in 0..n {
+                    let total: f64 = (0..n).map(|j| self.p(i, j)).sum();
+                    sum += (self.sizes[self.labels[i]] as f64).log2() + total.log2()
+                        - 2.0 * self.within[i].log2();
+                }
+                sum / (n as f64)
+            }
+        }
+    }
+
+    /// The change in expected loss if `item` were moved to the cluster labeled `label`, which
+    /// may be empty.
+    pub fn move_delta(&self, item: usize, label: usize) -> f64 {
+        let from = self.labels[item];
+        if label == from {
+            return 0.0;
+        }
+        let n = self.n_items;
+        match self.loss {
+            PsmLoss::Binder(a) => {
+                let cost = |j: usize| {
+                    let p = self.p(item, j);
+                    (2.0 - a) * (1.0 - p) - a * p
+                };
+                let mut delta = 0.0;
+                for j in 0..n {
+                    if j == item {
+                        continue;
+                    }
+                    if self.labels[j] == label {
+                        delta += cost(j);
+                    } else if self.labels[j] == from {
+                        delta -= cost(j);
+                    }
+                }
+                delta
+            }
+            PsmLoss::VIlb => {
+                let size_of = |label: usize| self.sizes.get(label).copied().unwrap_or(0) as f64;
+                let (size_from, size_to) = (size_of(from), size_of(label));
+                let mut delta = 0.0;
+                // The cluster size terms.
+                delta += (size_from - 1.0) * log2_or_zero(size_from - 1.0)
+                    - size_from * log2_or_zero(size_from);
+                delta +=
+                    (size_to + 1.0) * log2_or_zero(size_to + 1.0) - size_to * log2_or_zero(size_to);
+                // The within-cluster similarity terms.
+                let mut within_item = self.p(item, item);
+                for j in 0..n {
+                    if j == item {
+                        continue;
+                    }
+                    if self.labels[j] == label {
+                        let p = self.p(item, j);
+                        within_item += p;
+                        delta -= 2.0 * ((self.within[j] + p).log2() - self.within[j].log2());
+                    } else if self.labels[j] == from {
+                        let p = self.p(item, j);
+                        delta -= 2.0 * ((self.within[j] - p).log2() - self.within[j].log2());
+                    }
+                }
+                delta -= 2.0 * (within_item.log2() - self.within[item].log2());
+                delta / (n as f64)
+            }
+        }
+    }
+
+    /// The change in expected loss for moving `item` to each nonempty cluster and to a new
+    /// cluster, as `(label, delta)` pairs with the new cluster last.  The delta is zero for the
+    /// cluster of `item`.
+    pub fn move_deltas(&self, item: usize) -> Vec<(usize, f64)> {
+        let mut labels = self.active_labels();
+        labels.push(self.new_label());
+        labels
+            .into_iter()
+            .map(|label| (label, self.move_delta(item, label)))
+            .collect()
+    }
+
+    /// Move `item` to the cluster labeled `label`, returning the change in expected loss.
+    pub fn move_item(&mut self, item: usize, label: usize) -> f64 {
+        let delta = self.move_delta(item, label);
+        let from = self.labels[item];
+        if label == from {
+            return delta;
+        }
+        if label >= self.sizes.len() {
+            self.sizes.resize(label + 1, 0);
+        }
+        let mut within_item = self.p(item, item);
+        for j in 0..self.n_items {
+            if j == item {
+                continue;
+            }
+            if self.labels[j] == label {
+                let p = self.p(item, j);
+                within_item += p;
+                self.within[j] += p;
+            } else if self.labels[j] == from {
+                self.within[j] -= self.p(item, j);
+            }
+        }
+        self.within[item] = within_item;
+        self.sizes[from] -= 1;
+        self.sizes[label] += 1;
+        self.labels[item] = label;
+        delta
+    }
+}
+
+fn log2_or_zero(x: f64) -> f64 {
+    if x > 0.0 {
+        x.log2()
+    } else {
+        0.0
+    }
+}
+
+/// The result of [`anneal`].
+#[derive(Debug, Clone)]
+pub struct AnnealResult {
+    /// The best partition found, with labels `0, 1, ..., k - 1` in order of first appearance.
+    pub labels: Vec<usize>,
+    /// The expected loss of the best partition found.
+    pub expected_loss: f64,
+    /// The decrease in expected loss relative to the initial partition.
+    pub improvement: f64,
+}
+
+/// Refine a partition estimate by simulated annealing on its expected loss.
+///
+/// Each of the `n_iterations` proposals either moves one item to another (possibly new)
+/// cluster, merges two clusters, or splits a cluster in two at random.  A proposal is accepted
+/// when it decreases the expected loss or otherwise with probability `exp(-delta / t)`, where
+/// the temperature `t` starts at `temperature` and is multiplied by `cooling` after every
+/// proposal.
+pub fn anneal<T: Rng>(
+    mut state: PsmLossState,
+    n_iterations: usize,
+    temperature: f64,
+    cooling: f64,
+    rng: &mut T,
+) -> AnnealResult {
+    let n_items = state.n_items();
+    let initial = state.expected_loss();
+    let mut current = initial;
+    let mut best = initial;
+    let mut best_labels = state.labels().to_vec();
+    let mut t = temperature;
+    let mut moves = Vec::new();
+    for _ in 0..n_iterations {
+        if n_items == 0 {
+            break;
+        }
+        moves.clear();
+        let active = state.active_labels();
+        let mut delta = 0.0;
+        match rng.random_range(0..3) {
+            0 => {
+                let item = rng.random_range(0..n_items);
+                let new_label = state.new_label();
+                let label = if rng.random_range(0..=active.len()) == active.len() {
+                    new_label
+                } else {
+                    active[rng.random_range(0..active.len())]
+                };
+                moves.push((item, state.labels()[item]));
+                delta += state.move_item(item, label);
+            }
+            1 if active.len() > 1 => {
+                let mut pair = active.choose_multiple(rng, 2);
+                let (from, to) = (*pair.next().unwrap(), *pair.next().unwrap());
+                for item in 0..n_items {
+                    if state.labels()[item] == from {
+                        moves.push((item, from));
+                        delta += state.move_item(item, to);
+                    }
+                }
+            }
+            _ => {
+                let from = active[rng.random_range(0..active.len())];
+                let to = state.new_label();
+                for item in 0..n_items {
+                    if state.labels()[item] == from && rng.random::<bool>() {
+                        moves.push((item, from));
+                        delta += state.move_item(item, to);
+                    }
+                }
+            }
+        }
+        if delta <= 0.0 || rng.random::<f64>() < (-delta / t).exp() {
+            current += delta;
+            if current < best {
+                best = current;
+                best_labels.copy_from_slice(state.labels());
+            }
+        } else {
+            for (item, label) in moves.iter().rev() {
+                state.move_item(*item, *label);
+            }
+        }
+        t *= cooling;
+    }
+    AnnealResult {
+        labels: crate::map::canonical(&best_labels),
+        expected_loss: best,
+        improvement: initial - best,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_move_delta() {
+        let psm = [
+            1.0, 0.9, 0.2, 0.1, 0.9, 1.0, 0.3, 0.2, 0.2, 0.3, 1.0, 0.8, 0.1, 0.2, 0.8, 1.0,
+        ];
+        for loss in [PsmLoss::Binder(1.0), PsmLoss::Binder(0.5), PsmLoss::VIlb] {
+            let mut state = PsmLossState::new(&psm, 4, &[0, 0, 0, 1], loss);
+            let before = state.expected_loss();
+            let delta = state.move_item(2, 1);
+            let after = PsmLossState::new(&psm, 4, &[0, 0, 1, 1], loss).expected_loss();
+            assert!((before + delta - after).abs() < 1e-12);
+            assert!((state.expected_loss() - after).abs() < 1e-12);
+            let delta = state.move_item(3, 2);
+            let after = PsmLossState::new(&psm, 4, &[0, 0, 1, 2], loss).expected_loss();
+            assert!((state.expected_loss() - after).abs() < 1e-12);
+            assert!(delta.is_finite());
+            let deltas = state.move_deltas(0);
+            assert_eq!(deltas.len(), 4);
+            assert_eq!(deltas[0], (0, 0.0));
+            assert!((deltas[3].1 - state.move_delta(0, 3)).abs() < 1e-12);
+        }
+    }
+
+    #[test]
+    fn test_anneal() {
+        let psm = [
+            1.0, 0.9, 0.2, 0.1, 0.9, 1.0, 0.3, 0.2, 0.2, 0.3, 1.0, 0.8, 0.1, 0.2, 0.8, 1.0,
+        ];
+        let state = PsmLossState::new(&psm, 4, &[0, 1, 2, 3], PsmLoss::Binder(1.0));
+        let mut rng = rand::rngs::StdRng::seed_from_u64(1);
+        let fit = anneal(state, 1000, 1.0, 0.99, &mut rng);
+        assert_eq!(fit.labels, vec![0, 0, 1, 1]);
+        assert!(fit.improvement > 0.0);
+    }
+}
@@ -0,0 +1,261 @@
+// Search for the partition with maximum probability under the EPA distribution
+
+use crate::epa::{log_probability, sample, EpaParameters, Similarity};
+use crate::perm::Permutation;
+
+use rand::prelude::*;
+use std::collections::HashMap;
+
+/// The result of [`map_search`].
+#[derive(Debug, Clone)]
+pub struct MapEstimate {
+    /// Cluster labels `0, 1, ..., k - 1` in order of first appearance.
+    pub labels: Vec<usize>,
+    /// The estimated log probability of the partition.
+    pub log_probability: f64,
+}
+
+/// A Monte Carlo estimate of the log of the EPA probability of a partition, where the EPA
+/// probability is averaged over the permutations of the supplied parameters.
+pub fn log_probability_averaged<M: Similarity>(
+    labels: &[usize],
+    parameters: &[EpaParameters<M>],
+) -> f64 {
+    let logs: Vec<_> = parameters
+        .iter()
+        .map(|p| log_probability(labels, p))
+        .collect();
+    let max = logs.iter().copied().fold(f64::NEG_INFINITY, f64::max);
+    if max == f64::NEG_INFINITY {
+        return max;
+    }
+    let sum: f64 = logs.iter().map(|x| (x - max).exp()).sum();
+    max + (sum / (logs.len() as f64)).ln()
+}
+
+/// Search for the partition maximizing the (permutation-averaged) EPA probability.
+///
+/// The probability is estimated by averaging over `n_permutations` random permutations, which
+/// are fixed during the search.  Each of `n_restarts` searches starts from the best of
+/// `n_initial` draws from the EPA distribution and then climbs greedily.  In a sweep, items are
+/// visited in random order and each is moved to the existing or new cluster that most increases
+/// the estimated probability.  When a sweep brings no improvement, the best merge of two
+/// clusters and the best split of a cluster (each split taking two random items of the cluster
+/// as seeds and giving every other item to the seed to which it is more similar) are tried, and
+/// the search stops if neither helps.  A search also stops after `max_sweeps` sweeps.  The best
+/// partition over the searches is returned.
+#[allow(clippy::too_many_arguments)]
+pub fn map_search<M: Similarity, T: Rng>(
+    similarity: M,
+    mass: f64,
+    discount: f64,
+    n_permutations: usize,
+    n_initial: usize,
+    n_restarts: usize,
+    max_sweeps: usize,
+    rng: &mut T,
+) -> MapEstimate {
+    let n_items = similarity.n_items();
+    let new_parameters = |permutation| {
+        EpaParameters::new(similarity, permutation, mass)
+            .and_then(|x| x.with_discount(discount))
+            .unwrap()
+    };
+    let parameters: Vec<_> = (0..n_permutations.max(1))
+        .map(|_| new_parameters(Permutation::random(n_items, rng)))
+        .collect();
+    let mut initial_parameters = new_parameters(Permutation::natural(n_items));
+    let mut result = MapEstimate {
+        labels: Vec::new(),
+        log_probability: f64::NEG_INFINITY,
+    };
+    for _ in 0..n_restarts.max(1) {
+        let mut labels = Vec::new();
+        let mut best = f64::NEG_INFINITY;
+        for _ in 0..n_initial.max(1) {
+            initial_parameters.shuffle_permutation(rng);
+            let candidate = canonical(sample(&initial_parameters, rng).allocation());
+            let value = log_probability_averaged(&candidate, &parameters);
+            if labels.is_empty() || value > best {
+                labels = candidate;
+                best = value;
+            }
+        }
+        climb(similarity, &parameters, &mut labels, &mut best, max_sweeps, rng);
+        if result.labels.is_empty() || best > result.log_probability {
+            result = MapEstimate {
+                labels,
+                log_probability: best,
+            };
+        }
+    }
+    result
+}
+
+// Greedy item moves, merges, and splits from 'labels', whose estimated log probability is 'best'.
+fn climb<M: Similarity, T: Rng>(
+    similarity: M,
+    parameters: &[EpaParameters<M>],
+    labels: &mut Vec<usize>,
+    best: &mut f64,
+    max_sweeps: usize,
+    rng: &mut T,
+) {
+    let n_items = labels.len();
+    let mut order: Vec<_> = (0..n_items).collect();
+    for _ in 0..max_sweeps {
+        let mut improved = false;
+        order.shuffle(rng);
+        for item in order.iter().copied() {
+            let current = labels[item];
+            let new_label = labels.iter().max().map_or(0, |x| x + 1);
+            let is_singleton = labels.iter().filter(|x| **x == current).count() == 1;
+            let mut best_label = current;
+            for label in 0..=new_label {
+                if label == current || (label == new_label && is_singleton) {
+                    continue;
+                }
+                labels[item] = label;
+                let value = log_probability_averaged(labels, parameters);
+                if value > *best {
+                    *best = value;
+                    best_label = label;
+                }
+            }
+            labels[item] = best_label;
+            if best_label != current {
+                improved = true;
+                *labels = canonical(labels);
+            }
+        }
+        if improved {
+            continue;
+        }
+        let n_clusters = labels.iter().max().map_or(0, |x| x + 1);
+        let mut best_move = None;
+        for k in 0..n_clusters {
+            for l in (k + 1)..n_clusters {
+                let candidate: Vec<_> =
+                    labels.iter().map(|x| if *x == l { k } else { *x }).collect();
+                let value = log_probability_averaged(&candidate, parameters);
+                if value > *best {
+                    *best = value;
+                    best_move = Some(candidate);
+                }
+            }
+        }
+        for k in 0..n_clusters {
+            let members: Vec<_> = (0..n_items).filter(|i| labels[*i] == k).collect();
+            if members.len() < 2 {
+                continue;
+            }
+            let seeds: Vec<_> = members.choose_multiple(rng, 2).copied().collect();
+            let candidate: Vec<_> = (0..n_items)
+                .map(|i| {
+                    let closer_to_second = i == seeds[1]
+                        || (i != seeds[0]
+                            && similarity.get(i, seeds[1]) > similarity.get(i, seeds[0]));
+                    if labels[i] == k && closer_to_second {
+                        n_clusters
+                    } else {
+                        labels[i]
+                    }
+                })
+                .collect();
+            let value = log_probability_averaged(&candidate, parameters);
+            if value > *best {
+                *best = value;
+                best_move = Some(candidate);
+            }
+        }
+        match best_move {
+            Some(candidate) => *labels = canonical(&candidate),
+            None => break,
+        }
+    }
+}
+
+/// Relabel to `0, 1, ..., k - 1` in order of first appearance.
+pub fn canonical(labels: &[usize]) -> Vec<usize> {
+    let mut map = vec![usize::MAX; labels.iter().max().map_or(0, |x| x + 1)];
+    let mut next = 0;
+    labels
+        .iter()
+        .map(|label| {
+            if map[*label] == usize::MAX {
+                map[*label] = next;
+                next += 1;
+            }
+            map[*label]
+        })
+        .collect()
+}
+
+/// The distinct partitions among `draws`, given consecutively with `n_items` labels each, with the
+/// number of draws equal to each.  Partitions are relabeled as by [`canonical`], so draws which
+/// differ only in their labels are the same, and are in order of first appearance.
+pub fn deduplicate<L: Copy + Into<usize>>(
+    draws: &[L],
+    n_items: usize,
+) -> (Vec<Vec<usize>>, Vec<usize>) {
+    let mut index = HashMap::new();
+    let mut partitions = Vec::new();
+    let mut counts = Vec::new();
+    if n_items == 0 {
+        return (partitions, counts);
+    }
+    for draw in draws.chunks_exact(n_items) {
+        let labels: Vec<_> = draw.iter().map(|x| (*x).into()).collect();
+        let labels = canonical(&labels);
+        match index.get(&labels) {
+            Some(k) => counts[*k] += 1,
+            None => {
+                index.insert(labels.clone(), partitions.len());
+                partitions.push(labels);
+                counts.push(1);
+            }
+        }
+    }
+    (partitions, counts)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::epa::SquareMatrix;
+
+    #[test]
+    fn test_map_search() {
+        // With constant similarity, the EPA distribution is the Chinese restaurant process.
+        let similarity = SquareMatrix::ones(3);
+        let parameters =
+            EpaParameters::new(similarity.view(), Permutation::natural(3), 0.5).unwrap();
+        let expected = (1.0 / 1.5 * 2.0 / 2.5_f64).ln();
+        assert!((log_probability(&[0, 0, 0], &parameters) - expected).abs() < 1e-12);
+        // With a small mass, the most probable partition has one cluster.
+        let mut rng = rand::rngs::StdRng::seed_from_u64(1);
+        let fit = map_search(similarity.view(), 0.5, 0.0, 5, 1, 2, 10, &mut rng);
+        assert_eq!(fit.labels, vec![0, 0, 0]);
+        assert!((fit.log_probability - expected).abs() < 1e-12);
+        // With a discount, it is the Pitman-Yor process.
+        let parameters = parameters.with_discount(0.25).unwrap();
+        let expected = (0.75 / 1.5 * 1.75 / 2.5_f64).ln();
+        assert!((log_probability(&[0, 0, 0], &parameters) - expected).abs() < 1e-12);
+        let expected = (0.75 / 1.5 * 0.75 / 2.5_f64).ln();
+        assert!((log_probability(&[0, 0, 1], &parameters) - expected).abs() < 1e-12);
+        assert!((log_probability(&[0, 1, 1], &parameters) - expected).abs() < 1e-12);
+        // With a large mass, the most probable partition has singletons.
+        let fit = map_search(similarity.view(), 5.0, 0.25, 5, 1, 2, 10, &mut rng);
+        assert_eq!(fit.labels, vec![0, 1, 2]);
+        let expected = (5.25 / 6.0 * 5.5 / 7.0_f64).ln();
+        assert!((fit.log_probability - expected).abs() < 1e-12);
+    }
+
+    #[test]
+    fn test_deduplicate() {
+        let draws: [u16; 12] = [0, 0, 1, 1, 1, 0, 0, 1, 2, 2, 2, 2];
+        let (partitions, counts) = deduplicate(&draws, 3);
+        assert_eq!(partitions, vec![vec![0, 0, 1], vec![0, 1, 2], vec![0, 0, 0]]);
+        assert_eq!(counts, vec![2, 1, 1]);
+    }
+}
@@ -0,0 +1,177 @@
+// Orders in which the EPA sampler allocates items, as alternatives to uniform shuffling
+
+use crate::epa::Similarity;
+use rand::Rng;
+
+/// How the permutation of the items is chosen for each draw.
+///
+/// `Uniform` shuffles the items uniformly.  `TotalSimilarity` orders them by decreasing total
+/// similarity to the other items, so the most central items are allocated first, and `Fiedler`
+/// orders them by the Fiedler vector of the similarity graph (the eigenvector of its Laplacian
+/// for the second smallest eigenvalue), so items of the same cluster tend to be allocated in
+/// turn.  A heuristic order is used for every draw if it is fixed, and is otherwise perturbed
+/// randomly for each draw by [`perturb_order`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum PermutationStrategy {
+    #[default]
+    Uniform,
+    TotalSimilarity,
+    Fiedler,
+}
+
+impl PermutationStrategy {
+    /// The heuristic order of the items, or `None` for uniform shuffling.
+    pub fn order<M: Similarity>(&self, similarity: M) -> Option<Vec<usize>> {
+        match self {
+            Self::Uniform => None,
+            Self::TotalSimilarity => Some(total_similarity_order(similarity)),
+            Self::Fiedler => Some(fiedler_order(similarity)),
+        }
+    }
+}
+
+// The total similarity of each item to the other items.
+fn total_similarities<M: Similarity>(similarity: M) -> Vec<f64> {
+    let n_items = similarity.n_items();
+    (0..n_items)
+        .map(|i| (0..n_items).filter(|j| *j != i).map(|j| similarity.get(i, j)).sum())
+        .collect()
+}
+
+/// The items in order of decreasing total similarity to the other items, with ties in order of
+/// the items.
+pub fn total_similarity_order<M: Similarity>(similarity: M) -> Vec<usize> {
+    let totals = total_similarities(similarity);
+    let mut order: Vec<_> = (0..totals.len()).collect();
+    order.sort_by(|i, j| totals[*j].total_cmp(&totals[*i]));
+    order
+}
+
+// The maximum number of power iterations for the Fiedler vector and the change in the vector (in
+// Euclidean norm) at which they stop early.
+const FIEDLER_MAX_ITERATIONS: usize = 500;
+const FIEDLER_TOLERANCE: f64 = 1e-9;
+
+/// The items in increasing order of the Fiedler vector of the similarity graph, with ties in
+/// order of the items.
+///
+/// The Fiedler vector is found by power iteration on `c I - L`, where `L` is the Laplacian of the
+/// graph whose edge weights are the similarities and `c` is twice the largest total similarity (a
+/// bound on the eigenvalues of `L`), projecting out the constant eigenvector at each step.  Its
+/// sign, which is arbitrary, is chosen so that its component of largest magnitude is positive.
+pub fn fiedler_order<M: Similarity>(similarity: M) -> Vec<usize> {
+    let n_items = similarity.n_items();
+    let mut order: Vec<_> = (0..n_items).collect();
+    if n_items < 3 {
+        return order;
+    }
+    let degrees = total_similarities(similarity);
+    let shift = 2.0 * degrees.iter().fold(0.0, |max, x| f64::max(max, *x));
+    if shift <= 0.0 {
+        return order;
+    }
+    // A deterministic start which is not orthogonal to the Fiedler vector for typical data.
+    let mut x: Vec<_> = (0..n_items).map(|i| ((i + 1) as f64).sqrt().fract() - 0.5).collect();
+    let normalize = |x: &mut [f64]| {
+        let mean = x.iter().sum::<f64>() / (x.len() as f64);
+        x.iter_mut().for_each(|v| *v -= mean);
+        let norm = x.iter().map(|v| v * v).sum::<f64>().sqrt();
+        if norm > 0.0 {
+            x.iter_mut().for_each(|v| *v /= norm);
+        }
+        norm > 0.0
+    };
+    if !normalize(&mut x) {
+        return order;
+    }
+    let mut y = vec![0.0; n_items];
+    for _ in 0..FIEDLER_MAX_ITERATIONS {
+        // y = (c I - L) x = (c - d_i) x_i + sum_{j != i} s_ij x_j
+        for (i, y) in y.iter_mut().enumerate() {
+            let neighbors: f64 = (0..n_items)
+                .filter(|j| *j != i)
+                .map(|j| similarity.get(i, j) * x[j])
+                .sum();
+            *y = (shift - degrees[i]) * x[i] + neighbors;
+        }
+        if !normalize(&mut y) {
+            break;
+        }
+        let change = x.iter().zip(&y).map(|(a, b)| (a - b).powi(2)).sum::<f64>().sqrt();
+        std::mem::swap(&mut x, &mut y);
+        if change < FIEDLER_TOLERANCE {
+            break;
+        }
+    }
+    let largest = x.iter().fold(0.0, |a: f64, b| if b.abs() > a.abs() { *b } else { a });
+    if largest < 0.0 {
+        x.iter_mut().for_each(|v| *v = -*v);
+    }
+    order.sort_by(|i, j| x[*i].total_cmp(&x[*j]));
+    order
+}
+
+/// A random order near `order`, written into `output`: the items are drawn without replacement
+/// with probability proportional to `n - r` for the item at position `r` of `order`, where `n` is
+/// the number of items, so early items of `order` tend to stay early.  The `keys` are working
+/// storage, which is reused across calls to avoid an allocation for each draw.
+pub fn perturb_order<T: Rng>(
+    order: &[usize],
+    rng: &mut T,
+    keys: &mut Vec<(f64, usize)>,
+    output: &mut Vec<usize>,
+) {
+    // Efraimidis and Spirakis: the items with the largest keys 'u^(1/w)', i.e., 'ln(u) / w'.
+    let n_items = order.len();
+    keys.clear();
+    keys.extend(order.iter().enumerate().map(|(r, item)| {
+        let u: f64 = 1.0 - rng.random::<f64>();
+        (u.ln() / ((n_items - r) as f64), *item)
+    }));
+    keys.sort_by(|a, b| b.0.total_cmp(&a.0));
+    output.clear();
+    output.extend(keys.iter().map(|x| x.1));
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::epa::SquareMatrixBorrower;
+    use rand::SeedableRng;
+
+    #[test]
+    fn test_orders() {
+        // Two clusters, {0, 2, 4} and {1, 3}, with item 2 the most similar to the others.
+        let labels = [0, 1, 0, 1, 0];
+        let mut data = vec![0.0; 25];
+        for j in 0..5 {
+            for i in 0..5 {
+                data[5 * j + i] = if labels[i] == labels[j] { 1.0 } else { 0.25 };
+                if i != j && (i == 2 || j == 2) {
+                    data[5 * j + i] += 0.5;
+                }
+            }
+        }
+        let similarity = SquareMatrixBorrower::from_slice(&data, 5);
+        assert_eq!(total_similarity_order(similarity), vec![2, 0, 4, 1, 3]);
+        // The Fiedler vector separates the clusters.
+        let sides: Vec<_> = fiedler_order(similarity).iter().map(|x| labels[*x]).collect();
+        assert!(sides == [0, 0, 0, 1, 1] || sides == [1, 1, 0, 0, 0]);
+        assert_eq!(PermutationStrategy::Uniform.order(similarity), None);
+        let mut rng = rand::rngs::StdRng::seed_from_u64(0);
+        let (mut keys, mut output) = (Vec::new(), Vec::new());
+        let mut first_counts = [0; 5];
+        for _ in 0..2000 {
+            perturb_order(&[2, 0, 4, 1, 3], &mut rng, &mut keys, &mut output);
+            let mut sorted = output.clone();
+            sorted.sort_unstable();
+            assert_eq!(sorted, vec![0, 1, 2, 3, 4]);
+            first_counts[output[0]] += 1;
+        }
+        // The first item is drawn with probability proportional to 5, 4, 3, 2, 1.
+        for (item, weight) in [(2, 5.0), (0, 4.0), (4, 3.0), (1, 2.0), (3, 1.0)] {
+            let p = f64::from(first_counts[item]) / 2000.0;
+            assert!((p - weight / 15.0).abs() < 0.04);
+        }
+    }
+}
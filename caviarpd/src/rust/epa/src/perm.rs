@@ -73,10 +73,22 @@ impl Permutation {
         }
     }
 
+    /// Return to the natural order without reallocating.
+    pub fn reset(&mut self) {
+        for (i, x) in self.x.iter_mut().enumerate() {
+            *x = i;
+        }
+    }
+
     pub fn shuffle<T: Rng>(&mut self, rng: &mut T) {
         self.x.shuffle(rng)
     }
 
+    /// Shuffle all but the first `start` elements.
+    pub fn shuffle_from<T: Rng>(&mut self, start: usize, rng: &mut T) {
+        self.x[start..].shuffle(rng)
+    }
+
     pub fn n_items(&self) -> usize {
         self.n_items
     }
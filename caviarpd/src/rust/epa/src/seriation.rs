@@ -0,0 +1,124 @@
+// Orderings of items which display a pairwise similarity matrix as a heatmap with clear blocks
+
+/// The order of `n` things given by the Fiedler vector (the eigenvector of the second smallest
+/// eigenvalue of the graph Laplacian) of the symmetric, nonnegative similarities `w(i, j)`, so that
+/// similar things are placed near one another.
+///
+/// The vector is found by power iteration on a shifted Laplacian, deflated by the constant
+/// vector.  Its sign is chosen so that the order starts with the lower indices when possible, and
+/// ties are broken by index, so the order is deterministic.
+fn spectral_order(n: usize, w: impl Fn(usize, usize) -> f64) -> Vec<usize> {
+    if n < 3 {
+        return (0..n).collect();
+    }
+    let degrees: Vec<f64> = (0..n)
+        .map(|i| (0..n).filter(|j| *j != i).map(|j| w(i, j)).sum())
+        .collect();
+    // The eigenvalues of the Laplacian lie in [0, 2 * max degree], so those of the shifted matrix
+    // are nonnegative and in reverse order, the Fiedler vector's the largest but the constant's.
+    let shift = 2.0 * degrees.iter().copied().fold(0.0, f64::max);
+    if shift == 0.0 {
+        return (0..n).collect();
+    }
+    let center = ((n - 1) as f64) / 2.0;
+    let mut v: Vec<f64> = (0..n).map(|i| (i as f64) - center).collect();
+    let normalize = |v: &mut Vec<f64>| {
+        let mean = v.iter().sum::<f64>() / (n as f64);
+        v.iter_mut().for_each(|x| *x -= mean);
+        let norm = v.iter().map(|x| x * x).sum::<f64>().sqrt();
+        if norm > 0.0 {
+            v.iter_mut().for_each(|x| *x /= norm);
+        }
+    };
+    normalize(&mut v);
+    for _ in 0..500 {
+        let mut next: Vec<f64> = (0..n)
+            .map(|i| {
+                let product: f64 = (0..n).filter(|j| *j != i).map(|j| w(i, j) * v[j]).sum();
+                (shift - degrees[i]) * v[i] + product
+            })
+            .collect();
+        normalize(&mut next);
+        let change = next
+            .iter()
+            .zip(&v)
+            .map(|(a, b)| (a - b).abs())
+            .fold(0.0, f64::max);
+        v = next;
+        if change < 1e-10 {
+            break;
+        }
+    }
+    let trend: f64 = v.iter().enumerate().map(|(i, x)| (i as f64 - center) * x).sum();
+    if trend < 0.0 {
+        v.iter_mut().for_each(|x| *x = -*x);
+    }
+    let mut order: Vec<_> = (0..n).collect();
+    order.sort_by(|i, j| v[*i].total_cmp(&v[*j]).then(i.cmp(j)));
+    order
+}
+
+/// An ordering of the items for displaying `psm`, a pairwise similarity matrix (column-major) of
+/// the items, as a heatmap: the items of each cluster of `labels` (which must be
+/// `0, 1, ..., k - 1`) are contiguous, the clusters are in spectral order of their mean
+/// similarities to one another, and the items within each cluster are in spectral order of their
+/// similarities.
+pub fn seriate(psm: &[f64], labels: &[usize]) -> Vec<usize> {
+    let n_items = labels.len();
+    assert_eq!(psm.len(), n_items * n_items);
+    let n_clusters = labels.iter().max().map_or(0, |x| x + 1);
+    let mut members = vec![Vec::new(); n_clusters];
+    for (i, label) in labels.iter().enumerate() {
+        members[*label].push(i);
+    }
+    let mut means = vec![0.0; n_clusters * n_clusters];
+    for (j, label_j) in labels.iter().enumerate() {
+        for (i, label_i) in labels.iter().enumerate() {
+            means[n_clusters * label_j + label_i] += psm[n_items * j + i];
+        }
+    }
+    for k in 0..n_clusters {
+        for l in 0..n_clusters {
+            means[n_clusters * l + k] /= (members[k].len() * members[l].len()) as f64;
+        }
+    }
+    spectral_order(n_clusters, |k, l| means[n_clusters * l + k])
+        .into_iter()
+        .flat_map(|k| {
+            let members = &members[k];
+            spectral_order(members.len(), |i, j| psm[n_items * members[j] + members[i]])
+                .into_iter()
+                .map(|i| members[i])
+                .collect::<Vec<_>>()
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_seriate() {
+        // Items 0, 2, and 4 form a chain 4 - 0 - 2, and items 1, 3, and 5 a chain 5 - 1 - 3.
+        let n_items = 6;
+        let labels = [0, 1, 0, 1, 0, 1];
+        let mut psm = vec![0.05; n_items * n_items];
+        for i in 0..n_items {
+            psm[n_items * i + i] = 1.0;
+        }
+        for (i, j, p) in [(4, 0, 0.9), (0, 2, 0.9), (4, 2, 0.3)] {
+            for (i, j) in [(i, j), (j, i), (i + 1, j + 1), (j + 1, i + 1)] {
+                psm[n_items * j + i] = p;
+            }
+        }
+        let order = seriate(&psm, &labels);
+        let mut sorted = order.clone();
+        sorted.sort_unstable();
+        assert_eq!(sorted, (0..n_items).collect::<Vec<_>>());
+        assert!(order[..3].iter().all(|i| labels[*i] == labels[order[0]]));
+        // The middle of each chain is in the middle of its cluster.
+        assert!(order[1] < 2 && order[4] < 2);
+        assert_eq!(spectral_order(2, |_, _| 1.0), vec![0, 1]);
+    }
+}
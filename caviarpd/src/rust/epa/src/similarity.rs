@@ -0,0 +1,348 @@
+// Similarity matrices generated from a partition
+
+use rand::Rng;
+
+/// A block-structured similarity matrix (column-major) for the partition given by `labels`.
+///
+/// Items in the same cluster have similarity `within` and items in different clusters have
+/// similarity `between`, which is then perturbed by [`perturb_similarity`] with `noise`.  The
+/// diagonal is `within`.
+pub fn similarity_from_partition<T: Rng>(
+    labels: &[usize],
+    within: f64,
+    between: f64,
+    noise: f64,
+    rng: &mut T,
+) -> Vec<f64> {
+    let n_items = labels.len();
+    let mut similarity = vec![within; n_items * n_items];
+    for j in 0..n_items {
+        for i in 0..j {
+            if labels[i] != labels[j] {
+                similarity[n_items * j + i] = between;
+                similarity[n_items * i + j] = between;
+            }
+        }
+    }
+    perturb_similarity(&mut similarity, n_items, noise, rng);
+    similarity
+}
+
+/// Perturb a symmetric similarity matrix (column-major) in place.
+///
+/// When `noise` is positive, each off-diagonal similarity is multiplied by `exp(noise * u)`, where
+/// `u` is uniform on (-1, 1), independently for each pair of items, so the matrix stays symmetric
+/// and nonnegative.
+pub fn perturb_similarity<T: Rng>(similarity: &mut [f64], n_items: usize, noise: f64, rng: &mut T) {
+    if noise <= 0.0 {
+        return;
+    }
+    for j in 0..n_items {
+        for i in 0..j {
+            let value = similarity[n_items * j + i] * (noise * rng.random_range(-1.0..1.0)).exp();
+            similarity[n_items * j + i] = value;
+            similarity[n_items * i + j] = value;
+        }
+    }
+}
+
+/// A balanced partition of `n_items` items into `n_clusters` clusters, whose sizes differ by at
+/// most one, with labels `0, 1, ..., n_clusters - 1` in order.
+///
+/// # Panics
+///
+/// Panics if `n_clusters` is zero or greater than `n_items`.
+pub fn balanced_partition(n_items: usize, n_clusters: usize) -> Vec<usize> {
+    assert!(n_clusters >= 1 && n_clusters <= n_items);
+    (0..n_items).map(|i| i * n_clusters / n_items).collect()
+}
+
+/// A noisy block-structured similarity matrix (column-major) with the partition given by `labels`
+/// as its ground truth, e.g., for simulation studies.
+///
+/// As in [`similarity_from_partition`], items in the same cluster have similarity `within` and
+/// items in different clusters have similarity `between`, but each off-diagonal similarity is
+/// multiplied by `exp(noise_sd * z)`, where `z` is standard normal, independently for each pair of
+/// items.  The diagonal is `within`.
+pub fn simulate_similarity<T: Rng>(
+    labels: &[usize],
+    within: f64,
+    between: f64,
+    noise_sd: f64,
+    rng: &mut T,
+) -> Vec<f64> {
+    let n_items = labels.len();
+    let mut similarity = similarity_from_partition(labels, within, between, 0.0, rng);
+    if noise_sd <= 0.0 {
+        return similarity;
+    }
+    for j in 0..n_items {
+        for i in 0..j {
+            let value = similarity[n_items * j + i] * (noise_sd * standard_normal(rng)).exp();
+            similarity[n_items * j + i] = value;
+            similarity[n_items * i + j] = value;
+        }
+    }
+    similarity
+}
+
+// A standard normal deviate by the Box-Muller transform.
+fn standard_normal<T: Rng>(rng: &mut T) -> f64 {
+    let u: f64 = 1.0 - rng.random::<f64>();
+    let v: f64 = rng.random();
+    (-2.0 * u.ln()).sqrt() * (std::f64::consts::TAU * v).cos()
+}
+
+/// Scale each row of a square similarity matrix (column-major) in place so that its similarities
+/// to the other items sum to one, e.g., to put items with many and few co-occurrences on an equal
+/// footing.  Rows whose sum is zero or not finite are left as they are.
+pub fn normalize_rows(similarity: &mut [f64], n_items: usize) {
+    assert_eq!(similarity.len(), n_items * n_items);
+    let mut sums = vec![0.0; n_items];
+    for j in 0..n_items {
+        for (i, sum) in sums.iter_mut().enumerate() {
+            if i != j {
+                *sum += similarity[n_items * j + i];
+            }
+        }
+    }
+    for j in 0..n_items {
+        for (i, sum) in sums.iter().enumerate() {
+            if sum.is_finite() && *sum > 0.0 {
+                similarity[n_items * j + i] /= sum;
+            }
+        }
+    }
+}
+
+/// The similarity matrix (column-major) among `items` from the matrix `full` among all `n_items`
+/// items.  Items may be repeated.
+pub fn sub_similarity(full: &[f64], n_items: usize, items: &[usize]) -> Vec<f64> {
+    let mut data = Vec::with_capacity(items.len() * items.len());
+    for j in items {
+        for i in items {
+            data.push(full[n_items * j + i]);
+        }
+    }
+    data
+}
+
+/// How [`repair_similarity`] replaces similarities which are not finite and nonnegative.
+///
+/// `Clamp` replaces NaN and negative values by zero and positive infinity by the largest valid
+/// similarity.  `Epsilon` replaces every invalid value by a small positive similarity, namely
+/// machine epsilon times the largest valid similarity, so the pair is all but dissimilar without
+/// risking an item with zero similarity to all others.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SimilarityRepair {
+    Clamp,
+    Epsilon,
+}
+
+/// Replace the similarities of `similarity` which are not finite and nonnegative according to
+/// `repair`, returning how many were replaced.
+pub fn repair_similarity(similarity: &mut [f64], repair: SimilarityRepair) -> usize {
+    let valid = |x: f64| x.is_finite() && x >= 0.0;
+    let largest = similarity
+        .iter()
+        .filter(|x| valid(**x))
+        .fold(0.0, |max, x| f64::max(max, *x));
+    let mut n_repaired = 0;
+    for x in similarity.iter_mut().filter(|x| !valid(**x)) {
+        *x = match repair {
+            SimilarityRepair::Clamp if *x == f64::INFINITY => largest,
+            SimilarityRepair::Clamp => 0.0,
+            SimilarityRepair::Epsilon if largest > 0.0 => f64::EPSILON * largest,
+            SimilarityRepair::Epsilon => f64::EPSILON,
+        };
+        n_repaired += 1;
+    }
+    n_repaired
+}
+
+// The number of offending entries or items listed in the errors of `validate_similarity`.
+const MAX_REPORTED: usize = 5;
+
+// A list of the first of `total` offending entries or items, noting how many are not listed.
+fn report(listed: &[String], total: usize) -> String {
+    let mut report = listed.join(", ");
+    if total > listed.len() {
+        report.push_str(&format!(", and {} more", total - listed.len()));
+    }
+    report
+}
+
+// The entries (row, column) of a column-major matrix at which `bad` holds, numbered from one.
+fn offending_entries(
+    data: &[f64],
+    n_items: usize,
+    bad: impl Fn(usize, usize) -> bool,
+) -> Option<String> {
+    let mut listed = Vec::new();
+    let mut total = 0;
+    for j in 0..n_items {
+        for i in 0..n_items {
+            if bad(i, j) {
+                if listed.len() < MAX_REPORTED {
+                    listed.push(format!("[{}, {}] = {}", i + 1, j + 1, data[n_items * j + i]));
+                }
+                total += 1;
+            }
+        }
+    }
+    (total > 0).then(|| report(&listed, total))
+}
+
+/// Check that a similarity matrix (column-major, with `n_rows` rows and `n_cols` columns) is valid
+/// for the EPA distribution, with an error message naming the offending entries or items.
+///
+/// The matrix must be square with finite, nonnegative values, and every item must have a positive
+/// similarity to some other item, since otherwise the probability of allocating it to an existing
+/// cluster is zero over zero.  If `symmetry_tolerance` is given, the similarities `s(i, j)` and
+/// `s(j, i)` may also differ by no more than this proportion of the larger of them.  Entries and
+/// items are numbered from one in the message, and only the first few are listed.
+pub fn validate_similarity(
+    data: &[f64],
+    n_rows: usize,
+    n_cols: usize,
+    symmetry_tolerance: Option<f64>,
+) -> Result<(), String> {
+    if n_rows != n_cols || data.len() != n_rows * n_cols {
+        return Err(format!(
+            "The similarity must be a square matrix, but it has {} rows and {} columns.",
+            n_rows, n_cols
+        ));
+    }
+    let n_items = n_rows;
+    let get = |i: usize, j: usize| data[n_items * j + i];
+    if let Some(entries) = offending_entries(data, n_items, |i, j| !get(i, j).is_finite()) {
+        return Err(format!("The similarity has nonfinite values: {}.", entries));
+    }
+    if let Some(entries) = offending_entries(data, n_items, |i, j| get(i, j) < 0.0) {
+        return Err(format!("The similarity has negative values: {}.", entries));
+    }
+    if let Some(tolerance) = symmetry_tolerance {
+        let asymmetric = |i: usize, j: usize| {
+            let (x, y) = (get(i, j), get(j, i));
+            i < j && (x - y).abs() > tolerance * x.max(y)
+        };
+        if let Some(entries) = offending_entries(data, n_items, asymmetric) {
+            return Err(format!(
+                "The similarity is not symmetric; these entries differ from their transposes: {}.",
+                entries
+            ));
+        }
+    }
+    if n_items > 1 {
+        let degenerate: Vec<_> = (0..n_items)
+            .filter(|&i| (0..n_items).all(|j| j == i || get(i, j) == 0.0))
+            .collect();
+        if !degenerate.is_empty() {
+            let listed: Vec<_> = degenerate
+                .iter()
+                .take(MAX_REPORTED)
+                .map(|i| (i + 1).to_string())
+                .collect();
+            return Err(format!(
+                "These items have zero similarity to all other items: {}.",
+                report(&listed, degenerate.len())
+            ));
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rand::SeedableRng;
+
+    #[test]
+    fn test_similarity_from_partition() {
+        let mut rng = rand::rngs::StdRng::seed_from_u64(0);
+        let x = similarity_from_partition(&[0, 1, 0], 2.0, 0.5, 0.0, &mut rng);
+        assert_eq!(x, vec![2.0, 0.5, 2.0, 0.5, 2.0, 0.5, 2.0, 0.5, 2.0]);
+        let x = similarity_from_partition(&[0, 1, 0, 1], 2.0, 0.5, 0.1, &mut rng);
+        for j in 0..4 {
+            for i in 0..4 {
+                assert_eq!(x[4 * j + i], x[4 * i + j]);
+                let base = if i == j || (i + j) % 2 == 0 { 2.0 } else { 0.5 };
+                assert!((x[4 * j + i] / base).ln().abs() <= 0.1);
+            }
+        }
+    }
+
+    #[test]
+    fn test_simulate_similarity() {
+        assert_eq!(balanced_partition(7, 3), vec![0, 0, 0, 1, 1, 2, 2]);
+        assert_eq!(balanced_partition(3, 3), vec![0, 1, 2]);
+        let mut rng = rand::rngs::StdRng::seed_from_u64(0);
+        let truth = balanced_partition(40, 2);
+        let x = simulate_similarity(&truth, 2.0, 0.5, 0.0, &mut rng);
+        assert_eq!(x, similarity_from_partition(&truth, 2.0, 0.5, 0.0, &mut rng));
+        let x = simulate_similarity(&truth, 2.0, 0.5, 0.3, &mut rng);
+        let mut log_ratios = Vec::new();
+        for j in 0..40 {
+            assert_eq!(x[40 * j + j], 2.0);
+            for i in 0..j {
+                assert_eq!(x[40 * j + i], x[40 * i + j]);
+                let base = if truth[i] == truth[j] { 2.0 } else { 0.5 };
+                log_ratios.push((x[40 * j + i] / base).ln());
+            }
+        }
+        let n = log_ratios.len() as f64;
+        let mean = log_ratios.iter().sum::<f64>() / n;
+        let sd = (log_ratios.iter().map(|x| (x - mean).powi(2)).sum::<f64>() / n).sqrt();
+        assert!(mean.abs() < 0.05);
+        assert!((sd - 0.3).abs() < 0.03);
+    }
+
+    #[test]
+    fn test_validate_similarity() {
+        let mut x = vec![1.0, 0.5, 0.0, 0.5, 1.0, 0.2, 0.0, 0.2, 1.0];
+        assert_eq!(validate_similarity(&x, 3, 3, Some(1e-8)), Ok(()));
+        let error = validate_similarity(&x[..6], 3, 2, None).unwrap_err();
+        assert!(error.contains("3 rows and 2 columns"));
+        x[5] = f64::NAN;
+        let error = validate_similarity(&x, 3, 3, None).unwrap_err();
+        assert_eq!(error, "The similarity has nonfinite values: [3, 2] = NaN.");
+        x[5] = -0.2;
+        let error = validate_similarity(&x, 3, 3, None).unwrap_err();
+        assert_eq!(error, "The similarity has negative values: [3, 2] = -0.2.");
+        x[5] = 0.2 + 1e-12;
+        assert_eq!(validate_similarity(&x, 3, 3, Some(1e-8)), Ok(()));
+        x[5] = 0.3;
+        assert_eq!(validate_similarity(&x, 3, 3, None), Ok(()));
+        let error = validate_similarity(&x, 3, 3, Some(1e-8)).unwrap_err();
+        assert!(error.ends_with("[2, 3] = 0.2."));
+        x[5] = 0.0;
+        x[7] = 0.0;
+        let error = validate_similarity(&x, 3, 3, Some(1e-8)).unwrap_err();
+        assert_eq!(error, "These items have zero similarity to all other items: 3.");
+        // A single item has no other items.
+        assert_eq!(validate_similarity(&[0.0], 1, 1, Some(1e-8)), Ok(()));
+        let error = validate_similarity(&[f64::INFINITY; 49], 7, 7, None).unwrap_err();
+        assert!(error.ends_with("[5, 1] = inf, and 44 more."));
+    }
+
+    #[test]
+    fn test_repair_similarity() {
+        let x = [1.0, f64::NAN, -0.5, f64::NAN, 2.0, f64::INFINITY, -0.5, f64::INFINITY, 1.0];
+        let mut clamped = x;
+        assert_eq!(repair_similarity(&mut clamped, SimilarityRepair::Clamp), 6);
+        assert_eq!(clamped, [1.0, 0.0, 0.0, 0.0, 2.0, 2.0, 0.0, 2.0, 1.0]);
+        let mut dropped = x;
+        assert_eq!(repair_similarity(&mut dropped, SimilarityRepair::Epsilon), 6);
+        let e = 2.0 * f64::EPSILON;
+        assert_eq!(dropped, [1.0, e, e, e, 2.0, e, e, e, 1.0]);
+        assert_eq!(validate_similarity(&dropped, 3, 3, Some(0.0)), Ok(()));
+        assert_eq!(repair_similarity(&mut dropped, SimilarityRepair::Clamp), 0);
+    }
+
+    #[test]
+    fn test_normalize_rows() {
+        let mut x = [5.0, 2.0, 0.0, 1.0, 5.0, 0.0, 3.0, 6.0, 5.0];
+        normalize_rows(&mut x, 3);
+        assert_eq!(x, [1.25, 0.25, 0.0, 0.25, 0.625, 0.0, 0.75, 0.75, 5.0]);
+    }
+}
@@ -0,0 +1,147 @@
+// Similarities of subjects with replicate measurements
+
+use crate::epa::Similarity;
+
+/// The rule by which the similarities between the replicates of two subjects are combined into
+/// the similarity between the subjects.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Aggregation {
+    Mean,
+    Min,
+    Max,
+}
+
+impl Aggregation {
+    fn aggregate(self, values: impl Iterator<Item = f64>) -> f64 {
+        match self {
+            Self::Mean => {
+                let (sum, count) = values.fold((0.0, 0.0), |(s, c), x| (s + x, c + 1.0));
+                sum / count
+            }
+            Self::Min => values.fold(f64::INFINITY, f64::min),
+            Self::Max => values.fold(f64::NEG_INFINITY, f64::max),
+        }
+    }
+}
+
+/// The replicates (rows of the similarity) of each subject, given the subject of each replicate.
+///
+/// Subjects are `0, 1, ..., m - 1`, each of which must have at least one replicate.
+pub fn replicates_of_subjects(subjects: &[usize]) -> Option<Vec<Vec<usize>>> {
+    let n_subjects = subjects.iter().max().map_or(0, |x| x + 1);
+    let mut replicates = vec![Vec::new(); n_subjects];
+    for (i, subject) in subjects.iter().enumerate() {
+        replicates[*subject].push(i);
+    }
+    if replicates.iter().any(|x| x.is_empty()) {
+        None
+    } else {
+        Some(replicates)
+    }
+}
+
+/// The similarity between subjects (column-major), combining the similarities between all pairs
+/// of their replicates by `aggregation`.
+///
+/// The diagonal combines all pairs of a subject's replicates, including each replicate with
+/// itself.
+pub fn subject_similarity<M: Similarity>(
+    similarity: M,
+    replicates: &[Vec<usize>],
+    aggregation: Aggregation,
+) -> Vec<f64> {
+    let n_subjects = replicates.len();
+    let mut result = vec![0.0; n_subjects * n_subjects];
+    for b in 0..n_subjects {
+        for a in 0..=b {
+            let value = aggregation.aggregate(
+                replicates[a]
+                    .iter()
+                    .flat_map(|i| replicates[b].iter().map(move |j| similarity.get(*i, *j))),
+            );
+            result[n_subjects * b + a] = value;
+            result[n_subjects * a + b] = value;
+        }
+    }
+    result
+}
+
+/// For each subject, the proportion of its replicates which, on their own, would be closest to
+/// the subject's cluster.
+///
+/// A replicate is closest to the cluster whose other replicates have the largest mean similarity
+/// to it, where `labels` gives the cluster of each subject.  Clusters without other replicates are
+/// not considered, and the proportion is `NaN` if the subject's own cluster has no replicates
+/// other than the one at hand.
+pub fn replicate_consistency<M: Similarity>(
+    similarity: M,
+    replicates: &[Vec<usize>],
+    labels: &[usize],
+) -> Vec<f64> {
+    let n_clusters = labels.iter().max().map_or(0, |x| x + 1);
+    let mut members = vec![Vec::new(); n_clusters];
+    for (subject, label) in labels.iter().enumerate() {
+        members[*label].extend_from_slice(&replicates[subject]);
+    }
+    replicates
+        .iter()
+        .zip(labels)
+        .map(|(own, label)| {
+            let mut n_consistent = 0;
+            for i in own {
+                let means: Vec<_> = members
+                    .iter()
+                    .map(|members| {
+                        let (sum, count) = members
+                            .iter()
+                            .filter(|j| *j != i)
+                            .fold((0.0, 0), |(s, c), j| (s + similarity.get(*i, *j), c + 1));
+                        if count == 0 {
+                            f64::NEG_INFINITY
+                        } else {
+                            sum / (count as f64)
+                        }
+                    })
+                    .collect();
+                if means[*label] == f64::NEG_INFINITY {
+                    return f64::NAN;
+                }
+                if means.iter().all(|x| *x <= means[*label]) {
+                    n_consistent += 1;
+                }
+            }
+            (n_consistent as f64) / (own.len() as f64)
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::epa::SquareMatrixBorrower;
+
+    #[test]
+    fn test_subjects() {
+        assert!(replicates_of_subjects(&[0, 2]).is_none());
+        let replicates = replicates_of_subjects(&[0, 1, 0, 2]).unwrap();
+        assert_eq!(replicates, vec![vec![0, 2], vec![1], vec![3]]);
+        #[rustfmt::skip]
+        let data = [
+            1.0, 0.2, 0.8, 0.1,
+            0.2, 1.0, 0.4, 0.9,
+            0.8, 0.4, 1.0, 0.3,
+            0.1, 0.9, 0.3, 1.0,
+        ];
+        let similarity = SquareMatrixBorrower::from_slice(&data, 4);
+        let x = subject_similarity(similarity, &replicates, Aggregation::Mean);
+        assert!((x[3] - 0.3).abs() < 1e-12 && (x[1] - 0.3).abs() < 1e-12);
+        assert!((x[0] - 0.9).abs() < 1e-12);
+        let x = subject_similarity(similarity, &replicates, Aggregation::Max);
+        assert_eq!(x[3], 0.4);
+        let consistency = replicate_consistency(similarity, &replicates, &[0, 1, 1]);
+        assert_eq!(consistency, vec![1.0, 1.0, 1.0]);
+        let consistency = replicate_consistency(similarity, &replicates, &[1, 0, 2]);
+        assert_eq!(consistency[0], 1.0);
+        assert!(consistency[1].is_nan() && consistency[2].is_nan());
+    }
+}
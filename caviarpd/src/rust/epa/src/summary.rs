@@ -0,0 +1,407 @@
+// Summaries of a clustering estimate
+
+use crate::distance::variation_of_information;
+use crate::epa::Similarity;
+use crate::map::canonical;
+use std::collections::HashMap;
+
+/// For each cluster of `labels` (which must be `0, 1, ..., k - 1`), the mean similarity between
+/// distinct members of the cluster and the mean similarity between members and nonmembers.
+///
+/// A mean over no pairs (for a singleton cluster or a cluster of all items) is `NaN`.
+pub fn cluster_similarities<M: Similarity>(similarity: M, labels: &[usize]) -> Vec<(f64, f64)> {
+    let n_clusters = labels.iter().max().map_or(0, |x| x + 1);
+    let mut sums = vec![(0.0, 0.0, 0.0, 0.0); n_clusters];
+    for (j, label_j) in labels.iter().enumerate() {
+        for (i, label_i) in labels.iter().enumerate() {
+            if i == j {
+                continue;
+            }
+            let value = similarity.get(i, j);
+            let x = &mut sums[*label_j];
+            if label_i == label_j {
+                x.0 += value;
+                x.1 += 1.0;
+            } else {
+                x.2 += value;
+                x.3 += 1.0;
+            }
+        }
+    }
+    let mean = |sum: f64, count: f64| if count > 0.0 { sum / count } else { f64::NAN };
+    sums.into_iter()
+        .map(|x| (mean(x.0, x.1), mean(x.2, x.3)))
+        .collect()
+}
+
+/// For each cluster of `labels` (which must be `0, 1, ..., k - 1`), its exemplar: the member with
+/// the largest mean similarity to the other members, the first such member in case of ties.
+pub fn exemplars<M: Similarity>(similarity: M, labels: &[usize]) -> Vec<usize> {
+    let n_clusters = labels.iter().max().map_or(0, |x| x + 1);
+    let mut members = vec![Vec::new(); n_clusters];
+    for (i, label) in labels.iter().enumerate() {
+        members[*label].push(i);
+    }
+    members
+        .iter()
+        .map(|members| {
+            let mut best = (members[0], f64::NEG_INFINITY);
+            for item in members {
+                // Every member has as many mates, so sums rank the members as means do.
+                let sum = similarity.sum_of_row_subset(*item, members) - similarity.get(*item, *item);
+                if sum > best.1 {
+                    best = (*item, sum);
+                }
+            }
+            best.0
+        })
+        .collect()
+}
+
+/// The silhouette width of each item for the clusters of `labels` (which must be
+/// `0, 1, ..., k - 1`), computed on `n_threads` threads.
+///
+/// For an item, let `a` be its mean similarity to the other members of its cluster and `b` its
+/// largest mean similarity to the members of another cluster.  Its width is `(a - b) / max(a, b)`,
+/// which is near 1 when the item is much more similar to its own cluster than to any other and
+/// negative when it is more similar to another cluster.  The width is 0 for the member of a
+/// singleton, when there is only one cluster, or when `a` and `b` are both 0.
+pub fn silhouette<M: Similarity>(similarity: M, labels: &[usize], n_threads: usize) -> Vec<f64> {
+    let n_items = labels.len();
+    let n_clusters = labels.iter().max().map_or(0, |x| x + 1);
+    let mut sizes = vec![0_usize; n_clusters];
+    labels.iter().for_each(|label| sizes[*label] += 1);
+    let sizes = &sizes;
+    let width = |i: usize| {
+        let own = labels[i];
+        if sizes[own] < 2 || n_clusters < 2 {
+            return 0.0;
+        }
+        let mut sums = vec![0.0; n_clusters];
+        for (j, label) in labels.iter().enumerate() {
+            if j != i {
+                sums[*label] += similarity.get(i, j);
+            }
+        }
+        let a = sums[own] / ((sizes[own] - 1) as f64);
+        let b = (0..n_clusters)
+            .filter(|k| *k != own)
+            .map(|k| sums[k] / (sizes[k] as f64))
+            .fold(f64::NEG_INFINITY, f64::max);
+        let scale = a.max(b);
+        if scale > 0.0 {
+            (a - b) / scale
+        } else {
+            0.0
+        }
+    };
+    let mut widths = vec![0.0; n_items];
+    let chunk_size = n_items.div_ceil(n_threads.max(1)).max(1);
+    let width = &width;
+    std::thread::scope(|s| {
+        for (t, chunk) in widths.chunks_mut(chunk_size).enumerate() {
+            s.spawn(move || {
+                for (ii, value) in chunk.iter_mut().enumerate() {
+                    *value = width(t * chunk_size + ii);
+                }
+            });
+        }
+    });
+    widths
+}
+
+/// How well the block structure of a clustering explains a similarity matrix, as given by
+/// [`block_fit`].
+#[derive(Debug, Clone)]
+pub struct BlockFit {
+    /// The `k` by `k` matrix (column-major) of the mean similarity between the members of two
+    /// clusters, excluding the similarity of an item to itself, which is `NaN` for no pairs.
+    pub means: Vec<f64>,
+    /// The mean similarity within each cluster, the diagonal of `means`.
+    pub cohesion: Vec<f64>,
+    /// The cohesion of each cluster less its largest mean similarity to another cluster, which is
+    /// positive when the cluster is more similar to itself than to any other cluster.
+    pub separation: Vec<f64>,
+    /// The `n_items` by `n_items` matrix (column-major) of each similarity less the mean of its
+    /// block, with zeros on the diagonal.
+    pub residuals: Vec<f64>,
+}
+
+/// The mean similarity within and between the clusters of `labels` (which must be
+/// `0, 1, ..., k - 1`) and the residuals of the similarities from these means.
+pub fn block_fit<M: Similarity>(similarity: M, labels: &[usize]) -> BlockFit {
+    let n_items = labels.len();
+    let n_clusters = labels.iter().max().map_or(0, |x| x + 1);
+    let mut sums = vec![(0.0, 0.0); n_clusters * n_clusters];
+    for (j, label_j) in labels.iter().enumerate() {
+        for (i, label_i) in labels.iter().enumerate() {
+            if i != j {
+                let x = &mut sums[n_clusters * label_j + label_i];
+                x.0 += similarity.get(i, j);
+                x.1 += 1.0;
+            }
+        }
+    }
+    let means: Vec<_> = sums
+        .iter()
+        .map(|(sum, count)| if *count > 0.0 { sum / count } else { f64::NAN })
+        .collect();
+    let cohesion: Vec<_> = (0..n_clusters)
+        .map(|k| means[n_clusters * k + k])
+        .collect();
+    let separation = (0..n_clusters)
+        .map(|k| {
+            let nearest = (0..n_clusters)
+                .filter(|l| *l != k)
+                .map(|l| means[n_clusters * l + k])
+                .fold(f64::NAN, f64::max);
+            cohesion[k] - nearest
+        })
+        .collect();
+    let mut residuals = vec![0.0; n_items * n_items];
+    for (j, label_j) in labels.iter().enumerate() {
+        for (i, label_i) in labels.iter().enumerate() {
+            if i != j {
+                residuals[n_items * j + i] =
+                    similarity.get(i, j) - means[n_clusters * label_j + label_i];
+            }
+        }
+    }
+    BlockFit {
+        means,
+        cohesion,
+        separation,
+        residuals,
+    }
+}
+
+/// For each item, the mean over the other items of the absolute difference between their
+/// co-clustering probability in `psm` (column-major) and whether `labels` clusters them together.
+///
+/// This is the item's share of the expected Binder loss of `labels`, so items with large values are
+/// those whose allocation is most uncertain.
+pub fn item_uncertainty(psm: &[f64], labels: &[usize]) -> Vec<f64> {
+    let n_items = labels.len();
+    (0..n_items)
+        .map(|j| {
+            if n_items < 2 {
+                return 0.0;
+            }
+            let sum: f64 = (0..n_items)
+                .filter(|i| *i != j)
+                .map(|i| {
+                    let together = if labels[i] == labels[j] { 1.0 } else { 0.0 };
+                    (psm[n_items * j + i] - together).abs()
+                })
+                .sum();
+            sum / ((n_items - 1) as f64)
+        })
+        .collect()
+}
+
+/// For each item, the mean over `draws` (each giving the labels of the items) of the proportion of
+/// the other items whose co-clustering with the item in the draw disagrees with `labels`.
+///
+/// This equals [`item_uncertainty`] for the pairwise similarity matrix of the draws, but each draw
+/// takes time linear in the number of items, so the matrix is never formed.
+pub fn item_disagreement<D: AsRef<[usize]>>(
+    draws: impl IntoIterator<Item = D>,
+    labels: &[usize],
+) -> Vec<f64> {
+    let n_items = labels.len();
+    let mut sums = vec![0_usize; n_items];
+    let mut sizes = vec![0_usize; labels.iter().max().map_or(0, |x| x + 1)];
+    labels.iter().for_each(|label| sizes[*label] += 1);
+    let mut n_draws = 0;
+    let mut draw_sizes = Vec::new();
+    let mut cells = HashMap::new();
+    for draw in draws {
+        let draw = draw.as_ref();
+        assert_eq!(draw.len(), n_items);
+        n_draws += 1;
+        draw_sizes.clear();
+        draw_sizes.resize(draw.iter().max().map_or(0, |x| x + 1), 0_usize);
+        cells.clear();
+        for (x, label) in draw.iter().zip(labels) {
+            draw_sizes[*x] += 1;
+            *cells.entry((*x, *label)).or_insert(0_usize) += 1;
+        }
+        // The items clustered with an item in the draw or in the estimate, but not in both.
+        for ((sum, x), label) in sums.iter_mut().zip(draw).zip(labels) {
+            *sum += draw_sizes[*x] + sizes[*label] - 2 * cells[&(*x, *label)];
+        }
+    }
+    if n_items < 2 || n_draws == 0 {
+        return vec![0.0; n_items];
+    }
+    let denominator = (n_draws * (n_items - 1)) as f64;
+    sums.into_iter()
+        .map(|sum| (sum as f64) / denominator)
+        .collect()
+}
+
+/// The result of [`credible_ball`], with each bound given by its distinct partitions, labeled
+/// `0, 1, ..., k - 1` in order of first appearance.
+#[derive(Debug, Clone)]
+pub struct CredibleBall {
+    /// The variation of information (in base 2) from the estimate to the edge of the ball.
+    pub radius: f64,
+    /// The partitions of the ball with the fewest clusters which are farthest from the estimate.
+    pub upper_vertical: Vec<Vec<usize>>,
+    /// The partitions of the ball with the most clusters which are farthest from the estimate.
+    pub lower_vertical: Vec<Vec<usize>>,
+    /// The partitions of the ball farthest from the estimate, regardless of their numbers of
+    /// clusters.
+    pub horizontal: Vec<Vec<usize>>,
+}
+
+/// The credible ball of Wade and Ghahramani (2018) around `estimate` given `draws` from the
+/// posterior, each giving the labels of the items.
+///
+/// The ball is the smallest ball in the variation of information centered at the estimate which
+/// contains at least a proportion `probability` of the draws, and it is summarized by its radius
+/// and by the draws on its boundary.  The vertical bounds are the extremes in the number of
+/// clusters and the horizontal bound the extreme in distance.
+///
+/// # Panics
+///
+/// Panics if there are no draws, a draw has a different number of items than the estimate, or
+/// `probability` is not in (0, 1].
+pub fn credible_ball<D: AsRef<[usize]>>(
+    draws: &[D],
+    estimate: &[usize],
+    probability: f64,
+) -> CredibleBall {
+    assert!(!draws.is_empty(), "There must be at least one draw.");
+    assert!(probability > 0.0 && probability <= 1.0);
+    let draws: Vec<_> = draws
+        .iter()
+        .map(|draw| {
+            assert_eq!(draw.as_ref().len(), estimate.len());
+            canonical(draw.as_ref())
+        })
+        .collect();
+    let distances: Vec<_> = draws
+        .iter()
+        .map(|draw| variation_of_information(draw, estimate))
+        .collect();
+    let mut sorted = distances.clone();
+    sorted.sort_by(f64::total_cmp);
+    let n_inside = ((probability * (draws.len() as f64)).ceil() as usize).clamp(1, draws.len());
+    let radius = sorted[n_inside - 1];
+    let ball: Vec<_> = draws
+        .iter()
+        .zip(&distances)
+        .filter(|(_, distance)| **distance <= radius)
+        .map(|(draw, distance)| (draw, draw.iter().max().map_or(0, |x| x + 1), *distance))
+        .collect();
+    // The distinct partitions farthest from the estimate among those of the ball with 'k' clusters,
+    // or among all of them.
+    let farthest = |k: Option<usize>| {
+        let members = ball.iter().filter(|x| k.is_none_or(|k| x.1 == k));
+        let max = members.clone().map(|x| x.2).fold(f64::NEG_INFINITY, f64::max);
+        let mut partitions: Vec<Vec<usize>> = Vec::new();
+        for (draw, _, _) in members.filter(|x| x.2 == max) {
+            if !partitions.contains(draw) {
+                partitions.push(draw.to_vec());
+            }
+        }
+        partitions
+    };
+    let fewest = ball.iter().map(|x| x.1).min();
+    let most = ball.iter().map(|x| x.1).max();
+    CredibleBall {
+        radius,
+        upper_vertical: farthest(fewest),
+        lower_vertical: farthest(most),
+        horizontal: farthest(None),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::epa::SquareMatrixBorrower;
+
+    #[test]
+    fn test_summary() {
+        #[rustfmt::skip]
+        let data = [
+            1.0, 0.8, 0.2,
+            0.8, 1.0, 0.4,
+            0.2, 0.4, 1.0,
+        ];
+        let similarity = SquareMatrixBorrower::from_slice(&data, 3);
+        let x = cluster_similarities(similarity, &[0, 0, 1]);
+        assert_eq!(x[0].0, 0.8);
+        assert!((x[0].1 - 0.3).abs() < 1e-12 && (x[1].1 - 0.3).abs() < 1e-12);
+        assert!(x[1].0.is_nan());
+        #[rustfmt::skip]
+        let psm = [
+            1.0, 0.9, 0.1,
+            0.9, 1.0, 0.5,
+            0.1, 0.5, 1.0,
+        ];
+        assert_eq!(exemplars(similarity, &[0, 0, 0]), vec![1]);
+        assert_eq!(exemplars(similarity, &[0, 1, 0]), vec![0, 1]);
+        let widths = silhouette(similarity, &[0, 0, 1], 2);
+        assert!((widths[0] - 0.75).abs() < 1e-12 && (widths[1] - 0.5).abs() < 1e-12);
+        assert_eq!(widths[2], 0.0);
+        assert_eq!(silhouette(similarity, &[0, 0, 0], 1), vec![0.0; 3]);
+        let fit = block_fit(similarity, &[0, 0, 1]);
+        assert!((fit.means[1] - 0.3).abs() < 1e-12 && fit.means[1] == fit.means[2]);
+        assert_eq!(fit.cohesion[0], 0.8);
+        assert!(fit.cohesion[1].is_nan() && fit.separation[1].is_nan());
+        assert!((fit.separation[0] - 0.5).abs() < 1e-12);
+        assert!((fit.residuals[2] + 0.1).abs() < 1e-12 && fit.residuals[1] == 0.0);
+        let x = item_uncertainty(&psm, &[0, 0, 1]);
+        assert!((x[0] - 0.1).abs() < 1e-12 && (x[1] - 0.3).abs() < 1e-12);
+        assert!((x[2] - 0.3).abs() < 1e-12);
+    }
+
+    #[test]
+    fn test_item_disagreement() {
+        let draws = [vec![0, 0, 1, 1], vec![0, 1, 1, 2], vec![3, 3, 3, 0]];
+        let n_items = 4;
+        let mut psm = vec![0.0; n_items * n_items];
+        for draw in &draws {
+            for j in 0..n_items {
+                for i in 0..n_items {
+                    if draw[i] == draw[j] {
+                        psm[n_items * j + i] += 1.0 / 3.0;
+                    }
+                }
+            }
+        }
+        let labels = [0, 0, 1, 2];
+        let x = item_disagreement(&draws, &labels);
+        let y = item_uncertainty(&psm, &labels);
+        assert!(x.iter().zip(&y).all(|(x, y)| (x - y).abs() < 1e-12));
+        assert_eq!(item_disagreement(&draws[..1], &[1, 1, 0, 0]), vec![0.0; 4]);
+    }
+
+    #[test]
+    fn test_credible_ball() {
+        let estimate = [0, 0, 1, 1];
+        let draws = [
+            vec![0, 0, 1, 1],
+            vec![1, 1, 0, 0],
+            vec![0, 0, 0, 0],
+            vec![0, 0, 1, 2],
+            vec![0, 1, 2, 3],
+        ];
+        let ball = credible_ball(&draws, &estimate, 0.6);
+        assert!((ball.radius - 0.5).abs() < 1e-12);
+        assert_eq!(ball.upper_vertical, vec![vec![0, 0, 1, 1]]);
+        assert_eq!(ball.lower_vertical, vec![vec![0, 0, 1, 2]]);
+        assert_eq!(ball.horizontal, vec![vec![0, 0, 1, 2]]);
+        let ball = credible_ball(&draws, &estimate, 0.8);
+        assert!((ball.radius - 1.0).abs() < 1e-12);
+        assert_eq!(ball.upper_vertical, vec![vec![0, 0, 0, 0]]);
+        assert_eq!(ball.lower_vertical, vec![vec![0, 1, 2, 3]]);
+        assert_eq!(ball.horizontal.len(), 2);
+        let ball = credible_ball(&draws, &estimate, 0.4);
+        assert_eq!(ball.radius, 0.0);
+        assert_eq!(ball.horizontal, vec![vec![0, 0, 1, 1]]);
+    }
+}
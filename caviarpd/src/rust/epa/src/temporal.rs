@@ -0,0 +1,211 @@
+// Partition estimates for a sequence of time slices that are smooth across time
+
+use crate::distance::variation_of_information;
+use crate::loss::{PsmLoss, PsmLossState};
+use crate::map::canonical;
+use std::collections::HashMap;
+
+/// The counts of the contingency table between an estimate and a fixed partition of a
+/// neighboring time slice, for updating their variation of information as items move.
+struct Coupling<'a> {
+    neighbor: &'a [usize],
+    sizes: Vec<usize>,
+    cells: HashMap<(usize, usize), usize>,
+}
+
+impl<'a> Coupling<'a> {
+    fn new(labels: &[usize], neighbor: &'a [usize]) -> Self {
+        let mut sizes = vec![0; labels.iter().max().map_or(0, |x| x + 1)];
+        let mut cells = HashMap::new();
+        for (label, other) in labels.iter().zip(neighbor) {
+            sizes[*label] += 1;
+            *cells.entry((*label, *other)).or_insert(0) += 1;
+        }
+        Self {
+            neighbor,
+            sizes,
+            cells,
+        }
+    }
+
+    /// The change in the variation of information (in base 2) if `item` were moved from the
+    /// cluster labeled `from` to the one labeled `to`.
+    fn move_delta(&self, item: usize, from: usize, to: usize) -> f64 {
+        if from == to {
+            return 0.0;
+        }
+        let f = |x: usize| {
+            let x = x as f64;
+            if x > 0.0 {
+                x * x.log2()
+            } else {
+                0.0
+            }
+        };
+        let size = |label: usize| self.sizes.get(label).copied().unwrap_or(0);
+        let cell = |label: usize| {
+            let key = (label, self.neighbor[item]);
+            self.cells.get(&key).copied().unwrap_or(0)
+        };
+        let (size_from, size_to) = (size(from), size(to));
+        let (cell_from, cell_to) = (cell(from), cell(to));
+        let delta = f(size_from - 1) - f(size_from) + f(size_to + 1) - f(size_to)
+            - 2.0 * (f(cell_from - 1) - f(cell_from) + f(cell_to + 1) - f(cell_to));
+        delta / (self.neighbor.len() as f64)
+    }
+
+    fn move_item(&mut self, item: usize, from: usize, to: usize) {
+        if to >= self.sizes.len() {
+            self.sizes.resize(to + 1, 0);
+        }
+        self.sizes[from] -= 1;
+        self.sizes[to] += 1;
+        let other = self.neighbor[item];
+        *self.cells.get_mut(&(from, other)).unwrap() -= 1;
+        *self.cells.entry((to, other)).or_insert(0) += 1;
+    }
+}
+
+/// The result of [`smooth_estimates`].
+#[derive(Debug, Clone)]
+pub struct TemporalFit {
+    /// The estimate of each time slice, with labels `0, 1, ..., k - 1` in order of first
+    /// appearance.
+    pub labels: Vec<Vec<usize>>,
+    /// The expected loss of the estimate of each time slice.
+    pub expected_losses: Vec<f64>,
+    /// The variation of information between the estimates of consecutive time slices.
+    pub distances: Vec<f64>,
+    /// The sum of the expected losses plus `smoothing` times the sum of the distances.
+    pub objective: f64,
+    /// The number of sweeps made over the time slices.
+    pub n_sweeps: usize,
+}
+
+/// Jointly refine the partition estimates of a sequence of time slices, given the pairwise
+/// similarity matrix (column-major with `n_items` rows and columns) of each slice and an initial
+/// estimate of each slice, e.g., from SALSO applied to each slice separately.
+///
+/// The objective is the sum over slices of the expected `loss` of their estimates plus
+/// `smoothing` times the sum of the variation of information between the estimates of
+/// consecutive slices.  Each sweep visits the slices in order and moves each item of the slice to
+/// the cluster (possibly new) that most decreases the objective, holding the other slices fixed.
+/// Sweeps stop when no item moves or after `max_sweeps` sweeps.
+pub fn smooth_estimates(
+    psms: &[&[f64]],
+    n_items: usize,
+    initial: &[Vec<usize>],
+    loss: PsmLoss,
+    smoothing: f64,
+    max_sweeps: usize,
+) -> TemporalFit {
+    assert_eq!(psms.len(), initial.len());
+    let mut states: Vec<_> = psms
+        .iter()
+        .zip(initial)
+        .map(|(psm, labels)| PsmLossState::new(psm, n_items, labels, loss))
+        .collect();
+    let n_slices = states.len();
+    let mut n_sweeps = 0;
+    while n_sweeps < max_sweeps {
+        n_sweeps += 1;
+        let mut moved = false;
+        for t in 0..n_slices {
+            let neighbors: Vec<Vec<usize>> = [t.wrapping_sub(1), t + 1]
+                .into_iter()
+                .filter(|s| *s < n_slices)
+                .map(|s| states[s].labels().to_vec())
+                .collect();
+            let state = &mut states[t];
+            let mut couplings: Vec<_> = neighbors
+                .iter()
+                .map(|neighbor| Coupling::new(state.labels(), neighbor))
+                .collect();
+            for item in 0..n_items {
+                let from = state.labels()[item];
+                let mut best = (from, 0.0);
+                for (label, delta) in state.move_deltas(item) {
+                    let coupled: f64 = couplings
+                        .iter()
+                        .map(|coupling| coupling.move_delta(item, from, label))
+                        .sum();
+                    let delta = delta + smoothing * coupled;
+                    if delta < best.1 - 1e-12 {
+                        best = (label, delta);
+                    }
+                }
+                if best.0 != from {
+                    state.move_item(item, best.0);
+                    for coupling in couplings.iter_mut() {
+                        coupling.move_item(item, from, best.0);
+                    }
+                    moved = true;
+                }
+            }
+        }
+        if !moved {
+            break;
+        }
+    }
+    let expected_losses: Vec<_> = states.iter().map(|state| state.expected_loss()).collect();
+    let labels: Vec<_> = states
+        .iter()
+        .map(|state| canonical(state.labels()))
+        .collect();
+    let distances: Vec<_> = labels
+        .windows(2)
+        .map(|pair| variation_of_information(&pair[0], &pair[1]))
+        .collect();
+    let objective =
+        expected_losses.iter().sum::<f64>() + smoothing * distances.iter().sum::<f64>();
+    TemporalFit {
+        labels,
+        expected_losses,
+        distances,
+        objective,
+        n_sweeps,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_coupling() {
+        let neighbor = [0, 0, 1, 1, 2];
+        let mut labels = vec![0, 1, 1, 2, 2];
+        let mut coupling = Coupling::new(&labels, &neighbor);
+        for (item, to) in [(0, 1), (3, 0), (4, 3)] {
+            let before = variation_of_information(&canonical(&labels), &neighbor);
+            let delta = coupling.move_delta(item, labels[item], to);
+            coupling.move_item(item, labels[item], to);
+            labels[item] = to;
+            let after = variation_of_information(&canonical(&labels), &neighbor);
+            assert!((before + delta - after).abs() < 1e-12);
+        }
+    }
+
+    #[test]
+    fn test_smooth_estimates() {
+        #[rustfmt::skip]
+        let clear = [
+            1.0, 0.9, 0.1, 0.1,
+            0.9, 1.0, 0.1, 0.1,
+            0.1, 0.1, 1.0, 0.9,
+            0.1, 0.1, 0.9, 1.0,
+        ];
+        let ambiguous = [0.5; 16];
+        let psms = [&clear[..], &ambiguous[..]];
+        let initial = [vec![0, 0, 1, 1], vec![0, 1, 2, 3]];
+        let loss = PsmLoss::Binder(1.0);
+        // Without smoothing, the ambiguous slice has no reason to change.
+        let fit = smooth_estimates(&psms, 4, &initial, loss, 0.0, 10);
+        assert_eq!(fit.labels[1], vec![0, 1, 2, 3]);
+        // With smoothing, it follows its neighbor.
+        let fit = smooth_estimates(&psms, 4, &initial, loss, 1.0, 10);
+        assert_eq!(fit.labels, vec![vec![0, 0, 1, 1], vec![0, 0, 1, 1]]);
+        assert_eq!(fit.distances, vec![0.0]);
+        assert!((fit.objective - fit.expected_losses.iter().sum::<f64>()).abs() < 1e-12);
+    }
+}
@@ -0,0 +1,362 @@
+// Similarity matrices stored on disk, for problems too large for memory
+
+use crate::epa::{compensated_sum, lane_sum, Similarity, SquareMatrixBorrower};
+use memmap2::{Mmap, MmapOptions};
+use std::collections::{BTreeMap, HashMap};
+use std::fs::File;
+use std::io::{BufWriter, Error, ErrorKind, Read, Result, Write};
+use std::path::Path;
+use std::sync::{Arc, Mutex};
+
+const MAGIC: &[u8; 8] = b"EPATILE1";
+const HEADER_LENGTH: usize = 24;
+
+/// A similarity matrix stored on disk as square tiles, so that the similarities between items near
+/// one another in the matrix are near one another on disk.
+///
+/// The file has a header (a magic string, the number of items, and the tile size) followed by the
+/// tiles in column-major order, each of which holds `tile_size * tile_size` little-endian `f64`
+/// values in column-major order.  Tiles on the edges are padded to the full size.  Tiles are
+/// memory-mapped on demand, one at a time, and at most `max_resident_gb` gigabytes of them stay
+/// mapped, with the least recently used tile unmapped to make room for another.  A tile in use by
+/// a thread stays mapped until the thread is done with it, so each thread may hold one more tile.
+/// The file must not be modified while it is open.  Use `&TiledSimilarity` as the [`Similarity`].
+#[derive(Debug)]
+pub struct TiledSimilarity {
+    file: File,
+    n_items: usize,
+    tile_size: usize,
+    n_tiles: usize,
+    max_resident_tiles: usize,
+    cache: Mutex<TileCache>,
+}
+
+// The mapped tiles, by index, with the time of their last use, and the tiles by the time of their
+// last use, for finding the least recently used tile.
+#[derive(Debug, Default)]
+struct TileCache {
+    tiles: HashMap<usize, (Arc<Mmap>, u64)>,
+    by_last_use: BTreeMap<u64, usize>,
+    clock: u64,
+}
+
+impl TiledSimilarity {
+    /// Write the `n_items` by `n_items` similarity given by `similarity(i, j)` to `path` in tiles
+    /// of `tile_size` by `tile_size`, without ever holding more than one tile in memory.
+    pub fn write<P: AsRef<Path>>(
+        path: P,
+        n_items: usize,
+        tile_size: usize,
+        mut similarity: impl FnMut(usize, usize) -> f64,
+    ) -> Result<()> {
+        if tile_size == 0 {
+            return Err(Error::new(
+                ErrorKind::InvalidInput,
+                "tile size must be positive",
+            ));
+        }
+        let n_tiles = n_items.div_ceil(tile_size);
+        let mut file = BufWriter::new(File::create(path)?);
+        file.write_all(MAGIC)?;
+        file.write_all(&(n_items as u64).to_le_bytes())?;
+        file.write_all(&(tile_size as u64).to_le_bytes())?;
+        for tile_column in 0..n_tiles {
+            for tile_row in 0..n_tiles {
+                for jj in 0..tile_size {
+                    for ii in 0..tile_size {
+                        let (i, j) = (tile_row * tile_size + ii, tile_column * tile_size + jj);
+                        let value = if i < n_items && j < n_items {
+                            similarity(i, j)
+                        } else {
+                            0.0
+                        };
+                        file.write_all(&value.to_le_bytes())?;
+                    }
+                }
+            }
+        }
+        file.flush()
+    }
+
+    /// Open a tiled similarity written by [`Self::write`], keeping at most `max_resident_gb`
+    /// gigabytes (of 10^9 bytes) of tiles mapped, but always at least one tile.  An infinite
+    /// `max_resident_gb` keeps every tile mapped once it is used.
+    pub fn open<P: AsRef<Path>>(path: P, max_resident_gb: f64) -> Result<Self> {
+        let invalid = |message: &str| Error::new(ErrorKind::InvalidData, message.to_string());
+        if max_resident_gb.is_nan() || max_resident_gb <= 0.0 {
+            return Err(Error::new(
+                ErrorKind::InvalidInput,
+                "the maximum resident size must be positive",
+            ));
+        }
+        let mut file = File::open(path)?;
+        let length = file.metadata()?.len();
+        if length < HEADER_LENGTH as u64 {
+            return Err(invalid("not a tiled similarity"));
+        }
+        let mut header = [0; HEADER_LENGTH];
+        file.read_exact(&mut header)?;
+        if &header[0..8] != MAGIC {
+            return Err(invalid("not a tiled similarity"));
+        }
+        let n_items = u64::from_le_bytes(header[8..16].try_into().unwrap()) as usize;
+        let tile_size = u64::from_le_bytes(header[16..24].try_into().unwrap()) as usize;
+        if tile_size == 0 {
+            return Err(invalid("tile size must be positive"));
+        }
+        let n_tiles = n_items.div_ceil(tile_size);
+        let n_values = (n_tiles * tile_size).checked_mul(n_tiles * tile_size);
+        let expected = n_values.and_then(|x| x.checked_mul(std::mem::size_of::<f64>()));
+        if expected.map(|x| (HEADER_LENGTH + x) as u64) != Some(length) {
+            return Err(invalid("tiled similarity has the wrong length"));
+        }
+        let tile_bytes = (tile_size * tile_size * std::mem::size_of::<f64>()) as f64;
+        let max_resident_tiles = (max_resident_gb * 1e9 / tile_bytes)
+            .min((n_tiles * n_tiles) as f64)
+            .max(1.0) as usize;
+        Ok(Self {
+            file,
+            n_items,
+            tile_size,
+            n_tiles,
+            max_resident_tiles,
+            cache: Mutex::new(TileCache::default()),
+        })
+    }
+
+    pub fn tile_size(&self) -> usize {
+        self.tile_size
+    }
+
+    /// The largest number of tiles kept mapped.
+    pub fn max_resident_tiles(&self) -> usize {
+        self.max_resident_tiles
+    }
+
+    /// The tile with index `tile` (in the order of the file), mapping it if it is not already
+    /// mapped and unmapping the least recently used tiles to stay within the limit.
+    fn tile(&self, tile: usize) -> Arc<Mmap> {
+        let mut cache = self.cache.lock().unwrap();
+        let cache = &mut *cache;
+        cache.clock += 1;
+        let now = cache.clock;
+        if let Some((map, last_use)) = cache.tiles.get_mut(&tile) {
+            cache.by_last_use.remove(last_use);
+            cache.by_last_use.insert(now, tile);
+            *last_use = now;
+            return map.clone();
+        }
+        while cache.tiles.len() >= self.max_resident_tiles {
+            let (_, oldest) = cache.by_last_use.pop_first().unwrap();
+            cache.tiles.remove(&oldest);
+        }
+        let tile_length = self.tile_size * self.tile_size * std::mem::size_of::<f64>();
+        // SAFETY: The mapping is read-only and the caller is told not to modify the file.
+        let map = unsafe {
+            MmapOptions::new()
+                .offset((HEADER_LENGTH + tile * tile_length) as u64)
+                .len(tile_length)
+                .map(&self.file)
+        };
+        let map = Arc::new(map.expect("tiles of the similarity should be mappable"));
+        cache.tiles.insert(tile, (map.clone(), now));
+        cache.by_last_use.insert(now, tile);
+        map
+    }
+
+    /// The values of a tile.  The map starts a multiple of eight bytes past a page boundary, so
+    /// the values are aligned.
+    fn values(map: &Mmap) -> &[u64] {
+        // SAFETY: Every bit pattern is a valid u64.
+        let (prefix, values, suffix) = unsafe { map.align_to::<u64>() };
+        assert!(prefix.is_empty() && suffix.is_empty());
+        values
+    }
+
+    /// The similarities between item `row` and the items in `columns`, in order, mapping each
+    /// tile once for a run of columns in the same tile.
+    fn row_subset<'a>(
+        &'a self,
+        row: usize,
+        columns: &'a [usize],
+    ) -> impl Iterator<Item = f64> + 'a {
+        let (tile_row, ii) = (row / self.tile_size, row % self.tile_size);
+        let mut current: Option<(usize, Arc<Mmap>)> = None;
+        columns.iter().map(move |j| {
+            let (tile_column, jj) = (j / self.tile_size, j % self.tile_size);
+            let tile = tile_column * self.n_tiles + tile_row;
+            if current.as_ref().map(|x| x.0) != Some(tile) {
+                current = Some((tile, self.tile(tile)));
+            }
+            let map = &current.as_ref().unwrap().1;
+            f64::from_bits(u64::from_le(Self::values(map)[jj * self.tile_size + ii]))
+        })
+    }
+}
+
+impl Similarity for &TiledSimilarity {
+    fn n_items(&self) -> usize {
+        self.n_items
+    }
+
+    fn get(&self, i: usize, j: usize) -> f64 {
+        self.row_subset(i, &[j]).next().unwrap()
+    }
+
+    fn sum_of_row_subset(&self, row: usize, columns: &[usize]) -> f64 {
+        lane_sum(self.row_subset(row, columns))
+    }
+
+    fn sum_of_row_subset_compensated(&self, row: usize, columns: &[usize]) -> f64 {
+        compensated_sum(self.row_subset(row, columns))
+    }
+}
+
+/// A similarity stored on disk as a column-major `n_items` by `n_items` matrix of native-endian
+/// `f64` values without a header (e.g., the backing file of a file-backed `bigmemory::big.matrix`).
+///
+/// The file is memory-mapped, so only the pages touched by the sampler are read.  The file must not
+/// be modified while it is mapped.
+#[derive(Debug)]
+pub struct MappedSimilarity {
+    map: Mmap,
+    n_items: usize,
+}
+
+impl MappedSimilarity {
+    /// Map the file at `path`, checking that its length is that of an `n_items` by `n_items`
+    /// matrix of `f64` values.
+    pub fn open<P: AsRef<Path>>(path: P, n_items: usize) -> Result<Self> {
+        let invalid = |message: &str| Error::new(ErrorKind::InvalidData, message.to_string());
+        if n_items == 0 {
+            return Err(invalid("the number of items must be positive"));
+        }
+        let file = File::open(path)?;
+        let n_bytes = n_items
+            .checked_mul(n_items)
+            .and_then(|x| x.checked_mul(std::mem::size_of::<f64>()));
+        if n_bytes.map(|x| x as u64) != Some(file.metadata()?.len()) {
+            return Err(invalid(
+                "the file is not the length of a square matrix of doubles of that size",
+            ));
+        }
+        // SAFETY: The mapping is read-only and the caller is told not to modify the file.
+        let map = unsafe { Mmap::map(&file)? };
+        Ok(Self { map, n_items })
+    }
+
+    pub fn n_items(&self) -> usize {
+        self.n_items
+    }
+
+    /// The mapped data, which starts on a page boundary and is therefore aligned for `f64`.
+    pub fn data(&self) -> &[f64] {
+        // SAFETY: Every bit pattern is a valid f64.
+        let (prefix, data, suffix) = unsafe { self.map.align_to::<f64>() };
+        assert!(prefix.is_empty() && suffix.is_empty());
+        data
+    }
+
+    pub fn view(&self) -> SquareMatrixBorrower<'_> {
+        SquareMatrixBorrower::from_slice(self.data(), self.n_items)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::epa::{sample, EpaParameters, SquareMatrixBorrower};
+    use crate::perm::Permutation;
+    use rand::SeedableRng;
+
+    fn draw<M: Similarity>(similarity: M) -> Vec<usize> {
+        let mut rng = rand::rngs::StdRng::seed_from_u64(3);
+        let n_items = similarity.n_items();
+        let mut parameters =
+            EpaParameters::new(similarity, Permutation::natural(n_items), 1.0).unwrap();
+        parameters.shuffle_permutation(&mut rng);
+        sample(&parameters, &mut rng).allocation().to_vec()
+    }
+
+    #[test]
+    fn test_tiled_similarity() {
+        let n_items = 7;
+        let data: Vec<_> = (0..n_items * n_items)
+            .map(|x| 1.0 / (1.0 + ((x % n_items) as f64 - (x / n_items) as f64).abs()))
+            .collect();
+        let dense = SquareMatrixBorrower::from_slice(&data, n_items);
+        let path = std::env::temp_dir().join(format!("epa-tiled-{}", std::process::id()));
+        TiledSimilarity::write(&path, n_items, 3, |i, j| dense[(i, j)]).unwrap();
+        let tiled = TiledSimilarity::open(&path, f64::INFINITY).unwrap();
+        for i in 0..n_items {
+            for j in 0..n_items {
+                assert_eq!((&tiled).get(i, j), dense[(i, j)]);
+            }
+        }
+        let columns = [6, 0, 3, 4];
+        assert_eq!(
+            (&tiled).sum_of_row_subset(2, &columns),
+            dense.sum_of_row_subset(2, &columns)
+        );
+        assert_eq!(draw(&tiled), draw(dense));
+        drop(tiled);
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn test_tiled_similarity_eviction() {
+        let n_items = 7;
+        let data: Vec<_> = (0..n_items * n_items).map(|x| x as f64).collect();
+        let dense = SquareMatrixBorrower::from_slice(&data, n_items);
+        let path = std::env::temp_dir().join(format!("epa-evict-{}", std::process::id()));
+        TiledSimilarity::write(&path, n_items, 3, |i, j| dense[(i, j)]).unwrap();
+        assert!(TiledSimilarity::open(&path, 0.0).is_err());
+        // Tiles of 72 bytes, with room for two.
+        let tiled = TiledSimilarity::open(&path, 150e-9).unwrap();
+        assert_eq!(tiled.max_resident_tiles(), 2);
+        let resident = || {
+            let mut x: Vec<_> = tiled.cache.lock().unwrap().tiles.keys().copied().collect();
+            x.sort_unstable();
+            x
+        };
+        // Items 0, 3, and 6 are in tiles 0, 1, and 2 of the first tile column.
+        assert_eq!((&tiled).get(0, 0), dense[(0, 0)]);
+        assert_eq!((&tiled).get(3, 0), dense[(3, 0)]);
+        assert_eq!(resident(), [0, 1]);
+        assert_eq!((&tiled).get(0, 1), dense[(0, 1)]);
+        assert_eq!((&tiled).get(6, 0), dense[(6, 0)]);
+        assert_eq!(resident(), [0, 2]);
+        for i in 0..n_items {
+            for j in 0..n_items {
+                assert_eq!((&tiled).get(i, j), dense[(i, j)]);
+            }
+        }
+        assert_eq!(resident().len(), 2);
+        let columns = [6, 0, 3, 4];
+        assert_eq!(
+            (&tiled).sum_of_row_subset(2, &columns),
+            dense.sum_of_row_subset(2, &columns)
+        );
+        assert_eq!(draw(&tiled), draw(dense));
+        drop(tiled);
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn test_mapped_similarity() {
+        let n_items = 5;
+        let data: Vec<_> = (0..n_items * n_items)
+            .map(|x| (x % 7) as f64 + 0.5)
+            .collect();
+        let path = std::env::temp_dir().join(format!("epa-mapped-{}", std::process::id()));
+        let bytes: Vec<_> = data.iter().flat_map(|x| x.to_ne_bytes()).collect();
+        std::fs::write(&path, bytes).unwrap();
+        assert!(MappedSimilarity::open(&path, n_items + 1).is_err());
+        let mapped = MappedSimilarity::open(&path, n_items).unwrap();
+        assert_eq!(mapped.data(), &data[..]);
+        let dense = SquareMatrixBorrower::from_slice(&data, n_items);
+        assert_eq!(draw(mapped.view()), draw(dense));
+        drop(mapped);
+        std::fs::remove_file(&path).unwrap();
+    }
+}
@@ -0,0 +1,166 @@
+// Similarities combined from several views of the same items, such as layers of omics data
+
+use crate::features::fill_columns;
+
+/// How the similarities of the views are combined, after each view is normalized to have mean
+/// off-diagonal similarity 1.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ViewCombination {
+    /// The weighted mean of the normalized similarities.
+    Sum,
+    /// The weighted geometric mean of the normalized similarities, so two items are similar only
+    /// if they are similar in every view with positive weight.
+    Product,
+}
+
+/// The mean off-diagonal element of an `n_items` by `n_items` matrix (column-major).
+fn mean_off_diagonal(view: &[f64], n_items: usize) -> f64 {
+    let sum: f64 = view
+        .chunks(n_items)
+        .enumerate()
+        .map(|(j, column)| column.iter().sum::<f64>() - column[j])
+        .sum();
+    sum / ((n_items * (n_items - 1)) as f64)
+}
+
+/// Write into `combined` the similarities (column-major) of `n_items` items combined from the
+/// similarities of the `views` (each column-major) with nonnegative `weights`, one per view.
+///
+/// The weights are scaled to sum to one, and each view is divided by its mean off-diagonal
+/// similarity, so that views on different scales contribute according to their weights.  The
+/// combination is done column by column on `n_threads` threads without copying the views.
+pub fn combine_views_into(
+    views: &[&[f64]],
+    n_items: usize,
+    weights: &[f64],
+    combination: ViewCombination,
+    n_threads: usize,
+    combined: &mut [f64],
+) -> Result<(), &'static str> {
+    if views.is_empty() || weights.len() != views.len() {
+        return Err("There must be at least one view and one weight per view.");
+    }
+    if views.iter().any(|view| view.len() != n_items * n_items)
+        || combined.len() != n_items * n_items
+    {
+        return Err("Each view must be a square matrix with one row per item.");
+    }
+    if views.iter().any(|view| view.iter().any(|x| !(x.is_finite() && *x >= 0.0))) {
+        return Err("The similarities of the views must be finite and nonnegative.");
+    }
+    let total: f64 = weights.iter().sum();
+    if weights.iter().any(|w| !(w.is_finite() && *w >= 0.0)) || total <= 0.0 {
+        return Err("The weights must be finite, nonnegative, and not all zero.");
+    }
+    let scales: Vec<f64> = if n_items < 2 {
+        vec![1.0; views.len()]
+    } else {
+        let means: Vec<_> = views.iter().map(|v| mean_off_diagonal(v, n_items)).collect();
+        if means.iter().any(|mean| *mean <= 0.0) {
+            return Err("Each view must have some positive off-diagonal similarity.");
+        }
+        means.iter().map(|mean| 1.0 / mean).collect()
+    };
+    let weights: Vec<_> = weights.iter().map(|w| w / total).collect();
+    let (scales, weights) = (&scales, &weights);
+    fill_columns(combined, n_items, n_threads, |j, column| {
+        let start = n_items * j;
+        for (i, value) in column.iter_mut().enumerate() {
+            let parts = views
+                .iter()
+                .zip(scales.iter().zip(weights))
+                .filter(|(_, (_, w))| **w > 0.0)
+                .map(|(view, (scale, w))| (view[start + i] * scale, *w));
+            *value = match combination {
+                ViewCombination::Sum => parts.map(|(x, w)| w * x).sum(),
+                ViewCombination::Product => parts.map(|(x, w)| x.powf(w)).product(),
+            };
+        }
+    });
+    Ok(())
+}
+
+/// Weights for views learned from `psms`, the pairwise similarity matrices (column-major) of
+/// partitions drawn from each view separately, starting from `weights`.
+///
+/// The weights are chosen so that the partitions are stable across views: each weight is
+/// proportional to the agreement (one minus the mean absolute difference of the co-clustering
+/// probabilities) between the view's matrix and the weighted mean of the matrices.  Views whose
+/// partitions disagree with the consensus lose weight, which in turn moves the consensus toward
+/// the views that agree with one another.  The weights are iterated to a fixed point and sum to
+/// one.
+pub fn stability_weights(psms: &[Vec<f64>], weights: &[f64]) -> Vec<f64> {
+    let total: f64 = weights.iter().sum();
+    let mut weights: Vec<_> = weights.iter().map(|w| w / total).collect();
+    let Some(size) = psms.first().map(|x| x.len()) else {
+        return weights;
+    };
+    if size == 0 {
+        return weights;
+    }
+    let mut consensus = vec![0.0; size];
+    for _ in 0..1000 {
+        consensus.iter_mut().for_each(|x| *x = 0.0);
+        for (psm, w) in psms.iter().zip(&weights) {
+            for (x, p) in consensus.iter_mut().zip(psm) {
+                *x += w * p;
+            }
+        }
+        let agreements: Vec<f64> = psms
+            .iter()
+            .map(|psm| {
+                let distance: f64 = psm.iter().zip(&consensus).map(|(p, q)| (p - q).abs()).sum();
+                1.0 - distance / (size as f64)
+            })
+            .collect();
+        let total: f64 = agreements.iter().sum();
+        let updated: Vec<_> = agreements.iter().map(|a| a / total).collect();
+        let change = updated
+            .iter()
+            .zip(&weights)
+            .map(|(a, b)| (a - b).abs())
+            .fold(0.0, f64::max);
+        weights = updated;
+        if change < 1e-12 {
+            break;
+        }
+    }
+    weights
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_combine_views() {
+        #[rustfmt::skip]
+        let a = [
+            1.0, 2.0, 0.0,
+            2.0, 1.0, 1.0,
+            0.0, 1.0, 1.0,
+        ];
+        let b: Vec<_> = a.iter().map(|x| 10.0 * x).collect();
+        let mut combined = vec![0.0; 9];
+        for combination in [ViewCombination::Sum, ViewCombination::Product] {
+            // A view and a multiple of it combine to the normalized view.
+            combine_views_into(&[&a, &b], 3, &[1.0, 3.0], combination, 2, &mut combined).unwrap();
+            assert!((combined[1] - 2.0).abs() < 1e-12 && (combined[5] - 1.0).abs() < 1e-12);
+            assert_eq!(combined[2], 0.0);
+        }
+        let c = [1.0, 1.0, 1.0, 1.0, 1.0, 4.0, 1.0, 4.0, 1.0];
+        combine_views_into(&[&a, &c], 3, &[1.0, 1.0], ViewCombination::Product, 1, &mut combined)
+            .unwrap();
+        assert!((combined[1] - 1.0).abs() < 1e-12 && combined[2] == 0.0);
+        assert!(combine_views_into(&[&a], 3, &[0.0], ViewCombination::Sum, 1, &mut combined).is_err());
+    }
+
+    #[test]
+    fn test_stability_weights() {
+        let x = vec![1.0, 0.9, 0.9, 1.0];
+        let y = vec![1.0, 0.1, 0.1, 1.0];
+        let weights = stability_weights(&[x.clone(), x, y], &[1.0, 1.0, 1.0]);
+        assert!((weights.iter().sum::<f64>() - 1.0).abs() < 1e-12);
+        assert!(weights[0] == weights[1] && weights[2] < weights[0]);
+    }
+}
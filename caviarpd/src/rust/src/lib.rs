@@ -2,315 +2,4650 @@
 roxido_registration!();
 use roxido::*;
 
+use dahl_partition::PartitionsHolderBorrower;
 use dahl_salso::clustering::Clusterings;
-use dahl_salso::optimize::{minimize_by_salso, SALSOParameters};
+use dahl_salso::log2cache::Log2Cache;
+use dahl_salso::loss::compute_loss_multiple;
+use dahl_salso::optimize::{
+    minimize_by_salso, BinderCMLossComputer, SALSOParameters, VICMLossComputer,
+};
 use dahl_salso::{LabelType, LossFunction, PartitionDistributionInformation};
-use epa::epa::{sample, EpaParameters, SquareMatrixBorrower};
+use epa::align::{align_to_reference, cluster_events, ContingencyTable};
+use epa::api::{
+    caviarpd_with_observer, expected_number_of_clusters, find_mass, kept_candidates, preview,
+    salso_alternatives, sample_crp, sample_epa as sample_epa_draws, sample_psm,
+    search_loss_parameter, simulation_study, trimmed_candidates, CaviarpdOptions, Draws, Loss,
+    Observer, ParameterSearch, SalsoInfo, SalsoOptions, SamplerOptions, Snapshot,
+};
+use epa::clust::{FromUsize, UnitIncrementor};
+use epa::distance::{pairwise_distances, variation_of_information, PartitionDistance};
+use epa::epa::Similarity as _;
+use epa::epa::{
+    allocation_probabilities, first_invalid_similarity, Anchors, Attraction, Constraints,
+    EpaParameters, Noise, QuantizedMatrix, SquareMatrix, SquareMatrixBorrower,
+};
+use epa::features::{
+    correlation_similarity_into, data_distance_into, gower_similarity_into,
+    similarity_from_distance_in_place, sparsify_nearest_neighbors, Correlation,
+    CorrelationTransform, DataDistance, FeatureSimilarity, GowerColumn, SparseFeatures,
+};
+use epa::fit::fit_parameters;
+use epa::loss::{anneal, PsmLoss, PsmLossState};
+use epa::map::{canonical, map_search};
+use epa::ordering::PermutationStrategy;
 use epa::perm::Permutation;
+use epa::seriation::seriate;
+use epa::similarity::{
+    balanced_partition, normalize_rows, perturb_similarity, repair_similarity,
+    similarity_from_partition, simulate_similarity, sub_similarity, validate_similarity,
+    SimilarityRepair,
+};
+use epa::subjects::{
+    replicate_consistency, replicates_of_subjects, subject_similarity, Aggregation,
+};
+use epa::summary::{
+    block_fit, cluster_similarities, credible_ball, item_disagreement, item_uncertainty, silhouette,
+};
+use epa::temporal::smooth_estimates;
+use epa::tiled::{MappedSimilarity, TiledSimilarity};
+use epa::views::{combine_views_into, stability_weights, ViewCombination};
 use rand::prelude::SliceRandom;
 use rand::Rng;
 use rand::SeedableRng;
-use rand_distr::{Beta, Distribution};
 use rand_pcg::Pcg64Mcg;
-use roots::find_root_regula_falsi as find_root;
 use std::convert::TryFrom;
+use std::sync::Arc;
+use std::time::Instant;
 
-fn sample_epa_engine<T: Rng>(
+// The similarity matrix is either borrowed from R as doubles, copied into single precision, or
+// quantized to 16 bits, as given by R's 'precision' of "double", "single", or "quantized".
+enum Similarity<'a> {
+    Double(SquareMatrixBorrower<'a>),
+    Single(SquareMatrix<f32>),
+    Quantized(QuantizedMatrix),
+}
+
+impl<'a> Similarity<'a> {
+    fn new(similarity: &'a RMatrix<f64>, precision: &str) -> Self {
+        let n_items = similarity.nrow();
+        validate_similarity(similarity.slice(), n_items, similarity.ncol(), None).stop();
+        match precision {
+            "double" => Self::Double(
+                SquareMatrixBorrower::from_slice(similarity.slice(), n_items).detect_symmetry(),
+            ),
+            "single" => Self::Single(SquareMatrix::from_f64(similarity.slice(), n_items)),
+            "quantized" => Self::Quantized(QuantizedMatrix::from_f64(similarity.slice(), n_items)),
+            _ => stop!("'precision' must be one of 'double', 'single', or 'quantized'."),
+        }
+    }
+
+    fn n_items(&self) -> usize {
+        match self {
+            Self::Double(x) => x.n_items(),
+            Self::Single(x) => x.n_items(),
+            Self::Quantized(x) => x.n_items(),
+        }
+    }
+}
+
+// The similarity as a matrix of doubles, which may be given as an integer or logical matrix (e.g.,
+// co-occurrence counts or the adjacency matrix of a network) and is then coerced here rather than
+// in R.  If 'normalize', the rows are scaled as by 'normalize_rows' in a copy.
+fn similarity_matrix<'a>(similarity: &'a RObject, normalize: bool, pc: &'a Pc) -> &'a RMatrix<f64> {
+    let matrix = similarity
+        .as_matrix()
+        .stop_str("'similarity' must be a matrix.");
+    if !(matrix.is_f64() || matrix.is_i32() || matrix.is_bool()) {
+        stop!("'similarity' must be a double, integer, or logical matrix.");
+    }
+    let matrix = matrix.to_f64(pc);
+    if !normalize {
+        return matrix;
+    }
+    let n_items = matrix.nrow();
+    if matrix.ncol() != n_items {
+        stop!("'similarity' must be a square matrix.");
+    }
+    let result = RMatrix::<f64>::new(n_items, n_items, pc);
+    result.slice_mut().copy_from_slice(matrix.slice());
+    normalize_rows(result.slice_mut(), n_items);
+    result
+}
+
+// The integer type used to store the cluster labels of draws.  SALSO requires 'LabelType', but
+// wider labels let the sampler alone handle more items than 'LabelType' can label.
+trait Label: FromUsize + UnitIncrementor + Copy + Send + Sync + Into<i64> {}
+
+impl Label for u16 {}
+impl Label for u32 {}
+
+// SALSO stores labels and cluster counts as 'LabelType', so it cannot handle more items.
+fn check_n_items_for_salso(n_items: usize) {
+    if n_items == 0 {
+        stop!("There must be at least one item.");
+    }
+    let max = LabelType::MAX as usize - 1;
+    if n_items > max {
+        stop!(
+            "The number of items ({}) exceeds the maximum of {} supported by the SALSO step.",
+            n_items,
+            max
+        );
+    }
+}
+
+fn check_discount(discount: f64) {
+    if !(0.0..1.0).contains(&discount) {
+        stop!("'discount' must be in [0, 1), but is {}.", discount);
+    }
+}
+
+fn check_mass(mass: f64, discount: f64) {
+    check_discount(discount);
+    if !(mass.is_finite() && mass > -discount) {
+        stop!(
+            "'mass' must be finite and greater than {}, but is {}.",
+            -discount,
+            mass
+        );
+    }
+}
+
+// Tuning parameters of SALSO which R may optionally supply as a named list.  The time budget
+// applies to each call of SALSO.  In strict mode (see 'SamplerOptions::strict'), SALSO runs on one
+// thread with no time budget.
+struct SalsoControl {
+    options: SalsoOptions,
+    strict: bool,
+}
+
+impl SalsoControl {
+    fn new(x: &RObject, strict: bool) -> Self {
+        let mut result = Self {
+            options: SalsoOptions::default(),
+            strict,
+        };
+        if x.is_null() {
+            return result;
+        }
+        let list = x.as_list().stop_str("'salsoControl' must be a named list.");
+        let mut map = list.make_map();
+        let count = |x: &RObject, name: &str| -> u32 {
+            x.as_scalar()
+                .ok()
+                .and_then(|x| x.usize().ok())
+                .and_then(|x| u32::try_from(x).ok())
+                .stop_closure(|| format!("'{}' must be a nonnegative integer.", name))
+        };
+        let probability = |x: &RObject, name: &str| -> f64 {
+            let x = x
+                .as_scalar()
+                .stop_closure(|| format!("'{}' must be a scalar.", name))
+                .f64();
+            if !(0.0..=1.0).contains(&x) {
+                stop!("'{}' must be in [0, 1].", name);
+            }
+            x
+        };
+        if let Ok(x) = map.get("maxScans") {
+            result.options.max_scans = count(x, "maxScans");
+        }
+        if let Ok(x) = map.get("maxZealousUpdates") {
+            result.options.max_zealous_updates = count(x, "maxZealousUpdates");
+        }
+        if let Ok(x) = map.get("probSequentialAllocation") {
+            result.options.prob_sequential_allocation = probability(x, "probSequentialAllocation");
+        }
+        if let Ok(x) = map.get("probSingletonsInitialization") {
+            result.options.prob_singletons_initialization =
+                probability(x, "probSingletonsInitialization");
+        }
+        if let Ok(x) = map.get("seconds") {
+            result.options.seconds = x.as_scalar().stop_str("'seconds' must be a scalar.").f64();
+            if result.options.seconds.is_nan() || result.options.seconds <= 0.0 {
+                stop!("'seconds' must be greater than 0.");
+            }
+        }
+        if strict {
+            result.options.seconds = f64::INFINITY;
+        }
+        map.exhaustive().stop();
+        result
+    }
+
+    fn n_cores(&self, n_cores: usize) -> u32 {
+        if self.strict {
+            return 1;
+        }
+        u32::try_from(n_cores).stop_str("The number of cores is too large.")
+    }
+}
+
+// SALSO takes its tuning parameters as narrower integers than R provides.
+fn salso_parameters(
+    n_items: usize,
+    max_size: i32,
+    n_runs: i32,
+    control: &SalsoControl,
+) -> SALSOParameters {
+    SALSOParameters {
+        n_items,
+        max_size: LabelType::try_from(max_size).stop_closure(|| {
+            format!(
+                "The maximum number of clusters must be between 0 and {}.",
+                LabelType::MAX
+            )
+        }),
+        max_size_as_rf: false,
+        max_scans: control.options.max_scans,
+        max_zealous_updates: control.options.max_zealous_updates,
+        n_runs: u32::try_from(n_runs).stop_str("The number of runs must be nonnegative."),
+        prob_sequential_allocation: control.options.prob_sequential_allocation,
+        prob_singletons_initialization: control.options.prob_singletons_initialization,
+    }
+}
+
+fn available_threads() -> usize {
+    std::thread::available_parallelism()
+        .map(|x| x.get())
+        .unwrap_or(1)
+}
+
+// Zero cores means all available threads.
+fn n_threads(n_cores: usize) -> usize {
+    if n_cores == 0 {
+        available_threads()
+    } else {
+        n_cores
+    }
+}
+
+extern "C" {
+    fn clock() -> std::os::raw::c_long;
+    fn DATAPTR_RO(x: SEXP) -> *const std::os::raw::c_void;
+}
+
+// The CPU time of the process, summed over its threads.  (On Windows, this is the wall time.)
+fn cpu_seconds() -> f64 {
+    let clocks_per_second = if cfg!(windows) { 1e3 } else { 1e6 };
+    (unsafe { clock() }) as f64 / clocks_per_second
+}
+
+// The resource usage of a call, which is reported as the 'resources' attribute of its result when
+// requested.  Phases of the same name accumulate their times.
+struct Resources {
+    enabled: bool,
+    threads: usize,
+    phases: Vec<(&'static str, f64, f64)>,
+}
+
+impl Resources {
+    fn new(enabled: bool, threads: usize) -> Self {
+        Self {
+            enabled,
+            threads,
+            phases: Vec::new(),
+        }
+    }
+
+    fn time<R>(&mut self, phase: &'static str, f: impl FnOnce() -> R) -> R {
+        if !self.enabled {
+            return f();
+        }
+        let cpu = cpu_seconds();
+        let start = Instant::now();
+        let result = f();
+        let cpu = cpu_seconds() - cpu;
+        let elapsed = start.elapsed().as_secs_f64();
+        match self.phases.iter_mut().find(|x| x.0 == phase) {
+            Some(x) => {
+                x.1 += cpu;
+                x.2 += elapsed;
+            }
+            None => self.phases.push((phase, cpu, elapsed)),
+        }
+        result
+    }
+
+    fn attach<'a, T: RObjectVariant>(&self, result: &'a mut T, pc: &Pc) -> &'a mut T {
+        if !self.enabled {
+            return result;
+        }
+        let bytes_to_r = r_bytes(result.as_robject());
+        let phases = RList::with_names(&["phase", "cpuSeconds", "elapsedSeconds"], pc);
+        let names: Vec<_> = self.phases.iter().map(|x| x.0).collect();
+        let cpu: Vec<_> = self.phases.iter().map(|x| x.1).collect();
+        let elapsed: Vec<_> = self.phases.iter().map(|x| x.2).collect();
+        phases.set(0, names.as_slice().to_r(pc)).stop();
+        phases.set(1, cpu.as_slice().to_r(pc)).stop();
+        phases.set(2, elapsed.as_slice().to_r(pc)).stop();
+        let list = RList::with_names(&["bytesToR", "threads", "phases"], pc);
+        list.set(0, (bytes_to_r as f64).to_r(pc)).stop();
+        list.set(1, i32::try_from(self.threads).unwrap().to_r(pc))
+            .stop();
+        list.set(2, phases).stop();
+        result.set_attribute(RSymbol::from("resources").unwrap(), list);
+        result
+    }
+}
+
+// The bytes of data in an R result (ignoring attributes), counting a pointer per string.
+fn r_bytes(x: &RObject) -> usize {
+    if let Ok(list) = x.as_list() {
+        (0..list.len()).map(|i| r_bytes(list.get(i).unwrap())).sum()
+    } else if let Ok(vector) = x.as_vector() {
+        let size = if vector.is_f64() {
+            8
+        } else if vector.is_i32() || vector.is_bool() {
+            4
+        } else if vector.is_u8() {
+            1
+        } else {
+            std::mem::size_of::<usize>()
+        };
+        vector.len() * size
+    } else {
+        0
+    }
+}
+
+fn sample_epa_engine<L: Label, T: Rng>(
+    n_samples: usize,
+    similarity: &Similarity,
+    mass: f64,
+    discount: f64,
+    conditions: &Conditions,
+    n_cores: usize,
+    rng: &mut T,
+) -> (Vec<L>, Vec<L>) {
+    // Validate here since R errors cannot be raised from the worker threads.
+    check_mass(mass, discount);
+    match similarity {
+        Similarity::Double(x) => {
+            sample_epa_engine_generic(n_samples, *x, mass, discount, conditions, n_cores, rng)
+        }
+        Similarity::Single(x) => {
+            let x = x.view().detect_symmetry();
+            sample_epa_engine_generic(n_samples, x, mass, discount, conditions, n_cores, rng)
+        }
+        Similarity::Quantized(x) => sample_epa_engine_generic(
+            n_samples,
+            x.view(),
+            mass,
+            discount,
+            conditions,
+            n_cores,
+            rng,
+        ),
+    }
+}
+
+// How the sampler turns an item's similarities into its attraction to clusters, by name in R.
+const ATTRACTIONS: [(&str, Attraction); 3] = [
+    ("raw", Attraction::Raw),
+    ("softmax", Attraction::Softmax),
+    ("rank", Attraction::Rank),
+];
+
+// How the sampler orders the items for each draw, by name in R.
+const PERMUTATIONS: [(&str, PermutationStrategy); 3] = [
+    ("uniform", PermutationStrategy::Uniform),
+    ("totalSimilarity", PermutationStrategy::TotalSimilarity),
+    ("fiedler", PermutationStrategy::Fiedler),
+];
+
+// Constraints from a list with optional elements 'must' and 'cannot', each a two-column matrix
+// whose rows are pairs of items (starting at one), or none if 'x' is NULL.
+fn constraints_from_r(x: &RObject, n_items: usize, pc: &Pc) -> Option<Arc<Constraints>> {
+    if x.is_null() {
+        return None;
+    }
+    let list = x
+        .as_list()
+        .stop_str("'constraints' must be NULL or a named list.");
+    let mut map = list.make_map();
+    let mut pairs = |name: &str| -> Vec<(usize, usize)> {
+        let Ok(x) = map.get(name) else {
+            return Vec::new();
+        };
+        let x = x
+            .as_matrix()
+            .stop_closure(|| format!("'{}' must be a matrix.", name))
+            .to_i32(pc);
+        if x.ncol() != 2 {
+            stop!("'{}' must have two columns.", name);
+        }
+        let item = |k: &i32| {
+            usize::try_from(*k)
+                .ok()
+                .filter(|k| *k >= 1)
+                .map(|k| k - 1)
+                .stop_closure(|| format!("The items of '{}' must be at least 1.", name))
+        };
+        let (first, second) = x.slice().split_at(x.nrow());
+        first
+            .iter()
+            .zip(second)
+            .map(|(i, j)| (item(i), item(j)))
+            .collect()
+    };
+    let must = pairs("must");
+    let cannot = pairs("cannot");
+    map.exhaustive().stop();
+    Some(Arc::new(Constraints::new(n_items, &must, &cannot).stop()))
+}
+
+// What EPA draws are conditioned on: constraints between pairs of items, the labels of the
+// anchored items, and the multiplicities of the items.  It also carries how the draws are made:
+// the other options of the sampler and whether the pairwise similarity matrix of the draws averages
+// the probabilities of the allocations made while sampling rather than the allocations themselves.
+#[derive(Default)]
+struct Conditions {
+    options: SamplerOptions,
+    rao_blackwell: bool,
+}
+
+impl Conditions {
+    // The options of the sampler, conditioned on these.
+    fn sampler_options(&self) -> SamplerOptions {
+        self.options.clone()
+    }
+
+    // The sampler is NULL for the default options or a list as made by R's 'samplerOptions'.
+    fn with_sampler(mut self, sampler: &RObject) -> Self {
+        if sampler.is_null() {
+            return self;
+        }
+        let mut map = sampler
+            .as_list()
+            .stop_str("'sampler' must be NULL or a named list.")
+            .make_map();
+        fn element<'a>(map: &'a mut RListMap, name: &str) -> &'a RScalar {
+            map.get(name)
+                .stop_closure(|| format!("'sampler' must have an element named '{}'.", name))
+                .as_scalar()
+                .stop_closure(|| format!("'{}' of 'sampler' must be a scalar.", name))
+        }
+        let flag = |map: &mut RListMap, name: &str| {
+            element(map, name)
+                .bool()
+                .stop_closure(|| format!("'{}' of 'sampler' must be TRUE or FALSE.", name))
+        };
+        let string = |map: &mut RListMap, name: &str| {
+            element(map, name)
+                .as_char()
+                .ok()
+                .and_then(|x| x.get().ok())
+                .stop_closure(|| format!("'{}' of 'sampler' must be a string.", name))
+                .to_string()
+        };
+        self.options.compensated = flag(&mut map, "compensated");
+        self.options.strict = flag(&mut map, "strict");
+        let attraction = string(&mut map, "attraction");
+        self.options.attraction = ATTRACTIONS
+            .iter()
+            .find(|x| x.0 == attraction)
+            .stop_str("'attraction' must be one of 'raw', 'softmax', or 'rank'.")
+            .1;
+        self.options.subset_size = match element(&mut map, "subsetSize").usize() {
+            Ok(0) => None,
+            Ok(size) => Some(size),
+            Err(_) => stop!("'subsetSize' must be a nonnegative integer."),
+        };
+        let permutation = string(&mut map, "permutation");
+        self.options.permutation = PERMUTATIONS
+            .iter()
+            .find(|x| x.0 == permutation)
+            .stop_str("'permutation' must be one of 'uniform', 'totalSimilarity', or 'fiedler'.")
+            .1;
+        self.options.fixed_permutation = flag(&mut map, "fixedPermutation");
+        self.rao_blackwell = flag(&mut map, "raoBlackwell");
+        map.exhaustive().stop();
+        self
+    }
+
+    // Anchors are NULL or a vector with the label of each item, NA for a free item.
+    // Multiplicities are NULL or a vector with the positive multiplicity of each item.
+    fn from_r(
+        constraints: &RObject,
+        anchors: &RObject,
+        multiplicities: &RObject,
+        n_items: usize,
+        pc: &Pc,
+    ) -> Self {
+        let constraints = constraints_from_r(constraints, n_items, pc);
+        let anchors = if anchors.is_null() {
+            None
+        } else {
+            let labels = anchors
+                .as_vector()
+                .stop_str("'anchors' must be NULL or a vector.")
+                .to_i32(pc);
+            if labels.len() != n_items {
+                stop!("'anchors' must have one element per item.");
+            }
+            let labels: Vec<_> = labels
+                .slice()
+                .iter()
+                .map(|x| (!R::is_na_i32(*x)).then_some(*x as usize))
+                .collect();
+            Some(Arc::new(Anchors::new(&labels)))
+        };
+        if let (Some(constraints), Some(anchors)) = (&constraints, &anchors) {
+            if !constraints.is_satisfied_by(anchors.labels()) {
+                stop!("The anchors violate the constraints.");
+            }
+        }
+        let multiplicities = if multiplicities.is_null() {
+            None
+        } else {
+            let x = multiplicities
+                .as_vector()
+                .stop_str("'multiplicities' must be NULL or a vector.")
+                .to_i32(pc);
+            if x.len() != n_items {
+                stop!("'multiplicities' must have one element per item.");
+            }
+            let x: Vec<_> = x
+                .slice()
+                .iter()
+                .map(|m| {
+                    usize::try_from(*m)
+                        .ok()
+                        .filter(|m| *m > 0)
+                        .stop_str("'multiplicities' must be positive integers.")
+                })
+                .collect();
+            Some(Arc::from(x))
+        };
+        Self {
+            options: SamplerOptions {
+                constraints,
+                anchors,
+                multiplicities,
+                ..SamplerOptions::default()
+            },
+            ..Self::default()
+        }
+    }
+
+    // Noise is NULL or a list with the 'threshold' on the total similarity of an item to the
+    // others below which it may be noise and the 'weight' of the noise pseudo-cluster.
+    fn with_noise(mut self, noise: &RObject, similarity: &Similarity) -> Self {
+        if noise.is_null() {
+            return self;
+        }
+        if self.options.constraints.is_some() {
+            stop!("'noise' cannot be combined with constraints.");
+        }
+        let mut map = noise
+            .as_list()
+            .stop_str("'noise' must be NULL or a named list.")
+            .make_map();
+        let mut get = |name: &str| {
+            map.get(name)
+                .stop_closure(|| format!("'noise' must have an element named '{}'.", name))
+                .as_scalar()
+                .stop_closure(|| format!("'{}' of 'noise' must be a scalar.", name))
+                .f64()
+        };
+        let (threshold, weight) = (get("threshold"), get("weight"));
+        if threshold.is_nan() {
+            stop!("'threshold' of 'noise' must not be NA.");
+        }
+        let noise = match similarity {
+            Similarity::Double(x) => Noise::new(x, threshold, weight),
+            Similarity::Single(x) => Noise::new(&x.view(), threshold, weight),
+            Similarity::Quantized(x) => Noise::new(&x.view(), threshold, weight),
+        };
+        let noise = noise.stop_str("'weight' of 'noise' must be finite and positive.");
+        self.options.noise = Some(Arc::new(noise));
+        self
+    }
+
+    // Compute the heuristic order of the items for the sampler once, since the draws for every mass
+    // are from the same similarity.
+    fn with_order(mut self, similarity: &Similarity) -> Self {
+        self.options = match similarity {
+            Similarity::Double(x) => self.options.with_order(*x),
+            Similarity::Single(x) => self.options.with_order(x.view()),
+            Similarity::Quantized(x) => self.options.with_order(x.view()),
+        };
+        self
+    }
+
+    // The labels of an estimate for R, starting at one, except that noise items are labeled zero.
+    fn labels_to_r(&self, clustering: &[usize]) -> Vec<i32> {
+        match &self.options.noise {
+            Some(noise) => noise
+                .relabel(clustering)
+                .iter()
+                .map(|x| x.map_or(0, |x| i32::try_from(x + 1).unwrap()))
+                .collect(),
+            None => clustering
+                .iter()
+                .map(|x| i32::try_from(*x + 1).unwrap())
+                .collect(),
+        }
+    }
+}
+
+fn fnv1a(values: impl IntoIterator<Item = u64>) -> u64 {
+    let mut hash: u64 = 0xcbf2_9ce4_8422_2325;
+    for value in values {
+        for byte in value.to_le_bytes() {
+            hash ^= u64::from(byte);
+            hash = hash.wrapping_mul(0x0100_0000_01b3);
+        }
+    }
+    hash
+}
+
+// Check that the random number generator and, in strict mode, the sampler give the expected
+// results, which were recorded once and must agree on every platform and for any number of
+// threads.  The similarities are ratios of small integers, so they are exact on every platform.
+#[roxido]
+fn caviarpd_self_test() {
+    let seed = 0x5eed_cab1_e5ca_fe00_u128;
+    let mut rng = Pcg64Mcg::new(seed);
+    let mut values = vec![
+        rng.random::<u64>(),
+        rng.random::<f64>().to_bits(),
+        u64::from(rng.random_range(0..1000_u32)),
+    ];
+    let mut permutation: Vec<u64> = (0..20).collect();
+    permutation.shuffle(&mut rng);
+    values.extend(permutation);
+    let rng_ok = fnv1a(values) == 0xe5d1_38e8_bb42_e8b4;
+    let n_items = 12;
+    let mut data = vec![0.0; n_items * n_items];
+    for j in 0..n_items {
+        for i in 0..n_items {
+            data[n_items * j + i] = 1.0 / (1.0 + i.abs_diff(j) as f64);
+        }
+    }
+    let similarity = SquareMatrixBorrower::from_slice(&data, n_items);
+    let conditions = Conditions {
+        options: SamplerOptions {
+            strict: true,
+            ..SamplerOptions::default()
+        },
+        ..Conditions::default()
+    };
+    let draws: Vec<_> = [1, 2, 3]
+        .into_iter()
+        .map(|n_cores| {
+            let mut rng = Pcg64Mcg::new(seed);
+            let (samples, _) = sample_epa_engine_generic::<u32, _, _>(
+                25,
+                similarity,
+                1.5,
+                0.2,
+                &conditions,
+                n_cores,
+                &mut rng,
+            );
+            fnv1a(samples.into_iter().map(u64::from))
+        })
+        .collect();
+    let threads_ok = draws.iter().all(|x| *x == draws[0]);
+    let draws_ok = draws[0] == 0x32b1_7de0_167e_c7e2;
+    let result = RList::with_names(&["check", "passed"], pc);
+    result
+        .set(0, ["rngStream", "threadInvariance", "draws"].to_r(pc))
+        .stop();
+    result
+        .set(1, [rng_ok, threads_ok, draws_ok].to_r(pc))
+        .stop();
+    result
+}
+
+fn sample_epa_engine_generic<L: Label, M: epa::epa::Similarity, T: Rng>(
+    n_samples: usize,
+    similarity: M,
+    mass: f64,
+    discount: f64,
+    conditions: &Conditions,
+    n_cores: usize,
+    rng: &mut T,
+) -> (Vec<L>, Vec<L>) {
+    let options = conditions.sampler_options();
+    sample_epa_draws(
+        n_samples,
+        similarity,
+        mass,
+        discount,
+        n_threads(n_cores),
+        options,
+        rng,
+    )
+}
+
+// The layout of the draws returned to R: either a matrix whose rows are the draws of the grid
+// points in turn, or (if 'grouped') an array of draws by items by grid points.
+struct DrawsLayout {
+    n_samples: usize,
+    n_points: usize,
+    n_items: usize,
+    grouped: bool,
+}
+
+impl DrawsLayout {
+    fn dim(&self) -> Vec<usize> {
+        if self.grouped {
+            vec![self.n_samples, self.n_items, self.n_points]
+        } else {
+            vec![self.n_samples * self.n_points, self.n_items]
+        }
+    }
+
+    // The position of an item of the draw 'j' of grid point 'i'.
+    fn index(&self, i: usize, j: usize, item: usize) -> usize {
+        if self.grouped {
+            self.n_samples * (self.n_items * i + item) + j
+        } else {
+            self.n_samples * (self.n_points * item + i) + j
+        }
+    }
+
+    // The column-major matrix holding the draws of grid point 'i', with its number of rows and the
+    // first of those rows, as for 'write_draws'.
+    fn block<'a>(&self, samples: &'a mut [i32], i: usize) -> (&'a mut [i32], usize, usize) {
+        let size = self.n_samples * self.n_items;
+        if self.grouped {
+            (&mut samples[size * i..size * (i + 1)], self.n_samples, 0)
+        } else {
+            (samples, self.n_samples * self.n_points, self.n_samples * i)
+        }
+    }
+}
+
+// Relabels each draw so that its clusters take the labels of the clusters of the estimate with
+// which they overlap most. Noise items of the estimate count as singletons, and clusters without a
+// counterpart get labels after those of the estimate.
+fn align_samples(
+    samples: &mut [i32],
+    layout: &DrawsLayout,
+    estimate_labels: &[i32],
+    n_cores: usize,
+) {
+    let DrawsLayout {
+        n_samples,
+        n_points,
+        n_items,
+        ..
+    } = *layout;
+    let n_clusters = estimate_labels.iter().max().map_or(0, |x| *x as usize);
+    let mut next = n_clusters;
+    let reference: Vec<_> = estimate_labels
+        .iter()
+        .map(|x| {
+            if *x > 0 {
+                (*x - 1) as usize
+            } else {
+                next += 1;
+                next - 1
+            }
+        })
+        .collect();
+    for i in 0..n_points {
+        let draws: Vec<_> = (0..n_samples).collect();
+        let aligned = parallel_map(&draws, n_cores, |j| {
+            let labels: Vec<_> = (0..n_items)
+                .map(|item| (samples[layout.index(i, *j, item)] - 1) as usize)
+                .collect();
+            align_to_reference(&reference, &canonical(&labels))
+                .into_iter()
+                .map(|x| u32::try_from(x).unwrap())
+                .collect::<Vec<_>>()
+        });
+        let (output, n_rows, first_row) = layout.block(samples, i);
+        write_draws(
+            |j| &aligned[j],
+            n_samples,
+            n_items,
+            output,
+            n_rows,
+            first_row,
+            n_threads(n_cores),
+        );
+    }
+}
+
+// Write draw 'j' (of 'n_draws', each with 'n_items' labels starting at zero) into row
+// 'first_row + j' of the column-major matrix 'output' with 'n_rows' rows, with labels starting at
+// one.  Reading a draw is contiguous but writing it is strided, so the copy goes tile by tile to
+// stay in cache, and large copies divide the columns among up to 'n_threads' threads.
+fn write_draws<'b, L: Label + 'b>(
+    draw: impl Fn(usize) -> &'b [L] + Sync,
+    n_draws: usize,
+    n_items: usize,
+    output: &mut [i32],
+    n_rows: usize,
+    first_row: usize,
+    n_threads: usize,
+) {
+    const TILE_SIZE: usize = 64;
+    if n_draws == 0 || n_items == 0 {
+        return;
+    }
+    let write = |first_column: usize, columns: &mut [i32]| {
+        let n_columns = columns.len() / n_rows;
+        for i0 in (0..n_columns).step_by(TILE_SIZE) {
+            for j0 in (0..n_draws).step_by(TILE_SIZE) {
+                let j1 = (j0 + TILE_SIZE).min(n_draws);
+                for i in i0..(i0 + TILE_SIZE).min(n_columns) {
+                    let start = n_rows * i + first_row;
+                    for (j, x) in (j0..j1).zip(&mut columns[start + j0..start + j1]) {
+                        let label: i64 = draw(j)[first_column + i].into();
+                        *x = (label + 1) as i32;
+                    }
+                }
+            }
+        }
+    };
+    let n_threads = if n_draws * n_items < 1 << 16 {
+        1
+    } else {
+        n_threads.clamp(1, n_items)
+    };
+    if n_threads == 1 {
+        write(0, output);
+        return;
+    }
+    let columns_per_thread = n_items.div_ceil(n_threads);
+    crossbeam::scope(|s| {
+        let write = &write;
+        for (t, columns) in output.chunks_mut(n_rows * columns_per_thread).enumerate() {
+            s.spawn(move |_| write(t * columns_per_thread, columns));
+        }
+    })
+    .unwrap();
+}
+
+fn samples_to_r<'a, L: Label>(
+    samples: &[L],
+    n_items: usize,
+    n_cores: usize,
+    pc: &'a Pc,
+) -> &'a mut RMatrix<i32> {
+    let n_samples = samples.len() / n_items;
+    let result = RMatrix::<i32>::new(n_samples, n_items, pc);
+    let draw = |j: usize| &samples[j * n_items..(j + 1) * n_items];
+    write_draws(
+        draw,
+        n_samples,
+        n_items,
+        result.slice_mut(),
+        n_samples,
+        0,
+        n_threads(n_cores),
+    );
+    result
+}
+
+#[roxido]
+fn sample_epa(
+    n_samples: usize,
+    similarity: &RObject,
+    mass: f64,
+    n_cores: usize,
+    precision: &str,
+    sizes: bool,
+    constraints: &RObject,
+    anchors: &RObject,
+    multiplicities: &RObject,
+    noise: &RObject,
+    normalize: bool,
+    sampler: &RObject,
+    backend: &str,
+) {
+    let mut rng = Pcg64Mcg::from_seed(R::random_bytes::<16>());
+    let similarity = similarity_matrix(similarity, normalize, pc);
+    let similarity = Similarity::new(similarity, precision);
+    let n_items = similarity.n_items();
+    let conditions = Conditions::from_r(constraints, anchors, multiplicities, n_items, pc)
+        .with_noise(noise, &similarity)
+        .with_sampler(sampler);
+    match backend {
+        "cpu" => sample_epa_to_r(
+            n_samples,
+            &similarity,
+            mass,
+            &conditions,
+            n_cores,
+            sizes,
+            &mut rng,
+            pc,
+        ),
+        "gpu" => sample_epa_gpu_to_r(
+            n_samples,
+            &similarity,
+            mass,
+            &conditions,
+            n_cores,
+            sizes,
+            &mut rng,
+            pc,
+        ),
+        _ => stop!("'backend' must be either 'cpu' or 'gpu'."),
+    }
+}
+
+// Like 'sample_epa_to_r', but with the draws made on a GPU.
+#[cfg(feature = "gpu")]
+#[allow(clippy::too_many_arguments)]
+fn sample_epa_gpu_to_r<'a, T: Rng>(
+    n_samples: usize,
+    similarity: &Similarity,
+    mass: f64,
+    conditions: &Conditions,
+    n_cores: usize,
+    sizes: bool,
+    rng: &mut T,
+    pc: &'a Pc,
+) -> &'a mut RList {
+    check_mass(mass, 0.0);
+    let n_items = similarity.n_items();
+    let options = &conditions.options;
+    let (samples, n_clusters) = match similarity {
+        Similarity::Double(x) => {
+            epa::gpu::sample_epa::<u32, _, _>(n_samples, *x, mass, 0.0, options, rng)
+        }
+        Similarity::Single(x) => epa::gpu::sample_epa(n_samples, x.view(), mass, 0.0, options, rng),
+        Similarity::Quantized(x) => {
+            epa::gpu::sample_epa(n_samples, x.view(), mass, 0.0, options, rng)
+        }
+    }
+    .stop();
+    draws_to_r(&samples, &n_clusters, n_items, sizes, n_cores, pc)
+}
+
+#[cfg(not(feature = "gpu"))]
+#[allow(clippy::too_many_arguments)]
+fn sample_epa_gpu_to_r<'a, T: Rng>(
+    _n_samples: usize,
+    _similarity: &Similarity,
+    _mass: f64,
+    _conditions: &Conditions,
+    _n_cores: usize,
+    _sizes: bool,
+    _rng: &mut T,
+    _pc: &'a Pc,
+) -> &'a mut RList {
+    stop!(
+        "The 'gpu' backend requires building the package with the 'gpu' feature of its Rust crate."
+    )
+}
+
+// Draws from the Chinese restaurant process (or Pitman-Yor process) by the EPA sampler with constant
+// similarities, which are neither stored nor summed.
+#[roxido]
+fn caviarpd_sample_crp(
+    n_samples: usize,
+    n_items: usize,
+    mass: f64,
+    discount: f64,
+    n_cores: usize,
+    sizes: bool,
+) {
+    check_mass(mass, discount);
+    if n_items == 0 {
+        stop!("'nItems' must be at least 1.");
+    }
+    let mut rng = Pcg64Mcg::from_seed(R::random_bytes::<16>());
+    let n_threads = n_threads(n_cores);
+    if n_items < LabelType::MAX as usize {
+        let (samples, n_clusters) =
+            sample_crp::<LabelType, _>(n_samples, n_items, mass, discount, n_threads, &mut rng);
+        draws_to_r(&samples, &n_clusters, n_items, sizes, n_cores, pc)
+    } else {
+        let (samples, n_clusters) =
+            sample_crp::<u32, _>(n_samples, n_items, mass, discount, n_threads, &mut rng);
+        draws_to_r(&samples, &n_clusters, n_items, sizes, n_cores, pc)
+    }
+}
+
+// Labels are as narrow as possible, but wide enough for any number of clusters.
+#[allow(clippy::too_many_arguments)]
+fn sample_epa_to_r<'a, T: Rng>(
+    n_samples: usize,
+    similarity: &Similarity,
+    mass: f64,
+    conditions: &Conditions,
+    n_cores: usize,
+    sizes: bool,
+    rng: &mut T,
+    pc: &'a Pc,
+) -> &'a mut RList {
+    let n_items = similarity.n_items();
+    if n_items < LabelType::MAX as usize {
+        let (samples, n_clusters) = sample_epa_engine::<LabelType, _>(
+            n_samples, similarity, mass, 0.0, conditions, n_cores, rng,
+        );
+        draws_to_r(&samples, &n_clusters, n_items, sizes, n_cores, pc)
+    } else {
+        let (samples, n_clusters) =
+            sample_epa_engine::<u32, _>(n_samples, similarity, mass, 0.0, conditions, n_cores, rng);
+        draws_to_r(&samples, &n_clusters, n_items, sizes, n_cores, pc)
+    }
+}
+
+// The draws along with their numbers of clusters and, optionally, their cluster sizes (in order
+// of the cluster labels).
+fn draws_to_r<'a, L: Label>(
+    samples: &[L],
+    n_clusters: &[L],
+    n_items: usize,
+    sizes: bool,
+    n_cores: usize,
+    pc: &'a Pc,
+) -> &'a mut RList {
+    let n_clusters: Vec<_> = n_clusters
+        .iter()
+        .map(|x| {
+            let x: i64 = (*x).into();
+            x as i32
+        })
+        .collect();
+    let sizes_rval = if sizes {
+        let list = RList::new(n_clusters.len(), pc);
+        for (k, (draw, n)) in samples.chunks(n_items).zip(&n_clusters).enumerate() {
+            let mut counts = vec![0; *n as usize];
+            for label in draw {
+                let label: i64 = (*label).into();
+                counts[label as usize] += 1;
+            }
+            list.set(k, counts.as_slice().to_r(pc)).stop();
+        }
+        list.as_robject()
+    } else {
+        R::null()
+    };
+    let result = RList::with_names(&["samples", "nClusters", "sizes"], pc);
+    result
+        .set(0, samples_to_r(samples, n_items, n_cores, pc))
+        .stop();
+    result.set(1, n_clusters.as_slice().to_r(pc)).stop();
+    result.set(2, sizes_rval).stop();
+    result
+}
+
+// What this build supports: its optional cargo features and the CPU features it was compiled for.
+#[roxido]
+fn caviarpd_capabilities() {
+    let features: Vec<_> = [
+        ("gpu", cfg!(feature = "gpu")),
+        ("sse4.2", cfg!(target_feature = "sse4.2")),
+        ("avx2", cfg!(target_feature = "avx2")),
+        ("fma", cfg!(target_feature = "fma")),
+        ("neon", cfg!(target_feature = "neon")),
+    ]
+    .into_iter()
+    .filter(|x| x.1)
+    .map(|x| x.0)
+    .collect();
+    let result = RList::with_names(
+        &[
+            "version",
+            "features",
+            "labelBits",
+            "samplerLabelBits",
+            "maxItems",
+            "samplerMaxItems",
+            "threads",
+            "threadPolicy",
+        ],
+        pc,
+    );
+    result.set(0, env!("CARGO_PKG_VERSION").to_r(pc)).stop();
+    result.set(1, features.as_slice().to_r(pc)).stop();
+    result.set(2, (LabelType::BITS as i32).to_r(pc)).stop();
+    result.set(3, (u32::BITS as i32).to_r(pc)).stop();
+    result.set(4, (LabelType::MAX as i32 - 1).to_r(pc)).stop();
+    result.set(5, f64::from(u32::MAX).to_r(pc)).stop();
+    result.set(6, (available_threads() as i32).to_r(pc)).stop();
+    result
+        .set(
+            7,
+            "Idle threads take blocks of draws from a shared queue; zero cores means all available \
+             threads."
+                .to_r(pc),
+        )
+        .stop();
+    result
+}
+
+// A similarity which R does not hold in its memory, as given by R's 'kind': "matrix" for a double
+// matrix (possibly an ALTREP, e.g., itself memory-mapped) whose data is read in place through
+// 'DATAPTR_RO' rather than coerced or copied, "mapped" for the path of the backing file of a
+// file-backed 'big.matrix' of doubles, or "tiled" for a list of the 'file' written by
+// 'caviarpd_write_tiled' and the 'maxResidentGB' of its tiles kept mapped.  Files are
+// memory-mapped, so only the pages touched are read.
+enum External<'a> {
+    Matrix(&'a [f64]),
+    Mapped(MappedSimilarity),
+    Tiled(TiledSimilarity),
+}
+
+impl<'a> External<'a> {
+    // The similarity, with its size checked against 'n_items'.
+    fn new(similarity: &'a RObject, kind: &str, n_items: usize) -> Self {
+        if n_items == 0 {
+            stop!("'n_items' must be at least one.");
+        }
+        let path = || {
+            similarity
+                .as_scalar()
+                .and_then(|x| x.as_char())
+                .and_then(|x| x.get())
+                .stop_str("'similarity' must be the path of a file.")
+        };
+        match kind {
+            "matrix" => {
+                let matrix = similarity
+                    .as_matrix()
+                    .stop_str("'similarity' must be a matrix.");
+                if !matrix.is_f64() {
+                    stop!("'similarity' must be a double matrix.");
+                }
+                if matrix.dim() != [n_items, n_items] {
+                    stop!("'similarity' must be a {} x {} matrix.", n_items, n_items);
+                }
+                // SAFETY: A double vector of length n_items * n_items, which R keeps alive for the
+                // call.
+                Self::Matrix(unsafe {
+                    std::slice::from_raw_parts(
+                        DATAPTR_RO(matrix.sexp()) as *const f64,
+                        n_items * n_items,
+                    )
+                })
+            }
+            "mapped" => {
+                let path = path();
+                Self::Mapped(
+                    MappedSimilarity::open(path, n_items)
+                        .unwrap_or_else(|e| stop!("Could not map '{}': {}.", path, e)),
+                )
+            }
+            "tiled" => {
+                let list = similarity
+                    .as_list()
+                    .stop_str("'similarity' must be a list.");
+                let path = list
+                    .get_by_key("file")
+                    .ok()
+                    .and_then(|x| x.as_scalar().ok())
+                    .and_then(|x| x.as_char().ok())
+                    .and_then(|x| x.get().ok())
+                    .stop_str("'file' must be the path of a file.");
+                let max_resident_gb = list
+                    .get_by_key("maxResidentGB")
+                    .ok()
+                    .and_then(|x| x.as_scalar().ok())
+                    .map(|x| x.f64())
+                    .filter(|x| *x > 0.0)
+                    .stop_str("'maxResidentGB' must be greater than 0.");
+                let tiled = TiledSimilarity::open(path, max_resident_gb)
+                    .unwrap_or_else(|e| stop!("Could not map '{}': {}.", path, e));
+                if (&tiled).n_items() != n_items {
+                    stop!(
+                        "'{}' has {} items, not {}.",
+                        path,
+                        (&tiled).n_items(),
+                        n_items
+                    );
+                }
+                Self::Tiled(tiled)
+            }
+            _ => stop!("'kind' must be one of 'matrix', 'mapped', or 'tiled'."),
+        }
+    }
+
+    // Stop unless the similarity of 'n_items' items is valid, which reads all of it.
+    fn validate(&self, n_items: usize) {
+        match self {
+            Self::Matrix(data) => validate_similarity(data, n_items, n_items, None),
+            Self::Mapped(x) => validate_similarity(x.data(), n_items, n_items, None),
+            Self::Tiled(x) => match first_invalid_similarity(&x) {
+                Some((i, j)) => Err(format!(
+                    "The similarity must be finite and nonnegative, but [{}, {}] = {}.",
+                    i + 1,
+                    j + 1,
+                    x.get(i, j)
+                )),
+                None => Ok(()),
+            },
+        }
+        .stop();
+    }
+}
+
+// A symmetric similarity is summed along its contiguous columns, but only if the caller declares
+// it 'symmetric', since checking would read all of it with strided reads.  (Tiled similarities
+// are read in tiles either way.)
+#[roxido]
+fn sample_epa_external(
+    n_samples: usize,
+    similarity: &RObject,
+    kind: &str,
+    n_items: usize,
+    symmetric: bool,
+    mass: f64,
+    n_cores: usize,
+    sizes: bool,
+    sampler: &RObject,
+) {
+    let similarity = External::new(similarity, kind, n_items);
+    similarity.validate(n_items);
+    let conditions = Conditions::default().with_sampler(sampler);
+    let mut rng = Pcg64Mcg::from_seed(R::random_bytes::<16>());
+    let (conditions, rng) = (&conditions, &mut rng);
+    match &similarity {
+        External::Matrix(data) => {
+            let x = SquareMatrixBorrower::from_slice(data, n_items).with_symmetry(symmetric);
+            external_draws_to_r(n_samples, x, mass, conditions, n_cores, sizes, rng, pc)
+        }
+        External::Mapped(x) => {
+            let x = x.view().with_symmetry(symmetric);
+            external_draws_to_r(n_samples, x, mass, conditions, n_cores, sizes, rng, pc)
+        }
+        External::Tiled(x) => {
+            external_draws_to_r(n_samples, x, mass, conditions, n_cores, sizes, rng, pc)
+        }
+    }
+}
+
+// Like 'sample_epa_to_r', but for any implementation of the similarity.
+#[allow(clippy::too_many_arguments)]
+fn external_draws_to_r<'a, M: epa::epa::Similarity, T: Rng>(
+    n_samples: usize,
+    similarity: M,
+    mass: f64,
+    conditions: &Conditions,
+    n_cores: usize,
+    sizes: bool,
+    rng: &mut T,
+    pc: &'a Pc,
+) -> &'a mut RList {
+    check_mass(mass, 0.0);
+    let n_items = similarity.n_items();
+    if n_items < LabelType::MAX as usize {
+        let (samples, n_clusters) = sample_epa_engine_generic::<LabelType, _, _>(
+            n_samples, similarity, mass, 0.0, conditions, n_cores, rng,
+        );
+        draws_to_r(&samples, &n_clusters, n_items, sizes, n_cores, pc)
+    } else {
+        let (samples, n_clusters) = sample_epa_engine_generic::<u32, _, _>(
+            n_samples, similarity, mass, 0.0, conditions, n_cores, rng,
+        );
+        draws_to_r(&samples, &n_clusters, n_items, sizes, n_cores, pc)
+    }
+}
+
+// Write the similarity (see 'External') to 'file' in tiles of 'tile_size' items, which
+// 'sample_epa_external' then reads with 'kind' of "tiled".
+#[roxido]
+fn caviarpd_write_tiled(
+    similarity: &RObject,
+    kind: &str,
+    n_items: usize,
+    file: &str,
+    tile_size: usize,
+) {
+    let similarity = External::new(similarity, kind, n_items);
+    similarity.validate(n_items);
+    let result = match &similarity {
+        External::Matrix(data) => {
+            TiledSimilarity::write(file, n_items, tile_size, |i, j| data[n_items * j + i])
+        }
+        External::Mapped(x) => {
+            let x = x.view();
+            TiledSimilarity::write(file, n_items, tile_size, |i, j| x[(i, j)])
+        }
+        External::Tiled(x) => TiledSimilarity::write(file, n_items, tile_size, |i, j| x.get(i, j)),
+    };
+    result.unwrap_or_else(|e| stop!("Could not write '{}': {}.", file, e));
+}
+
+// The state of an EPA sampler held by R: the validated similarity and parameters, the random
+// number generator, and all the draws so far.
+struct Sampler {
+    similarity: SquareMatrix,
+    mass: f64,
+    discount: f64,
+    rng: Pcg64Mcg,
+    samples: Vec<LabelType>,
+    n_clusters: Vec<LabelType>,
+}
+
+const SAMPLER_TAG: &str = "caviarpd_sampler";
+
+fn decode_sampler(sampler: &mut RExternalPtr) -> &mut Sampler {
+    if sampler.tag_str() != SAMPLER_TAG || sampler.is_null() {
+        stop!("'sampler' must be created by 'caviarpd_sampler_new'.");
+    }
+    sampler.decode_mut::<Sampler>()
+}
+
+// A sampler whose similarity is copied and validated once, so that draws may be added
+// incrementally (e.g., until the estimate is stable).
+#[roxido]
+fn caviarpd_sampler_new(similarity: &RMatrix<f64>, mass: f64, discount: f64) {
+    check_discount(discount);
+    check_mass(mass, discount);
+    let Similarity::Double(view) = Similarity::new(similarity, "double") else {
+        unreachable!()
+    };
+    let n_items = view.n_items();
+    check_n_items_for_salso(n_items);
+    let mut owned = SquareMatrix::zeros(n_items);
+    owned.data_mut().copy_from_slice(view.data());
+    let sampler = Sampler {
+        similarity: owned,
+        mass,
+        discount,
+        rng: Pcg64Mcg::from_seed(R::random_bytes::<16>()),
+        samples: Vec::new(),
+        n_clusters: Vec::new(),
+    };
+    RExternalPtr::encode(sampler, SAMPLER_TAG, pc)
+}
+
+// Add 'n_samples' draws to the sampler and return them.
+#[roxido]
+fn caviarpd_sampler_draw(
+    sampler: &mut RExternalPtr,
+    n_samples: usize,
+    n_cores: usize,
+    sampler_options: &RObject,
+) {
+    let conditions = Conditions::default().with_sampler(sampler_options);
+    let sampler = decode_sampler(sampler);
+    let n_items = sampler.similarity.n_items();
+    let (mut samples, mut n_clusters) = sample_epa_engine_generic::<LabelType, _, _>(
+        n_samples,
+        sampler.similarity.view().detect_symmetry(),
+        sampler.mass,
+        sampler.discount,
+        &conditions,
+        n_cores,
+        &mut sampler.rng,
+    );
+    samples.truncate(n_samples * n_items);
+    n_clusters.truncate(n_samples);
+    sampler.samples.extend_from_slice(&samples);
+    sampler.n_clusters.extend_from_slice(&n_clusters);
+    draws_to_r(&samples, &n_clusters, n_items, false, n_cores, pc)
+}
+
+// The SALSO estimate from all the draws of the sampler so far.
+#[roxido]
+fn caviarpd_sampler_estimate(
+    sampler: &mut RExternalPtr,
+    loss: &str,
+    a: f64,
+    n_runs: i32,
+    max_size: i32,
+    n_cores: usize,
+    salso_control: &RObject,
+    strict: bool,
+) {
+    let control = SalsoControl::new(salso_control, strict);
+    let loss = loss_function(loss, a);
+    let sampler = decode_sampler(sampler);
+    let n_items = sampler.similarity.n_items();
+    if sampler.n_clusters.is_empty() {
+        stop!("The sampler has no draws yet.");
+    }
+    let clusterings = Clusterings::unvalidated(
+        sampler.n_clusters.len(),
+        n_items,
+        sampler.samples.clone(),
+        sampler.n_clusters.clone(),
+    );
+    let pdi = PartitionDistributionInformation::Draws(&clusterings);
+    let p = salso_parameters(n_items, max_size, n_runs, &control);
+    let fit = minimize_by_salso(
+        pdi,
+        loss,
+        &p,
+        control.options.seconds,
+        control.n_cores(n_cores),
+        &mut sampler.rng,
+    );
+    let estimate: Vec<_> = fit
+        .clustering
+        .iter()
+        .map(|x| i32::try_from(*x + 1).unwrap())
+        .collect();
+    let result = RList::with_names(&["estimate", "nSamples"], pc);
+    result.set(0, estimate.as_slice().to_r(pc)).stop();
+    result
+        .set(1, i32::try_from(sampler.n_clusters.len()).stop().to_r(pc))
+        .stop();
+    result
+}
+
+#[roxido]
+fn caviarpd_map(
+    similarity: &RObject,
+    mass: f64,
+    discount: f64,
+    n_permutations: usize,
+    n_initial: usize,
+    n_restarts: usize,
+    max_sweeps: usize,
+    precision: &str,
+    resources: bool,
+    normalize: bool,
+) {
+    check_discount(discount);
+    check_mass(mass, discount);
+    let mut usage = Resources::new(resources, 1);
+    let mut rng = Pcg64Mcg::from_seed(R::random_bytes::<16>());
+    let similarity = similarity_matrix(similarity, normalize, pc);
+    let similarity = Similarity::new(similarity, precision);
+    let fit = usage.time("search", || match &similarity {
+        Similarity::Double(x) => map_search(
+            *x,
+            mass,
+            discount,
+            n_permutations,
+            n_initial,
+            n_restarts,
+            max_sweeps,
+            &mut rng,
+        ),
+        Similarity::Single(x) => map_search(
+            x.view().detect_symmetry(),
+            mass,
+            discount,
+            n_permutations,
+            n_initial,
+            n_restarts,
+            max_sweeps,
+            &mut rng,
+        ),
+        Similarity::Quantized(x) => map_search(
+            x.view(),
+            mass,
+            discount,
+            n_permutations,
+            n_initial,
+            n_restarts,
+            max_sweeps,
+            &mut rng,
+        ),
+    });
+    let estimate_rval = RVector::<i32>::new(fit.labels.len(), pc);
+    for (src, dst) in fit.labels.iter().zip(estimate_rval.slice_mut()) {
+        *dst = i32::try_from(*src + 1).unwrap();
+    }
+    let result = RList::with_names(&["estimate", "logProbability"], pc);
+    result.set(0, estimate_rval).stop();
+    result.set(1, fit.log_probability.to_r(pc)).stop();
+    usage.attach(result, pc)
+}
+
+// 'partitions' is a matrix with one observed partition per row.
+#[roxido]
+fn caviarpd_fit_epa(
+    similarity: &RObject,
+    partitions: &RMatrix<i32>,
+    fit_discount: bool,
+    n_permutations: usize,
+    precision: &str,
+    normalize: bool,
+) {
+    let similarity = similarity_matrix(similarity, normalize, pc);
+    let similarity = Similarity::new(similarity, precision);
+    if partitions.ncol() != similarity.n_items() {
+        stop!("'partitions' must have one column per item.");
+    }
+    let partitions = partitions_from_r(partitions, "partitions");
+    let mut rng = Pcg64Mcg::from_seed(R::random_bytes::<16>());
+    let fit = match &similarity {
+        Similarity::Double(x) => {
+            fit_parameters(*x, &partitions, fit_discount, n_permutations, &mut rng)
+        }
+        Similarity::Single(x) => fit_parameters(
+            x.view().detect_symmetry(),
+            &partitions,
+            fit_discount,
+            n_permutations,
+            &mut rng,
+        ),
+        Similarity::Quantized(x) => fit_parameters(
+            x.view(),
+            &partitions,
+            fit_discount,
+            n_permutations,
+            &mut rng,
+        ),
+    };
+    let result = RList::with_names(&["mass", "discount", "logLikelihood"], pc);
+    result.set(0, fit.mass.to_r(pc)).stop();
+    result.set(1, fit.discount.to_r(pc)).stop();
+    result.set(2, fit.log_likelihood.to_r(pc)).stop();
+    result
+}
+
+#[roxido]
+fn caviarpd_n_clusters(
+    n_samples: usize,
+    similarity: &RMatrix<f64>,
+    mass: f64,
+    loss: &str,
+    n_runs: i32,
+    max_size: i32,
+    n_cores: usize,
+    precision: &str,
+    salso_control: &RObject,
+    resources: bool,
+    n_alternatives: usize,
+    sampler: &RObject,
+) {
+    let conditions = Conditions::default().with_sampler(sampler);
+    let control = SalsoControl::new(salso_control, conditions.options.strict);
+    loss_function(loss, 1.0);
+    let mut usage = Resources::new(resources, n_threads(n_cores));
+    let mut rng = Pcg64Mcg::from_seed(R::random_bytes::<16>());
+    let similarity = Similarity::new(similarity, precision);
+    let n_items = similarity.n_items();
+    check_n_items_for_salso(n_items);
+    let (samples, n_clusters) = usage.time("sampling", || {
+        sample_epa_engine(
+            n_samples,
+            &similarity,
+            mass,
+            0.0,
+            &conditions,
+            n_cores,
+            &mut rng,
+        )
+    });
+    let n_samples = samples.len() / n_items;
+    let clusterings = Clusterings::unvalidated(n_samples, n_items, samples, n_clusters);
+    let pdi = PartitionDistributionInformation::Draws(&clusterings);
+    let p = salso_parameters(n_items, max_size, n_runs, &control);
+    let fit = usage.time("search", || {
+        minimize_by_salso(
+            pdi,
+            loss_function(loss, 1.0),
+            &p,
+            control.options.seconds,
+            control.n_cores(n_cores),
+            &mut rng,
+        )
+    });
+    let result = i32::try_from(fit.clustering.into_iter().max().unwrap() + 1)
+        .unwrap()
+        .to_r(pc);
+    if n_alternatives > 0 {
+        let alternatives = usage.time("alternatives", || {
+            salso_alternatives(
+                pdi,
+                loss_function(loss, 1.0),
+                &p,
+                control.options.seconds,
+                n_threads(n_cores),
+                &conditions.options,
+                n_alternatives,
+                &mut rng,
+            )
+        });
+        let alternatives = alternatives_to_r(&alternatives, n_items, &conditions, pc);
+        result.set_attribute(RSymbol::from("alternatives").unwrap(), alternatives);
+    }
+    usage.attach(result, pc)
+}
+
+// The alternatives of 'salso_alternatives' as a list of their labels, one per row, and their
+// expected losses.
+fn alternatives_to_r<'a>(
+    alternatives: &[(Vec<usize>, f64)],
+    n_items: usize,
+    conditions: &Conditions,
+    pc: &'a Pc,
+) -> &'a mut RList {
+    let n_alternatives = alternatives.len();
+    let labels_rval = RMatrix::<i32>::new(n_alternatives, n_items, pc);
+    let slice = labels_rval.slice_mut();
+    for (k, (clustering, _)) in alternatives.iter().enumerate() {
+        for (i, label) in conditions.labels_to_r(clustering).into_iter().enumerate() {
+            slice[n_alternatives * i + k] = label;
+        }
+    }
+    let expected_losses: Vec<_> = alternatives.iter().map(|x| x.1).collect();
+    let result = RList::with_names(&["labels", "expectedLoss"], pc);
+    result.set(0, labels_rval).stop();
+    result.set(1, expected_losses.as_slice().to_r(pc)).stop();
+    result
+}
+
+fn check_mass_tolerance(tol: f64) {
+    if !(tol.is_finite() && tol > 0.0) {
+        stop!("The tolerance for the mass must be finite and greater than 0.");
+    }
+}
+
+// Find the masses for several expected numbers of clusters at once, dividing them among threads.
+fn find_masses(enocs: &[f64], discount: f64, n_items: usize, tol: f64, n_cores: usize) -> Vec<f64> {
+    parallel_map(enocs, n_cores, |enoc| {
+        find_mass(*enoc, discount, n_items, tol)
+    })
+    .into_iter()
+    .map(|x| x.stop())
+    .collect()
+}
+
+// Apply 'f' to each element of 'x', dividing the elements evenly among threads.  Since R errors
+// cannot be raised from the worker threads, 'f' must not call 'stop!'.
+fn parallel_map<T: Sync, U: Send>(x: &[T], n_cores: usize, f: impl Fn(&T) -> U + Sync) -> Vec<U> {
+    let n_threads = n_threads(n_cores).min(x.len()).max(1);
+    let chunk_size = x.len().div_ceil(n_threads).max(1);
+    crossbeam::scope(|s| {
+        let f = &f;
+        let handles: Vec<_> = x
+            .chunks(chunk_size)
+            .map(|chunk| s.spawn(move |_| chunk.iter().map(f).collect::<Vec<_>>()))
+            .collect();
+        handles
+            .into_iter()
+            .flat_map(|handle| handle.join().unwrap())
+            .collect()
+    })
+    .unwrap()
+}
+
+fn warnings_to_r<'a>(warnings: &[String], pc: &'a Pc) -> &'a mut RVector<char> {
+    let warnings: Vec<_> = warnings.iter().map(|x| x.as_str()).collect();
+    warnings.as_slice().to_r(pc)
+}
+
+#[roxido]
+fn caviarpd_expected_number_of_clusters(
+    mass: &[f64],
+    discount: f64,
+    n_items: usize,
+    n_cores: usize,
+) {
+    check_discount(discount);
+    mass.iter().for_each(|mass| check_mass(*mass, discount));
+    let result = parallel_map(mass, n_cores, |mass| {
+        expected_number_of_clusters(*mass, discount, n_items)
+    });
+    result.as_slice().to_r(pc)
+}
+
+// The probability mass function of the number of clusters for 1, 2, ..., n_items items.  This is
+// the recursion for the (generalized) unsigned Stirling numbers of the first kind, normalized at
+// each step so that it stays on the probability scale.
+fn n_clusters_pmf(mass: f64, discount: f64, n_items: usize) -> Vec<f64> {
+    let mut pmf = vec![0.0; n_items + 1];
+    if n_items == 0 {
+        pmf[0] = 1.0;
+        return pmf;
+    }
+    pmf[1] = 1.0;
+    for n in 1..n_items {
+        let denominator = mass + (n as f64);
+        for k in (1..=(n + 1)).rev() {
+            let stay = pmf[k] * ((n as f64) - (k as f64) * discount);
+            let new = pmf[k - 1] * (mass + ((k - 1) as f64) * discount);
+            pmf[k] = (stay + new) / denominator;
+        }
+    }
+    pmf
+}
+
+#[roxido]
+fn caviarpd_n_clusters_distribution(mass: f64, discount: f64, n_items: usize) {
+    check_discount(discount);
+    check_mass(mass, discount);
+    let pmf = n_clusters_pmf(mass, discount, n_items);
+    let mean: f64 = pmf.iter().enumerate().map(|(k, p)| (k as f64) * p).sum();
+    let variance: f64 = pmf
+        .iter()
+        .enumerate()
+        .map(|(k, p)| ((k as f64) - mean).powi(2) * p)
+        .sum();
+    let result = RList::with_names(&["pmf", "mean", "variance"], pc);
+    result.set(0, pmf[1..].to_r(pc)).stop();
+    result.set(1, mean.to_r(pc)).stop();
+    result.set(2, variance.to_r(pc)).stop();
+    result
+}
+
+// The probability mass function of the size of the cluster containing a given item, for sizes
+// 0, 1, ..., n_items.  The other members are any 's - 1' of the other items, and the remaining
+// 'n_items - s' items are partitioned as if the mass were 'mass + discount', so the probability is
+// C(n - 1, s - 1) (1 - discount)_(s - 1) (mass + discount)_(n - s) / (mass + 1)_(n - 1) in terms of
+// rising factorials.
+fn cluster_size_pmf(mass: f64, discount: f64, n_items: usize) -> Vec<f64> {
+    let mut pmf = vec![0.0; n_items + 1];
+    if n_items == 0 {
+        return pmf;
+    }
+    // The logarithms of the rising factorials (x)_m for m = 0, 1, ..., n_items.
+    let log_rising = |x: f64| {
+        let mut result = vec![0.0; n_items + 1];
+        for m in 1..=n_items {
+            result[m] = result[m - 1] + (x + ((m - 1) as f64)).ln();
+        }
+        result
+    };
+    let log_factorial = log_rising(1.0);
+    let (a, b, c) = (
+        log_rising(1.0 - discount),
+        log_rising(mass + discount),
+        log_rising(mass + 1.0),
+    );
+    let n = n_items;
+    for s in 1..=n {
+        let log_choose = log_factorial[n - 1] - log_factorial[s - 1] - log_factorial[n - s];
+        pmf[s] = (log_choose + a[s - 1] + b[n - s] - c[n - 1]).exp();
+    }
+    pmf
+}
+
+// Pearson's chi-squared statistic and its degrees of freedom for the 'counts' of 'n' draws against
+// the probabilities 'pmf' of the same outcomes.  Outcomes are pooled in order until the expected
+// count is at least 5, and any remainder joins the last pooled bin.
+fn chi_squared(counts: &[usize], pmf: &[f64], n: usize) -> (f64, i32) {
+    let n = n as f64;
+    let mut bins: Vec<(f64, f64)> = Vec::new();
+    let mut current = (0.0, 0.0);
+    for (count, p) in counts.iter().zip(pmf) {
+        current.0 += *count as f64;
+        current.1 += n * p;
+        if current.1 >= 5.0 {
+            bins.push(current);
+            current = (0.0, 0.0);
+        }
+    }
+    match bins.last_mut() {
+        Some(last) => {
+            last.0 += current.0;
+            last.1 += current.1;
+        }
+        None => bins.push(current),
+    }
+    let statistic = bins
+        .iter()
+        .filter(|(_, expected)| *expected > 0.0)
+        .map(|(observed, expected)| (observed - expected).powi(2) / expected)
+        .sum();
+    (statistic, i32::try_from(bins.len()).unwrap() - 1)
+}
+
+// Check the sampler against the exact Chinese restaurant process (or Pitman-Yor process) to which
+// EPA reduces when all similarities are equal.  The numbers of clusters and the sizes of the
+// cluster of the first item of the draws are compared with their exact distributions.
+#[roxido]
+fn caviarpd_crp_self_test(
+    n_items: usize,
+    mass: f64,
+    discount: f64,
+    n_samples: usize,
+    n_cores: usize,
+) {
+    check_mass(mass, discount);
+    if n_items == 0 || n_samples == 0 {
+        stop!("'n_items' and 'n_samples' must be at least 1.");
+    }
+    let data = vec![1.0; n_items * n_items];
+    let similarity = SquareMatrixBorrower::from_slice(&data, n_items);
+    let mut rng = Pcg64Mcg::from_seed(R::random_bytes::<16>());
+    let (samples, n_clusters) = sample_epa_engine_generic::<u32, _, _>(
+        n_samples,
+        similarity,
+        mass,
+        discount,
+        &Conditions::default(),
+        n_cores,
+        &mut rng,
+    );
+    let mut k_counts = vec![0; n_items + 1];
+    for k in &n_clusters {
+        k_counts[*k as usize] += 1;
+    }
+    let mut size_counts = vec![0; n_items + 1];
+    for draw in samples.chunks(n_items) {
+        size_counts[draw.iter().filter(|x| **x == draw[0]).count()] += 1;
+    }
+    let k_pmf = n_clusters_pmf(mass, discount, n_items);
+    let size_pmf = cluster_size_pmf(mass, discount, n_items);
+    let (k_statistic, k_df) = chi_squared(&k_counts, &k_pmf, n_samples);
+    let (size_statistic, size_df) = chi_squared(&size_counts, &size_pmf, n_samples);
+    let exact_mean: f64 = k_pmf.iter().enumerate().map(|(k, p)| (k as f64) * p).sum();
+    let exact_variance: f64 = k_pmf
+        .iter()
+        .enumerate()
+        .map(|(k, p)| ((k as f64) - exact_mean).powi(2) * p)
+        .sum();
+    let mean = n_clusters.iter().map(|k| f64::from(*k)).sum::<f64>() / (n_samples as f64);
+    let z = (mean - exact_mean) / (exact_variance / (n_samples as f64)).sqrt();
+    let result = RList::with_names(
+        &[
+            "statistic",
+            "df",
+            "meanNClusters",
+            "exactMeanNClusters",
+            "z",
+        ],
+        pc,
+    );
+    let statistic = RList::with_names(&["nClusters", "clusterSize"], pc);
+    statistic.set(0, k_statistic.to_r(pc)).stop();
+    statistic.set(1, size_statistic.to_r(pc)).stop();
+    let df = RList::with_names(&["nClusters", "clusterSize"], pc);
+    df.set(0, k_df.to_r(pc)).stop();
+    df.set(1, size_df.to_r(pc)).stop();
+    result.set(0, statistic).stop();
+    result.set(1, df).stop();
+    result.set(2, mean.to_r(pc)).stop();
+    result.set(3, exact_mean.to_r(pc)).stop();
+    result.set(4, z.to_r(pc)).stop();
+    result
+}
+
+#[roxido]
+fn caviarpd_mass(
+    expected_number_of_clusters: &[f64],
+    discount: f64,
+    n_items: usize,
+    tol: f64,
+    n_cores: usize,
+) {
+    check_discount(discount);
+    check_mass_tolerance(tol);
+    let mass = find_masses(expected_number_of_clusters, discount, n_items, tol, n_cores);
+    mass.as_slice().to_r(pc)
+}
+
+// The mean number of clusters of EPA draws.  Each call starts from the same generator state, so
+// evaluations at different masses share random numbers and are nearly monotone in the mass.
+fn mean_n_clusters(
+    n_samples: usize,
+    similarity: &Similarity,
+    mass: f64,
+    discount: f64,
+    conditions: &Conditions,
+    n_cores: usize,
+    seed: &Pcg64Mcg,
+) -> f64 {
+    let mut rng = seed.clone();
+    let (_, n_clusters) = sample_epa_engine::<u32, _>(
+        n_samples, similarity, mass, discount, conditions, n_cores, &mut rng,
+    );
+    n_clusters.iter().map(|x| f64::from(*x)).sum::<f64>() / (n_clusters.len() as f64)
+}
+
+// Calibrate the mass so that the mean number of clusters of EPA draws (which, unlike the CRP
+// formula, reflects the attraction in the similarity matrix) hits the target.  Starting from the
+// CRP (or Pitman-Yor) mass, a bracket is found and then bisected on the log scale of the mass
+// plus the discount, until the bracket's relative width is below 'tol'.
+#[roxido]
+fn caviarpd_mass_epa(
+    target_n_clusters: f64,
+    similarity: &RObject,
+    discount: f64,
+    n_samples: usize,
+    tol: f64,
+    n_cores: usize,
+    normalize: bool,
+    sampler: &RObject,
+) {
+    check_discount(discount);
+    let conditions = Conditions::default().with_sampler(sampler);
+    if !(target_n_clusters.is_finite() && target_n_clusters >= 1.0) {
+        stop!("The target number of clusters must be finite and at least 1.");
+    }
+    if tol.is_nan() || tol <= 0.0 {
+        stop!("'tol' must be greater than 0.");
+    }
+    let similarity = similarity_matrix(similarity, normalize, pc);
+    let similarity = Similarity::new(similarity, "double");
+    let conditions = conditions.with_order(&similarity);
+    let n_items = similarity.n_items();
+    let mut warnings = Vec::new();
+    // The CRP (or Pitman-Yor) mass is only a starting point, so a loose tolerance suffices.
+    let initial = find_mass(target_n_clusters, discount, n_items, 1e-5).stop();
+    let seed = Pcg64Mcg::from_seed(R::random_bytes::<16>());
+    let f = |x: f64| {
+        mean_n_clusters(
+            n_samples,
+            &similarity,
+            x - discount,
+            discount,
+            &conditions,
+            n_cores,
+            &seed,
+        ) - target_n_clusters
+    };
+    let max_expansions = 30;
+    let mut lower = (initial + discount) / 2.0;
+    let mut upper = (initial + discount) * 2.0;
+    // Each evaluation is a Monte Carlo estimate, so the bracket is checked with the values it was
+    // built on rather than with new evaluations.
+    let mut f_lower = f(lower);
+    for _ in 0..max_expansions {
+        if f_lower <= 0.0 {
+            break;
+        }
+        lower /= 2.0;
+        f_lower = f(lower);
+    }
+    let mut f_upper = f(upper);
+    for _ in 0..max_expansions {
+        if f_upper >= 0.0 {
+            break;
+        }
+        upper *= 2.0;
+        f_upper = f(upper);
+    }
+    let mass = if f_lower > 0.0 || f_upper < 0.0 {
+        warnings.push(format!(
+            "Could not bracket the mass giving a mean of {} clusters. Using the mass {} from the CRP formula instead.",
+            target_n_clusters, initial
+        ));
+        initial
+    } else {
+        for _ in 0..100 {
+            if upper / lower - 1.0 <= tol {
+                break;
+            }
+            let middle = (lower * upper).sqrt();
+            if f(middle) < 0.0 {
+                lower = middle;
+            } else {
+                upper = middle;
+            }
+        }
+        (lower * upper).sqrt() - discount
+    };
+    let mean = f(mass + discount) + target_n_clusters;
+    let result = RList::with_names(&["mass", "meanNClusters", "warnings"], pc);
+    result.set(0, mass.to_r(pc)).stop();
+    result.set(1, mean.to_r(pc)).stop();
+    result.set(2, warnings_to_r(&warnings, pc)).stop();
+    result
+}
+
+// The calibration curve of the number of clusters of EPA draws against the mass: for each mass, the
+// Monte Carlo estimate of the mean number of clusters, its standard error, and the proportions of
+// the draws with 1, 2, ..., n_items clusters.  The masses are divided among the threads and, as in
+// 'mean_n_clusters', share random numbers, so the curve is nearly monotone.
+#[roxido]
+fn caviarpd_calibration(
+    masses: &[f64],
+    similarity: &RObject,
+    discount: f64,
+    n_samples: usize,
+    n_cores: usize,
+    normalize: bool,
+    sampler: &RObject,
+) {
+    masses.iter().for_each(|mass| check_mass(*mass, discount));
+    let conditions = Conditions::default().with_sampler(sampler);
+    if n_samples < 2 {
+        stop!("'n_samples' must be at least 2.");
+    }
+    let similarity = similarity_matrix(similarity, normalize, pc);
+    let similarity = Similarity::new(similarity, "double");
+    let conditions = conditions.with_order(&similarity);
+    let n_items = similarity.n_items();
+    if n_items == 0 {
+        stop!("There must be at least one item.");
+    }
+    let seed = Pcg64Mcg::from_seed(R::random_bytes::<16>());
+    let histograms = parallel_map(masses, n_cores, |mass| {
+        let (_, n_clusters) = sample_epa_engine::<u32, _>(
+            n_samples,
+            &similarity,
+            *mass,
+            discount,
+            &conditions,
+            1,
+            &mut seed.clone(),
+        );
+        let mut counts = vec![0_usize; n_items];
+        for k in n_clusters {
+            counts[k as usize - 1] += 1;
+        }
+        counts
+    });
+    let n = n_samples as f64;
+    let n_masses = masses.len();
+    let mut means = Vec::with_capacity(n_masses);
+    let mut standard_errors = Vec::with_capacity(n_masses);
+    let histogram_rval = RMatrix::<f64>::new(n_masses, n_items, pc);
+    let histogram = histogram_rval.slice_mut();
+    for (i, counts) in histograms.iter().enumerate() {
+        let mean: f64 = counts
+            .iter()
+            .enumerate()
+            .map(|(k, count)| ((k + 1) as f64) * (*count as f64))
+            .sum::<f64>()
+            / n;
+        let variance: f64 = counts
+            .iter()
+            .enumerate()
+            .map(|(k, count)| ((k + 1) as f64 - mean).powi(2) * (*count as f64))
+            .sum::<f64>()
+            / (n - 1.0);
+        means.push(mean);
+        standard_errors.push((variance / n).sqrt());
+        for (k, count) in counts.iter().enumerate() {
+            histogram[n_masses * k + i] = (*count as f64) / n;
+        }
+    }
+    let result = RList::with_names(&["mass", "meanNClusters", "standardError", "histogram"], pc);
+    result.set(0, masses.to_r(pc)).stop();
+    result.set(1, means.as_slice().to_r(pc)).stop();
+    result.set(2, standard_errors.as_slice().to_r(pc)).stop();
+    result.set(3, histogram_rval).stop();
+    result
+}
+
+// The mean silhouette width of the clusters of 'labels' (which must be 0, 1, ..., k - 1) for the
+// distances (column-major) between the items, which unlike similarities do not depend on the
+// temperature.
+fn mean_distance_silhouette(distance: &[f64], labels: &[usize]) -> f64 {
+    let n_items = labels.len();
+    let n_clusters = labels.iter().max().map_or(0, |x| x + 1);
+    if n_clusters < 2 {
+        return 0.0;
+    }
+    let mut sizes = vec![0_usize; n_clusters];
+    for label in labels {
+        sizes[*label] += 1;
+    }
+    let mut total = 0.0;
+    for (j, label) in labels.iter().enumerate() {
+        if sizes[*label] == 1 {
+            continue;
+        }
+        let mut sums = vec![0.0; n_clusters];
+        for (i, other) in labels.iter().enumerate() {
+            sums[*other] += distance[n_items * j + i];
+        }
+        let a = sums[*label] / ((sizes[*label] - 1) as f64);
+        let b = (0..n_clusters)
+            .filter(|k| k != label)
+            .map(|k| sums[k] / (sizes[k] as f64))
+            .fold(f64::INFINITY, f64::min);
+        if a.max(b) > 0.0 {
+            total += (b - a) / a.max(b);
+        }
+    }
+    total / (n_items as f64)
+}
+
+// Choose the temperature of the similarity from the distances by pilot fits at each of the
+// 'temperatures', reusing one similarity matrix.  Each pilot fit is the SALSO estimate from
+// 'n_samples' EPA draws with the CRP mass for 'n_clusters' expected clusters.  The "stability" of a
+// fit is one less the mean proportion of the other items on whose co-clustering with an item the
+// draws and the estimate disagree, and its "separation" is the mean silhouette width of the
+// estimate for the distances.  The temperature maximizing the 'criterion' is selected.
+#[roxido]
+fn caviarpd_tune_temperature(
+    distance: &RMatrix<f64>,
+    temperatures: &[f64],
+    similarity: &str,
+    n_clusters: f64,
+    n_samples: usize,
+    criterion: &str,
+    loss: &str,
+    a: f64,
+    n_runs: i32,
+    max_size: i32,
+    n_cores: usize,
+    salso_control: &RObject,
+    sampler: &RObject,
+) {
+    let n_items = distance.nrow();
+    if distance.ncol() != n_items {
+        stop!("'distance' must be a square matrix.");
+    }
+    check_n_items_for_salso(n_items);
+    let distance = distance.slice();
+    if distance.iter().any(|x| !(x.is_finite() && *x >= 0.0)) {
+        stop!("'distance' must contain only finite, nonnegative values.");
+    }
+    let reciprocal = match similarity {
+        "exponential" => false,
+        "reciprocal" => true,
+        _ => stop!("Unsupported similarity: {}", similarity),
+    };
+    if n_samples == 0 {
+        stop!("'n_samples' must be at least 1.");
+    }
+    let use_separation = match criterion {
+        "stability" => false,
+        "separation" => true,
+        _ => stop!("'criterion' must be 'stability' or 'separation'."),
+    };
+    if temperatures.is_empty() || temperatures.iter().any(|x| !(x.is_finite() && *x >= 0.0)) {
+        stop!("'temperatures' must be finite and nonnegative.");
+    }
+    let conditions = Conditions::default().with_sampler(sampler);
+    let control = SalsoControl::new(salso_control, conditions.options.strict);
+    let loss_function = loss_function(loss, a);
+    let mass = find_mass(n_clusters, 0.0, n_items, 1e-8).stop();
+    let p = salso_parameters(n_items, max_size, n_runs, &control);
+    // As in R, the distances are scaled by their median (including the zero diagonal).
+    let median = {
+        let mut sorted = distance.to_vec();
+        sorted.sort_unstable_by(|x, y| x.total_cmp(y));
+        let m = sorted.len();
+        (sorted[(m - 1) / 2] + sorted[m / 2]) / 2.0
+    };
+    if median <= 0.0 {
+        stop!("The median distance must be greater than 0.");
+    }
+    // Also as in R, zero distances are avoided for the reciprocal similarity.
+    let offset = if distance.contains(&0.0) { 0.01 } else { 0.0 };
+    let mut rng = Pcg64Mcg::from_seed(R::random_bytes::<16>());
+    let mut data = vec![0.0; n_items * n_items];
+    let (mut stability, mut separation, mut estimates_n_clusters) =
+        (Vec::new(), Vec::new(), Vec::new());
+    for temperature in temperatures {
+        for (x, d) in data.iter_mut().zip(distance) {
+            let d = d / median;
+            *x = if reciprocal {
+                1.0 / (d + offset).powf(*temperature)
+            } else {
+                (-temperature * d).exp()
+            };
+        }
+        if data.iter().any(|x| !x.is_finite()) {
+            stop!(
+                "The temperature {} yields similarity with nonfinite values.",
+                temperature
+            );
+        }
+        let similarity = Similarity::Double(SquareMatrixBorrower::from_slice(&data, n_items));
+        let (samples, counts) = sample_epa_engine::<LabelType, _>(
+            n_samples,
+            &similarity,
+            mass,
+            0.0,
+            &conditions,
+            n_cores,
+            &mut rng,
+        );
+        let clusterings = Clusterings::unvalidated(n_samples, n_items, samples, counts);
+        let fit = minimize_by_salso(
+            PartitionDistributionInformation::Draws(&clusterings),
+            loss_function,
+            &p,
+            control.options.seconds,
+            control.n_cores(n_cores),
+            &mut rng,
+        );
+        let estimate = canonical(&fit.clustering);
+        let draws = (0..n_samples).map(|j| {
+            clusterings
+                .labels(j)
+                .iter()
+                .map(|x| *x as usize)
+                .collect::<Vec<_>>()
+        });
+        let disagreement = item_disagreement(draws, &estimate);
+        stability.push(1.0 - disagreement.iter().sum::<f64>() / (n_items as f64));
+        separation.push(mean_distance_silhouette(distance, &estimate));
+        estimates_n_clusters.push(i32::try_from(estimate.iter().max().unwrap() + 1).unwrap());
+    }
+    let scores = if use_separation {
+        &separation
+    } else {
+        &stability
+    };
+    let best = scores
+        .iter()
+        .enumerate()
+        .max_by(|x, y| x.1.total_cmp(y.1))
+        .unwrap()
+        .0;
+    let result = RList::with_names(
+        &[
+            "temperature",
+            "stability",
+            "separation",
+            "nClusters",
+            "best",
+        ],
+        pc,
+    );
+    result.set(0, temperatures.to_r(pc)).stop();
+    result.set(1, stability.as_slice().to_r(pc)).stop();
+    result.set(2, separation.as_slice().to_r(pc)).stop();
+    result
+        .set(3, estimates_n_clusters.as_slice().to_r(pc))
+        .stop();
+    result
+        .set(4, i32::try_from(best + 1).unwrap().to_r(pc))
+        .stop();
+    result
+}
+
+// ---
+
+#[roxido]
+fn caviarpd_algorithm2(
+    similarity: &RObject,
+    min_n_clusters: f64,
+    max_n_clusters: f64,
+    mass: &RObject,
+    n_samples: usize,
+    grid_length: usize,
+    n0: f64,
+    tol: f64,
+    mass_tol: f64,
+    loss: &str,
+    salso_max_n_clusters: i32,
+    salso_n_runs: i32,
+    n_cores_sampling: usize,
+    n_cores_salso: usize,
+    precision: &str,
+    endpoints: bool,
+    trim_proportion: f64,
+    trim_distance: f64,
+    salso_control: &RObject,
+    use_psm: bool,
+    discount: f64,
+    resources: bool,
+    snapshot: &RObject,
+    parallel_grid: bool,
+    constraints: &RObject,
+    anchors: &RObject,
+    multiplicities: &RObject,
+    noise: &RObject,
+    n_alternatives: usize,
+    n_refine: usize,
+    a_search: &str,
+    a: &RObject,
+    exact_n_clusters: bool,
+    align_draws: bool,
+    group_draws: bool,
+    target_permutation: &RObject,
+    sampler: &RObject,
+) {
+    check_discount(discount);
+    let snapshot = if snapshot.is_null() {
+        None
+    } else {
+        Some(
+            snapshot
+                .as_function()
+                .stop_str("'snapshot' must be NULL or a function."),
+        )
+    };
+    let usage = Resources::new(
+        resources,
+        n_threads(n_cores_sampling).max(n_threads(n_cores_salso)),
+    );
+    let mut rng = Pcg64Mcg::from_seed(R::random_bytes::<16>());
+    let similarity = similarity_matrix(similarity, false, pc);
+    let similarity = Similarity::new(similarity, precision);
+    let n_items = similarity.n_items();
+    let conditions = Conditions::from_r(constraints, anchors, multiplicities, n_items, pc)
+        .with_noise(noise, &similarity)
+        .with_sampler(sampler)
+        .with_order(&similarity);
+    let control = SalsoControl::new(salso_control, conditions.options.strict);
+    let salso_max_n_clusters = usize::try_from(salso_max_n_clusters).stop_closure(|| {
+        format!(
+            "The maximum number of clusters must be between 0 and {}.",
+            LabelType::MAX
+        )
+    });
+    let mut options = CaviarpdOptions::new(min_n_clusters, max_n_clusters)
+        .with_n_samples(n_samples)
+        .with_grid_length(grid_length)
+        .with_loss(Loss::from_name(loss).stop())
+        .with_salso_max_n_clusters(salso_max_n_clusters)
+        .with_n_runs(u32::try_from(salso_n_runs.max(1)).unwrap())
+        .with_n_threads(n_threads(n_cores_sampling))
+        .with_salso_n_threads(n_threads(n_cores_salso))
+        .with_discount(discount)
+        .with_tolerances(tol, mass_tol)
+        .with_n0(n0)
+        .with_sampler_options(conditions.sampler_options())
+        .with_keep_draws(!use_psm)
+        .with_psm(use_psm, conditions.rao_blackwell)
+        .with_trimming(trim_proportion, trim_distance)
+        .with_salso_options(control.options.clone())
+        .with_n_alternatives(n_alternatives)
+        .with_exact_n_clusters(exact_n_clusters)
+        .with_refinement(n_refine)
+        .with_endpoints(endpoints)
+        .with_parallel_grid(parallel_grid);
+    if !mass.is_null() {
+        let mass = mass.as_vector().stop().to_f64(pc);
+        options = options.with_masses(mass.slice().to_vec());
+    }
+    if a.is_null() {
+        options = options.with_parameter_search(ParameterSearch::from_name(a_search).stop());
+    } else {
+        let a = a.as_vector().stop().to_f64(pc);
+        options = options.with_a(a.slice().to_vec());
+    }
+    if !target_permutation.is_null() {
+        let x = target_permutation
+            .as_vector()
+            .stop_str("'target_permutation' must be NULL or a vector.")
+            .to_i32(pc);
+        let x: Option<Vec<_>> = x
+            .slice()
+            .iter()
+            .map(|x| usize::try_from(*x).ok().and_then(|x| x.checked_sub(1)))
+            .collect();
+        match x.and_then(Permutation::from_vector) {
+            Some(x) if x.n_items() == n_items => {
+                options = options.with_target_permutation(x);
+            }
+            _ => stop!("'target_permutation' must be a permutation of the items."),
+        }
+    }
+    let mut observer = RObserver {
+        usage,
+        snapshot,
+        conditions: &conditions,
+        pc,
+    };
+    let fit = match &similarity {
+        Similarity::Double(x) => caviarpd_with_observer(*x, &options, &mut observer, &mut rng),
+        Similarity::Single(x) => caviarpd_with_observer(
+            x.view().detect_symmetry(),
+            &options,
+            &mut observer,
+            &mut rng,
+        ),
+        Similarity::Quantized(x) => {
+            caviarpd_with_observer(x.view(), &options, &mut observer, &mut rng)
+        }
+    };
+    let mut fit = fit.stop();
+    let mut usage = observer.usage;
+    let n_points = fit.masses.len();
+    let layout = DrawsLayout {
+        n_samples,
+        n_points,
+        n_items,
+        grouped: group_draws,
+    };
+    let estimate_labels = conditions.labels_to_r(&fit.estimate);
+    let samples_rval = if use_psm {
+        None
+    } else {
+        let samples_rval = RArray::<i32>::new(&layout.dim(), pc);
+        for (i, draws) in std::mem::take(&mut fit.draws).into_iter().enumerate() {
+            let Draws::Clusterings(clusterings) = draws else {
+                unreachable!("draws are kept as clusterings without the pairwise similarity matrix")
+            };
+            let (output, n_rows, first_row) = layout.block(samples_rval.slice_mut(), i);
+            write_draws(
+                |j| clusterings.labels(j),
+                n_samples,
+                n_items,
+                output,
+                n_rows,
+                first_row,
+                n_threads(n_cores_sampling),
+            );
+        }
+        if align_draws {
+            usage.time("alignment", || {
+                align_samples(
+                    samples_rval.slice_mut(),
+                    &layout,
+                    &estimate_labels,
+                    n_cores_sampling,
+                )
+            });
+        }
+        if group_draws {
+            samples_rval.set_attribute(
+                RSymbol::from("mass").unwrap(),
+                fit.masses.as_slice().to_r(pc),
+            );
+        }
+        Some(samples_rval)
+    };
+    let flatten = |x: &mut dyn Iterator<Item = &Vec<usize>>| -> Vec<LabelType> {
+        x.flatten()
+            .map(|label| LabelType::try_from(*label).unwrap())
+            .collect()
+    };
+    let endpoints_rval = if endpoints {
+        let lower = flatten(&mut fit.endpoints.iter().map(|x| &x.0));
+        let upper = flatten(&mut fit.endpoints.iter().map(|x| &x.1));
+        let list = RList::with_names(&["mass", "lower", "upper"], pc);
+        list.set(0, fit.masses.as_slice().to_r(pc)).stop();
+        list.set(1, samples_to_r(&lower, n_items, n_cores_sampling, pc))
+            .stop();
+        list.set(2, samples_to_r(&upper, n_items, n_cores_sampling, pc))
+            .stop();
+        list.as_robject()
+    } else {
+        R::null()
+    };
+    let grid_rval = RList::with_names(
+        &[
+            "mass",
+            "a",
+            "nClusters",
+            "expectedLoss",
+            "nSearches",
+            "seconds",
+        ],
+        pc,
+    );
+    let n_clusters: Vec<_> = fit
+        .candidates
+        .iter()
+        .map(|x| i32::try_from(x.iter().max().unwrap() + 1).unwrap())
+        .collect();
+    let n_searches: Vec<_> = fit
+        .traces
+        .iter()
+        .map(|x| i32::try_from(x.len()).unwrap())
+        .collect();
+    grid_rval.set(0, fit.masses.as_slice().to_r(pc)).stop();
+    grid_rval.set(1, fit.a.as_slice().to_r(pc)).stop();
+    grid_rval.set(2, n_clusters.as_slice().to_r(pc)).stop();
+    grid_rval
+        .set(3, fit.expected_losses.as_slice().to_r(pc))
+        .stop();
+    grid_rval.set(4, n_searches.as_slice().to_r(pc)).stop();
+    grid_rval.set(5, fit.seconds.as_slice().to_r(pc)).stop();
+    let candidates_labels = flatten(&mut fit.candidates.iter());
+    // The exemplar of each cluster, in order of the labels for R, which exclude noise items.
+    let exemplars_rval = {
+        let labels = canonical(&fit.estimate);
+        let n_clusters = estimate_labels.iter().max().map_or(0, |x| *x as usize);
+        let mut result = vec![0; n_clusters];
+        for (label, cluster) in estimate_labels.iter().zip(&labels) {
+            if *label > 0 {
+                result[(*label - 1) as usize] = i32::try_from(fit.exemplars[*cluster] + 1).unwrap();
+            }
+        }
+        result
+    };
+    let (mut trace_point, mut trace_a) = (Vec::new(), Vec::new());
+    let (mut trace_n_clusters, mut trace_expected_loss) = (Vec::new(), Vec::new());
+    for (i, trace) in fit.traces.iter().enumerate() {
+        for (a, n_clusters, expected_loss) in trace {
+            trace_point.push(i32::try_from(i + 1).unwrap());
+            trace_a.push(*a);
+            trace_n_clusters.push(i32::try_from(*n_clusters).unwrap());
+            trace_expected_loss.push(*expected_loss);
+        }
+    }
+    let result = RList::with_names(
+        &[
+            "estimate",
+            "samples",
+            "transitions",
+            "endpoints",
+            "trimmed",
+            "grid",
+            "candidates",
+            "uncertainty",
+            "alternatives",
+            "exemplars",
+            "expectedLoss",
+            "searchTrace",
+            "info",
+        ],
+        pc,
+    );
+    result.set(0, estimate_labels.as_slice().to_r(pc)).stop();
+    match samples_rval {
+        Some(samples_rval) => result.set(1, samples_rval).stop(),
+        None => result.set(1, R::null()).stop(),
+    }
+    result
+        .set(2, transitions_to_r(&fit.candidates, &fit.masses, pc))
+        .stop();
+    result.set(3, endpoints_rval).stop();
+    let trimmed: Vec<_> = fit
+        .trimmed
+        .iter()
+        .map(|k| i32::try_from(k + 1).unwrap())
+        .collect();
+    result.set(4, trimmed.as_slice().to_r(pc)).stop();
+    result.set(5, grid_rval).stop();
+    result
+        .set(
+            6,
+            samples_to_r(&candidates_labels, n_items, n_cores_sampling, pc),
+        )
+        .stop();
+    result.set(7, fit.uncertainty.as_slice().to_r(pc)).stop();
+    if n_alternatives > 0 {
+        let alternatives = alternatives_to_r(&fit.alternatives, n_items, &conditions, pc);
+        result.set(8, alternatives).stop();
+    } else {
+        result.set(8, R::null()).stop();
+    }
+    result.set(9, exemplars_rval.as_slice().to_r(pc)).stop();
+    result.set(10, fit.info.expected_loss.to_r(pc)).stop();
+    let trace_rval = RList::with_names(&["gridPoint", "a", "nClusters", "expectedLoss"], pc);
+    trace_rval.set(0, trace_point.as_slice().to_r(pc)).stop();
+    trace_rval.set(1, trace_a.as_slice().to_r(pc)).stop();
+    trace_rval
+        .set(2, trace_n_clusters.as_slice().to_r(pc))
+        .stop();
+    trace_rval
+        .set(3, trace_expected_loss.as_slice().to_r(pc))
+        .stop();
+    result.set(11, trace_rval).stop();
+    // The mass is that of the candidate closest to the estimate, as for the uncertainty.
+    let info = salso_info_to_r(&fit.info, fit.masses[fit.selected], pc);
+    result.set(12, info).stop();
+    usage.attach(result, pc)
+}
+
+// Reports the progress of 'caviarpd_algorithm2' to R: the resources used by each phase and, if R
+// supplied a function, the snapshot after each grid point.
+struct RObserver<'a> {
+    usage: Resources,
+    snapshot: Option<&'a RFunction>,
+    conditions: &'a Conditions,
+    pc: &'a Pc,
+}
+
+impl Observer for RObserver<'_> {
+    fn time<R>(&mut self, phase: &'static str, f: impl FnOnce() -> R) -> R {
+        self.usage.time(phase, f)
+    }
+
+    fn wants_snapshots(&self) -> bool {
+        self.snapshot.is_some()
+    }
+
+    fn snapshot(&mut self, snapshot: Snapshot) -> Result<(), String> {
+        let Some(function) = self.snapshot else {
+            return Ok(());
+        };
+        let pc = self.pc;
+        let estimate = self.conditions.labels_to_r(&snapshot.estimate);
+        let n_clusters: Vec<_> = snapshot
+            .n_clusters
+            .iter()
+            .map(|x| i32::try_from(*x).unwrap())
+            .collect();
+        let list = RList::with_names(
+            &[
+                "estimate",
+                "gridPoint",
+                "gridLength",
+                "mass",
+                "nClusters",
+                "expectedLoss",
+            ],
+            pc,
+        );
+        list.set(0, estimate.as_slice().to_r(pc)).stop();
+        list.set(1, i32::try_from(snapshot.grid_point + 1).unwrap().to_r(pc))
+            .stop();
+        list.set(2, i32::try_from(snapshot.n_points).unwrap().to_r(pc))
+            .stop();
+        list.set(3, snapshot.masses.to_r(pc)).stop();
+        list.set(4, n_clusters.as_slice().to_r(pc)).stop();
+        list.set(5, snapshot.expected_loss.to_r(pc)).stop();
+        match function.call1(list, pc) {
+            Ok(_) => Ok(()),
+            Err(_) => Err(format!(
+                "Could not write the snapshot of grid point {}.",
+                snapshot.grid_point + 1
+            )),
+        }
+    }
+}
+
+// The settings and diagnostics of a SALSO fit, as in the 'info' attribute of estimates from the
+// salso package, and the mass behind the estimate.
+fn salso_info_to_r<'a>(fit: &SalsoInfo, mass: f64, pc: &'a Pc) -> &'a mut RList {
+    let info = RList::with_names(
+        &[
+            "loss",
+            "a",
+            "maxNClusters",
+            "expectedLoss",
+            "initMethod",
+            "nScans",
+            "nZAcc",
+            "nZAtt",
+            "nRuns",
+            "seconds",
+            "mass",
+        ],
+        pc,
+    );
+    info.set(0, fit.loss.name().to_r(pc)).stop();
+    info.set(1, fit.a.to_r(pc)).stop();
+    info.set(2, i32::from(fit.max_n_clusters).to_r(pc)).stop();
+    info.set(3, fit.expected_loss.to_r(pc)).stop();
+    let init_method = i32::try_from(fit.initialization_method.to_code()).unwrap();
+    info.set(4, init_method.to_r(pc)).stop();
+    info.set(5, i32::try_from(fit.n_scans).unwrap().to_r(pc))
+        .stop();
+    info.set(6, i32::try_from(fit.n_zealous_accepts).unwrap().to_r(pc))
+        .stop();
+    info.set(7, i32::try_from(fit.n_zealous_attempts).unwrap().to_r(pc))
+        .stop();
+    info.set(8, i32::try_from(fit.n_runs).unwrap().to_r(pc))
+        .stop();
+    info.set(9, fit.seconds.to_r(pc)).stop();
+    info.set(10, mass.to_r(pc)).stop();
+    info
+}
+
+// The pairwise similarity matrix (column-major) of EPA draws, as given by 'sample_psm'.
+fn sample_epa_psm<T: Rng>(
+    n_samples: usize,
+    similarity: &Similarity,
+    mass: f64,
+    discount: f64,
+    conditions: &Conditions,
+    n_cores: usize,
+    rng: &mut T,
+) -> Vec<f64> {
+    check_mass(mass, discount);
+    let options = &conditions.options;
+    let n_threads = n_threads(n_cores);
+    let rao_blackwell = conditions.rao_blackwell;
+    match similarity {
+        Similarity::Double(x) => sample_psm(
+            n_samples,
+            *x,
+            mass,
+            discount,
+            n_threads,
+            options,
+            rao_blackwell,
+            None,
+            rng,
+        ),
+        Similarity::Single(x) => sample_psm(
+            n_samples,
+            x.view().detect_symmetry(),
+            mass,
+            discount,
+            n_threads,
+            options,
+            rao_blackwell,
+            None,
+            rng,
+        ),
+        Similarity::Quantized(x) => sample_psm(
+            n_samples,
+            x.view(),
+            mass,
+            discount,
+            n_threads,
+            options,
+            rao_blackwell,
+            None,
+            rng,
+        ),
+    }
+}
+
+// Only the Binder and VI losses have a parameter 'a', which is ignored for the other losses.
+fn loss_function(loss: &str, a: f64) -> LossFunction {
+    Loss::from_name(loss).stop().with_parameter(a)
+}
+
+// Describe how the candidates change between consecutive masses (in increasing order) as flows
+// of items from the clusters of one candidate to the clusters of the next.  A flow is part of a
+// "split" when its source cluster feeds several clusters, part of a "merge" when its target
+// cluster is fed by several clusters, "mixed" when both, and "same" otherwise.
+fn transitions_to_r<'a>(candidates: &[Vec<usize>], masses: &[f64], pc: &'a Pc) -> &'a mut RList {
+    let mut order: Vec<_> = (0..masses.len()).collect();
+    order.sort_by(|i, j| masses[*i].total_cmp(&masses[*j]));
+    let labels = |k: usize| -> &[usize] { &candidates[k] };
+    let (mut mass_from, mut mass_to) = (Vec::new(), Vec::new());
+    let (mut cluster_from, mut cluster_to) = (Vec::new(), Vec::new());
+    let (mut count, mut kind) = (Vec::new(), Vec::new());
+    for pair in order.windows(2) {
+        let table = ContingencyTable::new(labels(pair[0]), labels(pair[1]));
+        let cells = table.nonzero_cells();
+        let mut n_targets = vec![0; table.n_rows()];
+        let mut n_sources = vec![0; table.n_cols()];
+        for (row, col, _) in &cells {
+            n_targets[*row] += 1;
+            n_sources[*col] += 1;
+        }
+        for (row, col, n) in cells {
+            mass_from.push(masses[pair[0]]);
+            mass_to.push(masses[pair[1]]);
+            cluster_from.push(i32::try_from(row + 1).unwrap());
+            cluster_to.push(i32::try_from(col + 1).unwrap());
+            count.push(i32::try_from(n).unwrap());
+            kind.push(match (n_targets[row] > 1, n_sources[col] > 1) {
+                (false, false) => "same",
+                (true, false) => "split",
+                (false, true) => "merge",
+                (true, true) => "mixed",
+            });
+        }
+    }
+    let result = RList::with_names(
+        &[
+            "massFrom",
+            "massTo",
+            "clusterFrom",
+            "clusterTo",
+            "count",
+            "kind",
+        ],
+        pc,
+    );
+    result.set(0, mass_from.as_slice().to_r(pc)).stop();
+    result.set(1, mass_to.as_slice().to_r(pc)).stop();
+    result.set(2, cluster_from.as_slice().to_r(pc)).stop();
+    result.set(3, cluster_to.as_slice().to_r(pc)).stop();
+    result.set(4, count.as_slice().to_r(pc)).stop();
+    result.set(5, kind.as_slice().to_r(pc)).stop();
+    result
+}
+
+// Map arbitrary integer labels to 0, 1, ..., k - 1 (in increasing order of the original labels),
+// also returning the original label for each new label.
+fn dense_labels(x: &[i32], name: &str) -> (Vec<usize>, Vec<i32>) {
+    if x.iter().any(|label| R::is_na_i32(*label)) {
+        stop!("'{}' may not contain missing values.", name);
+    }
+    let mut originals = x.to_vec();
+    originals.sort_unstable();
+    originals.dedup();
+    let labels = x
+        .iter()
+        .map(|label| originals.binary_search(label).unwrap())
+        .collect();
+    (labels, originals)
+}
+
+// 'draws' is NULL (e.g., when the pairwise similarity matrix was used in their place) or a matrix
+// with one draw per row, from which the uncertainty of items and the Monte Carlo precision follow.
+#[roxido]
+fn caviarpd_summary(
+    estimate: &[i32],
+    similarity: &RMatrix<f64>,
+    draws: &RObject,
+    n_uncertain: usize,
+) {
+    let (labels, originals) = dense_labels(estimate, "estimate");
+    let n_items = labels.len();
+    if Similarity::new(similarity, "double").n_items() != n_items {
+        stop!("The dimension of 'similarity' must equal the length of 'estimate'.");
+    }
+    let similarity = SquareMatrixBorrower::from_slice(similarity.slice(), n_items);
+    let mut sizes = vec![0; originals.len()];
+    labels.iter().for_each(|label| sizes[*label] += 1);
+    let (within, between): (Vec<_>, Vec<_>) = cluster_similarities(similarity, &labels)
+        .into_iter()
+        .map(|(w, b)| {
+            let na = |x: f64| if x.is_nan() { R::na_f64() } else { x };
+            (na(w), na(b))
+        })
+        .unzip();
+    let (uncertain, uncertainty, n_draws, monte_carlo_se) = if draws.is_null() {
+        (Vec::new(), Vec::new(), 0, R::na_f64())
+    } else {
+        let draws = draws.as_matrix().stop().to_i32(pc);
+        if draws.ncol() != n_items {
+            stop!("The number of columns of 'draws' must equal the length of 'estimate'.");
+        }
+        let n_draws = draws.nrow();
+        let psm = psm_from_draws(draws);
+        let uncertainty = item_uncertainty(&psm, &labels);
+        let mut order: Vec<_> = (0..n_items).collect();
+        order.sort_by(|i, j| uncertainty[*j].total_cmp(&uncertainty[*i]).then(i.cmp(j)));
+        order.truncate(n_uncertain);
+        let values: Vec<_> = order.iter().map(|i| uncertainty[*i]).collect();
+        let items: Vec<_> = order
+            .iter()
+            .map(|i| i32::try_from(i + 1).unwrap())
+            .collect();
+        // The largest standard error of the co-clustering probabilities, as the draws are
+        // independent.
+        let max_variance = psm.iter().map(|p| p * (1.0 - p)).fold(0.0, f64::max);
+        let se = (max_variance / (n_draws.max(1) as f64)).sqrt();
+        (items, values, i32::try_from(n_draws).unwrap(), se)
+    };
+    let result = RList::with_names(
+        &[
+            "nClusters",
+            "labels",
+            "sizes",
+            "withinSimilarity",
+            "betweenSimilarity",
+            "uncertainItems",
+            "uncertainty",
+            "nDraws",
+            "monteCarloSE",
+        ],
+        pc,
+    );
+    result
+        .set(0, i32::try_from(originals.len()).unwrap().to_r(pc))
+        .stop();
+    result.set(1, originals.as_slice().to_r(pc)).stop();
+    result.set(2, sizes.as_slice().to_r(pc)).stop();
+    result.set(3, within.as_slice().to_r(pc)).stop();
+    result.set(4, between.as_slice().to_r(pc)).stop();
+    result.set(5, uncertain.as_slice().to_r(pc)).stop();
+    result.set(6, uncertainty.as_slice().to_r(pc)).stop();
+    result.set(7, n_draws.to_r(pc)).stop();
+    result.set(8, monte_carlo_se.to_r(pc)).stop();
+    result
+}
+
+#[roxido]
+fn caviarpd_silhouette(similarity: &RMatrix<f64>, partition: &[i32], n_cores: usize) {
+    let (labels, originals) = dense_labels(partition, "partition");
+    let n_items = labels.len();
+    if Similarity::new(similarity, "double").n_items() != n_items {
+        stop!("The dimension of 'similarity' must equal the length of 'partition'.");
+    }
+    let similarity = SquareMatrixBorrower::from_slice(similarity.slice(), n_items);
+    let widths = silhouette(similarity, &labels, n_threads(n_cores));
+    let mut sums = vec![(0.0, 0.0); originals.len()];
+    for (width, label) in widths.iter().zip(&labels) {
+        sums[*label].0 += width;
+        sums[*label].1 += 1.0;
+    }
+    let cluster_widths: Vec<_> = sums.iter().map(|(sum, count)| sum / count).collect();
+    let average = widths.iter().sum::<f64>() / (n_items.max(1) as f64);
+    let result = RList::with_names(&["widths", "labels", "clusterWidths", "averageWidth"], pc);
+    result.set(0, widths.as_slice().to_r(pc)).stop();
+    result.set(1, originals.as_slice().to_r(pc)).stop();
+    result.set(2, cluster_widths.as_slice().to_r(pc)).stop();
+    result.set(3, average.to_r(pc)).stop();
+    result
+}
+
+#[roxido]
+fn caviarpd_block_fit(estimate: &[i32], similarity: &RMatrix<f64>) {
+    let (labels, originals) = dense_labels(estimate, "estimate");
+    let n_items = labels.len();
+    if Similarity::new(similarity, "double").n_items() != n_items {
+        stop!("The dimension of 'similarity' must equal the length of 'estimate'.");
+    }
+    let similarity = SquareMatrixBorrower::from_slice(similarity.slice(), n_items);
+    let fit = block_fit(similarity, &labels);
+    let n_clusters = originals.len();
+    let na = |x: &f64| if x.is_nan() { R::na_f64() } else { *x };
+    let means_rval = RMatrix::<f64>::new(n_clusters, n_clusters, pc);
+    for (dst, src) in means_rval.slice_mut().iter_mut().zip(&fit.means) {
+        *dst = na(src);
+    }
+    let cohesion: Vec<_> = fit.cohesion.iter().map(na).collect();
+    let separation: Vec<_> = fit.separation.iter().map(na).collect();
+    let residuals_rval = RMatrix::<f64>::new(n_items, n_items, pc);
+    residuals_rval.slice_mut().copy_from_slice(&fit.residuals);
+    let result = RList::with_names(
+        &["labels", "means", "cohesion", "separation", "residuals"],
+        pc,
+    );
+    result.set(0, originals.as_slice().to_r(pc)).stop();
+    result.set(1, means_rval).stop();
+    result.set(2, cohesion.as_slice().to_r(pc)).stop();
+    result.set(3, separation.as_slice().to_r(pc)).stop();
+    result.set(4, residuals_rval).stop();
+    result
+}
+
+// Partitions of the items, one per row, with labels starting at one.
+fn partitions_to_r<'a>(
+    partitions: &[Vec<usize>],
+    n_items: usize,
+    pc: &'a Pc,
+) -> &'a mut RMatrix<i32> {
+    let n_partitions = partitions.len();
+    let result = RMatrix::<i32>::new(n_partitions, n_items, pc);
+    let slice = result.slice_mut();
+    for (k, partition) in partitions.iter().enumerate() {
+        for (i, label) in partition.iter().enumerate() {
+            slice[n_partitions * i + k] = i32::try_from(*label + 1).unwrap();
+        }
+    }
+    result
+}
+
+// The rows of a matrix of partitions, each with labels 0, 1, ..., k - 1.
+fn partitions_from_r(x: &RMatrix<i32>, name: &str) -> Vec<Vec<usize>> {
+    let (n_partitions, n_items) = (x.nrow(), x.ncol());
+    let slice = x.slice();
+    (0..n_partitions)
+        .map(|k| {
+            let labels: Vec<_> = (0..n_items).map(|i| slice[n_partitions * i + k]).collect();
+            dense_labels(&labels, name).0
+        })
+        .collect()
+}
+
+// 'partitions' is a matrix with one partition per row.
+#[roxido]
+fn caviarpd_partition_distance(partitions: &RMatrix<i32>, method: &str, n_cores: usize) {
+    let distance = match method {
+        "VI" => PartitionDistance::VI,
+        "binder" => PartitionDistance::Binder,
+        "ARI" => PartitionDistance::ARI,
+        _ => stop!("'method' must be one of 'VI', 'binder', or 'ARI'."),
+    };
+    let partitions = partitions_from_r(partitions, "partitions");
+    let n = partitions.len();
+    let distances = pairwise_distances(&partitions, distance, n_threads(n_cores));
+    let result = RMatrix::<f64>::new(n, n, pc);
+    result.slice_mut().copy_from_slice(&distances);
+    result
+}
+
+// 'draws' is a matrix with one draw per row.
+#[roxido]
+fn caviarpd_credible_ball(estimate: &[i32], draws: &RMatrix<i32>, probability: f64) {
+    let (estimate, _) = dense_labels(estimate, "estimate");
+    let n_items = estimate.len();
+    if draws.ncol() != n_items {
+        stop!("The number of columns of 'draws' must equal the length of 'estimate'.");
+    }
+    let n_draws = draws.nrow();
+    if n_draws == 0 {
+        stop!("There must be at least one draw.");
+    }
+    if !(probability > 0.0 && probability <= 1.0) {
+        stop!("'probability' must be in (0, 1].");
+    }
+    let draws = partitions_from_r(draws, "draws");
+    let ball = credible_ball(&draws, &estimate, probability);
+    let result = RList::with_names(
+        &["radius", "upperVertical", "lowerVertical", "horizontal"],
+        pc,
+    );
+    result.set(0, ball.radius.to_r(pc)).stop();
+    result
+        .set(1, partitions_to_r(&ball.upper_vertical, n_items, pc))
+        .stop();
+    result
+        .set(2, partitions_to_r(&ball.lower_vertical, n_items, pc))
+        .stop();
+    result
+        .set(3, partitions_to_r(&ball.horizontal, n_items, pc))
+        .stop();
+    result
+}
+
+#[roxido]
+fn caviarpd_track(estimate: &[i32], reference: &[i32], min_overlap: f64) {
+    if estimate.len() != reference.len() {
+        stop!("'estimate' and 'reference' must have the same length.");
+    }
+    let (estimate, _) = dense_labels(estimate, "estimate");
+    let (reference, reference_originals) = dense_labels(reference, "reference");
+    let n_reference_clusters = reference_originals.len();
+    let next_label = reference_originals.last().map_or(1, |x| x + 1);
+    let to_r_label = |label: usize| {
+        if label < n_reference_clusters {
+            reference_originals[label]
+        } else {
+            next_label + i32::try_from(label - n_reference_clusters).unwrap()
+        }
+    };
+    let aligned = align_to_reference(&reference, &estimate);
+    let mut map = vec![0; estimate.iter().max().map_or(0, |x| x + 1)];
+    for (old, new) in estimate.iter().zip(&aligned) {
+        map[*old] = *new;
+    }
+    let labels_rval = RVector::<i32>::new(aligned.len(), pc);
+    for (src, dst) in aligned.iter().zip(labels_rval.slice_mut()) {
+        *dst = to_r_label(*src);
+    }
+    let events = cluster_events(&reference, &estimate, min_overlap);
+    let kind_rval = RVector::<char>::new(events.len(), pc);
+    let reference_rval = RVector::<i32>::new(events.len(), pc);
+    let estimate_rval = RVector::<i32>::new(events.len(), pc);
+    for (i, event) in events.iter().enumerate() {
+        kind_rval.set(i, event.kind.as_str()).stop();
+        reference_rval.slice_mut()[i] = event
+            .reference
+            .map_or(R::na_i32(), |label| reference_originals[label]);
+        estimate_rval.slice_mut()[i] = event
+            .estimate
+            .map_or(R::na_i32(), |label| to_r_label(map[label]));
+    }
+    let result = RList::with_names(&["labels", "event", "reference", "estimate"], pc);
+    result.set(0, labels_rval).stop();
+    result.set(1, kind_rval).stop();
+    result.set(2, reference_rval).stop();
+    result.set(3, estimate_rval).stop();
+    result
+}
+
+// The pairwise similarity matrix (column-major) of draws stored as an R matrix with one row per
+// draw.
+fn psm_from_draws(draws: &RMatrix<i32>) -> Vec<f64> {
+    let n_draws = draws.nrow();
+    let n_items = draws.ncol();
+    let draws = draws.slice();
+    let mut psm = vec![0.0; n_items * n_items];
+    for j in 0..n_items {
+        psm[n_items * j + j] = 1.0;
+        for i in 0..j {
+            let (x, y) = (
+                &draws[n_draws * i..n_draws * (i + 1)],
+                &draws[n_draws * j..n_draws * (j + 1)],
+            );
+            let count = x.iter().zip(y).filter(|(a, b)| a == b).count();
+            let p = (count as f64) / (n_draws as f64);
+            psm[n_items * j + i] = p;
+            psm[n_items * i + j] = p;
+        }
+    }
+    psm
+}
+
+// An ordering of the items (starting at one) for displaying 'psm' as a heatmap, with the items of
+// each cluster of 'partition' contiguous.
+#[roxido]
+fn caviarpd_seriate(psm: &RMatrix<f64>, partition: &[i32]) {
+    let (labels, _) = dense_labels(partition, "partition");
+    let n_items = labels.len();
+    if psm.nrow() != n_items || psm.ncol() != n_items {
+        stop!("'psm' must be a square matrix whose dimension equals the length of 'partition'.");
+    }
+    if psm.slice().iter().any(|x| !(x.is_finite() && *x >= 0.0)) {
+        stop!("'psm' must contain only finite, nonnegative values.");
+    }
+    let order: Vec<_> = seriate(psm.slice(), &labels)
+        .into_iter()
+        .map(|i| i32::try_from(i + 1).unwrap())
+        .collect();
+    order.as_slice().to_r(pc)
+}
+
+#[roxido]
+fn caviarpd_psm(draws: &RMatrix<i32>) {
+    let n_items = draws.ncol();
+    let psm = psm_from_draws(draws);
+    let result = RMatrix::<f64>::new(n_items, n_items, pc);
+    result.slice_mut().copy_from_slice(&psm);
+    result
+}
+
+// Stops with an error naming the offending entries or items unless 'similarity' is valid for the
+// EPA distribution and symmetric within the relative 'tolerance'.
+#[roxido]
+fn caviarpd_validate_similarity(similarity: &RObject, tolerance: f64) {
+    if !(tolerance.is_finite() && tolerance >= 0.0) {
+        stop!("'tolerance' must be finite and nonnegative.");
+    }
+    let similarity = similarity_matrix(similarity, false, pc);
+    validate_similarity(
+        similarity.slice(),
+        similarity.nrow(),
+        similarity.ncol(),
+        Some(tolerance),
+    )
+    .stop();
+    true
+}
+
+// A copy of 'similarity' whose values which are not finite and nonnegative are replaced by the
+// "clamp" or "epsilon" 'repair', with the number replaced.
+#[roxido]
+fn caviarpd_repair_similarity(similarity: &RMatrix<f64>, repair: &str) {
+    let repair = match repair {
+        "clamp" => SimilarityRepair::Clamp,
+        "epsilon" => SimilarityRepair::Epsilon,
+        _ => stop!("'repair' must be either 'clamp' or 'epsilon'."),
+    };
+    let matrix = RMatrix::<f64>::new(similarity.nrow(), similarity.ncol(), pc);
+    matrix.slice_mut().copy_from_slice(similarity.slice());
+    let n_repaired = repair_similarity(matrix.slice_mut(), repair);
+    let result = RList::with_names(&["similarity", "nRepaired"], pc);
+    result.set(0, matrix).stop();
+    result.set(1, (n_repaired as f64).to_r(pc)).stop();
+    result
+}
+
+#[roxido]
+fn caviarpd_similarity_from_partition(partition: &[i32], within: f64, between: f64, noise: f64) {
+    for (value, name) in [(within, "within"), (between, "between"), (noise, "noise")] {
+        if !(value.is_finite() && value >= 0.0) {
+            stop!("'{}' must be finite and nonnegative.", name);
+        }
+    }
+    let (labels, _) = dense_labels(partition, "partition");
+    let n_items = labels.len();
+    let mut rng = Pcg64Mcg::from_seed(R::random_bytes::<16>());
+    let similarity = similarity_from_partition(&labels, within, between, noise, &mut rng);
+    let result = RMatrix::<f64>::new(n_items, n_items, pc);
+    result.slice_mut().copy_from_slice(&similarity);
+    result
+}
+
+// The ground truth of a simulation: 'true_partition' gives the labels of the 'n_items' items or,
+// when it is a single value, the number of clusters of a balanced partition.
+fn simulation_truth(true_partition: &[i32], n_items: usize) -> Vec<usize> {
+    if true_partition.len() == 1 && n_items != 1 {
+        let n_clusters = true_partition[0];
+        if R::is_na_i32(n_clusters) || n_clusters < 1 || n_clusters as usize > n_items {
+            stop!("The number of clusters must be between 1 and the number of items.");
+        }
+        return balanced_partition(n_items, n_clusters as usize);
+    }
+    if true_partition.len() != n_items {
+        stop!("The length of 'truePartition' must equal the number of items.");
+    }
+    dense_labels(true_partition, "truePartition").0
+}
+
+fn check_simulation_settings(within_sim: f64, between_sim: f64, noise_sd: f64) {
+    for (value, name) in [
+        (within_sim, "withinSim"),
+        (between_sim, "betweenSim"),
+        (noise_sd, "noiseSD"),
+    ] {
+        if !(value.is_finite() && value >= 0.0) {
+            stop!("'{}' must be finite and nonnegative.", name);
+        }
+    }
+}
+
+#[roxido]
+fn caviarpd_simulate(
+    true_partition: &[i32],
+    within_sim: f64,
+    between_sim: f64,
+    noise_sd: f64,
+    n_items: usize,
+) {
+    check_simulation_settings(within_sim, between_sim, noise_sd);
+    let labels = simulation_truth(true_partition, n_items);
+    let mut rng = Pcg64Mcg::from_seed(R::random_bytes::<16>());
+    let similarity = simulate_similarity(&labels, within_sim, between_sim, noise_sd, &mut rng);
+    let matrix = RMatrix::<f64>::new(n_items, n_items, pc);
+    matrix.slice_mut().copy_from_slice(&similarity);
+    let truth: Vec<_> = labels
+        .iter()
+        .map(|x| i32::try_from(*x + 1).unwrap())
+        .collect();
+    let result = RList::with_names(&["similarity", "truth"], pc);
+    result.set(0, matrix).stop();
+    result.set(1, truth.as_slice().to_r(pc)).stop();
+    result
+}
+
+// Fit replicates of simulated similarity matrices in parallel and report the recovery of the truth.
+#[allow(clippy::too_many_arguments)]
+#[roxido]
+fn caviarpd_simulation_study(
+    true_partition: &[i32],
+    within_sim: f64,
+    between_sim: f64,
+    noise_sd: f64,
+    n_items: usize,
+    n_replicates: usize,
+    min_n_clusters: f64,
+    max_n_clusters: f64,
     n_samples: usize,
+    grid_length: usize,
+    loss: &str,
+    discount: f64,
+    n_cores: usize,
+    sampler: &RObject,
+) {
+    check_simulation_settings(within_sim, between_sim, noise_sd);
+    check_discount(discount);
+    let loss = match loss {
+        "binder" => Loss::Binder,
+        "VI" => Loss::VI,
+        _ => stop!("'loss' must be either 'binder' or 'VI'."),
+    };
+    let labels = simulation_truth(true_partition, n_items);
+    check_n_items_for_salso(n_items);
+    let options = CaviarpdOptions::new(min_n_clusters, max_n_clusters)
+        .with_n_samples(n_samples)
+        .with_grid_length(grid_length)
+        .with_loss(loss)
+        .with_discount(discount)
+        .with_n_threads(n_threads(n_cores))
+        .with_sampler_options(
+            Conditions::default()
+                .with_sampler(sampler)
+                .sampler_options(),
+        );
+    let mut rng = Pcg64Mcg::from_seed(R::random_bytes::<16>());
+    let recoveries = simulation_study(
+        &labels,
+        within_sim,
+        between_sim,
+        noise_sd,
+        n_replicates,
+        &options,
+        &mut rng,
+    )
+    .stop();
+    let ari: Vec<_> = recoveries.iter().map(|x| x.ari).collect();
+    let vi: Vec<_> = recoveries.iter().map(|x| x.vi).collect();
+    let n_clusters: Vec<_> = recoveries
+        .iter()
+        .map(|x| i32::try_from(x.n_clusters).unwrap())
+        .collect();
+    let result = RList::with_names(&["ARI", "VI", "nClusters"], pc);
+    result.set(0, ari.as_slice().to_r(pc)).stop();
+    result.set(1, vi.as_slice().to_r(pc)).stop();
+    result.set(2, n_clusters.as_slice().to_r(pc)).stop();
+    result
+}
+
+// The items and features are zero-based, as in the slots of a 'dgCMatrix'.  Zero neighbors means
+// no sparsification.
+#[roxido]
+fn caviarpd_feature_similarity(
+    items: &[i32],
+    features: &[i32],
+    values: &[f64],
     n_items: usize,
-    similarity: &[f64],
+    n_features: usize,
+    kind: &str,
+    n_neighbors: usize,
+    n_cores: usize,
+) {
+    let kind = match kind {
+        "cosine" => FeatureSimilarity::Cosine,
+        "jaccard" => FeatureSimilarity::Jaccard,
+        _ => stop!("'kind' must be either 'cosine' or 'jaccard'."),
+    };
+    let to_usize = |x: &[i32]| -> Vec<usize> {
+        x.iter()
+            .map(|y| usize::try_from(*y).stop_str("Indices must be nonnegative."))
+            .collect()
+    };
+    let features = SparseFeatures::from_triplets(
+        n_items,
+        n_features,
+        &to_usize(items),
+        &to_usize(features),
+        values,
+    )
+    .stop();
+    let result = RMatrix::<f64>::new(n_items, n_items, pc);
+    features.similarity_into(kind, n_threads(n_cores), result.slice_mut());
+    if n_neighbors > 0 {
+        sparsify_nearest_neighbors(result.slice_mut(), n_items, n_neighbors);
+    }
+    result
+}
+
+// The items are the rows of 'x'.
+#[roxido]
+fn caviarpd_correlation_similarity(
+    x: &RMatrix<f64>,
+    method: &str,
+    shrinkage: f64,
+    transform: &str,
+    n_cores: usize,
+) {
+    let correlation = match method {
+        "pearson" => Correlation::Pearson,
+        "spearman" => Correlation::Spearman,
+        _ => stop!("'method' must be either 'pearson' or 'spearman'."),
+    };
+    let transform = match transform {
+        "linear" => CorrelationTransform::Linear,
+        "absolute" => CorrelationTransform::Absolute,
+        "positive" => CorrelationTransform::Positive,
+        _ => stop!("'transform' must be one of 'linear', 'absolute', or 'positive'."),
+    };
+    let n_items = x.nrow();
+    let result = RMatrix::<f64>::new(n_items, n_items, pc);
+    correlation_similarity_into(
+        x.slice(),
+        n_items,
+        x.ncol(),
+        correlation,
+        shrinkage,
+        transform,
+        n_threads(n_cores),
+        result.slice_mut(),
+    )
+    .stop();
+    result
+}
+
+// The items are the rows of 'x'.  The distances are computed directly into the result, which is
+// then converted in place to the similarity, so no other matrix of all pairs of items is formed.
+// Missing values (NA) are handled by using the pairwise-complete features, and the pairs of items
+// with fewer than 'min_overlap' features in common are the "insufficientOverlap" attribute, a
+// two-column matrix of the (one-based) items of each pair.
+#[roxido]
+fn caviarpd_similarity_from_data(
+    x: &RMatrix<f64>,
+    distance: &str,
+    temperature: f64,
+    similarity: &str,
+    min_overlap: usize,
+    n_cores: usize,
+) {
+    let kind = match distance {
+        "euclidean" => DataDistance::Euclidean,
+        "manhattan" => DataDistance::Manhattan,
+        "cosine" => DataDistance::Cosine,
+        "correlation" => DataDistance::Correlation,
+        _ => {
+            stop!("'distance' must be one of 'euclidean', 'manhattan', 'cosine', or 'correlation'.")
+        }
+    };
+    let reciprocal = match similarity {
+        "exponential" => false,
+        "reciprocal" => true,
+        _ => stop!("Unsupported similarity: {}", similarity),
+    };
+    if !(temperature.is_finite() && temperature >= 0.0) {
+        stop!("'temperature' must be finite and nonnegative.");
+    }
+    let n_items = x.nrow();
+    let result = RMatrix::<f64>::new(n_items, n_items, pc);
+    let insufficient = data_distance_into(
+        x.slice(),
+        n_items,
+        x.ncol(),
+        kind,
+        min_overlap,
+        n_threads(n_cores),
+        result.slice_mut(),
+    )
+    .stop();
+    similarity_from_distance_in_place(result.slice_mut(), temperature, reciprocal).stop();
+    let pairs = RMatrix::<i32>::new(insufficient.len(), 2, pc);
+    let slice = pairs.slice_mut();
+    for (k, (i, j)) in insufficient.iter().enumerate() {
+        slice[k] = i32::try_from(*i + 1).unwrap();
+        slice[insufficient.len() + k] = i32::try_from(*j + 1).unwrap();
+    }
+    result.set_attribute(RSymbol::from("insufficientOverlap").unwrap(), pairs);
+    result
+}
+
+// The items are the rows of 'x', with missing values as NA.  The type of each column is 1 for
+// numeric, 2 for (asymmetric) binary, and 3 for categorical.
+#[roxido]
+fn caviarpd_gower_similarity(x: &RMatrix<f64>, types: &[i32], weights: &[f64], n_cores: usize) {
+    let columns: Vec<_> = types
+        .iter()
+        .map(|x| match x {
+            1 => GowerColumn::Numeric,
+            2 => GowerColumn::Binary,
+            3 => GowerColumn::Categorical,
+            _ => stop!("Unsupported column type: {}", x),
+        })
+        .collect();
+    let n_items = x.nrow();
+    if x.ncol() != columns.len() {
+        stop!("There must be a type for each column.");
+    }
+    let result = RMatrix::<f64>::new(n_items, n_items, pc);
+    gower_similarity_into(
+        x.slice(),
+        n_items,
+        &columns,
+        weights,
+        n_threads(n_cores),
+        result.slice_mut(),
+    )
+    .stop();
+    result
+}
+
+// The elements of the list 'similarities', which must be square matrices of the same size, along
+// with their number of rows.
+fn similarities_from_r<'a>(x: &'a RObject, pc: &'a Pc) -> (Vec<&'a RMatrix<f64>>, usize) {
+    let list = x.as_list().stop_str("'similarities' must be a list.");
+    let matrices: Vec<_> = (0..list.len())
+        .map(|i| {
+            list.get(i)
+                .stop()
+                .as_matrix()
+                .stop_str("Each element of 'similarities' must be a matrix.")
+                .to_f64(pc)
+        })
+        .collect();
+    let Some(n_items) = matrices.first().map(|x| x.nrow()) else {
+        stop!("'similarities' must not be empty.");
+    };
+    if matrices
+        .iter()
+        .any(|x| x.nrow() != n_items || x.ncol() != n_items)
+    {
+        stop!("The elements of 'similarities' must be square matrices of the same size.");
+    }
+    (matrices, n_items)
+}
+
+// The views are the elements of the list 'similarities'.  When 'learn_weights' is true, EPA draws
+// with the given 'mass' are made from each view separately and the weights are learned from them.
+#[roxido]
+fn caviarpd_combine_similarities(
+    similarities: &RObject,
+    weights: &[f64],
+    combination: &str,
+    learn_weights: bool,
     mass: f64,
+    n_samples: usize,
+    n_cores: usize,
+    sampler: &RObject,
+) {
+    let combination = match combination {
+        "sum" => ViewCombination::Sum,
+        "product" => ViewCombination::Product,
+        _ => stop!("'combination' must be either 'sum' or 'product'."),
+    };
+    let (matrices, n_items) = similarities_from_r(similarities, pc);
+    let views: Vec<_> = matrices.iter().map(|x| x.slice()).collect();
+    let weights = if learn_weights {
+        let mut rng = Pcg64Mcg::from_seed(R::random_bytes::<16>());
+        let conditions = Conditions::default().with_sampler(sampler);
+        let psms: Vec<_> = matrices
+            .iter()
+            .map(|x| {
+                let similarity = Similarity::new(x, "double");
+                sample_epa_psm(
+                    n_samples,
+                    &similarity,
+                    mass,
+                    0.0,
+                    &conditions,
+                    n_cores,
+                    &mut rng,
+                )
+            })
+            .collect();
+        stability_weights(&psms, weights)
+    } else {
+        weights.to_vec()
+    };
+    let combined = RMatrix::<f64>::new(n_items, n_items, pc);
+    combine_views_into(
+        &views,
+        n_items,
+        &weights,
+        combination,
+        n_threads(n_cores),
+        combined.slice_mut(),
+    )
+    .stop();
+    let result = RList::with_names(&["similarity", "weights"], pc);
+    result.set(0, combined).stop();
+    result.set(1, weights.as_slice().to_r(pc)).stop();
+    result
+}
+
+// The time slices are the elements of the list 'similarities', with one mass for all slices or
+// one per slice.  Each slice is first estimated separately by SALSO from the pairwise similarity
+// matrix of its draws, and then the estimates are refined jointly.
+#[roxido]
+fn caviarpd_temporal(
+    similarities: &RObject,
+    mass: &[f64],
+    discount: f64,
+    n_samples: usize,
+    loss: &str,
+    a: f64,
+    smoothing: f64,
+    max_sweeps: usize,
+    n_runs: i32,
+    max_size: i32,
+    n_cores: usize,
+    salso_control: &RObject,
+    sampler: &RObject,
+) {
+    let conditions = Conditions::default().with_sampler(sampler);
+    let control = SalsoControl::new(salso_control, conditions.options.strict);
+    let psm_loss = psm_loss(loss, a);
+    let (matrices, n_items) = similarities_from_r(similarities, pc);
+    let n_slices = matrices.len();
+    if mass.len() != 1 && mass.len() != n_slices {
+        stop!("'mass' must have length 1 or one element per time slice.");
+    }
+    for mass in mass {
+        check_mass(*mass, discount);
+    }
+    if !(smoothing.is_finite() && smoothing >= 0.0) {
+        stop!("'smoothing' must be finite and nonnegative.");
+    }
+    check_n_items_for_salso(n_items);
+    let p = salso_parameters(n_items, max_size, n_runs, &control);
+    let a = if let PsmLoss::Binder(a) = psm_loss {
+        a
+    } else {
+        1.0
+    };
+    let mut rng = Pcg64Mcg::from_seed(R::random_bytes::<16>());
+    let mut psms = Vec::with_capacity(n_slices);
+    let mut initial = Vec::with_capacity(n_slices);
+    for (t, matrix) in matrices.iter().enumerate() {
+        let similarity = Similarity::new(matrix, "double");
+        let mass = mass[t.min(mass.len() - 1)];
+        let psm = sample_epa_psm(
+            n_samples,
+            &similarity,
+            mass,
+            discount,
+            &conditions,
+            n_cores,
+            &mut rng,
+        );
+        let draws = Draws::Psm(psm);
+        let (labels, _) = draws.minimize(
+            Loss::Binder,
+            a,
+            &p,
+            control.options.seconds,
+            control.n_cores(n_cores),
+            &mut rng,
+        );
+        let Draws::Psm(psm) = draws else {
+            unreachable!()
+        };
+        psms.push(psm);
+        initial.push(labels);
+    }
+    let psms: Vec<_> = psms.iter().map(|x| x.as_slice()).collect();
+    let fit = smooth_estimates(&psms, n_items, &initial, psm_loss, smoothing, max_sweeps);
+    let estimates_rval = RMatrix::<i32>::new(n_slices, n_items, pc);
+    let slice = estimates_rval.slice_mut();
+    for (t, estimate) in fit.labels.iter().enumerate() {
+        for (i, label) in estimate.iter().enumerate() {
+            slice[n_slices * i + t] = i32::try_from(*label + 1).unwrap();
+        }
+    }
+    let result = RList::with_names(
+        &[
+            "estimates",
+            "expectedLoss",
+            "distance",
+            "objective",
+            "nSweeps",
+        ],
+        pc,
+    );
+    result.set(0, estimates_rval).stop();
+    result
+        .set(1, fit.expected_losses.as_slice().to_r(pc))
+        .stop();
+    result.set(2, fit.distances.as_slice().to_r(pc)).stop();
+    result.set(3, fit.objective.to_r(pc)).stop();
+    result.set(4, (fit.n_sweeps as i32).to_r(pc)).stop();
+    result
+}
+
+fn psm_loss(loss: &str, a: f64) -> PsmLoss {
+    match loss {
+        "binder" => {
+            if !(0.0..=2.0).contains(&a) {
+                stop!("'a' must be in [0, 2].");
+            }
+            PsmLoss::Binder(a)
+        }
+        "VI" => PsmLoss::VIlb,
+        _ => stop!("'loss' must be either 'binder' or 'VI'."),
+    }
+}
+
+// The expected loss of each partition (one per row of 'partitions') given the draws (one per row),
+// as computed by SALSO for the draws, so that any partition can be compared with the estimates.
+#[roxido]
+fn caviarpd_expected_loss(partitions: &RMatrix<i32>, draws: &RMatrix<i32>, loss: &str, a: f64) {
+    let n_items = partitions.ncol();
+    if draws.ncol() != n_items {
+        stop!("'partitions' and 'draws' must have the same number of columns.");
+    }
+    if draws.nrow() == 0 {
+        stop!("There must be at least one draw.");
+    }
+    if partitions
+        .slice()
+        .iter()
+        .chain(draws.slice())
+        .any(|x| R::is_na_i32(*x))
+    {
+        stop!("'partitions' and 'draws' may not contain missing values.");
+    }
+    if !(0.0..=2.0).contains(&a) {
+        stop!("'a' must be in [0, 2].");
+    }
+    let n_partitions = partitions.nrow();
+    let mut partitions = partitions.slice().to_vec();
+    let mut draws_data = draws.slice().to_vec();
+    let partitions =
+        PartitionsHolderBorrower::from_slice(&mut partitions, n_partitions, n_items, false);
+    let draws = PartitionsHolderBorrower::from_slice(&mut draws_data, draws.nrow(), n_items, false);
+    let mut results = vec![0.0; n_partitions];
+    match loss {
+        "binder" => compute_loss_multiple(
+            Box::new(|| BinderCMLossComputer::new(a)),
+            &partitions,
+            &draws,
+            &mut results,
+        ),
+        "VI" => {
+            let cache = Log2Cache::new(n_items);
+            compute_loss_multiple(
+                Box::new(|| VICMLossComputer::new(a, &cache)),
+                &partitions,
+                &draws,
+                &mut results,
+            )
+        }
+        _ => stop!("'loss' must be either 'binder' or 'VI'."),
+    }
+    results.to_r(pc)
+}
+
+#[roxido]
+fn caviarpd_anneal(
+    estimate: &[i32],
+    draws: &RMatrix<i32>,
+    loss: &str,
+    a: f64,
+    n_iterations: usize,
+    temperature: f64,
+    cooling: f64,
+    resources: bool,
+) {
+    if draws.ncol() != estimate.len() {
+        stop!("The number of columns of 'draws' must equal the length of 'estimate'.");
+    }
+    if draws.nrow() == 0 {
+        stop!("'draws' must have at least one row.");
+    }
+    if !(temperature.is_finite() && temperature > 0.0) {
+        stop!("'temperature' must be finite and greater than 0.");
+    }
+    if !(cooling > 0.0 && cooling <= 1.0) {
+        stop!("'cooling' must be in (0, 1].");
+    }
+    let loss = psm_loss(loss, a);
+    let mut usage = Resources::new(resources, 1);
+    let (labels, _) = dense_labels(estimate, "estimate");
+    let psm = usage.time("psm", || psm_from_draws(draws));
+    let state = PsmLossState::new(&psm, labels.len(), &labels, loss);
+    let mut rng = Pcg64Mcg::from_seed(R::random_bytes::<16>());
+    let fit = usage.time("annealing", || {
+        anneal(state, n_iterations, temperature, cooling, &mut rng)
+    });
+    let estimate_rval = RVector::<i32>::new(fit.labels.len(), pc);
+    for (src, dst) in fit.labels.iter().zip(estimate_rval.slice_mut()) {
+        *dst = i32::try_from(*src + 1).unwrap();
+    }
+    let result = RList::with_names(&["estimate", "expectedLoss", "improvement"], pc);
+    result.set(0, estimate_rval).stop();
+    result.set(1, fit.expected_loss.to_r(pc)).stop();
+    result.set(2, fit.improvement.to_r(pc)).stop();
+    usage.attach(result, pc)
+}
+
+// The change in expected loss for moving an item to each cluster of the estimate (in increasing
+// order of the cluster labels) and then to a new cluster, whose label is NA.
+#[roxido]
+fn caviarpd_move_deltas(estimate: &[i32], psm: &RMatrix<f64>, loss: &str, a: f64, item: usize) {
+    let n_items = estimate.len();
+    if psm.nrow() != n_items || psm.ncol() != n_items {
+        stop!("'psm' must be a square matrix whose dimension equals the length of 'estimate'.");
+    }
+    if item == 0 || item > n_items {
+        stop!("'item' must be between 1 and {}.", n_items);
+    }
+    let loss = psm_loss(loss, a);
+    let (labels, originals) = dense_labels(estimate, "estimate");
+    let state = PsmLossState::new(psm.slice(), n_items, &labels, loss);
+    let deltas = state.move_deltas(item - 1);
+    let label_rval = RVector::<i32>::new(deltas.len(), pc);
+    let delta_rval = RVector::<f64>::new(deltas.len(), pc);
+    for (i, (label, delta)) in deltas.into_iter().enumerate() {
+        label_rval.slice_mut()[i] = originals.get(label).copied().unwrap_or(R::na_i32());
+        delta_rval.slice_mut()[i] = delta;
+    }
+    let result = RList::with_names(&["label", "delta"], pc);
+    result.set(0, label_rval).stop();
+    result.set(1, delta_rval).stop();
+    result
+}
+
+// The labels of a partition in which the label of 'item' is ignored, by giving it the label of
+// another item so that it does not form a cluster of its own.
+fn labels_without_item(partition: &[i32], item: usize) -> (Vec<usize>, Vec<i32>) {
+    let mut partition = partition.to_vec();
+    if let Some(other) = (0..partition.len()).find(|j| *j != item) {
+        partition[item] = partition[other];
+    }
+    dense_labels(&partition, "partition")
+}
+
+// The EPA allocation probabilities of an item (averaged over random permutations) given the
+// partition of the other items, whose clusters are in increasing order of their labels.
+fn mean_allocation_probabilities(
+    item: usize,
+    labels: &[usize],
+    parameters: &EpaParameters<SquareMatrixBorrower>,
+    n_permutations: usize,
     n_cores: usize,
-    rng: &mut T,
-) -> (Vec<LabelType>, Vec<LabelType>) {
-    let n_cores = if n_cores == 0 {
-        std::thread::available_parallelism()
-            .map(|x| x.get())
-            .unwrap_or(1)
+    strict: bool,
+    rng: &mut Pcg64Mcg,
+) -> Vec<f64> {
+    // The permutations are divided among a fixed number of tasks in strict mode.
+    let n_threads = if strict {
+        1
     } else {
-        n_cores
+        n_threads(n_cores).min(n_permutations).max(1)
     };
-    let n_samples = n_samples.max(1);
-    let n_samples_per_core = 1 + (n_samples - 1) / n_cores;
-    let chunk_size = n_samples_per_core * n_items;
-    let mut samples: Vec<LabelType> = vec![0; n_cores * chunk_size];
-    let mut n_clusters: Vec<LabelType> = vec![0; n_cores * n_samples_per_core];
-
-    let _result = crossbeam::scope(|s| {
-        let mut stick1 = &mut samples[..];
-        let mut stick2 = &mut n_clusters[..];
-        let mut plan = Vec::with_capacity(n_cores);
-        for _ in 0..n_cores - 1 {
-            let (left1, right1) = stick1.split_at_mut(chunk_size);
-            let (left2, right2) = stick2.split_at_mut(n_samples_per_core);
-            plan.push((left1, left2, rng.random::<u128>()));
-            stick1 = right1;
-            stick2 = right2;
-        }
-        plan.push((stick1, stick2, rng.random()));
-        let sim = SquareMatrixBorrower::from_slice(similarity, n_items);
-        plan.into_iter().for_each(|p| {
-            s.spawn(move |_| {
-                let mut rng = Pcg64Mcg::new(p.2);
-                let mut params =
-                    EpaParameters::new(sim, Permutation::natural(n_items), mass).unwrap();
-                for i in 0..n_samples_per_core {
-                    params.shuffle_permutation(&mut rng);
-                    let clustering = sample(&params, &mut rng);
-                    let zero: LabelType = 0;
-                    clustering.relabel_into_slice(zero, &mut p.0[i * n_items..(i + 1) * n_items]);
-                    p.1[i] = LabelType::try_from(clustering.max_label() + 1).unwrap();
-                }
-            });
-        });
+    let tasks: Vec<(u128, usize)> = (0..n_threads)
+        .map(|t| {
+            let n = n_permutations / n_threads + usize::from(t < n_permutations % n_threads);
+            (rng.random(), n)
+        })
+        .collect();
+    let sums = parallel_map(&tasks, n_cores, |(seed, n)| {
+        let mut rng = Pcg64Mcg::new(*seed);
+        let mut parameters = parameters.clone();
+        let mut sum = Vec::new();
+        for _ in 0..*n {
+            parameters.shuffle_permutation(&mut rng);
+            let p = allocation_probabilities(labels, item, &parameters);
+            sum.resize(p.len(), 0.0);
+            sum.iter_mut().zip(p).for_each(|(s, p)| *s += p);
+        }
+        sum
     });
-    (samples, n_clusters)
+    let mut mean = Vec::new();
+    for sum in sums {
+        mean.resize(sum.len().max(mean.len()), 0.0);
+        mean.iter_mut().zip(sum).for_each(|(m, s)| *m += s);
+    }
+    mean.iter_mut()
+        .for_each(|m| *m /= n_permutations.max(1) as f64);
+    mean
 }
 
 #[roxido]
-fn sample_epa(n_samples: usize, similarity: &RMatrix<f64>, mass: f64, n_cores: usize) {
+fn caviarpd_allocation_probabilities(
+    item: usize,
+    partition: &[i32],
+    similarity: &RMatrix<f64>,
+    mass: f64,
+    discount: f64,
+    n_permutations: usize,
+    n_cores: usize,
+    strict: bool,
+) {
+    check_discount(discount);
+    check_mass(mass, discount);
+    let n_items = partition.len();
+    let Similarity::Double(similarity) = Similarity::new(similarity, "double") else {
+        unreachable!()
+    };
+    if similarity.n_items() != n_items {
+        stop!("The dimension of 'similarity' must equal the length of 'partition'.");
+    }
+    if item == 0 || item > n_items {
+        stop!("'item' must be between 1 and {}.", n_items);
+    }
+    if n_permutations == 0 {
+        stop!("The number of permutations must be at least 1.");
+    }
+    let item = item - 1;
+    let (labels, originals) = labels_without_item(partition, item);
+    let parameters = EpaParameters::new(similarity, Permutation::natural(n_items), mass)
+        .and_then(|x| x.with_discount(discount))
+        .unwrap();
     let mut rng = Pcg64Mcg::from_seed(R::random_bytes::<16>());
-    let n_items = similarity.nrow();
-    let (samples, _) = sample_epa_engine(
-        n_samples,
-        n_items,
-        similarity.slice(),
-        mass,
+    let probabilities = mean_allocation_probabilities(
+        item,
+        &labels,
+        &parameters,
+        n_permutations,
         n_cores,
+        strict,
         &mut rng,
     );
-    let n_samples = samples.len() / n_items;
-    let result = RMatrix::<i32>::new(n_samples, n_items, pc);
-    let result_slice = result.slice_mut();
-    for i in 0..n_items {
-        for j in 0..n_samples {
-            result_slice[i * n_samples + j] = i32::from(samples[j * n_items + i] + 1);
-        }
+    let label_rval = RVector::<i32>::new(originals.len() + 1, pc);
+    let label_slice = label_rval.slice_mut();
+    label_slice[..originals.len()].copy_from_slice(&originals);
+    label_slice[originals.len()] = R::na_i32();
+    let result = RList::with_names(&["label", "probability"], pc);
+    result.set(0, label_rval).stop();
+    result.set(1, probabilities.as_slice().to_r(pc)).stop();
+    result
+}
+
+// Explain the assignment of an item in an estimate by: the items of its cluster with the largest
+// similarities to it, the margin of its allocation probability over the runner-up (possibly a new
+// cluster), and the derivative of that probability with respect to the log of the mass plus the
+// discount.  The derivative is a central difference using common random permutations.
+#[roxido]
+fn caviarpd_explain(
+    item: usize,
+    estimate: &[i32],
+    similarity: &RMatrix<f64>,
+    mass: f64,
+    discount: f64,
+    n_top: usize,
+    n_permutations: usize,
+    n_cores: usize,
+    strict: bool,
+) {
+    check_discount(discount);
+    check_mass(mass, discount);
+    let n_items = estimate.len();
+    let Similarity::Double(similarity) = Similarity::new(similarity, "double") else {
+        unreachable!()
+    };
+    if similarity.n_items() != n_items {
+        stop!("The dimension of 'similarity' must equal the length of 'estimate'.");
+    }
+    if item == 0 || item > n_items {
+        stop!("'item' must be between 1 and {}.", n_items);
     }
+    if n_permutations == 0 {
+        stop!("The number of permutations must be at least 1.");
+    }
+    let item = item - 1;
+    let (labels, originals) = labels_without_item(estimate, item);
+    let assigned = originals
+        .binary_search(&estimate[item])
+        .unwrap_or(originals.len());
+    let seed = Pcg64Mcg::from_seed(R::random_bytes::<16>());
+    let probabilities_at = |mass: f64| {
+        let parameters = EpaParameters::new(similarity, Permutation::natural(n_items), mass)
+            .and_then(|x| x.with_discount(discount))
+            .unwrap();
+        let mut rng = seed.clone();
+        mean_allocation_probabilities(
+            item,
+            &labels,
+            &parameters,
+            n_permutations,
+            n_cores,
+            strict,
+            &mut rng,
+        )
+    };
+    let probabilities = probabilities_at(mass);
+    let runner_up = (0..probabilities.len())
+        .filter(|k| *k != assigned)
+        .max_by(|i, j| probabilities[*i].total_cmp(&probabilities[*j]));
+    let runner_up_probability = runner_up.map_or(0.0, |k| probabilities[k]);
+    let h = 0.1;
+    let shifted = |sign: f64| (mass + discount) * (sign * h).exp() - discount;
+    let sensitivity = (probabilities_at(shifted(1.0))[assigned]
+        - probabilities_at(shifted(-1.0))[assigned])
+        / (2.0 * h);
+    let mut contributors: Vec<_> = (0..n_items)
+        .filter(|j| *j != item && estimate[*j] == estimate[item])
+        .map(|j| (j, similarity[(item, j)]))
+        .collect();
+    let total: f64 = contributors.iter().map(|x| x.1).sum();
+    contributors.sort_by(|x, y| y.1.total_cmp(&x.1));
+    contributors.truncate(n_top);
+    let label = |k: usize| originals.get(k).copied().unwrap_or(R::na_i32());
+    let contributors_rval = RList::with_names(&["item", "similarity", "share"], pc);
+    let items: Vec<_> = contributors
+        .iter()
+        .map(|x| i32::try_from(x.0 + 1).unwrap())
+        .collect();
+    let similarities: Vec<_> = contributors.iter().map(|x| x.1).collect();
+    let shares: Vec<_> = contributors.iter().map(|x| x.1 / total).collect();
+    contributors_rval.set(0, items.as_slice().to_r(pc)).stop();
+    contributors_rval
+        .set(1, similarities.as_slice().to_r(pc))
+        .stop();
+    contributors_rval.set(2, shares.as_slice().to_r(pc)).stop();
+    let result = RList::with_names(
+        &[
+            "label",
+            "probability",
+            "runnerUp",
+            "runnerUpProbability",
+            "margin",
+            "sensitivity",
+            "contributors",
+        ],
+        pc,
+    );
+    result.set(0, estimate[item].to_r(pc)).stop();
+    result.set(1, probabilities[assigned].to_r(pc)).stop();
+    result
+        .set(2, runner_up.map_or(R::na_i32(), label).to_r(pc))
+        .stop();
+    result.set(3, runner_up_probability.to_r(pc)).stop();
+    result
+        .set(
+            4,
+            (probabilities[assigned] - runner_up_probability).to_r(pc),
+        )
+        .stop();
+    result.set(5, sensitivity.to_r(pc)).stop();
+    result.set(6, contributors_rval).stop();
     result
 }
 
+// Refit the estimate on 'n_replicates' perturbations of the similarity, all with the same mass.
+// The replicates are divided among the threads, each of which samples and runs SALSO on a single
+// core.  The co-clustering proportions of the replicate estimates measure their robustness.
 #[roxido]
-fn caviarpd_n_clusters(
-    n_samples: usize,
+fn caviarpd_ensemble(
     similarity: &RMatrix<f64>,
     mass: f64,
-    use_vi: bool,
+    n_replicates: usize,
+    noise: f64,
+    n_samples: usize,
+    loss: &str,
+    a: f64,
     n_runs: i32,
     max_size: i32,
     n_cores: usize,
+    salso_control: &RObject,
+    sampler: &RObject,
 ) {
+    let conditions = Conditions::default().with_sampler(sampler);
+    let control = SalsoControl::new(salso_control, conditions.options.strict);
+    let loss = loss_function(loss, a);
+    check_mass(mass, 0.0);
+    if !(noise.is_finite() && noise >= 0.0) {
+        stop!("'noise' must be finite and nonnegative.");
+    }
+    if n_replicates == 0 {
+        stop!("The number of replicates must be at least 1.");
+    }
+    let base = similarity.slice();
+    let n_items = Similarity::new(similarity, "double").n_items();
+    check_n_items_for_salso(n_items);
+    let p = salso_parameters(n_items, max_size, n_runs, &control);
     let mut rng = Pcg64Mcg::from_seed(R::random_bytes::<16>());
-    let n_items = similarity.nrow();
-    let (samples, n_clusters) = sample_epa_engine(
-        n_samples,
-        n_items,
-        similarity.slice(),
-        mass,
-        n_cores,
-        &mut rng,
-    );
-    let n_samples = samples.len() / n_items;
-    let clusterings = Clusterings::unvalidated(n_samples, n_items, samples, n_clusters);
-    let pdi = PartitionDistributionInformation::Draws(&clusterings);
-    let a = 1.0;
-    let loss_function = if use_vi {
-        LossFunction::VI(a)
-    } else {
-        LossFunction::BinderDraws(a)
+    let seeds: Vec<u128> = (0..n_replicates).map(|_| rng.random()).collect();
+    let mut estimates = vec![0; n_replicates * n_items];
+    let n_threads = n_threads(n_cores).min(n_replicates);
+    let chunk_size = n_replicates.div_ceil(n_threads);
+    crossbeam::scope(|s| {
+        for (seeds, estimates) in seeds
+            .chunks(chunk_size)
+            .zip(estimates.chunks_mut(chunk_size * n_items))
+        {
+            let (p, conditions) = (&p, &conditions);
+            let seconds = control.options.seconds;
+            s.spawn(move |_| {
+                for (seed, estimate) in seeds.iter().zip(estimates.chunks_mut(n_items)) {
+                    let mut rng = Pcg64Mcg::new(*seed);
+                    let mut data = base.to_vec();
+                    perturb_similarity(&mut data, n_items, noise, &mut rng);
+                    let similarity =
+                        SquareMatrixBorrower::from_slice(&data, n_items).detect_symmetry();
+                    let (samples, n_clusters) = sample_epa_engine_generic::<LabelType, _, _>(
+                        n_samples, similarity, mass, 0.0, conditions, 1, &mut rng,
+                    );
+                    let clusterings = Clusterings::unvalidated(
+                        samples.len() / n_items,
+                        n_items,
+                        samples,
+                        n_clusters,
+                    );
+                    let pdi = PartitionDistributionInformation::Draws(&clusterings);
+                    let fit = minimize_by_salso(pdi, loss, p, seconds, 1, &mut rng);
+                    estimate.copy_from_slice(&fit.clustering);
+                }
+            });
+        }
+    })
+    .expect("a replicate thread panicked");
+    let estimates_rval = RMatrix::<i32>::new(n_replicates, n_items, pc);
+    let slice = estimates_rval.slice_mut();
+    for (r, estimate) in estimates.chunks(n_items).enumerate() {
+        for (i, label) in estimate.iter().enumerate() {
+            slice[n_replicates * i + r] = i32::try_from(*label + 1).unwrap();
+        }
+    }
+    let psm = psm_from_draws(estimates_rval);
+    let robustness_rval = RMatrix::<f64>::new(n_items, n_items, pc);
+    robustness_rval.slice_mut().copy_from_slice(&psm);
+    let result = RList::with_names(&["estimates", "robustness"], pc);
+    result.set(0, estimates_rval).stop();
+    result.set(1, robustness_rval).stop();
+    result
+}
+
+// The replicates of each subject, given the (1-based) subject of each row of the similarity.
+fn replicates(subjects: &[i32], n_items: usize) -> Vec<Vec<usize>> {
+    if subjects.len() != n_items {
+        stop!("The length of 'subjects' must equal the number of rows of 'similarity'.");
+    }
+    let subjects: Vec<_> = subjects
+        .iter()
+        .map(|x| usize::try_from(*x - 1).stop_str("'subjects' must be positive."))
+        .collect();
+    replicates_of_subjects(&subjects).stop_str("Every subject must have at least one replicate.")
+}
+
+// The similarity between subjects, combining the similarities between their replicates by the
+// 'aggregation' rule.
+#[roxido]
+fn caviarpd_subject_similarity(similarity: &RMatrix<f64>, subjects: &[i32], aggregation: &str) {
+    let aggregation = match aggregation {
+        "mean" => Aggregation::Mean,
+        "min" => Aggregation::Min,
+        "max" => Aggregation::Max,
+        _ => stop!("'aggregation' must be one of 'mean', 'min', or 'max'."),
     };
-    let p = SALSOParameters {
-        n_items,
-        max_size: LabelType::try_from(max_size).unwrap(),
-        max_size_as_rf: false,
-        max_scans: u32::MAX,
-        max_zealous_updates: 10,
-        n_runs: u32::try_from(n_runs).unwrap(),
-        prob_sequential_allocation: 0.5,
-        prob_singletons_initialization: 0.0,
+    let Similarity::Double(similarity) = Similarity::new(similarity, "double") else {
+        unreachable!()
     };
-    let fit = minimize_by_salso(
-        pdi,
-        loss_function,
-        &p,
-        f64::INFINITY,
-        u32::try_from(n_cores).unwrap(),
-        &mut rng,
-    );
-    let result = fit.clustering.into_iter().max().unwrap() + 1;
-    i32::try_from(result).unwrap().to_r(pc)
+    let replicates = replicates(subjects, similarity.n_items());
+    let n_subjects = replicates.len();
+    let result = RMatrix::<f64>::new(n_subjects, n_subjects, pc);
+    result
+        .slice_mut()
+        .copy_from_slice(&subject_similarity(similarity, &replicates, aggregation));
+    result
 }
 
-fn expected_number_of_clusters(mass: f64, n_items: usize) -> f64 {
-    (0..n_items).fold(0.0, |sum, i| sum + mass / (mass + (i as f64)))
+// For each subject, the proportion of its replicates which on their own are closest (by mean
+// similarity) to the subject's cluster in 'estimate', or NA if the cluster has no other replicates.
+#[roxido]
+fn caviarpd_replicate_consistency(similarity: &RMatrix<f64>, subjects: &[i32], estimate: &[i32]) {
+    let Similarity::Double(similarity) = Similarity::new(similarity, "double") else {
+        unreachable!()
+    };
+    let replicates = replicates(subjects, similarity.n_items());
+    if estimate.len() != replicates.len() {
+        stop!("The length of 'estimate' must equal the number of subjects.");
+    }
+    let (labels, _) = dense_labels(estimate, "estimate");
+    let consistency: Vec<_> = replicate_consistency(similarity, &replicates, &labels)
+        .into_iter()
+        .map(|x| if x.is_nan() { R::na_f64() } else { x })
+        .collect();
+    consistency.as_slice().to_r(pc)
 }
 
-fn find_mass(enoc: f64, n_items: usize) -> f64 {
-    let f = |mass| expected_number_of_clusters(mass, n_items) - enoc;
-    match find_root(f64::EPSILON, enoc, f, &mut 1e-5_f64) {
-        Ok(root) => root,
-        Err(e) => {
-            println!("Root finding error.... {}", e);
-            1.0
-        }
+// Fit a random subsample of the items sized to take about 'budget_seconds' and extrapolate the
+// time of a fit to all the items.  Labels of the estimate correspond to the returned items.
+#[roxido]
+fn caviarpd_preview(
+    similarity: &RMatrix<f64>,
+    budget_seconds: f64,
+    min_n_clusters: f64,
+    max_n_clusters: f64,
+    n_samples: usize,
+    grid_length: usize,
+    loss: &str,
+    discount: f64,
+    n_cores: usize,
+    sampler: &RObject,
+) {
+    check_discount(discount);
+    if !(budget_seconds.is_finite() && budget_seconds >= 0.0) {
+        stop!("'budgetSeconds' must be finite and nonnegative.");
     }
+    let loss = match loss {
+        "binder" => Loss::Binder,
+        "VI" => Loss::VI,
+        _ => stop!("'loss' must be either 'binder' or 'VI'."),
+    };
+    let Similarity::Double(similarity) = Similarity::new(similarity, "double") else {
+        unreachable!()
+    };
+    check_n_items_for_salso(similarity.n_items());
+    let options = CaviarpdOptions::new(min_n_clusters, max_n_clusters)
+        .with_n_samples(n_samples)
+        .with_grid_length(grid_length)
+        .with_loss(loss)
+        .with_discount(discount)
+        .with_n_threads(n_threads(n_cores))
+        .with_sampler_options(
+            Conditions::default()
+                .with_sampler(sampler)
+                .sampler_options(),
+        );
+    let mut rng = Pcg64Mcg::from_seed(R::random_bytes::<16>());
+    let preview = preview(similarity, &options, budget_seconds, &mut rng).stop();
+    let items: Vec<_> = preview
+        .items
+        .iter()
+        .map(|x| i32::try_from(*x + 1).unwrap())
+        .collect();
+    let estimate: Vec<_> = preview
+        .fit
+        .estimate
+        .iter()
+        .map(|x| i32::try_from(*x + 1).unwrap())
+        .collect();
+    let result = RList::with_names(&["items", "estimate", "seconds", "estimatedSeconds"], pc);
+    result.set(0, items.as_slice().to_r(pc)).stop();
+    result.set(1, estimate.as_slice().to_r(pc)).stop();
+    result.set(2, preview.seconds.to_r(pc)).stop();
+    result.set(3, preview.estimated_seconds.to_r(pc)).stop();
+    result
 }
 
+// Re-estimate the clustering of the items which are not locked.  Locked items keep their labels,
+// while the other items are clustered among themselves from EPA draws on their similarities and
+// receive labels after the largest locked label.
 #[roxido]
-fn caviarpd_expected_number_of_clusters(mass: f64, n_items: usize) {
-    expected_number_of_clusters(mass, n_items)
+fn caviarpd_refine(
+    estimate: &[i32],
+    locked: &[i32],
+    similarity: &RMatrix<f64>,
+    mass: f64,
+    n_samples: usize,
+    loss: &str,
+    a: f64,
+    n_runs: i32,
+    max_size: i32,
+    n_cores: usize,
+    salso_control: &RObject,
+    resources: bool,
+    sampler: &RObject,
+) {
+    let conditions = Conditions::default().with_sampler(sampler);
+    let control = SalsoControl::new(salso_control, conditions.options.strict);
+    let loss = loss_function(loss, a);
+    let mut usage = Resources::new(resources, n_threads(n_cores));
+    let n_items = estimate.len();
+    if Similarity::new(similarity, "double").n_items() != n_items {
+        stop!("The dimension of 'similarity' must equal the length of 'estimate'.");
+    }
+    if estimate.iter().any(|label| R::is_na_i32(*label)) {
+        stop!("'estimate' may not contain missing values.");
+    }
+    let mut is_locked = vec![false; n_items];
+    for item in locked {
+        let item = usize::try_from(*item)
+            .ok()
+            .filter(|item| (1..=n_items).contains(item))
+            .stop_closure(|| format!("Locked items must be between 1 and {}.", n_items));
+        is_locked[item - 1] = true;
+    }
+    let unlocked: Vec<_> = (0..n_items).filter(|i| !is_locked[*i]).collect();
+    let result = RVector::<i32>::new(n_items, pc);
+    result.slice_mut().copy_from_slice(estimate);
+    if unlocked.is_empty() {
+        return usage.attach(result, pc);
+    }
+    let n_unlocked = unlocked.len();
+    check_n_items_for_salso(n_unlocked);
+    let data = sub_similarity(similarity.slice(), n_items, &unlocked);
+    let similarity =
+        Similarity::Double(SquareMatrixBorrower::from_slice(&data, n_unlocked).detect_symmetry());
+    let mut rng = Pcg64Mcg::from_seed(R::random_bytes::<16>());
+    let (samples, n_clusters) = usage.time("sampling", || {
+        sample_epa_engine(
+            n_samples,
+            &similarity,
+            mass,
+            0.0,
+            &conditions,
+            n_cores,
+            &mut rng,
+        )
+    });
+    let clusterings =
+        Clusterings::unvalidated(samples.len() / n_unlocked, n_unlocked, samples, n_clusters);
+    let pdi = PartitionDistributionInformation::Draws(&clusterings);
+    let p = salso_parameters(n_unlocked, max_size, n_runs, &control);
+    let fit = usage.time("search", || {
+        minimize_by_salso(
+            pdi,
+            loss,
+            &p,
+            control.options.seconds,
+            control.n_cores(n_cores),
+            &mut rng,
+        )
+    });
+    let next_label = (0..n_items)
+        .filter(|i| is_locked[*i])
+        .map(|i| estimate[i])
+        .max()
+        .map_or(1, |x| x + 1);
+    let result_slice = result.slice_mut();
+    for (item, label) in unlocked.iter().zip(fit.clustering) {
+        result_slice[*item] = next_label + i32::try_from(label).unwrap();
+    }
+    usage.attach(result, pc)
 }
 
+// The elements of the list 'runs' are matrices with one partition per row, e.g., the draws or the
+// estimates of independent runs.  The partitions are pooled for the consensus, and the agreement of
+// each run is the mean variation of information between its partitions and the consensus.
 #[roxido]
-fn caviarpd_mass(expected_number_of_clusters: f64, n_items: usize) {
-    find_mass(expected_number_of_clusters, n_items)
+fn caviarpd_consensus(
+    runs: &RObject,
+    loss: &str,
+    n_runs: i32,
+    max_size: i32,
+    n_cores: usize,
+    salso_control: &RObject,
+    strict: bool,
+) {
+    let control = SalsoControl::new(salso_control, strict);
+    let loss = loss_function(loss, 1.0);
+    let list = runs.as_list().stop_str("'runs' must be a list.");
+    let runs: Vec<_> = (0..list.len())
+        .map(|i| {
+            let x = list
+                .get(i)
+                .stop()
+                .as_matrix()
+                .stop_str("Each element of 'runs' must be a matrix.")
+                .to_i32(pc);
+            partitions_from_r(x, "runs")
+        })
+        .collect();
+    let Some(n_items) = runs.iter().flatten().map(|x| x.len()).next() else {
+        stop!("'runs' must contain at least one partition.");
+    };
+    if runs.iter().flatten().any(|x| x.len() != n_items) {
+        stop!("The partitions of 'runs' must all have the same number of items.");
+    }
+    check_n_items_for_salso(n_items);
+    let pooled: Vec<_> = runs.iter().flatten().collect();
+    let labels: Vec<LabelType> = pooled
+        .iter()
+        .flat_map(|x| canonical(x))
+        .map(|label| LabelType::try_from(label).unwrap())
+        .collect();
+    let n_clusters: Vec<LabelType> = pooled
+        .iter()
+        .map(|x| LabelType::try_from(x.iter().max().unwrap() + 1).unwrap())
+        .collect();
+    let clusterings = Clusterings::unvalidated(pooled.len(), n_items, labels, n_clusters);
+    let p = salso_parameters(n_items, max_size, n_runs, &control);
+    let mut rng = Pcg64Mcg::from_seed(R::random_bytes::<16>());
+    let fit = minimize_by_salso(
+        PartitionDistributionInformation::Draws(&clusterings),
+        loss,
+        &p,
+        control.options.seconds,
+        control.n_cores(n_cores),
+        &mut rng,
+    );
+    let estimate = canonical(&fit.clustering);
+    let distances: Vec<_> = runs
+        .iter()
+        .map(|run| {
+            let sum: f64 = run
+                .iter()
+                .map(|x| variation_of_information(x, &estimate))
+                .sum();
+            if run.is_empty() {
+                R::na_f64()
+            } else {
+                sum / (run.len() as f64)
+            }
+        })
+        .collect();
+    let uncertainty = item_disagreement(&pooled, &estimate);
+    let estimate: Vec<_> = estimate
+        .iter()
+        .map(|x| i32::try_from(*x + 1).unwrap())
+        .collect();
+    let result = RList::with_names(
+        &["estimate", "expectedLoss", "runDistance", "uncertainty"],
+        pc,
+    );
+    result.set(0, estimate.as_slice().to_r(pc)).stop();
+    result.set(1, fit.expected_loss.to_r(pc)).stop();
+    result.set(2, distances.as_slice().to_r(pc)).stop();
+    result.set(3, uncertainty.as_slice().to_r(pc)).stop();
+    result
 }
 
-// ---
+// The adaptive search for the loss parameter 'a' and the consensus of 'caviarpd_algorithm2', applied
+// to partitions drawn by other means, e.g., MCMC draws from a Dirichlet process mixture model.
+#[roxido]
+fn caviarpd_from_draws(
+    draws: &RMatrix<i32>,
+    min_n_clusters: f64,
+    max_n_clusters: f64,
+    grid_length: usize,
+    n0: f64,
+    tol: f64,
+    loss: &str,
+    trim_proportion: f64,
+    trim_distance: f64,
+    salso_max_n_clusters: i32,
+    salso_n_runs: i32,
+    n_cores: usize,
+    salso_control: &RObject,
+    strict: bool,
+) {
+    let draws = partitions_from_r(draws, "draws");
+    let Some(n_items) = draws.first().map(|x| x.len()) else {
+        stop!("'draws' must contain at least one partition.");
+    };
+    check_n_items_for_salso(n_items);
+    let labels: Vec<LabelType> = draws
+        .iter()
+        .flatten()
+        .map(|label| LabelType::try_from(*label).unwrap())
+        .collect();
+    let n_clusters: Vec<LabelType> = draws
+        .iter()
+        .map(|x| LabelType::try_from(x.iter().max().unwrap() + 1).unwrap())
+        .collect();
+    let clusterings = Clusterings::unvalidated(draws.len(), n_items, labels, n_clusters);
+    let settings = LossParameterSearch {
+        n_clusters_range: (min_n_clusters, max_n_clusters),
+        grid_length,
+        n0,
+        tol,
+        loss,
+        trim_proportion,
+        trim_distance,
+        salso_max_n_clusters,
+        salso_n_runs,
+        n_cores,
+    };
+    settings.run(
+        &Draws::Clusterings(clusterings),
+        n_items,
+        salso_control,
+        strict,
+        pc,
+    )
+}
 
+// As 'caviarpd_from_draws', but for a pairwise similarity matrix of the items (e.g., the posterior
+// co-clustering probabilities from another method) in place of draws.
 #[roxido]
-fn caviarpd_algorithm2(
-    similarity: &RMatrix<f64>,
+fn caviarpd_from_psm(
+    psm: &RMatrix<f64>,
     min_n_clusters: f64,
     max_n_clusters: f64,
-    mass: &RObject,
-    n_samples: usize,
     grid_length: usize,
     n0: f64,
     tol: f64,
-    use_vi: bool,
+    loss: &str,
+    trim_proportion: f64,
+    trim_distance: f64,
     salso_max_n_clusters: i32,
     salso_n_runs: i32,
     n_cores: usize,
+    salso_control: &RObject,
+    strict: bool,
 ) {
-    let mut rng = Pcg64Mcg::from_seed(R::random_bytes::<16>());
-    let n_items = similarity.nrow();
-    let similarity_rval = similarity;
-    let similarity = similarity_rval.slice();
-    let (min_n_clusters, max_n_clusters) = {
-        let x1 = min_n_clusters;
-        let x2 = max_n_clusters;
-        if x1 < x2 {
-            (x1, x2)
-        } else {
-            (x2, x1)
-        }
+    if loss != "binder" {
+        stop!("Only the 'binder' loss is supported when using the pairwise similarity matrix.");
+    }
+    let n_items = psm.nrow();
+    if psm.ncol() != n_items {
+        stop!("'psm' must be a square matrix.");
+    }
+    check_n_items_for_salso(n_items);
+    let psm = psm.slice();
+    if psm.iter().any(|x| !(0.0..=1.0).contains(x)) {
+        stop!("The elements of 'psm' must be in [0, 1].");
+    }
+    let settings = LossParameterSearch {
+        n_clusters_range: (min_n_clusters, max_n_clusters),
+        grid_length,
+        n0,
+        tol,
+        loss,
+        trim_proportion,
+        trim_distance,
+        salso_max_n_clusters,
+        salso_n_runs,
+        n_cores,
     };
-    let grid_length = grid_length.max(if min_n_clusters == max_n_clusters {
-        1
-    } else {
-        2
-    });
-    let salso_n_runs = salso_n_runs.max(1);
-    let samples_rval = RMatrix::<i32>::new(n_samples * grid_length, n_items, pc);
-    let samples_slice = samples_rval.slice_mut();
-    let p = SALSOParameters {
+    settings.run(
+        &Draws::Psm(psm.to_vec()),
         n_items,
-        max_size: LabelType::try_from(salso_max_n_clusters).unwrap(),
-        max_size_as_rf: false,
-        max_scans: u32::MAX,
-        max_zealous_updates: 10,
-        n_runs: u32::try_from(salso_n_runs).unwrap(),
-        prob_sequential_allocation: 0.5,
-        prob_singletons_initialization: 0.0,
-    };
-    let mut previous = 1.0;
-    let mut candidates_labels = Vec::with_capacity(grid_length * n_items);
-    let mut candidates_n_clusters = Vec::with_capacity(grid_length);
-    let masses = {
-        let mut masses = if mass.is_null() {
-            let step_size = (max_n_clusters - min_n_clusters) / (grid_length as f64);
-            (0..grid_length)
-                .map(|x| find_mass(min_n_clusters + (x as f64) * step_size, n_items))
-                .collect::<Vec<_>>()
+        salso_control,
+        strict,
+        pc,
+    )
+}
+
+// The settings of the search for the loss parameter 'a' on fixed draws (or their pairwise
+// similarity matrix).  Unlike the mass grid of 'caviarpd_algorithm2', the grid is over the number
+// of clusters: the numbers targeted by the bisection on 'a' are spread evenly over the range.
+struct LossParameterSearch<'a> {
+    n_clusters_range: (f64, f64),
+    grid_length: usize,
+    n0: f64,
+    tol: f64,
+    loss: &'a str,
+    trim_proportion: f64,
+    trim_distance: f64,
+    salso_max_n_clusters: i32,
+    salso_n_runs: i32,
+    n_cores: usize,
+}
+
+impl LossParameterSearch<'_> {
+    fn run<'a>(
+        &self,
+        draws: &Draws,
+        n_items: usize,
+        salso_control: &RObject,
+        strict: bool,
+        pc: &'a Pc,
+    ) -> &'a RList {
+        let control = SalsoControl::new(salso_control, strict);
+        let loss = Loss::from_name(self.loss).stop();
+        if !(self.n0.is_finite() && self.n0 > 0.0) {
+            stop!("'n0' must be finite and greater than 0.");
+        }
+        if !(self.tol > 0.0 && self.tol < 1.0) {
+            stop!("'tol' must be in (0, 1).");
+        }
+        if !(0.0..1.0).contains(&self.trim_proportion) {
+            stop!("'trim_proportion' must be in [0, 1).");
+        }
+        if self.trim_distance.is_nan() || self.trim_distance < 0.0 {
+            stop!("'trim_distance' must be nonnegative.");
+        }
+        let (x1, x2) = self.n_clusters_range;
+        if !(x1.is_finite() && x2.is_finite()) {
+            stop!("The range for the number of clusters must be finite.");
+        }
+        let (min_n_clusters, max_n_clusters) = if x1 < x2 { (x1, x2) } else { (x2, x1) };
+        let grid_length = if min_n_clusters == max_n_clusters {
+            1
         } else {
-            let mass_rval = mass.as_vector().stop().to_f64(pc);
-            let mass = mass_rval.slice();
-            if mass.len() == 1 {
-                vec![mass[0]; grid_length]
-            } else {
-                mass.to_vec()
-            }
+            self.grid_length.max(1)
         };
-        masses.shuffle(&mut rng);
-        masses
-    };
-    for (i, mass) in masses.into_iter().enumerate() {
-        let (samples, n_clusters) =
-            sample_epa_engine(n_samples, n_items, similarity, mass, n_cores, &mut rng);
-        let clusterings =
-            Clusterings::unvalidated(samples.len() / n_items, n_items, samples, n_clusters);
-        for jj in 0..n_samples {
-            let labels = clusterings.labels(jj);
-            for (ii, value) in labels.iter().enumerate() {
-                samples_slice[n_samples * (ii * grid_length + i) + jj] = i32::from(*value + 1);
-            }
-        }
-        let pdi = PartitionDistributionInformation::Draws(&clusterings);
-        let (mut lower, mut upper) = (0.0, 2.0);
-        let beta = Beta::new(n0 * previous / 2.0, n0 * (1.0 - previous / 2.0)).unwrap();
-        let mut a = 2.0 * beta.sample(&mut rng);
-        let candidate;
-        loop {
-            let loss_function = if use_vi {
-                LossFunction::VI(a)
-            } else {
-                LossFunction::BinderDraws(a)
-            };
-            let fit = minimize_by_salso(
-                pdi,
-                loss_function,
-                &p,
-                f64::INFINITY,
-                u32::try_from(n_cores).unwrap(),
+        // A single point targets the whole range; otherwise, each point targets the integer
+        // nearest to its number of clusters.
+        let targets: Vec<(f64, f64)> = if grid_length == 1 {
+            vec![(min_n_clusters, max_n_clusters)]
+        } else {
+            (0..grid_length)
+                .map(|g| {
+                    let step = (max_n_clusters - min_n_clusters) / ((grid_length - 1) as f64);
+                    let target = min_n_clusters + step * (g as f64);
+                    (target - 0.5, target + 0.5)
+                })
+                .collect()
+        };
+        let p = salso_parameters(
+            n_items,
+            self.salso_max_n_clusters,
+            self.salso_n_runs.max(1),
+            &control,
+        );
+        let n_cores = control.n_cores(self.n_cores);
+        let mut rng = Pcg64Mcg::from_seed(R::random_bytes::<16>());
+        let mut candidates_labels = Vec::with_capacity(grid_length * n_items);
+        let mut candidates_n_clusters = Vec::with_capacity(grid_length);
+        let (mut grid_a, mut grid_expected_loss, mut grid_n_searches) =
+            (Vec::new(), Vec::new(), Vec::new());
+        let mut previous = 1.0;
+        for range in &targets {
+            let (candidate, a, expected_loss, trace) = search_loss_parameter(
+                |a, rng| draws.minimize(loss, a, &p, control.options.seconds, n_cores, rng),
+                loss,
+                ParameterSearch::Bisection,
+                *range,
+                self.n0,
+                self.tol,
+                previous,
                 &mut rng,
             );
-            let n_clusters = fit.clustering.iter().max().unwrap() + 1;
-            if upper - lower <= tol {
-                candidate = fit.clustering;
-                break;
-            } else if (n_clusters as f64) < min_n_clusters {
-                upper = a;
-                a = (lower + a) / 2.0;
-            } else if (n_clusters as f64) > max_n_clusters {
-                lower = a;
-                a = (upper + a) / 2.0;
-            } else {
-                candidate = fit.clustering;
-                break;
-            }
+            previous = a;
+            grid_a.push(a);
+            grid_expected_loss.push(expected_loss);
+            grid_n_searches.push(i32::try_from(trace.len()).unwrap());
+            candidates_labels.extend(candidate.iter().map(|x| LabelType::try_from(*x).unwrap()));
+            candidates_n_clusters
+                .push(LabelType::try_from(candidate.iter().max().unwrap() + 1).unwrap());
         }
-        previous = a;
-        candidates_labels.extend(candidate.iter().map(|x| LabelType::try_from(*x).unwrap()));
-        candidates_n_clusters
-            .push(LabelType::try_from(candidate.iter().max().unwrap() + 1).unwrap());
+        let trimmed = trimmed_candidates(
+            &candidates_labels,
+            n_items,
+            self.trim_proportion,
+            self.trim_distance,
+        );
+        let kept = kept_candidates(
+            &candidates_labels,
+            &candidates_n_clusters,
+            n_items,
+            &trimmed,
+        );
+        let fit = minimize_by_salso(
+            PartitionDistributionInformation::Draws(&kept),
+            loss.with_parameter(1.0),
+            &p,
+            control.options.seconds,
+            n_cores,
+            &mut rng,
+        );
+        let estimate = canonical(&fit.clustering);
+        let uncertainty = draws.uncertainty(&estimate);
+        let grid_rval = RList::with_names(
+            &["target", "a", "nClusters", "expectedLoss", "nSearches"],
+            pc,
+        );
+        let target: Vec<_> = targets.iter().map(|(x1, x2)| (x1 + x2) / 2.0).collect();
+        grid_rval.set(0, target.as_slice().to_r(pc)).stop();
+        grid_rval.set(1, grid_a.as_slice().to_r(pc)).stop();
+        let n_clusters: Vec<_> = candidates_n_clusters
+            .iter()
+            .map(|x| i32::from(*x))
+            .collect();
+        grid_rval.set(2, n_clusters.as_slice().to_r(pc)).stop();
+        grid_rval
+            .set(3, grid_expected_loss.as_slice().to_r(pc))
+            .stop();
+        grid_rval.set(4, grid_n_searches.as_slice().to_r(pc)).stop();
+        let estimate: Vec<_> = estimate
+            .iter()
+            .map(|x| i32::try_from(*x + 1).unwrap())
+            .collect();
+        let trimmed: Vec<_> = trimmed
+            .iter()
+            .map(|k| i32::try_from(k + 1).unwrap())
+            .collect();
+        let result = RList::with_names(
+            &[
+                "estimate",
+                "expectedLoss",
+                "grid",
+                "candidates",
+                "trimmed",
+                "uncertainty",
+            ],
+            pc,
+        );
+        result.set(0, estimate.as_slice().to_r(pc)).stop();
+        result.set(1, fit.expected_loss.to_r(pc)).stop();
+        result.set(2, grid_rval).stop();
+        result
+            .set(
+                3,
+                samples_to_r(&candidates_labels, n_items, self.n_cores, pc),
+            )
+            .stop();
+        result.set(4, trimmed.as_slice().to_r(pc)).stop();
+        result.set(5, uncertainty.as_slice().to_r(pc)).stop();
+        result
     }
-    let candidates = Clusterings::unvalidated(
-        grid_length,
-        n_items,
-        candidates_labels,
-        candidates_n_clusters,
-    );
-    let pdi = PartitionDistributionInformation::Draws(&candidates);
-    let loss_function = if use_vi {
-        LossFunction::VI(1.0)
-    } else {
-        LossFunction::BinderDraws(1.0)
+}
+
+// Items are first clustered with 'mass' and then the items of each of those clusters are
+// clustered among themselves with 'inner_mass'.  The inner labels start at one in each cluster.
+#[roxido]
+fn caviarpd_hierarchical(
+    similarity: &RMatrix<f64>,
+    mass: f64,
+    inner_mass: f64,
+    n_samples: usize,
+    loss: &str,
+    a: f64,
+    n_runs: i32,
+    max_size: i32,
+    n_cores: usize,
+    salso_control: &RObject,
+    sampler: &RObject,
+) {
+    let conditions = Conditions::default().with_sampler(sampler);
+    let control = SalsoControl::new(salso_control, conditions.options.strict);
+    let loss = loss_function(loss, a);
+    check_mass(mass, 0.0);
+    check_mass(inner_mass, 0.0);
+    let n_items = Similarity::new(similarity, "double").n_items();
+    check_n_items_for_salso(n_items);
+    let mut rng = Pcg64Mcg::from_seed(R::random_bytes::<16>());
+    let estimate = |data: &[f64], n: usize, mass: f64, rng: &mut Pcg64Mcg| {
+        let similarity =
+            Similarity::Double(SquareMatrixBorrower::from_slice(data, n).detect_symmetry());
+        let (samples, n_clusters) =
+            sample_epa_engine(n_samples, &similarity, mass, 0.0, &conditions, n_cores, rng);
+        let clusterings = Clusterings::unvalidated(samples.len() / n, n, samples, n_clusters);
+        let pdi = PartitionDistributionInformation::Draws(&clusterings);
+        let p = salso_parameters(n, max_size, n_runs, &control);
+        let fit = minimize_by_salso(
+            pdi,
+            loss,
+            &p,
+            control.options.seconds,
+            control.n_cores(n_cores),
+            rng,
+        );
+        fit.clustering
     };
-    let fit = minimize_by_salso(
-        pdi,
-        loss_function,
-        &p,
-        f64::INFINITY,
-        u32::try_from(n_cores).unwrap(),
-        &mut rng,
-    );
-    let estimate_rval = RVector::<i32>::new(n_items, pc);
-    for (src, dst) in fit.clustering.iter().zip(estimate_rval.slice_mut()) {
-        *dst = i32::try_from(*src + 1).unwrap();
+    let outer = estimate(similarity.slice(), n_items, mass, &mut rng);
+    let mut inner = vec![0; n_items];
+    let n_clusters = outer.iter().max().map_or(0, |x| x + 1);
+    for label in 0..n_clusters {
+        let members: Vec<_> = (0..n_items).filter(|i| outer[*i] == label).collect();
+        if members.len() < 2 {
+            continue;
+        }
+        let data = sub_similarity(similarity.slice(), n_items, &members);
+        let labels = estimate(&data, members.len(), inner_mass, &mut rng);
+        for (item, label) in members.iter().zip(labels) {
+            inner[*item] = label;
+        }
     }
-    let result = RList::with_names(&["estimate", "samples"], pc);
-    result.set(0, estimate_rval).stop();
-    result.set(1, samples_rval).stop();
+    let outer_rval = RVector::<i32>::new(n_items, pc);
+    let inner_rval = RVector::<i32>::new(n_items, pc);
+    for (i, (x, y)) in outer.iter().zip(&inner).enumerate() {
+        outer_rval.slice_mut()[i] = i32::try_from(x + 1).unwrap();
+        inner_rval.slice_mut()[i] = i32::try_from(y + 1).unwrap();
+    }
+    let result = RList::with_names(&["outer", "inner"], pc);
+    result.set(0, outer_rval).stop();
+    result.set(1, inner_rval).stop();
     result
 }